@@ -0,0 +1,49 @@
+// Records what the bot was built from: when, as the earliest time its clock can sensibly show
+// (see the `clock` module), and the git commit (see the `build_info` module).
+// `SOURCE_DATE_EPOCH` is used for the time instead if set, for reproducible builds, and
+// `CUBEGLOBE_BOT_GIT_HASH` for the commit, for builds from outside a git checkout.
+
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Output of running git with `args`, if that works
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+}
+
+/// Short hash of the checked out commit, marked if there are uncommitted changes
+fn git_hash() -> Option<String> {
+    let hash = git(&["rev-parse", "--short=12", "HEAD"])?.trim().to_string();
+    if hash.is_empty() {
+        return None;
+    }
+    match git(&["status", "--porcelain", "--untracked-files=no"]) {
+        Some(ref changes) if !changes.trim().is_empty() => Some(format!("{}-dirty", hash)),
+        _ => Some(hash),
+    }
+}
+
+fn main() {
+    let built = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=CUBEGLOBE_BOT_BUILT_AT={}", built);
+
+    let hash = env::var("CUBEGLOBE_BOT_GIT_HASH")
+        .ok()
+        .or_else(git_hash)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=CUBEGLOBE_BOT_GIT_HASH={}", hash);
+}
@@ -0,0 +1,189 @@
+//! Minimal receiver for the webhook backend
+//!
+//! Listens for the bot's requests, checks the signature if `CUBEGLOBE_WEBHOOK_SECRET` is set,
+//! prints the metadata, and saves the image to the current directory. Good enough for trying the
+//! webhook backend out and as a starting point for your own integration, not for production.
+//!
+//! ```shell
+//! CUBEGLOBE_WEBHOOK_SECRET=hunter2 cargo run --example webhook_receiver -- 127.0.0.1:8080
+//! ```
+
+extern crate hmac;
+extern crate sha2;
+
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Headers we care about, lowercased
+struct Request {
+    content_length: Option<usize>,
+    content_type: Option<String>,
+    signature: Option<String>,
+    body: Vec<u8>,
+}
+
+fn read_request(stream: &TcpStream) -> std::io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut request = Request {
+        content_length: None,
+        content_type: None,
+        signature: None,
+        body: Vec::new(),
+    };
+
+    let mut line = String::new();
+    // Request line, ignored
+    reader.read_line(&mut line)?;
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        let mut split = header.splitn(2, ':');
+        let name = split.next().unwrap_or("").trim().to_lowercase();
+        let value = split.next().unwrap_or("").trim().to_string();
+        match name.as_str() {
+            "content-length" => request.content_length = value.parse().ok(),
+            "content-type" => request.content_type = Some(value),
+            "x-cubeglobe-signature" => request.signature = Some(value),
+            _ => {}
+        }
+    }
+
+    if let Some(length) = request.content_length {
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body)?;
+    }
+
+    Ok(request)
+}
+
+/// Split a multipart body into (name, content) pairs
+fn parse_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<(String, &'a [u8])> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut parts = Vec::new();
+
+    let mut positions = Vec::new();
+    let mut i = 0;
+    while i + delimiter.len() <= body.len() {
+        if &body[i..i + delimiter.len()] == delimiter.as_slice() {
+            positions.push(i);
+            i += delimiter.len();
+        } else {
+            i += 1;
+        }
+    }
+
+    for window in positions.windows(2) {
+        // Skip the delimiter and its CRLF, drop the CRLF before the next delimiter
+        let (start, end) = (window[0] + delimiter.len() + 2, window[1].saturating_sub(2));
+        if start > end {
+            continue;
+        }
+        let part = &body[start..end];
+        let split = match part.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(split) => split,
+            None => continue,
+        };
+
+        let headers = String::from_utf8_lossy(&part[..split]);
+        let name = headers
+            .split("name=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap_or("")
+            .to_string();
+        parts.push((name, &part[split + 4..]));
+    }
+
+    parts
+}
+
+fn signature(secret: &str, metadata: &[u8], image: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("any key length works");
+    // Each part is preceded by its length, as 8 big-endian bytes
+    for part in &[metadata, image] {
+        mac.input(&(part.len() as u64).to_be_bytes());
+        mac.input(part);
+    }
+    let hex: String = mac
+        .result()
+        .code()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+fn handle(mut stream: TcpStream, secret: &Option<String>) -> std::io::Result<()> {
+    let request = read_request(&stream)?;
+    let boundary = request
+        .content_type
+        .as_ref()
+        .and_then(|t| t.split("boundary=").nth(1))
+        .map(|b| b.trim_matches('"').to_string());
+
+    let status = match boundary {
+        None => "400 Bad Request",
+        Some(boundary) => {
+            let parts = parse_multipart(&request.body, &boundary);
+            let find = |name: &str| parts.iter().find(|p| p.0 == name).map(|p| p.1);
+
+            match (find("metadata"), find("image")) {
+                (Some(metadata), Some(image)) => {
+                    let valid = match *secret {
+                        Some(ref secret) => {
+                            request.signature == Some(signature(secret, metadata, image))
+                        }
+                        None => true,
+                    };
+
+                    if valid {
+                        println!("Metadata: {}", String::from_utf8_lossy(metadata));
+                        let filename = if image.starts_with(b"GIF8") {
+                            "received.gif"
+                        } else {
+                            "received.png"
+                        };
+                        File::create(filename)?.write_all(image)?;
+                        println!("Saved {} byte image to {}", image.len(), filename);
+                        "204 No Content"
+                    } else {
+                        eprintln!("Signature mismatch, ignoring request");
+                        "401 Unauthorized"
+                    }
+                }
+                _ => "400 Bad Request",
+            }
+        }
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status)
+}
+
+fn main() {
+    let address = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let secret = env::var("CUBEGLOBE_WEBHOOK_SECRET").ok();
+
+    let listener = TcpListener::bind(&address).expect("Unable to listen");
+    println!("Listening on {}", address);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle(stream, &secret) {
+                    eprintln!("Error handling request: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Connection failed: {}", e),
+        }
+    }
+}
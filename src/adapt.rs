@@ -0,0 +1,47 @@
+//! Posts made differently for each backend
+//!
+//! One body rarely suits every place an image is posted to: Bluesky has a tighter length limit,
+//! hashtags are noise in a Matrix room, and so on. A `[bot.backends.NAME]` section, where NAME is
+//! `mastodon`, `bluesky`, `matrix` or `webhook`, changes what that backend gets: its own `body`
+//! and `alt_text` templates, its own `hashtags`, whether the post is `public`, and `still = true`
+//! to post the first frame instead of an animation. Anything not set is as for every backend.
+//!
+//! Each backend's post is drafted from the same image and parameters, and logged, and emitted as
+//! a `PostDrafted` event with the backend named, when it differs from the shared one. Each is
+//! then fitted to that backend's limits like any other. Retries only go to the backends still
+//! left, so a backend's post doesn't change between attempts.
+
+/// Names of the backends which can be given overrides
+pub const BACKENDS: &[&str] = &["mastodon", "bluesky", "matrix", "webhook"];
+
+/// What one backend gets instead of the shared post
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct BackendOverrides {
+    /// Post body template, with the same placeholders as `body`
+    #[serde(default)]
+    pub body: Option<String>,
+
+    /// Alt text template, with the same placeholders as `alt_text`
+    #[serde(default)]
+    pub alt_text: Option<String>,
+
+    /// Hashtags used instead of the locale's, with an empty list leaving them out
+    #[serde(default)]
+    pub hashtags: Option<Vec<String>>,
+
+    /// Post publicly even when `boost_after_minutes` has statuses go out unlisted, or with false,
+    /// not even milestones. Only Mastodon has visibility.
+    #[serde(default)]
+    pub public: Option<bool>,
+
+    /// Post a still of the first frame instead of an animation
+    #[serde(default)]
+    pub still: bool,
+}
+
+impl BackendOverrides {
+    /// Whether anything about the post has to be drafted again
+    pub fn changes_text(&self) -> bool {
+        self.body.is_some() || self.alt_text.is_some() || self.hashtags.is_some()
+    }
+}
@@ -0,0 +1,77 @@
+//! Animated output
+//!
+//! Instead of a single still, the map can be shown turning around, one frame per side, as a
+//! looping GIF.
+
+use std::io;
+
+use gif::{self, Repeat, SetParameter};
+use image::{DynamicImage, FilterType, GenericImageView};
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AnimationConfig {
+    /// How long each frame is shown, in milliseconds
+    #[serde(default = "default_frame_delay")]
+    pub frame_delay_ms: u32,
+
+    /// Frames larger than this many pixels in either dimension are scaled down to fit. Applies to
+    /// animations only, stills are posted at full size.
+    #[serde(default = "default_max_dimension")]
+    pub max_dimension: u32,
+}
+
+fn default_frame_delay() -> u32 {
+    800
+}
+
+fn default_max_dimension() -> u32 {
+    800
+}
+
+impl Default for AnimationConfig {
+    fn default() -> AnimationConfig {
+        AnimationConfig {
+            frame_delay_ms: default_frame_delay(),
+            max_dimension: default_max_dimension(),
+        }
+    }
+}
+
+/// Encode `frames` as a looping GIF
+///
+/// All frames are expected to be the same size.
+pub fn encode_gif(frames: &[DynamicImage], config: &AnimationConfig) -> Result<Vec<u8>, io::Error> {
+    let mut data = Vec::new();
+    if frames.is_empty() {
+        return Ok(data);
+    }
+
+    let frames: Vec<DynamicImage> = frames
+        .iter()
+        .map(|frame| {
+            let (width, height) = frame.dimensions();
+            if width > config.max_dimension || height > config.max_dimension {
+                // Nearest keeps the block edges crisp
+                frame.resize(config.max_dimension, config.max_dimension, FilterType::Nearest)
+            } else {
+                frame.clone()
+            }
+        }).collect();
+    let (width, height) = frames[0].dimensions();
+
+    {
+        let mut encoder = gif::Encoder::new(&mut data, width as u16, height as u16, &[])?;
+        encoder.set(Repeat::Infinite)?;
+
+        // GIF delays are in hundredths of a second
+        let delay = (config.frame_delay_ms / 10).min(u32::from(u16::max_value())) as u16;
+        for frame in frames {
+            let mut pixels = frame.to_rgba().into_raw();
+            let mut gif_frame = gif::Frame::from_rgba(width as u16, height as u16, &mut pixels);
+            gif_frame.delay = delay;
+            encoder.write_frame(&gif_frame)?;
+        }
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,309 @@
+//! Posting only after approval
+//!
+//! With `approval_required`, each generated image waits in the `AwaitingApproval` phase until it
+//! is approved or rejected. Next to the image, the bot writes `{name}.pending.toml` describing
+//! the post it would make, where `{name}` is the image's file name without the extension. An
+//! empty `{name}.approve` file, or `cubeglobe-bot approve`, releases the post, and
+//! `{name}.reject`, or `cubeglobe-bot approve --reject`, discards the image so a new one is
+//! generated. The files are polled for, so they can also be made over a network share.
+
+use std::fs::{remove_file, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration as StdDuration;
+
+use anyhow::Error;
+use chrono::{DateTime, Duration, Utc};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use toml;
+
+use shutdown::{Cancelled, Shutdown};
+use {BotConfig, Phase, State, StatePaths};
+
+/// How often the approval files are checked for
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+
+/// What happens to an image nobody decided on within `approval_timeout_minutes`
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeoutAction {
+    Approve,
+    Reject,
+}
+
+impl Default for TimeoutAction {
+    fn default() -> TimeoutAction {
+        TimeoutAction::Reject
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Approve,
+    Reject,
+}
+
+/// The post the bot would make, as written to `{name}.pending.toml`
+#[derive(Serialize)]
+pub struct PendingPost<'a> {
+    pub id: u32,
+    pub image: &'a str,
+    pub body: String,
+    pub alt_text: String,
+    pub requested: DateTime<Utc>,
+}
+
+/// Files deciding on the image saved as `filename` in `images`
+pub struct ApprovalFiles {
+    pub pending: PathBuf,
+    pub approve: PathBuf,
+    pub reject: PathBuf,
+}
+
+impl ApprovalFiles {
+    pub fn new(images: &Path, filename: &str) -> ApprovalFiles {
+        let stem = Path::new(filename)
+            .file_stem()
+            .map_or_else(|| filename.to_string(), |stem| stem.to_string_lossy().into_owned());
+        ApprovalFiles {
+            pending: images.join(format!("{}.pending.toml", stem)),
+            approve: images.join(format!("{}.approve", stem)),
+            reject: images.join(format!("{}.reject", stem)),
+        }
+    }
+
+    /// Write out the would-be post
+    pub fn write_pending(&self, post: &PendingPost) -> Result<(), Error> {
+        let mut file = File::create(&self.pending)?;
+        file.write_all(toml::to_string(post)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Remove all of the files, once a decision was acted on
+    pub fn clean_up(&self) {
+        for path in &[&self.pending, &self.approve, &self.reject] {
+            // Most of them won't be there
+            let _ = remove_file(path);
+        }
+    }
+
+    /// The decision made so far, if any. Rejecting wins if both files are there.
+    fn decision(&self) -> Option<Decision> {
+        if self.reject.exists() {
+            Some(Decision::Reject)
+        } else if self.approve.exists() {
+            Some(Decision::Approve)
+        } else {
+            None
+        }
+    }
+}
+
+/// Wait until the pending image is approved or rejected, or until the timeout decides for it
+///
+/// `requested` is when approval was first asked for, which is kept in the state, so restarts
+/// don't extend the timeout.
+pub fn wait(
+    files: &ApprovalFiles,
+    config: &BotConfig,
+    requested: DateTime<Utc>,
+    shutdown: &Shutdown,
+) -> Result<Decision, Cancelled> {
+    let deadline = config
+        .approval_timeout_minutes
+        .map(|minutes| requested + Duration::minutes(minutes));
+    eprintln!(
+        "Waiting for {} or {} to be created...",
+        files.approve.display(),
+        files.reject.display()
+    );
+
+    loop {
+        if let Some(decision) = files.decision() {
+            return Ok(decision);
+        }
+        if deadline.map_or(false, |deadline| Utc::now() >= deadline) {
+            eprintln!("Nobody decided on the image in time");
+            return Ok(match config.approval_timeout_action {
+                TimeoutAction::Approve => Decision::Approve,
+                TimeoutAction::Reject => Decision::Reject,
+            });
+        }
+        if !shutdown.sleep(POLL_INTERVAL) {
+            return Err(Cancelled);
+        }
+    }
+}
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("approve")
+        .about("approve the image waiting for approval, so the running bot posts it")
+        .arg(
+            Arg::with_name("reject")
+                .long("reject")
+                .help("reject it instead, so a new one is generated"),
+        )
+}
+
+/// Create the approve or reject file for the image waiting for approval
+pub fn run(matches: &ArgMatches, config: &BotConfig) -> Result<(), Error> {
+    let state = State::get_state(StatePaths::from_config(config));
+    let filename = match (&state.phase, &state.filename) {
+        (&Phase::AwaitingApproval, &Some(ref filename)) => filename,
+        _ => return Err(Error::msg("no image is waiting for approval")),
+    };
+
+    let files = ApprovalFiles::new(&state.paths.images, filename);
+    let path = if matches.is_present("reject") {
+        &files.reject
+    } else {
+        &files.approve
+    };
+    File::create(path)?;
+    println!("Created {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_to_string, write};
+    use tempfile::{tempdir, TempDir};
+
+    /// Settings keeping the state and images in `dir`, with `extra` in them
+    fn config_in(dir: &Path, extra: &str) -> BotConfig {
+        toml::from_str(&format!(
+            "map_size = 16\nimages_dir = '{}'\nstate_path = '{}'\n{}",
+            dir.join("images").display(),
+            dir.join("state").display(),
+            extra
+        )).expect("Invalid config")
+    }
+
+    /// Approval files for image 5, in a temporary images directory
+    fn files() -> (TempDir, ApprovalFiles) {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let images = dir.path().join("images");
+        create_dir_all(&images).expect("Unable to create images directory");
+        let files = ApprovalFiles::new(&images, "5.png");
+        (dir, files)
+    }
+
+    #[test]
+    fn files_are_named_after_the_image() {
+        let files = ApprovalFiles::new(Path::new("images"), "islands-20240501-5.png");
+        assert_eq!(files.pending, Path::new("images/islands-20240501-5.pending.toml"));
+        assert_eq!(files.approve, Path::new("images/islands-20240501-5.approve"));
+        assert_eq!(files.reject, Path::new("images/islands-20240501-5.reject"));
+    }
+
+    #[test]
+    fn reject_wins() {
+        let (_dir, files) = files();
+        assert_eq!(files.decision(), None);
+        write(&files.approve, "").unwrap();
+        assert_eq!(files.decision(), Some(Decision::Approve));
+        write(&files.reject, "").unwrap();
+        assert_eq!(files.decision(), Some(Decision::Reject));
+    }
+
+    #[test]
+    fn pending_post_is_written_and_cleaned_up() {
+        let (_dir, files) = files();
+        let requested = Utc::now();
+        files
+            .write_pending(&PendingPost {
+                id: 5,
+                image: "5.png",
+                body: "The Misty Steppes of Narlun".to_string(),
+                alt_text: "An isometric landscape".to_string(),
+                requested,
+            })
+            .expect("Unable to write pending post");
+        let pending: toml::Value =
+            toml::from_str(&read_to_string(&files.pending).unwrap()).expect("Invalid TOML");
+        assert_eq!(pending["id"].as_integer(), Some(5));
+        assert_eq!(pending["image"].as_str(), Some("5.png"));
+        assert_eq!(pending["body"].as_str(), Some("The Misty Steppes of Narlun"));
+
+        write(&files.approve, "").unwrap();
+        files.clean_up();
+        assert!(!files.pending.exists());
+        assert!(!files.approve.exists());
+    }
+
+    #[test]
+    fn decision_files_end_the_wait() {
+        let (dir, files) = files();
+        let config = config_in(dir.path(), "");
+        write(&files.approve, "").unwrap();
+        let decision = wait(&files, &config, Utc::now(), &Shutdown::default());
+        assert_eq!(decision.ok(), Some(Decision::Approve));
+    }
+
+    #[test]
+    fn timeout_decides() {
+        let (dir, files) = files();
+        let requested = Utc::now() - Duration::minutes(61);
+        let cases: &[(&str, Decision)] = &[
+            ("approval_timeout_minutes = 60\n", Decision::Reject),
+            (
+                "approval_timeout_minutes = 60\napproval_timeout_action = 'approve'\n",
+                Decision::Approve,
+            ),
+            (
+                "approval_timeout_minutes = 60\napproval_timeout_action = 'reject'\n",
+                Decision::Reject,
+            ),
+        ];
+        for &(extra, ref expected) in cases {
+            let config = config_in(dir.path(), extra);
+            let decision = wait(&files, &config, requested, &Shutdown::default());
+            assert_eq!(decision.ok().as_ref(), Some(expected), "{}", extra);
+        }
+    }
+
+    #[test]
+    fn decision_file_wins_over_timeout() {
+        let (dir, files) = files();
+        let config = config_in(dir.path(), "approval_timeout_minutes = 1\n");
+        write(&files.approve, "").unwrap();
+        let requested = Utc::now() - Duration::minutes(10);
+        let decision = wait(&files, &config, requested, &Shutdown::default());
+        assert_eq!(decision.ok(), Some(Decision::Approve));
+    }
+
+    #[test]
+    fn approve_creates_the_file_for_the_waiting_image() {
+        let (dir, files) = files();
+        let config = config_in(dir.path(), "");
+        write(
+            dir.path().join("state"),
+            "id = 5\nphase = \"AwaitingApproval\"\nfilename = \"5.png\"\n",
+        ).expect("Unable to write state");
+
+        let matches = subcommand().get_matches_from(vec!["approve", "--reject"]);
+        run(&matches, &config).expect("Unable to reject");
+        assert!(files.reject.exists());
+        assert!(!files.approve.exists());
+
+        let matches = subcommand().get_matches_from(vec!["approve"]);
+        run(&matches, &config).expect("Unable to approve");
+        assert!(files.approve.exists());
+    }
+
+    #[test]
+    fn approve_refuses_without_a_waiting_image() {
+        let (dir, files) = files();
+        let config = config_in(dir.path(), "");
+        write(
+            dir.path().join("state"),
+            "id = 5\nphase = \"Generated\"\nfilename = \"5.png\"\n",
+        ).expect("Unable to write state");
+
+        let matches = subcommand().get_matches_from(vec!["approve"]);
+        let error = run(&matches, &config).unwrap_err();
+        assert!(error.to_string().contains("no image is waiting"));
+        assert!(!files.approve.exists());
+    }
+}
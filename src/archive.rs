@@ -0,0 +1,298 @@
+//! Inventory of the images directory
+//!
+//! Besides the images themselves, named by `filename_template`, the images directory holds files
+//! kept next to them: `{stem}.media.toml` from `remote_media`, `{stem}.attempts.toml` from
+//! `attempts`, `{stem}.webp` or `{stem}.avif` from `archive_copy`, and `{stem}.pending.toml`,
+//! `{stem}.approve` and `{stem}.reject` from `approval`. There can also be files left over from
+//! writes that were cut short, ending in `.tmp`, and files the bot has nothing to do with. `scan`
+//! sorts all of them out in one place, so that everything walking the directory agrees on what
+//! each file is. The bot doesn't make thumbnails, so there are none to tell apart.
+//!
+//! Files may come and go while the directory is being read, as the bot may be running. Those
+//! which disappear before they can be looked at are left out, rather than failing the scan.
+
+use std::collections::BTreeSet;
+use std::fs::read_dir;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use posting::ImageFormat;
+use template_placeholders;
+
+/// Endings of the files kept next to images, after the image's stem
+const SIDECAR_SUFFIXES: &[&str] = &[
+    ".media.toml",
+    ".attempts.toml",
+    ".webp",
+    ".avif",
+    ".pending.toml",
+    ".approve",
+    ".reject",
+];
+
+/// Ending of files being written, or left over from writes that were cut short
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// What a file in the images directory is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// An image, named by the filename template
+    Full,
+    /// A file kept next to an image which is there
+    Sidecar,
+    /// A file kept next to an image which isn't there anymore
+    Orphan,
+    /// Left over from a write that was cut short, or still being written
+    Temp,
+    /// Anything else, like images of other bots sharing the directory
+    Foreign,
+}
+
+/// One file in the images directory
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Id of the image the file is, or belongs to, if its name says
+    pub id: Option<u32>,
+    pub kind: Kind,
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Every file directly in `dir`, ordered by id, with files of no id last, then by name
+///
+/// Names are matched against `template`, the full filename template including any bot name.
+/// Subdirectories, like those of remote images and orphaned ones, are left out. A missing
+/// directory has no files.
+pub fn scan(dir: &Path, template: &str) -> Result<Vec<Entry>, io::Error> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        // Follows symlinks, like opening the file would
+        let metadata = match entry.path().metadata() {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let (kind, id) = classify(template, &name);
+        entries.push(Entry {
+            id,
+            kind,
+            name,
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    // Sidecars whose image is gone
+    let stems: BTreeSet<String> = entries
+        .iter()
+        .filter(|entry| entry.kind == Kind::Full)
+        .filter_map(|entry| Some(Path::new(&entry.name).file_stem()?.to_str()?.to_string()))
+        .collect();
+    for entry in &mut entries {
+        let has_image = sidecar_stem(&entry.name).map_or(false, |stem| stems.contains(stem));
+        if entry.kind == Kind::Sidecar && !has_image {
+            entry.kind = Kind::Orphan;
+        }
+    }
+
+    entries.sort_by(|a, b| {
+        (a.id.is_none(), a.id, &a.name).cmp(&(b.id.is_none(), b.id, &b.name))
+    });
+    entries.dedup_by(|a, b| a.name == b.name);
+    Ok(entries)
+}
+
+/// What the file called `name` is, and the id of its image if there is one
+fn classify(template: &str, name: &str) -> (Kind, Option<u32>) {
+    if name.ends_with(TEMP_SUFFIX) {
+        return (Kind::Temp, None);
+    }
+    if let Some(stem) = sidecar_stem(name) {
+        if let Some(id) = parse_stem(template, stem) {
+            return (Kind::Sidecar, Some(id));
+        }
+    }
+    match parse_filename(template, name) {
+        Some(id) => (Kind::Full, Some(id)),
+        None => (Kind::Foreign, None),
+    }
+}
+
+/// Stem of the image a file kept next to it belongs to, if `name` is one
+fn sidecar_stem(name: &str) -> Option<&str> {
+    SIDECAR_SUFFIXES
+        .iter()
+        .find(|suffix| name.ends_with(*suffix))
+        .map(|suffix| &name[..name.len() - suffix.len()])
+        .filter(|stem| !stem.is_empty())
+}
+
+/// Get the image id out of a file name rendered from `template`
+///
+/// Returns `None` for names the template could not have produced.
+fn parse_filename(template: &str, name: &str) -> Option<u32> {
+    let path = Path::new(name);
+    ImageFormat::from_path(path)?;
+    parse_stem(template, path.file_stem()?.to_str()?)
+}
+
+/// Get the image id out of a file name rendered from `template`, without its extension
+fn parse_stem(template: &str, stem: &str) -> Option<u32> {
+    // Split into the literal text and placeholders between them
+    let placeholders = template_placeholders(template).ok()?;
+    let mut literals = Vec::with_capacity(placeholders.len() + 1);
+    let mut rest = template;
+    for placeholder in &placeholders {
+        let marker = format!("{{{}}}", placeholder);
+        let start = rest.find(&marker)?;
+        literals.push(&rest[..start]);
+        rest = &rest[start + marker.len()..];
+    }
+    literals.push(rest);
+
+    match_parts(stem, &literals, &placeholders, None)
+}
+
+/// Match `input` against alternating literals and placeholders, returning the id
+///
+/// Both placeholders are all digits, {date} always eight of them, so only the length of {id}
+/// needs to be searched for.
+fn match_parts(
+    input: &str,
+    literals: &[&str],
+    placeholders: &[&str],
+    id: Option<u32>,
+) -> Option<u32> {
+    if !input.starts_with(literals[0]) {
+        return None;
+    }
+    let input = &input[literals[0].len()..];
+    if placeholders.is_empty() {
+        return if input.is_empty() { id } else { None };
+    }
+
+    let digits = input.bytes().take_while(u8::is_ascii_digit).count();
+    let lengths: Vec<usize> = match placeholders[0] {
+        "date" if digits >= 8 => vec![8],
+        "id" => (1..=digits).rev().collect(),
+        _ => return None,
+    };
+
+    lengths.into_iter().find_map(|len| {
+        let found = match placeholders[0] {
+            "id" => Some(input[..len].parse().ok()?),
+            _ => id,
+        };
+        match_parts(&input[len..], &literals[1..], &placeholders[1..], found)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, write};
+    use tempfile;
+
+    #[test]
+    fn parses_filenames() {
+        let cases: &[(&str, &str, Option<u32>)] = &[
+            ("{id}", "5.png", Some(5)),
+            ("{id}", "12.gif", Some(12)),
+            ("{id}", "5.jpg", None),
+            ("{id}", "5", None),
+            ("{id}", "five.png", None),
+            ("{id}", "5a.png", None),
+            ("{id}", "99999999999.png", None),
+            ("cubeglobe-{id}", "cubeglobe-42.png", Some(42)),
+            ("cubeglobe-{id}", "other-42.png", None),
+            ("{date}-{id}", "20240501-7.png", Some(7)),
+            ("{date}-{id}", "2024051-7.png", None),
+            ("{id}-{date}", "7-20240501.png", Some(7)),
+            ("{id}-{date}", "7-2024050.png", None),
+            // Only the date's length tells them apart
+            ("{id}{date}", "720240501.png", Some(7)),
+            ("{id}{date}", "1720240501.png", Some(17)),
+            ("{date}", "20240501.png", None),
+        ];
+        for &(template, name, expected) in cases {
+            assert_eq!(parse_filename(template, name), expected, "{} {}", template, name);
+        }
+    }
+
+    #[test]
+    fn classifies_files() {
+        let cases: &[(&str, Kind, Option<u32>)] = &[
+            ("5.png", Kind::Full, Some(5)),
+            ("5.media.toml", Kind::Sidecar, Some(5)),
+            ("5.attempts.toml", Kind::Sidecar, Some(5)),
+            ("5.webp", Kind::Sidecar, Some(5)),
+            ("5.approve", Kind::Sidecar, Some(5)),
+            ("5.png.tmp", Kind::Temp, None),
+            (".media.toml", Kind::Foreign, None),
+            ("other.media.toml", Kind::Foreign, None),
+            ("notes.txt", Kind::Foreign, None),
+        ];
+        for &(name, kind, id) in cases {
+            assert_eq!(classify("{id}", name), (kind, id), "{}", name);
+        }
+    }
+
+    #[test]
+    fn scans_in_order() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let files = &[
+            "12.png",
+            "notes.txt",
+            "5.png",
+            "3.attempts.toml",
+            "5.media.toml",
+            "7.png.tmp",
+        ];
+        for name in files {
+            write(dir.path().join(name), name).expect("Unable to write file");
+        }
+        create_dir(dir.path().join("remote")).expect("Unable to create directory");
+
+        let entries = scan(dir.path(), "{id}").expect("Unable to scan");
+        let found: Vec<(&str, Kind, Option<u32>)> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.kind, entry.id))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("3.attempts.toml", Kind::Orphan, Some(3)),
+                ("5.media.toml", Kind::Sidecar, Some(5)),
+                ("5.png", Kind::Full, Some(5)),
+                ("12.png", Kind::Full, Some(12)),
+                ("7.png.tmp", Kind::Temp, None),
+                ("notes.txt", Kind::Foreign, None),
+            ]
+        );
+        assert_eq!(entries[2].size, "5.png".len() as u64);
+        assert!(entries[2].modified.is_some());
+    }
+
+    #[test]
+    fn missing_directory_is_empty() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let entries = scan(&dir.path().join("images"), "{id}").expect("Unable to scan");
+        assert!(entries.is_empty());
+    }
+}
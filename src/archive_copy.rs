@@ -0,0 +1,134 @@
+//! Copies of the images in a smaller format, for keeping
+//!
+//! The images directory doubles as the archive of everything posted, and PNGs make a big one.
+//! With `[bot.archive_format]`, each still is also encoded as WebP, or with the `avif` feature
+//! as AVIF, and saved next to the PNG under the same stem, like `cubeglobe-42.webp`. Only the
+//! PNG is uploaded. The copy is encoded from the rendered pixels while they're still in memory,
+//! before quantizing, rather than from the PNG.
+//!
+//! WebP copies carry the `[bot.attribution]` metadata, see the `attribution` module.
+//!
+//! Animations get no copy. A copy which can't be encoded or written is only warned about, as the
+//! post doesn't depend on it.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use image::DynamicImage;
+use webp;
+
+use attribution::{self, Credits};
+
+/// Extensions of every format copies can have, for finding them next to an image
+pub const EXTENSIONS: &[&str] = &["webp", "avif"];
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Webp,
+    /// Only with the `avif` feature
+    Avif,
+}
+
+impl Codec {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Webp => "webp",
+            Codec::Avif => "avif",
+        }
+    }
+}
+
+/// Format of the copies
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ArchiveFormat {
+    pub format: Codec,
+
+    /// From 0 to 100, with 100 for WebP being lossless
+    #[serde(default = "default_quality")]
+    pub quality: f32,
+}
+
+fn default_quality() -> f32 {
+    80.0
+}
+
+impl ArchiveFormat {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.quality >= 0.0 && self.quality <= 100.0) {
+            return Err(format!("quality must be from 0 to 100, got {}", self.quality));
+        }
+        if self.format == Codec::Avif && !cfg!(feature = "avif") {
+            return Err("avif needs the bot built with the avif feature".to_string());
+        }
+        Ok(())
+    }
+
+    /// Encode `still` in this format, with `credits` in it if the format takes them
+    pub fn encode(
+        &self,
+        still: &DynamicImage,
+        credits: Option<&Credits>,
+    ) -> Result<Vec<u8>, Error> {
+        // Rendered images are RGBA already, so this is only a copy for odd ones
+        let rgba = match still.as_rgba8() {
+            Some(rgba) => Cow::Borrowed(rgba),
+            None => Cow::Owned(still.to_rgba()),
+        };
+        let (width, height) = rgba.dimensions();
+        match self.format {
+            Codec::Webp => {
+                let encoder = webp::Encoder::from_rgba(&rgba, width, height);
+                let encoded = if self.quality >= 100.0 {
+                    encoder.encode_lossless()
+                } else {
+                    encoder.encode(self.quality)
+                };
+                let encoded = encoded.to_vec();
+                Ok(match credits {
+                    Some(credits) => attribution::tag_webp(encoded, credits, width, height),
+                    None => encoded,
+                })
+            }
+            Codec::Avif => encode_avif(&rgba, width, height, self.quality),
+        }
+    }
+}
+
+#[cfg(feature = "avif")]
+fn encode_avif(rgba: &[u8], width: u32, height: u32, quality: f32) -> Result<Vec<u8>, Error> {
+    use imgref::Img;
+    use ravif::Encoder;
+    use rgb::FromSlice;
+
+    let pixels = Img::new(rgba.as_rgba(), width as usize, height as usize);
+    let encoded = Encoder::new()
+        .with_quality(quality)
+        .encode_rgba(pixels)
+        .map_err(|e| Error::msg(e.to_string()))?;
+    Ok(encoded.avif_file)
+}
+
+#[cfg(not(feature = "avif"))]
+fn encode_avif(_rgba: &[u8], _width: u32, _height: u32, _quality: f32) -> Result<Vec<u8>, Error> {
+    Err(Error::msg("built without the avif feature"))
+}
+
+/// Where the copy of the image at `image` goes
+pub fn path(image: &Path, format: Codec) -> PathBuf {
+    image.with_extension(format.extension())
+}
+
+/// Save `data` as the copy of the image at `image`
+///
+/// Problems are only warned about.
+pub fn save(image: &Path, format: Codec, data: &[u8]) {
+    let path = path(image, format);
+    let written = File::create(&path).and_then(|mut outfile| outfile.write_all(data));
+    if let Err(e) = written {
+        eprintln!("WARNING: Unable to save {}: {}", path.display(), e);
+    }
+}
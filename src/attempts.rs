@@ -0,0 +1,262 @@
+//! A log of the attempts at posting each image
+//!
+//! Every attempt at posting the pending image is kept in the state file as it's made: when it
+//! started, how long it took, how it went, and how long the bot waited before the next one. Once
+//! the image is posted, or set aside as stale or damaged, the log is written next to it as
+//! `{stem}.attempts.toml`, so a post which went out after a string of failures can be looked
+//! into afterwards without piecing the attempts together from the bot's output.
+//!
+//! A long outage can mean hundreds of attempts at one image, so only the first `KEEP_FIRST` and
+//! the last `KEEP_LAST` are kept, along with a count of the ones left out in between.
+//!
+//! `cubeglobe-bot stats` shows how many attempts the images took, over every log in the images
+//! directory.
+
+use std::collections::BTreeMap;
+use std::fs::{read_dir, read_to_string, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use chrono::{DateTime, Utc};
+use clap::{App, SubCommand};
+use toml;
+
+use {BotConfig, StatePaths, QUARANTINE_DIR, STALE_DIR};
+
+/// Attempts kept from the start of the log
+const KEEP_FIRST: usize = 3;
+/// Attempts kept from the end of the log
+const KEEP_LAST: usize = 20;
+/// Widest bar `stats` draws
+const BAR_WIDTH: usize = 40;
+
+/// How an attempt went
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Posted,
+    Failed,
+}
+
+/// One attempt at posting an image
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Attempt {
+    /// Counting from 1, over every attempt at the image, including ones left out of the log
+    pub number: u32,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub outcome: Outcome,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Seconds waited before the next attempt
+    #[serde(default)]
+    pub backoff_secs: Option<u64>,
+}
+
+/// The attempts at posting one image
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AttemptLog {
+    #[serde(default)]
+    pub attempts: Vec<Attempt>,
+    /// Attempts left out of `attempts` to keep it short
+    #[serde(default)]
+    pub elided: u32,
+}
+
+impl AttemptLog {
+    pub fn is_empty(&self) -> bool {
+        self.attempts.is_empty()
+    }
+
+    /// Attempts made in all, including the ones left out
+    pub fn count(&self) -> u32 {
+        self.attempts.len() as u32 + self.elided
+    }
+
+    /// Add an attempt which started at `started_at` and took `duration_ms`, failing with `error`
+    /// if there is one
+    pub fn record(&mut self, started_at: DateTime<Utc>, duration_ms: u64, error: Option<&str>) {
+        let attempt = Attempt {
+            number: self.count() + 1,
+            started_at,
+            duration_ms,
+            outcome: if error.is_some() {
+                Outcome::Failed
+            } else {
+                Outcome::Posted
+            },
+            error: error.map(str::to_string),
+            backoff_secs: None,
+        };
+        self.attempts.push(attempt);
+        if self.attempts.len() > KEEP_FIRST + KEEP_LAST {
+            self.attempts.remove(KEEP_FIRST);
+            self.elided += 1;
+        }
+    }
+
+    /// Note that the bot is waiting `secs` after the last attempt before the next one
+    pub fn backed_off(&mut self, secs: u64) {
+        if let Some(last) = self.attempts.last_mut() {
+            last.backoff_secs = Some(secs);
+        }
+    }
+
+    /// Write the log next to the image saved as `filename` in `dir`
+    ///
+    /// Problems writing it are only logged, as they can't be helped by then.
+    pub fn write(&self, dir: &Path, filename: &str) {
+        let path = dir.join(record_name(filename));
+        let written = toml::to_string(self)
+            .map_err(Error::from)
+            .and_then(|serialized| Ok(File::create(&path)?.write_all(serialized.as_bytes())?));
+        if let Err(e) = written {
+            eprintln!("WARNING: Unable to write {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn record_name(filename: &str) -> String {
+    let stem = Path::new(filename)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| filename.to_string());
+    format!("{}.attempts.toml", stem)
+}
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("stats")
+        .about("show how many attempts posting the images took, from their attempt logs")
+}
+
+/// Read every attempt log in `dir`
+fn read_logs(dir: &Path, logs: &mut Vec<AttemptLog>) -> Result<(), Error> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("unable to read {}", dir.display())),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let is_log = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name.ends_with(".attempts.toml"));
+        if !is_log {
+            continue;
+        }
+        let log = read_to_string(&path)
+            .map_err(Error::from)
+            .and_then(|contents| Ok(toml::from_str(&contents)?));
+        match log {
+            Ok(log) => logs.push(log),
+            Err(e) => eprintln!("WARNING: Skipping {}: {}", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+/// Print a histogram of the attempts the images in the images directory took
+pub fn run(config: &BotConfig) -> Result<(), Error> {
+    let images = StatePaths::from_config(config).images;
+    let mut logs = Vec::new();
+    read_logs(&images, &mut logs)?;
+    let posted = logs.len();
+    for dir in &[STALE_DIR, QUARANTINE_DIR] {
+        read_logs(&images.join(dir), &mut logs)?;
+    }
+    if logs.is_empty() {
+        println!("No attempt logs in {}", images.display());
+        return Ok(());
+    }
+
+    let mut histogram: BTreeMap<u32, usize> = BTreeMap::new();
+    for log in &logs {
+        *histogram.entry(log.count()).or_insert(0) += 1;
+    }
+    let most = histogram.values().cloned().max().unwrap_or(1);
+    println!(
+        "Attempts per image, over {} images ({} posted, {} set aside):",
+        logs.len(),
+        posted,
+        logs.len() - posted
+    );
+    for (attempts, &images) in &histogram {
+        let bar = "#".repeat((images * BAR_WIDTH + most - 1) / most);
+        println!("{:>6} {:>6}  {}", attempts, images, bar);
+    }
+
+    let failures: u32 = logs.iter().map(|log| log.count()).sum::<u32>() - posted as u32;
+    println!("{} failed attempts in all", failures);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    fn failed_attempts(count: u32) -> AttemptLog {
+        let start = Utc::now();
+        let mut log = AttemptLog::default();
+        for i in 0..count {
+            log.record(start + Duration::minutes(i64::from(i)), 100, Some("Timed out"));
+            log.backed_off(60);
+        }
+        log
+    }
+
+    #[test]
+    fn keeps_the_first_and_last_attempts() {
+        let mut log = failed_attempts(100);
+        log.record(Utc::now(), 100, None);
+
+        assert_eq!(log.count(), 101);
+        assert_eq!(log.attempts.len(), KEEP_FIRST + KEEP_LAST);
+        assert_eq!(log.elided, 101 - (KEEP_FIRST + KEEP_LAST) as u32);
+        let numbers: Vec<u32> = log.attempts.iter().map(|attempt| attempt.number).collect();
+        let mut expected: Vec<u32> = (1..=KEEP_FIRST as u32).collect();
+        expected.extend(102 - KEEP_LAST as u32..=101);
+        assert_eq!(numbers, expected);
+
+        let last = log.attempts.last().expect("Log is empty");
+        assert_eq!(last.outcome, Outcome::Posted);
+        assert_eq!(last.error, None);
+        assert_eq!(last.backoff_secs, None);
+        assert_eq!(log.attempts[0].backoff_secs, Some(60));
+    }
+
+    #[test]
+    fn written_logs_are_read_back() {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        failed_attempts(2).write(dir.path(), "landscape-7.png");
+        failed_attempts(30).write(dir.path(), "landscape-8.gif");
+        assert!(dir.path().join("landscape-7.attempts.toml").exists());
+
+        let mut logs = Vec::new();
+        read_logs(dir.path(), &mut logs).expect("Unable to read logs");
+        let mut counts: Vec<u32> = logs.iter().map(AttemptLog::count).collect();
+        counts.sort();
+        assert_eq!(counts, vec![2, 30]);
+    }
+
+    #[test]
+    fn skips_malformed_logs() {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        failed_attempts(4).write(dir.path(), "5.png");
+        write(dir.path().join("6.attempts.toml"), "attempts = 'many'").expect("Unable to write");
+        write(dir.path().join("7.attempts.toml"), &[0xffu8, 0xfe][..]).expect("Unable to write");
+        write(dir.path().join("notes.toml"), "elided = 3").expect("Unable to write");
+
+        let mut logs = Vec::new();
+        read_logs(dir.path(), &mut logs).expect("Unable to read logs");
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].count(), 4);
+
+        read_logs(&dir.path().join("missing"), &mut logs).expect("Unable to read logs");
+        assert_eq!(logs.len(), 1);
+    }
+}
@@ -0,0 +1,407 @@
+//! Authorship metadata in the saved images
+//!
+//! With `[bot.attribution]`, stills carry who made them, under what license, and where they come
+//! from, so a copy found elsewhere still says so. Each of `creator`, `license` and `source` is a
+//! template with the same placeholders as the alt text, plus {id} and {year}.
+//!
+//! PNGs get an XMP packet in an `iTXt` chunk with the `XML:com.adobe.xmp` keyword, which is where
+//! image editors and `exiftool` look for it: the creator as `dc:creator`, the license as
+//! `dc:rights` and `xmpRights:UsageTerms`, and the source as `dc:source`. WebP archive copies get
+//! the same packet as an `XMP ` chunk, and an `EXIF` chunk with the creator as `Artist` and the
+//! license as `Copyright`. AVIF copies and animations get nothing.
+//!
+//! The metadata is added after the optimizer has run, so its stripping never takes it out, and
+//! `strip_metadata`, which is about what the bot says of itself, leaves it in: it was asked for.
+
+use build_info;
+
+/// WebP's extended format header flags for EXIF and XMP chunks, and for an alpha channel
+const WEBP_EXIF: u8 = 0x08;
+const WEBP_XMP: u8 = 0x04;
+const WEBP_ALPHA: u8 = 0x10;
+/// TIFF tags and the ASCII field type, for the EXIF block
+const TIFF_ARTIST: u16 = 0x013b;
+const TIFF_COPYRIGHT: u16 = 0x8298;
+const TIFF_ASCII: u16 = 2;
+
+/// Templates for the authorship metadata, each left out if not set
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Attribution {
+    /// Who made the image, like the bot's name and its operator's
+    #[serde(default)]
+    pub creator: Option<String>,
+
+    /// License the image is under, like "CC BY 4.0"
+    #[serde(default)]
+    pub license: Option<String>,
+
+    /// Where the image comes from, like the bot's account URL
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+impl Attribution {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.templates().is_empty() {
+            return Err("set at least one of creator, license and source".to_string());
+        }
+        Ok(())
+    }
+
+    /// Every template which is set
+    pub fn templates(&self) -> Vec<&str> {
+        [&self.creator, &self.license, &self.source]
+            .iter()
+            .filter_map(|template| template.as_ref().map(String::as_str))
+            .collect()
+    }
+
+    /// The metadata for one image, with each template filled out by `fill`
+    pub fn fill<F>(&self, mut fill: F) -> Credits
+    where
+        F: FnMut(&str) -> String,
+    {
+        Credits {
+            creator: self.creator.as_ref().map(|template| fill(template)),
+            license: self.license.as_ref().map(|template| fill(template)),
+            source: self.source.as_ref().map(|template| fill(template)),
+        }
+    }
+}
+
+/// Authorship metadata for one image
+#[derive(Clone, Debug)]
+pub struct Credits {
+    pub creator: Option<String>,
+    pub license: Option<String>,
+    pub source: Option<String>,
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// An XMP packet with `credits`
+fn xmp_packet(credits: &Credits) -> String {
+    let mut properties = String::new();
+    if let Some(ref creator) = credits.creator {
+        properties.push_str(&format!(
+            "   <dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n",
+            escape_xml(creator)
+        ));
+    }
+    if let Some(ref license) = credits.license {
+        let license = escape_xml(license);
+        properties.push_str(&format!(
+            "   <dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt>\
+             </dc:rights>\n   <xmpRights:UsageTerms><rdf:Alt>\
+             <rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></xmpRights:UsageTerms>\n",
+            license, license
+        ));
+    }
+    if let Some(ref source) = credits.source {
+        properties.push_str(&format!("   <dc:source>{}</dc:source>\n", escape_xml(source)));
+    }
+
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n \
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n  \
+         <rdf:Description rdf:about=\"\"\n    \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n    \
+         xmlns:xmpRights=\"http://ns.adobe.com/xap/1.0/rights/\">\n\
+         {}  </rdf:Description>\n \
+         </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"r\"?>",
+        properties
+    )
+}
+
+/// A little-endian TIFF block with `credits` as EXIF has them, if there's anything EXIF has a
+/// field for
+fn exif_block(credits: &Credits) -> Option<Vec<u8>> {
+    let fields: Vec<(u16, &str)> = [
+        (TIFF_ARTIST, &credits.creator),
+        (TIFF_COPYRIGHT, &credits.license),
+    ]
+    .iter()
+    .filter_map(|&(tag, text)| text.as_ref().map(|text| (tag, text.as_str())))
+    .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    // Header, then one directory of fields, then the values too long to fit in their field
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+    tiff.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+    let mut values_at = 8 + 2 + fields.len() * 12 + 4;
+    let mut values = Vec::new();
+    for (tag, text) in fields {
+        let mut value = text.as_bytes().to_vec();
+        value.push(0);
+        tiff.extend_from_slice(&tag.to_le_bytes());
+        tiff.extend_from_slice(&TIFF_ASCII.to_le_bytes());
+        tiff.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        if value.len() <= 4 {
+            value.resize(4, 0);
+            tiff.extend_from_slice(&value);
+        } else {
+            tiff.extend_from_slice(&(values_at as u32).to_le_bytes());
+            // Values start on even offsets
+            if value.len() % 2 == 1 {
+                value.push(0);
+            }
+            values_at += value.len();
+            values.extend_from_slice(&value);
+        }
+    }
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    tiff.extend_from_slice(&values);
+    Some(tiff)
+}
+
+/// `png` with `credits` in an XMP `iTXt` chunk, right after the header chunk
+///
+/// Data which doesn't start like a PNG is returned as it is.
+pub fn tag_png(png: Vec<u8>, credits: &Credits) -> Vec<u8> {
+    // Keyword, then no compression, and no language or translated keyword
+    let mut chunk = b"iTXtXML:com.adobe.xmp\0\0\0\0\0".to_vec();
+    chunk.extend_from_slice(xmp_packet(credits).as_bytes());
+    build_info::insert_png_chunk(png, &chunk)
+}
+
+fn push_riff_chunk(riff: &mut Vec<u8>, fourcc: &[u8], data: &[u8]) {
+    riff.extend_from_slice(fourcc);
+    riff.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    riff.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        riff.push(0);
+    }
+}
+
+/// `webp`, a `width` by `height` image, with `credits` in `EXIF` and `XMP ` chunks
+///
+/// Those chunks need WebP's extended format, so a simple file has a `VP8X` header put in front
+/// of its image. Data which doesn't start like a WebP is returned as it is.
+pub fn tag_webp(webp: Vec<u8>, credits: &Credits, width: u32, height: u32) -> Vec<u8> {
+    if webp.len() < 25 || &webp[..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return webp;
+    }
+    let exif = exif_block(credits);
+    let flags = WEBP_XMP | if exif.is_some() { WEBP_EXIF } else { 0 };
+
+    let mut tagged = Vec::with_capacity(webp.len() + 1024);
+    tagged.extend_from_slice(&webp[..12]);
+    if &webp[12..16] == b"VP8X" {
+        tagged.extend_from_slice(&webp[12..]);
+        tagged[20] |= flags;
+    } else {
+        // Lossless images say in their own header whether they use their alpha channel, and
+        // lossy ones with one would have come in the extended format already
+        let alpha = &webp[12..16] == b"VP8L" && webp[24] & 0x10 != 0;
+        let mut header = vec![flags | if alpha { WEBP_ALPHA } else { 0 }, 0, 0, 0];
+        header.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        header.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        push_riff_chunk(&mut tagged, b"VP8X", &header);
+        tagged.extend_from_slice(&webp[12..]);
+    }
+    if let Some(exif) = exif {
+        push_riff_chunk(&mut tagged, b"EXIF", &exif);
+    }
+    push_riff_chunk(&mut tagged, b"XMP ", xmp_packet(credits).as_bytes());
+
+    let riff_size = (tagged.len() - 8) as u32;
+    tagged[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    tagged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{self, DynamicImage, ImageOutputFormat, RgbaImage};
+    use std::str;
+    use webp;
+
+    fn credits() -> Credits {
+        Credits {
+            creator: Some("cubeglobe & Dee".to_string()),
+            license: Some("CC BY 4.0".to_string()),
+            source: Some("https://example.org/@cubeglobe".to_string()),
+        }
+    }
+
+    fn still() -> RgbaImage {
+        RgbaImage::from_fn(5, 3, |x, y| image::Rgba {
+            data: [x as u8 * 40, y as u8 * 80, 200, 255],
+        })
+    }
+
+    /// Text between `start` and the next `end` in `text`, unescaped
+    fn between(text: &str, start: &str, end: &str) -> Option<String> {
+        let from = text.find(start)? + start.len();
+        let to = from + text[from..].find(end)?;
+        Some(
+            text[from..to]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&amp;", "&"),
+        )
+    }
+
+    /// What an XMP packet says
+    fn read_xmp(xmp: &str) -> Credits {
+        Credits {
+            creator: between(xmp, "<dc:creator><rdf:Seq><rdf:li>", "</rdf:li>"),
+            license: between(xmp, "<dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">", "<"),
+            source: between(xmp, "<dc:source>", "</dc:source>"),
+        }
+    }
+
+    fn assert_same(read: &Credits, written: &Credits) {
+        assert_eq!(read.creator, written.creator);
+        assert_eq!(read.license, written.license);
+        assert_eq!(read.source, written.source);
+    }
+
+    /// Type and data of each chunk of `png`
+    fn png_chunks(png: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut at = 8;
+        while at + 12 <= png.len() {
+            let mut len = [0; 4];
+            len.copy_from_slice(&png[at..at + 4]);
+            let len = u32::from_be_bytes(len) as usize;
+            chunks.push((&png[at + 4..at + 8], &png[at + 8..at + 8 + len]));
+            at += 12 + len;
+        }
+        chunks
+    }
+
+    /// FourCC and data of each chunk of `webp`
+    fn riff_chunks(webp: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut at = 12;
+        while at + 8 <= webp.len() {
+            let mut len = [0; 4];
+            len.copy_from_slice(&webp[at + 4..at + 8]);
+            let len = u32::from_le_bytes(len) as usize;
+            chunks.push((&webp[at..at + 4], &webp[at + 8..at + 8 + len]));
+            at += 8 + len + len % 2;
+        }
+        chunks
+    }
+
+    /// ASCII fields of the first directory of a little-endian TIFF block
+    fn exif_fields(tiff: &[u8]) -> Vec<(u16, String)> {
+        let u16_at = |at: usize| u16::from_le_bytes([tiff[at], tiff[at + 1]]);
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([tiff[at], tiff[at + 1], tiff[at + 2], tiff[at + 3]]) as usize
+        };
+        assert_eq!(&tiff[..4], b"II*\0");
+        let directory = u32_at(4);
+        (0..u16_at(directory) as usize)
+            .map(|i| {
+                let field = directory + 2 + i * 12;
+                assert_eq!(u16_at(field + 2), TIFF_ASCII);
+                let len = u32_at(field + 4);
+                let at = if len <= 4 { field + 8 } else { u32_at(field + 8) };
+                let text = str::from_utf8(&tiff[at..at + len - 1]).expect("Field isn't UTF-8");
+                (u16_at(field), text.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fills_templates_which_are_set() {
+        let attribution = Attribution {
+            creator: Some("{name} bot".to_string()),
+            license: None,
+            source: Some("https://example.org/{id}".to_string()),
+        };
+        assert!(attribution.validate().is_ok());
+        assert!(Attribution::default().validate().is_err());
+
+        let credits = attribution.fill(|template| {
+            template.replace("{name}", "cubeglobe").replace("{id}", "42")
+        });
+        assert_eq!(credits.creator, Some("cubeglobe bot".to_string()));
+        assert_eq!(credits.license, None);
+        assert_eq!(credits.source, Some("https://example.org/42".to_string()));
+    }
+
+    #[test]
+    fn png_round_trips() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(still())
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .expect("Unable to encode PNG");
+        let tagged = tag_png(png.clone(), &credits());
+
+        let chunks = png_chunks(&tagged);
+        assert_eq!(chunks[1].0, b"iTXt");
+        let prefix = b"XML:com.adobe.xmp\0\0\0\0\0";
+        assert!(chunks[1].1.starts_with(prefix));
+        let xmp = str::from_utf8(&chunks[1].1[prefix.len()..]).expect("XMP isn't UTF-8");
+        assert_same(&read_xmp(xmp), &credits());
+
+        let decoded = image::load_from_memory(&tagged).expect("Unable to decode tagged PNG");
+        assert_eq!(decoded.to_rgba().into_raw(), still().into_raw());
+        assert_eq!(tag_png(b"GIF89a".to_vec(), &credits()), b"GIF89a".to_vec());
+    }
+
+    #[test]
+    fn webp_round_trips() {
+        let still = still();
+        let simple = webp::Encoder::from_rgba(&still, 5, 3).encode_lossless().to_vec();
+        let tagged = tag_webp(simple, &credits(), 5, 3);
+
+        let chunks = riff_chunks(&tagged);
+        let fourccs: Vec<&[u8]> = chunks.iter().map(|chunk| chunk.0).collect();
+        assert_eq!(fourccs, vec![&b"VP8X"[..], b"VP8L", b"EXIF", b"XMP "]);
+        let header = chunks[0].1;
+        assert_eq!(header[0] & (WEBP_EXIF | WEBP_XMP), WEBP_EXIF | WEBP_XMP);
+        assert_eq!(&header[4..], &[4, 0, 0, 2, 0, 0]);
+        let riff_size = u32::from_le_bytes([tagged[4], tagged[5], tagged[6], tagged[7]]);
+        assert_eq!(riff_size as usize, tagged.len() - 8);
+
+        assert_eq!(
+            exif_fields(chunks[2].1),
+            vec![
+                (TIFF_ARTIST, "cubeglobe & Dee".to_string()),
+                (TIFF_COPYRIGHT, "CC BY 4.0".to_string()),
+            ]
+        );
+        let xmp = str::from_utf8(chunks[3].1).expect("XMP isn't UTF-8");
+        assert_same(&read_xmp(xmp), &credits());
+
+        let decoded = webp::Decoder::new(&tagged)
+            .decode()
+            .expect("Unable to decode tagged WebP");
+        assert_eq!((decoded.width(), decoded.height()), (5, 3));
+    }
+
+    #[test]
+    fn short_values_fit_in_their_exif_field() {
+        let credits = Credits {
+            creator: Some("Dee".to_string()),
+            license: None,
+            source: None,
+        };
+        let tiff = exif_block(&credits).expect("No EXIF block");
+        assert_eq!(tiff.len(), 8 + 2 + 12 + 4);
+        assert_eq!(exif_fields(&tiff), vec![(TIFF_ARTIST, "Dee".to_string())]);
+
+        let source_only = Credits {
+            creator: None,
+            license: None,
+            source: Some("https://example.org".to_string()),
+        };
+        assert!(exif_block(&source_only).is_none());
+    }
+}
@@ -0,0 +1,140 @@
+//! Background detection and replacement
+//!
+//! The renderer leaves a flat color around the map. Background pixels are those of that color
+//! which are reachable from the edges of the image, so the same color inside the map is left
+//! alone.
+
+use std::fmt;
+
+use image::{DynamicImage, Rgba, RgbaImage};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+/// What to replace the background with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Background {
+    /// An opaque RGB color
+    Color([u8; 3]),
+    Transparent,
+}
+
+impl Background {
+    /// Parse `"transparent"` or a `#rrggbb` color
+    pub fn parse(input: &str) -> Result<Background, String> {
+        if input == "transparent" {
+            Ok(Background::Transparent)
+        } else {
+            parse_color(input)
+                .map(Background::Color)
+                .map_err(|e| format!("{}, or \"transparent\"", e))
+        }
+    }
+
+    /// Replace the background of `image`
+    ///
+    /// Transparent backgrounds give an RGBA image, colors an RGB one.
+    pub fn apply(self, image: &DynamicImage) -> DynamicImage {
+        let mut rgba = image.to_rgba();
+        let mask = background_mask(&rgba);
+        let replacement = match self {
+            Background::Color([r, g, b]) => Rgba { data: [r, g, b, 255] },
+            Background::Transparent => Rgba { data: [0, 0, 0, 0] },
+        };
+
+        for (pixel, &is_background) in rgba.pixels_mut().zip(mask.iter()) {
+            if is_background {
+                *pixel = replacement;
+            }
+        }
+
+        match self {
+            Background::Transparent => DynamicImage::ImageRgba8(rgba),
+            Background::Color(_) => {
+                DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb())
+            }
+        }
+    }
+}
+
+/// Parse a `#rrggbb` color
+pub fn parse_color(input: &str) -> Result<[u8; 3], String> {
+    let hex = input.trim_start_matches('#');
+    if !input.starts_with('#') || hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("expected a color like \"#1e1e2e\", got {:?}", input));
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).expect("checked above");
+    Ok([channel(0), channel(2), channel(4)])
+}
+
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Background::Color([r, g, b]) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+            Background::Transparent => write!(f, "transparent"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Background {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Background, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        Background::parse(&input).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Background {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Find the background pixels of `image`, in row-major order
+///
+/// The background color is taken from the top left corner. Pixels of that color are background
+/// if they connect to the edge of the image through other background pixels.
+fn background_mask(image: &RgbaImage) -> Vec<bool> {
+    let (width, height) = image.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let mut mask = vec![false; width * height];
+    if width == 0 || height == 0 {
+        return mask;
+    }
+
+    let color = *image.get_pixel(0, 0);
+    let is_candidate = |x: usize, y: usize| *image.get_pixel(x as u32, y as u32) == color;
+
+    // Seed with every matching edge pixel, then flood fill
+    let mut stack = Vec::new();
+    for x in 0..width {
+        stack.push((x, 0));
+        stack.push((x, height - 1));
+    }
+    for y in 0..height {
+        stack.push((0, y));
+        stack.push((width - 1, y));
+    }
+
+    while let Some((x, y)) = stack.pop() {
+        let index = y * width + x;
+        if mask[index] || !is_candidate(x, y) {
+            continue;
+        }
+        mask[index] = true;
+
+        if x > 0 {
+            stack.push((x - 1, y));
+        }
+        if x + 1 < width {
+            stack.push((x + 1, y));
+        }
+        if y > 0 {
+            stack.push((x, y - 1));
+        }
+        if y + 1 < height {
+            stack.push((x, y + 1));
+        }
+    }
+
+    mask
+}
@@ -0,0 +1,175 @@
+//! Bluesky backend
+//!
+//! Talks to the PDS over plain XRPC calls: log in with an app password, upload the image as a
+//! blob, then create an `app.bsky.feed.post` record embedding it.
+
+use chrono::{SecondsFormat, Utc};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde_json::Value;
+
+use build_info;
+use errors::PostingError;
+use posting::{parse_response, Limits, Post, Poster, Progress};
+
+const BACKEND_NAME: &str = "bluesky";
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct BlueskyConfig {
+    /// Base URL of the PDS hosting the account
+    #[serde(default = "default_service")]
+    pub service: String,
+    /// Handle or DID of the account
+    pub identifier: String,
+    /// App password, not the main account password
+    pub app_password: String,
+}
+
+fn default_service() -> String {
+    "https://bsky.social".to_string()
+}
+
+#[derive(Serialize)]
+struct CreateSession<'a> {
+    identifier: &'a str,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+    access_jwt: String,
+    did: String,
+}
+
+#[derive(Deserialize)]
+struct UploadedBlob {
+    /// Blob reference, passed back to the PDS as is
+    blob: Value,
+}
+
+#[derive(Serialize)]
+struct CreateRecord<'a> {
+    repo: &'a str,
+    collection: &'a str,
+    record: PostRecord<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PostRecord<'a> {
+    #[serde(rename = "$type")]
+    record_type: &'a str,
+    text: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    langs: Vec<&'a str>,
+    created_at: String,
+    embed: ImagesEmbed<'a>,
+}
+
+#[derive(Serialize)]
+struct ImagesEmbed<'a> {
+    #[serde(rename = "$type")]
+    embed_type: &'a str,
+    images: Vec<EmbeddedImage<'a>>,
+}
+
+#[derive(Serialize)]
+struct EmbeddedImage<'a> {
+    alt: &'a str,
+    image: &'a Value,
+}
+
+#[derive(Deserialize)]
+struct CreatedRecord {
+    uri: String,
+}
+
+pub struct BlueskyPoster {
+    config: BlueskyConfig,
+    client: Client,
+}
+
+impl BlueskyPoster {
+    pub fn new(config: BlueskyConfig) -> BlueskyPoster {
+        BlueskyPoster {
+            config,
+            client: build_info::http_client(),
+        }
+    }
+
+    fn xrpc_url(&self, method: &str) -> String {
+        format!(
+            "{}/xrpc/{}",
+            self.config.service.trim_end_matches('/'),
+            method
+        )
+    }
+
+    fn create_session(&self) -> Result<Session, PostingError> {
+        let response = self
+            .client
+            .post(&self.xrpc_url("com.atproto.server.createSession"))
+            .json(&CreateSession {
+                identifier: &self.config.identifier,
+                password: &self.config.app_password,
+            }).send()?;
+
+        parse_response(BACKEND_NAME, response)
+    }
+}
+
+impl Poster for BlueskyPoster {
+    fn name(&self) -> &str {
+        BACKEND_NAME
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            max_chars: Some(300),
+            max_image_bytes: Some(1_000_000),
+            max_alt_chars: None,
+            ..Limits::default()
+        }
+    }
+
+    fn post(&self, post: &Post, _progress: &mut Progress) -> Result<(), PostingError> {
+        let session = self.create_session()?;
+
+        let response = self
+            .client
+            .post(&self.xrpc_url("com.atproto.repo.uploadBlob"))
+            .bearer_auth(&session.access_jwt)
+            .header(CONTENT_TYPE, post.format.mimetype())
+            .body(post.image.to_vec())
+            .send()?;
+        let uploaded: UploadedBlob = parse_response(BACKEND_NAME, response)?;
+
+        let response = self
+            .client
+            .post(&self.xrpc_url("com.atproto.repo.createRecord"))
+            .bearer_auth(&session.access_jwt)
+            .json(&CreateRecord {
+                repo: &session.did,
+                collection: "app.bsky.feed.post",
+                record: PostRecord {
+                    record_type: "app.bsky.feed.post",
+                    text: &post.body,
+                    langs: post.language.iter().map(String::as_str).collect(),
+                    created_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                    embed: ImagesEmbed {
+                        embed_type: "app.bsky.embed.images",
+                        images: vec![EmbeddedImage {
+                            alt: &post.alt_text,
+                            image: &uploaded.blob,
+                        }],
+                    },
+                },
+            }).send()?;
+        let created: CreatedRecord = parse_response(BACKEND_NAME, response)?;
+
+        eprintln!("New Bluesky post created at: {}", created.uri);
+
+        Ok(())
+    }
+}
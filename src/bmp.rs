@@ -0,0 +1,263 @@
+//! Decoding the BMPs SDL writes
+//!
+//! The only way out of an SDL surface is as a BMP, which SDL writes uncompressed: 24 bits per
+//! pixel, or 32 with bit masks for surfaces with alpha. That's simple enough to read ourselves,
+//! which spares building the `image` crate's general BMP decoder just for this. Anything else,
+//! like palettes or run-length encoding, is refused.
+
+use std::io;
+
+use image::{DynamicImage, RgbImage, RgbaImage};
+
+/// `BI_RGB`, no compression
+const UNCOMPRESSED: u32 = 0;
+
+/// `BI_BITFIELDS`, uncompressed with masks saying where each channel is
+const BITFIELDS: u32 = 3;
+
+fn invalid(problem: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unsupported BMP: {}", problem))
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16, io::Error> {
+    data.get(at..at + 2)
+        .map(|bytes| u16::from(bytes[0]) | (u16::from(bytes[1]) << 8))
+        .ok_or_else(|| invalid("header cut short"))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, io::Error> {
+    data.get(at..at + 4)
+        .map(|bytes| {
+            u32::from(bytes[0])
+                | (u32::from(bytes[1]) << 8)
+                | (u32::from(bytes[2]) << 16)
+                | (u32::from(bytes[3]) << 24)
+        })
+        .ok_or_else(|| invalid("header cut short"))
+}
+
+/// One channel of a 32 bit pixel, going by its mask
+fn channel(pixel: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 255;
+    }
+    let shift = mask.trailing_zeros();
+    let max = mask >> shift;
+    let value = (pixel & mask) >> shift;
+    if max == 255 {
+        value as u8
+    } else {
+        (value * 255 / max) as u8
+    }
+}
+
+/// Decode a BMP as written by SDL, as RGB for 24 bit ones, and RGBA for 32 bit ones
+///
+/// Trailing bytes after the pixels are ignored, so `data` can be a buffer larger than the BMP.
+pub fn decode(data: &[u8]) -> Result<DynamicImage, io::Error> {
+    if data.get(..2) != Some(&b"BM"[..]) {
+        return Err(invalid("not a BMP"));
+    }
+    let offset = read_u32(data, 10)? as usize;
+    let header_size = read_u32(data, 14)? as usize;
+    let width = read_u32(data, 18)? as i32;
+    let height = read_u32(data, 22)? as i32;
+    let bits = read_u16(data, 28)?;
+    let compression = read_u32(data, 30)?;
+    if width <= 0 || height == 0 {
+        return Err(invalid("empty image"));
+    }
+
+    // Rows are stored bottom up, unless the height is negative
+    let top_down = height < 0;
+    let (width, height) = (width as u32, height.abs() as u32);
+    let bytes_per_pixel = match (bits, compression) {
+        (24, UNCOMPRESSED) => 3,
+        (32, UNCOMPRESSED) | (32, BITFIELDS) => 4,
+        _ => {
+            return Err(invalid(&format!(
+                "{} bits per pixel with compression {}",
+                bits, compression
+            )))
+        }
+    };
+
+    let stride = (width as usize * bytes_per_pixel + 3) / 4 * 4;
+    let end = offset + stride * height as usize;
+    if data.len() < end {
+        return Err(invalid("pixel data cut short"));
+    }
+    let row = |y: u32| {
+        let y = if top_down { y } else { height - 1 - y } as usize;
+        &data[offset + y * stride..offset + y * stride + width as usize * bytes_per_pixel]
+    };
+
+    if bytes_per_pixel == 3 {
+        let mut image = RgbImage::new(width, height);
+        for y in 0..height {
+            for (x, pixel) in row(y).chunks(3).enumerate() {
+                image.get_pixel_mut(x as u32, y).data = [pixel[2], pixel[1], pixel[0]];
+            }
+        }
+        return Ok(DynamicImage::ImageRgb8(image));
+    }
+
+    // Masks follow the 40 byte header with BI_BITFIELDS, and are part of the longer headers
+    let masks = if compression == BITFIELDS {
+        let alpha = if header_size >= 56 {
+            read_u32(data, 14 + 52)?
+        } else {
+            0
+        };
+        [
+            read_u32(data, 14 + 40)?,
+            read_u32(data, 14 + 44)?,
+            read_u32(data, 14 + 48)?,
+            alpha,
+        ]
+    } else {
+        [0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0]
+    };
+    let mut image = RgbaImage::new(width, height);
+    for y in 0..height {
+        for (x, pixel) in row(y).chunks(4).enumerate() {
+            let value = u32::from(pixel[0])
+                | (u32::from(pixel[1]) << 8)
+                | (u32::from(pixel[2]) << 16)
+                | (u32::from(pixel[3]) << 24);
+            image.get_pixel_mut(x as u32, y).data = [
+                channel(value, masks[0]),
+                channel(value, masks[1]),
+                channel(value, masks[2]),
+                channel(value, masks[3]),
+            ];
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u32(data: &mut Vec<u8>, value: u32) {
+        data.extend((0..4).map(|i| (value >> (i * 8)) as u8));
+    }
+
+    /// A BMP with `rows` as its pixel rows, in the order stored, each padded to four bytes
+    ///
+    /// `masks` follow the 40 byte header, which is made long enough to hold an alpha mask if
+    /// there are four of them.
+    fn bmp(
+        bits: u16,
+        compression: u32,
+        size: (i32, i32),
+        masks: &[u32],
+        rows: &[&[u8]],
+    ) -> Vec<u8> {
+        let header_size = if masks.len() == 4 { 56 } else { 40 };
+        let offset = 14 + header_size as usize + if masks.len() == 3 { 12 } else { 0 };
+        let mut data = b"BM".to_vec();
+        push_u32(&mut data, 0);
+        push_u32(&mut data, 0);
+        push_u32(&mut data, offset as u32);
+        push_u32(&mut data, header_size);
+        push_u32(&mut data, size.0 as u32);
+        push_u32(&mut data, size.1 as u32);
+        data.extend(&[1, 0]);
+        data.extend(&[bits as u8, (bits >> 8) as u8]);
+        push_u32(&mut data, compression);
+        for _ in 0..5 {
+            push_u32(&mut data, 0);
+        }
+        for &mask in masks {
+            push_u32(&mut data, mask);
+        }
+        assert_eq!(data.len(), offset);
+        for row in rows {
+            data.extend(*row);
+            data.extend(vec![0; (4 - row.len() % 4) % 4]);
+        }
+        data
+    }
+
+    #[test]
+    fn decodes_24_bit_bottom_up() {
+        // Three pixels a row, so each row is padded by three bytes; blue, green, red
+        let data = bmp(
+            24,
+            UNCOMPRESSED,
+            (3, 2),
+            &[],
+            &[&[1, 2, 3, 4, 5, 6, 7, 8, 9], &[10, 11, 12, 13, 14, 15, 16, 17, 18]],
+        );
+        let image = decode(&data).expect("Unable to decode").to_rgb();
+        assert_eq!(image.dimensions(), (3, 2));
+        assert_eq!(image.get_pixel(0, 0).data, [12, 11, 10]);
+        assert_eq!(image.get_pixel(2, 0).data, [18, 17, 16]);
+        assert_eq!(image.get_pixel(0, 1).data, [3, 2, 1]);
+    }
+
+    #[test]
+    fn decodes_top_down() {
+        let data = bmp(24, UNCOMPRESSED, (1, -2), &[], &[&[1, 2, 3], &[4, 5, 6]]);
+        let image = decode(&data).expect("Unable to decode").to_rgb();
+        assert_eq!(image.get_pixel(0, 0).data, [3, 2, 1]);
+        assert_eq!(image.get_pixel(0, 1).data, [6, 5, 4]);
+    }
+
+    #[test]
+    fn decodes_32_bit_with_masks() {
+        // As SDL writes surfaces with alpha: ARGB, stored little endian
+        let masks = [0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0xff00_0000];
+        let data = bmp(32, BITFIELDS, (2, 1), &masks, &[&[1, 2, 3, 128, 4, 5, 6, 0]]);
+        let image = match decode(&data).expect("Unable to decode") {
+            DynamicImage::ImageRgba8(image) => image,
+            _ => panic!("Not decoded as RGBA"),
+        };
+        assert_eq!(image.get_pixel(0, 0).data, [3, 2, 1, 128]);
+        assert_eq!(image.get_pixel(1, 0).data, [6, 5, 4, 0]);
+
+        // Without an alpha mask, pixels are opaque
+        let data = bmp(32, BITFIELDS, (1, 1), &masks[..3], &[&[1, 2, 3, 0]]);
+        let image = decode(&data).expect("Unable to decode").to_rgba();
+        assert_eq!(image.get_pixel(0, 0).data, [3, 2, 1, 255]);
+        let data = bmp(32, UNCOMPRESSED, (1, 1), &[], &[&[1, 2, 3, 0]]);
+        let image = decode(&data).expect("Unable to decode").to_rgba();
+        assert_eq!(image.get_pixel(0, 0).data, [3, 2, 1, 255]);
+    }
+
+    #[test]
+    fn scales_narrow_channels() {
+        assert_eq!(channel(0x1f, 0x1f), 255);
+        assert_eq!(channel(0x10 << 5, 0x1f << 5), 131);
+        assert_eq!(channel(0, 0x1f), 0);
+        assert_eq!(channel(0x1234, 0), 255);
+    }
+
+    #[test]
+    fn ignores_trailing_bytes() {
+        let mut data = bmp(24, UNCOMPRESSED, (1, 1), &[], &[&[1, 2, 3]]);
+        data.extend(vec![0xaa; 100]);
+        let image = decode(&data).expect("Unable to decode").to_rgb();
+        assert_eq!(image.dimensions(), (1, 1));
+        assert_eq!(image.get_pixel(0, 0).data, [3, 2, 1]);
+    }
+
+    #[test]
+    fn refuses_what_sdl_doesnt_write() {
+        let cut_short = bmp(24, UNCOMPRESSED, (2, 2), &[], &[&[1, 2, 3, 4, 5, 6]]);
+        let cases: &[(Vec<u8>, &str)] = &[
+            (b"GIF89a".to_vec(), "not a BMP"),
+            (b"BM\0\0".to_vec(), "header cut short"),
+            (bmp(8, UNCOMPRESSED, (1, 1), &[], &[&[0]]), "8 bits per pixel"),
+            (bmp(24, 1, (1, 1), &[], &[&[1, 2, 3]]), "compression 1"),
+            (bmp(24, UNCOMPRESSED, (0, 1), &[], &[]), "empty"),
+            (cut_short, "cut short"),
+        ];
+        for &(ref data, expected) in cases {
+            let problem = decode(data).unwrap_err().to_string();
+            assert!(problem.contains(expected), "{} lacks {:?}", problem, expected);
+        }
+    }
+}
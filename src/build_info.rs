@@ -0,0 +1,222 @@
+//! What build of the bot is running
+//!
+//! To tell months later which build made an image, the build script records the git commit it
+//! was built from, and when. Along with the version and the enabled features, that's printed by
+//! `--build-info`, written into `.media.toml` records, webhook metadata and debug bundles, and,
+//! unless `strip_metadata` is set, into PNGs as a `Software` text chunk. Every HTTP request the
+//! bot makes names the version and commit as its User-Agent, so instance admins can tell what's
+//! posting.
+
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+use reqwest::{Client, ClientBuilder};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("CUBEGLOBE_BOT_GIT_HASH");
+
+/// Length of the signature at the start of every PNG, and of its `IHDR` chunk after it
+const PNG_SIGNATURE_LEN: usize = 8;
+const IHDR_CHUNK_LEN: usize = 4 + 4 + 13 + 4;
+
+/// Where an image, or anything else, came from
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_hash: String,
+    pub built_at: DateTime<Utc>,
+    pub features: Vec<String>,
+}
+
+impl BuildInfo {
+    /// The running build
+    pub fn current() -> BuildInfo {
+        BuildInfo {
+            version: VERSION.to_string(),
+            git_hash: GIT_HASH.to_string(),
+            built_at: built_at(),
+            features: features().into_iter().map(String::from).collect(),
+        }
+    }
+
+    /// As printed by `--build-info`, one field per line
+    pub fn report(&self) -> String {
+        format!(
+            "cubeglobe-bot {}\ncommit: {}\nbuilt: {}\nfeatures: {}\n",
+            self.version,
+            self.git_hash,
+            self.built_at.to_rfc3339(),
+            self.features.join(", ")
+        )
+    }
+}
+
+/// When the build script ran
+pub fn built_at() -> DateTime<Utc> {
+    let built: i64 = env!("CUBEGLOBE_BOT_BUILT_AT").parse().unwrap_or(0);
+    DateTime::from_utc(NaiveDateTime::from_timestamp(built, 0), Utc)
+}
+
+/// Cargo features the bot was built with
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "avif") {
+        features.push("avif");
+    }
+    if cfg!(feature = "bluesky") {
+        features.push("bluesky");
+    }
+    if cfg!(feature = "chaos") {
+        features.push("chaos");
+    }
+    if cfg!(feature = "matrix") {
+        features.push("matrix");
+    }
+    if cfg!(feature = "sdlbundled") {
+        features.push("sdlbundled");
+    }
+    features
+}
+
+/// Version and commit, like `cubeglobe-bot 0.1.3 (1a2b3c4d5e6f)`
+pub fn describe() -> String {
+    format!("cubeglobe-bot {} ({})", VERSION, GIT_HASH)
+}
+
+/// HTTP client for every request the bot makes, which sends the version as its User-Agent
+pub fn http_client() -> Client {
+    client_builder().build().unwrap_or_else(|_| Client::new())
+}
+
+/// Like `http_client`, but giving up on requests after `timeout`
+pub fn http_client_with_timeout(timeout: Duration) -> Client {
+    client_builder()
+        .timeout(timeout)
+        .build()
+        .unwrap_or_else(|_| http_client())
+}
+
+fn client_builder() -> ClientBuilder {
+    let agent = format!("cubeglobe-bot/{} (+{})", VERSION, GIT_HASH);
+    let mut headers = HeaderMap::new();
+    if let Ok(value) = HeaderValue::from_str(&agent) {
+        headers.insert(USER_AGENT, value);
+    }
+    Client::builder().default_headers(headers)
+}
+
+/// `png` with a `Software` text chunk naming this build, right after the header chunk
+///
+/// Data which doesn't start like a PNG is returned as it is.
+pub fn tag_png(png: Vec<u8>) -> Vec<u8> {
+    let mut chunk = b"tEXtSoftware\0".to_vec();
+    chunk.extend_from_slice(describe().as_bytes());
+    insert_png_chunk(png, &chunk)
+}
+
+/// `png` with `chunk`, its type followed by its data, right after the header chunk
+///
+/// Data which doesn't start like a PNG is returned as it is.
+pub fn insert_png_chunk(png: Vec<u8>, chunk: &[u8]) -> Vec<u8> {
+    let header_end = PNG_SIGNATURE_LEN + IHDR_CHUNK_LEN;
+    if png.len() < header_end || &png[PNG_SIGNATURE_LEN + 4..PNG_SIGNATURE_LEN + 8] != b"IHDR" {
+        return png;
+    }
+
+    let crc = crc32(chunk);
+
+    let mut tagged = Vec::with_capacity(png.len() + chunk.len() + 8);
+    tagged.extend_from_slice(&png[..header_end]);
+    tagged.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+    tagged.extend_from_slice(chunk);
+    tagged.extend_from_slice(&crc.to_be_bytes());
+    tagged.extend_from_slice(&png[header_end..]);
+    tagged
+}
+
+/// CRC-32 as PNG chunks use it
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{self, DynamicImage, ImageOutputFormat, RgbImage};
+    use test_support::MockServer;
+    use toml;
+
+    fn png() -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(4, 4))
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .expect("Unable to encode PNG");
+        png
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn computes_png_crcs() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn tags_pngs_with_the_build() {
+        let png = png();
+        let tagged = tag_png(png.clone());
+        let software = format!("tEXtSoftware\0{}", describe());
+        assert!(contains(&tagged, software.as_bytes()));
+        assert_eq!(tagged.len(), png.len() + software.len() + 8);
+        // Right after the header, and still a valid PNG
+        let header_end = PNG_SIGNATURE_LEN + IHDR_CHUNK_LEN;
+        assert_eq!(&tagged[header_end + 4..header_end + 8], b"tEXt");
+        let decoded = image::load_from_memory(&tagged).expect("Tagged PNG doesn't decode");
+        assert_eq!(decoded.to_rgb(), image::load_from_memory(&png).unwrap().to_rgb());
+    }
+
+    #[test]
+    fn leaves_other_data_alone() {
+        for data in &[&b""[..], b"GIF89a", b"\x89PNG\r\n\x1a\n cut short"] {
+            assert_eq!(tag_png(data.to_vec()), data.to_vec());
+        }
+    }
+
+    #[test]
+    fn build_info_round_trips() {
+        let current = BuildInfo::current();
+        assert_eq!(current.version, VERSION);
+        assert_eq!(current.git_hash, GIT_HASH);
+        let parsed: BuildInfo = toml::from_str(&toml::to_string(&current).expect("Unable to write"))
+            .expect("Unable to read");
+        assert_eq!(parsed.report(), current.report());
+
+        let report = current.report();
+        assert!(report.starts_with(&format!("cubeglobe-bot {}\ncommit: ", VERSION)));
+        assert_eq!(report.lines().count(), 4);
+    }
+
+    #[test]
+    fn requests_name_the_build() {
+        let server = MockServer::start(vec![(200, "{}".to_string())]);
+        http_client().get(&server.url).send().expect("Unable to send request");
+        let request = &server.requests()[0];
+        let agent = format!("cubeglobe-bot/{} (+{})", VERSION, GIT_HASH);
+        assert_eq!(request.header("user-agent"), Some(agent.as_str()));
+    }
+}
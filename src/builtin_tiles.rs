@@ -0,0 +1,99 @@
+//! Built-in fallback tileset
+//!
+//! Plain, flat-colored tiles drawn at startup, so the bot can be tried out without a tileset.
+//! Selected with `--tiles builtin` (or `tiles = "builtin"`) only; a configured tiles config which
+//! fails to load is always an error, never a reason to fall back to these.
+//!
+//! The renderer loads tiles from image files, so they are written to a directory under the
+//! system's temporary directory, along with a tiles config pointing at them.
+
+use std::env::temp_dir;
+use std::fs::create_dir_all;
+use std::path::Path;
+
+use anyhow::Error;
+use image::{Rgba, RgbaImage};
+use toml;
+
+/// Value of `--tiles` which selects the built-in tileset
+pub const BUILTIN: &str = "builtin";
+
+/// Tiles are this many pixels wide, and as tall
+const TILE_SIZE: u32 = 32;
+
+/// Block name and the color of its top face
+const TILES: &[(&str, [u8; 3])] = &[
+    ("stone", [0x8a, 0x8a, 0x8f]),
+    ("soil", [0x8b, 0x5a, 0x2b]),
+    ("grass", [0x5f, 0xa8, 0x3c]),
+    ("water", [0x3a, 0x7b, 0xd5]),
+];
+
+/// Whether `path` selects the built-in tileset rather than a file
+pub fn is_builtin(path: &Path) -> bool {
+    path == Path::new(BUILTIN)
+}
+
+/// Draw the tiles and return a tiles config using them
+pub fn tiles_config() -> Result<String, Error> {
+    let dir = temp_dir().join("cubeglobe-bot-builtin-tiles");
+    create_dir_all(&dir)
+        .map_err(|e| Error::msg(format!("unable to create {}: {}", dir.display(), e)))?;
+
+    let mut tiles = String::new();
+    for &(name, color) in TILES {
+        let file_name = format!("{}.png", name);
+        let path = dir.join(&file_name);
+        draw_tile(color)
+            .save(&path)
+            .map_err(|e| Error::msg(format!("unable to write {}: {}", path.display(), e)))?;
+        tiles.push_str(&format!("{} = {}\n", name, toml_string(&file_name)));
+    }
+
+    Ok(format!(
+        "assets_path = {}\n\n[tiles]\n{}",
+        toml_string(&dir.to_string_lossy()),
+        tiles
+    ))
+}
+
+/// Quote `value` as a TOML string
+fn toml_string(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+/// Draw an isometric cube: a lit top face, and two shaded side faces below it
+fn draw_tile([r, g, b]: [u8; 3]) -> RgbaImage {
+    let shade = |percent: u32| Rgba {
+        data: [
+            (u32::from(r) * percent / 100) as u8,
+            (u32::from(g) * percent / 100) as u8,
+            (u32::from(b) * percent / 100) as u8,
+            255,
+        ],
+    };
+    let (top, left, right) = (shade(100), shade(75), shade(55));
+
+    // The top face is a diamond half as tall as it is wide, and the side faces hang below its
+    // lower edges, as tall as the diamond
+    let size = TILE_SIZE as f32;
+    let (center, face) = (size / 2.0, size / 2.0);
+    RgbaImage::from_fn(TILE_SIZE, TILE_SIZE, |x, y| {
+        let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+        // Half the height of the diamond in this column
+        let reach = (center - (x - center).abs()) / 2.0;
+        let lower_edge = face / 2.0 + reach;
+
+        if (y - face / 2.0).abs() < reach {
+            top
+        } else if y >= lower_edge && y < lower_edge + face {
+            if x < center {
+                left
+            } else {
+                right
+            }
+        } else {
+            Rgba { data: [0, 0, 0, 0] }
+        }
+    })
+}
@@ -0,0 +1,296 @@
+//! Failures on purpose, to see how the bot copes with them
+//!
+//! Only built with the `chaos` feature. `--chaos SPEC` then makes some of what the bot does fail
+//! as if it had gone wrong for real, so the retrying and recovering can be watched in a staging
+//! setup. SPEC is a comma separated list of `point:chance`, failing with that chance from 0 to 1
+//! every time, or `point:once`, failing only the first time. The points are:
+//!
+//! - `upload`: posting to a backend, failing as if it had responded with status 503
+//! - `generate`: generating an image
+//! - `disk`: writing an image file
+//!
+//! Every injected failure is logged with `CHAOS:` in front, so it can't be mistaken for a real
+//! one.
+
+use std::sync::Mutex;
+
+use rand::{thread_rng, Rng};
+
+/// Where failures can be injected
+pub const POINTS: &[&str] = &["upload", "generate", "disk"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Rule {
+    Chance(f64),
+    Once,
+}
+
+/// Which points to fail, and how often
+#[derive(Debug)]
+pub struct Chaos {
+    rules: Vec<(&'static str, Rule)>,
+    /// Points with a `once` rule which already failed
+    fired: Mutex<Vec<&'static str>>,
+}
+
+impl Chaos {
+    /// Read a spec like `upload:0.5,generate:0.1,disk:once`
+    pub fn parse(spec: &str) -> Result<Chaos, String> {
+        let mut rules = Vec::new();
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let mut halves = part.splitn(2, ':');
+            let name = halves.next().unwrap_or("").trim();
+            let point = match POINTS.iter().find(|&&point| point == name) {
+                Some(&point) => point,
+                None => {
+                    return Err(format!(
+                        "unknown point {:?}, expected one of {}",
+                        name,
+                        POINTS.join(", ")
+                    ))
+                }
+            };
+            let rule = match halves.next().map(str::trim) {
+                Some("once") => Rule::Once,
+                Some(chance) => match chance.parse::<f64>() {
+                    Ok(chance) if chance >= 0.0 && chance <= 1.0 => Rule::Chance(chance),
+                    _ => {
+                        return Err(format!(
+                            "{:?} should be a chance from 0 to 1, or once",
+                            part
+                        ))
+                    }
+                },
+                None => return Err(format!("{:?} is missing :chance or :once", part)),
+            };
+            if rules.iter().any(|&(known, _)| known == point) {
+                return Err(format!("{} is given more than once", point));
+            }
+            rules.push((point, rule));
+        }
+        if rules.is_empty() {
+            return Err("no points given".to_string());
+        }
+        Ok(Chaos {
+            rules,
+            fired: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Whether `point` is to fail this time, logging it if so
+    pub fn strike(&self, point: &str) -> bool {
+        let (point, rule) = match self.rules.iter().find(|&&(known, _)| known == point) {
+            Some(&rule) => rule,
+            None => return false,
+        };
+        let strikes = match rule {
+            Rule::Chance(chance) => thread_rng().gen::<f64>() < chance,
+            Rule::Once => {
+                let mut fired = self.fired.lock().unwrap_or_else(|e| e.into_inner());
+                if fired.iter().any(|&done| done == point) {
+                    false
+                } else {
+                    fired.push(point);
+                    true
+                }
+            }
+        };
+        if strikes {
+            eprintln!("CHAOS: injecting a failure at {}", point);
+        }
+        strikes
+    }
+
+    /// What's being injected, for the startup log
+    pub fn describe(&self) -> String {
+        let rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|&(point, rule)| match rule {
+                Rule::Chance(chance) => format!("{} with chance {}", point, chance),
+                Rule::Once => format!("{} once", point),
+            })
+            .collect();
+        rules.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use errors::{DiskError, PostingError};
+    use posting::{Post, Poster, Progress};
+    use std::fs::{create_dir_all, read_dir, read_to_string};
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempfile;
+    use toml;
+    use {
+        builtin_tiles, create_image_within_budget, tiles, BotConfig, Cancelled, EventLog, Phase,
+        Shutdown, State, StatePaths,
+    };
+
+    #[test]
+    fn parses_specs() {
+        let chaos = Chaos::parse("upload:0.5, generate:0 ,disk:once,").expect("Unable to parse");
+        assert_eq!(
+            chaos.rules,
+            vec![
+                ("upload", Rule::Chance(0.5)),
+                ("generate", Rule::Chance(0.0)),
+                ("disk", Rule::Once),
+            ]
+        );
+        assert_eq!(chaos.describe(), "upload with chance 0.5, generate with chance 0, disk once");
+    }
+
+    #[test]
+    fn refuses_bad_specs() {
+        let specs = &[
+            "",
+            " , ",
+            "network:0.5",
+            "upload",
+            "upload:1.5",
+            "upload:often",
+            "disk:once,disk:0.1",
+        ];
+        for spec in specs {
+            assert!(Chaos::parse(spec).is_err(), "{:?} accepted", spec);
+        }
+    }
+
+    #[test]
+    fn strikes_as_the_rules_say() {
+        let chaos = Chaos::parse("upload:1,generate:0,disk:once").expect("Unable to parse");
+        assert!(chaos.strike("upload"));
+        assert!(chaos.strike("upload"));
+        assert!(!chaos.strike("generate"));
+        assert!(chaos.strike("disk"));
+        assert!(!chaos.strike("disk"));
+        assert!(!chaos.strike("elsewhere"));
+
+        let unlisted = Chaos::parse("disk:1").expect("Unable to parse");
+        assert!(!unlisted.strike("upload"));
+    }
+
+    /// Backend which posts whatever it's given
+    struct Accepting;
+
+    impl Poster for Accepting {
+        fn name(&self) -> &str {
+            "mastodon"
+        }
+
+        fn post(&self, _post: &Post, _progress: &mut Progress) -> Result<(), PostingError> {
+            Ok(())
+        }
+    }
+
+    /// Check what a bot whose state is `state` should have on disk, given `posts` made so far
+    fn assert_consistent(state: &State, posts: u32) {
+        let saved = read_to_string(&state.paths.state).expect("Unable to read state");
+        let mut saved: State = toml::from_str(&saved).expect("Unable to parse state");
+        saved.paths = state.paths.clone();
+        assert_eq!(saved.id, state.id);
+        assert_eq!(saved.id, posts + 1);
+
+        let images: Vec<String> = read_dir(&state.paths.images)
+            .expect("Unable to list images")
+            .map(|entry| entry.expect("Unable to list images").file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        assert!(!images.iter().any(|name| name.contains(".tmp")), "{:?}", images);
+        let pngs = images.iter().filter(|name| name.ends_with(".png")).count() as u32;
+        match saved.phase {
+            Phase::Generated => {
+                let data = saved.get_saved_image().expect("Pending image is missing");
+                saved.check_saved_image(&data).expect("Pending image is damaged");
+                assert_eq!(pngs, posts + 1, "{:?}", images);
+            }
+            Phase::Awaiting => {
+                assert_eq!(saved.filename, None);
+                assert!(saved.posted_to.is_empty());
+                assert!(saved.progress.is_empty());
+                assert_eq!(pngs, posts, "{:?}", images);
+            }
+            Phase::AwaitingApproval => panic!("Approval isn't required"),
+        }
+    }
+
+    #[test]
+    fn state_stays_consistent_through_failures() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let paths = StatePaths {
+            state: dir.path().join("state"),
+            images: dir.path().join("images"),
+        };
+        create_dir_all(&paths.images).expect("Unable to create images directory");
+        let mut config: BotConfig = toml::from_str(&format!(
+            "map_size = 16\nimages_dir = '{}'\nmax_regenerations = 1\n",
+            paths.images.display()
+        )).expect("Invalid config");
+        let chaos = Chaos::parse("upload:0.4,generate:0.3,disk:0.3").expect("Unable to parse");
+        config.chaos = Some(Arc::new(chaos));
+
+        let tiles_config = builtin_tiles::tiles_config().expect("Unable to draw built-in tiles");
+        let renderer = tiles::load_renderer(Path::new(builtin_tiles::BUILTIN), &tiles_config)
+            .expect("Unable to load built-in tiles");
+        let posters: Vec<Arc<dyn Poster>> = vec![Arc::new(Accepting)];
+        let events = EventLog::new(false);
+        let shutdown = Shutdown::default();
+
+        // What the main loop does, a cycle at a time
+        let mut state = State::get_state(paths);
+        state.persist().expect("Unable to persist state");
+        let (mut posts, mut attempt) = (0, 0);
+        for _ in 0..30 {
+            match state.phase {
+                Phase::Awaiting => {
+                    let created = create_image_within_budget(
+                        &config,
+                        &renderer,
+                        &mut state,
+                        &events,
+                        &shutdown,
+                    );
+                    match created {
+                        Ok(image) => {
+                            state = state.generated(&image, &config);
+                            state.persist().expect("Unable to persist state");
+                        }
+                        Err(e) => {
+                            assert!(e.downcast_ref::<Cancelled>().is_none());
+                            if e.downcast_ref::<DiskError>().is_some() {
+                                state.failed();
+                            } else {
+                                state.skip_slot();
+                            }
+                        }
+                    }
+                }
+                Phase::Generated => {
+                    let data = state.get_saved_image().expect("Pending image is missing");
+                    state.note_gap(&config);
+                    let post = state.draft_post(&config, data.into());
+                    attempt += 1;
+                    match state.post_everywhere(&posters, &post, attempt, &config, &events) {
+                        Ok(()) => {
+                            state = state.complete_post(&config);
+                            assert_eq!(state.failures, 0);
+                            posts += 1;
+                            attempt = 0;
+                        }
+                        Err(_) => {
+                            state.failed();
+                            assert!(state.failures > 0);
+                        }
+                    }
+                }
+                Phase::AwaitingApproval => panic!("Approval isn't required"),
+            }
+            assert_consistent(&state, posts);
+        }
+        assert!(posts > 0, "Nothing was posted in 30 cycles");
+    }
+}
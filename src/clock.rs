@@ -0,0 +1,230 @@
+//! Checking the system clock before trusting it
+//!
+//! A machine without a real-time clock, like a Raspberry Pi, can start up thinking it's 1970 and
+//! only get the right time once NTP syncs. The schedule would then have a post due in 1970, long
+//! overdue, so one would go out at once, and maybe more as the clock jumps about. Instead, the
+//! time is checked against two times it can't be earlier than: the last post, give or take
+//! `SKEW_ALLOWANCE` as its time comes from the instance, and the day the bot was built. While it's
+//! earlier, the bot waits for it to be set right, up to `max_clock_wait_secs`, then gives up.
+//!
+//! The clock is read and slept on through `Clock`, so the waiting can be tried with a clock that
+//! shows whatever is needed.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Duration as ChrDuration, Utc};
+
+use build_info;
+use shutdown::Shutdown;
+
+/// How much earlier than the last post the clock may be, as the instance's clock may be ahead
+const SKEW_ALLOWANCE: i64 = 300;
+
+/// How long to sleep between looks at the clock
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where the time comes from
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Sleep for `duration`, returning false if woken early to shut down
+    fn sleep(&self, duration: Duration) -> bool;
+}
+
+/// The system clock, with sleeps cut short by shutdown
+pub struct SystemClock<'a>(pub &'a Shutdown);
+
+impl<'a> Clock for SystemClock<'a> {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> bool {
+        self.0.sleep(duration)
+    }
+}
+
+/// Start of the day the bot was built
+pub fn build_floor() -> DateTime<Utc> {
+    build_info::built_at().date().and_hms(0, 0, 0)
+}
+
+/// What's wrong with `now`, if it's earlier than it can be
+pub fn problem(
+    now: DateTime<Utc>,
+    last_post: Option<DateTime<Utc>>,
+    floor: DateTime<Utc>,
+) -> Option<String> {
+    if now < floor {
+        return Some(format!("it is {}, before the bot was built on {}", now, floor.date()));
+    }
+    match last_post {
+        Some(last_post) if now < last_post - ChrDuration::seconds(SKEW_ALLOWANCE) => Some(format!(
+            "it is {}, before the last post at {}",
+            now, last_post
+        )),
+        _ => None,
+    }
+}
+
+/// How waiting for the clock went
+#[derive(Debug, PartialEq)]
+pub enum Sanity {
+    /// The clock is, or came to be, plausible
+    Sane,
+    /// It was still wrong after the longest wait allowed
+    GaveUp,
+    /// Shutdown was requested while waiting
+    ShutDown,
+}
+
+/// Wait until `clock` is no earlier than `last_post` and `floor` allow, for at most `max_wait`
+///
+/// The wait is measured by the sleeps it took, as the clock itself can't be relied on for it.
+pub fn wait_until_sane<C: Clock>(
+    clock: &C,
+    last_post: Option<DateTime<Utc>>,
+    floor: DateTime<Utc>,
+    max_wait: Duration,
+) -> Sanity {
+    let mut waited = Duration::from_secs(0);
+    let mut logged = false;
+    loop {
+        let problem = match problem(clock.now(), last_post, floor) {
+            Some(problem) => problem,
+            None => {
+                if logged {
+                    eprintln!("The system clock looks right now, at {}", clock.now());
+                }
+                return Sanity::Sane;
+            }
+        };
+        if waited >= max_wait {
+            eprintln!(
+                "ERROR: The system clock is still wrong after {} seconds ({}), giving up",
+                waited.as_secs(),
+                problem
+            );
+            return Sanity::GaveUp;
+        }
+        if !logged {
+            eprintln!(
+                "WARNING: The system clock looks wrong ({}), waiting up to {} seconds for it to be \
+                 set",
+                problem,
+                max_wait.as_secs()
+            );
+            logged = true;
+        }
+
+        let nap = CHECK_INTERVAL.min(max_wait - waited);
+        if !clock.sleep(nap) {
+            return Sanity::ShutDown;
+        }
+        waited += nap;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::cell::{Cell, RefCell};
+
+    /// A clock which shows `times` in turn, one per sleep, staying on the last
+    struct FakeClock {
+        times: Vec<DateTime<Utc>>,
+        slept: RefCell<Vec<Duration>>,
+        /// Sleeps to allow before shutdown is requested
+        sleeps_left: Cell<usize>,
+    }
+
+    impl FakeClock {
+        fn new(times: Vec<DateTime<Utc>>) -> FakeClock {
+            FakeClock {
+                times,
+                slept: RefCell::new(Vec::new()),
+                sleeps_left: Cell::new(usize::max_value()),
+            }
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Utc> {
+            let index = self.slept.borrow().len().min(self.times.len() - 1);
+            self.times[index]
+        }
+
+        fn sleep(&self, duration: Duration) -> bool {
+            if self.sleeps_left.get() == 0 {
+                return false;
+            }
+            self.sleeps_left.set(self.sleeps_left.get() - 1);
+            self.slept.borrow_mut().push(duration);
+            true
+        }
+    }
+
+    fn floor() -> DateTime<Utc> {
+        Utc.ymd(2024, 5, 1).and_hms(0, 0, 0)
+    }
+
+    fn epoch() -> DateTime<Utc> {
+        Utc.ymd(1970, 1, 1).and_hms(0, 0, 0)
+    }
+
+    #[test]
+    fn spots_implausible_times() {
+        let last_post = Utc.ymd(2024, 6, 1).and_hms(12, 0, 0);
+        let problem = |now| problem(now, Some(last_post), floor());
+
+        let early = problem(epoch()).expect("1970 passed");
+        assert!(early.contains("before the bot was built"), "{}", early);
+        let before_last = problem(Utc.ymd(2024, 5, 20).and_hms(0, 0, 0)).expect("Passed");
+        assert!(before_last.contains("before the last post"), "{}", before_last);
+
+        // The instance's clock may be a little ahead
+        assert_eq!(problem(last_post - ChrDuration::seconds(SKEW_ALLOWANCE)), None);
+        assert!(problem(last_post - ChrDuration::seconds(SKEW_ALLOWANCE + 1)).is_some());
+        assert_eq!(problem(last_post + ChrDuration::days(1)), None);
+        assert_eq!(self::problem(floor(), None, floor()), None);
+    }
+
+    #[test]
+    fn sane_clock_doesnt_wait() {
+        let clock = FakeClock::new(vec![Utc.ymd(2024, 6, 1).and_hms(0, 0, 0)]);
+        let sanity = wait_until_sane(&clock, None, floor(), Duration::from_secs(3600));
+        assert_eq!(sanity, Sanity::Sane);
+        assert!(clock.slept.borrow().is_empty());
+    }
+
+    #[test]
+    fn waits_for_clock_to_be_set() {
+        let set = Utc.ymd(2024, 6, 1).and_hms(0, 0, 0);
+        let clock = FakeClock::new(vec![epoch(), epoch(), epoch(), set]);
+        let sanity = wait_until_sane(&clock, None, floor(), Duration::from_secs(3600));
+        assert_eq!(sanity, Sanity::Sane);
+        assert_eq!(*clock.slept.borrow(), vec![CHECK_INTERVAL; 3]);
+    }
+
+    #[test]
+    fn gives_up_after_max_wait() {
+        let clock = FakeClock::new(vec![epoch()]);
+        let sanity = wait_until_sane(&clock, None, floor(), Duration::from_secs(25));
+        assert_eq!(sanity, Sanity::GaveUp);
+        // The last nap only takes what's left
+        assert_eq!(
+            *clock.slept.borrow(),
+            vec![CHECK_INTERVAL, CHECK_INTERVAL, Duration::from_secs(5)]
+        );
+    }
+
+    #[test]
+    fn stops_waiting_on_shutdown() {
+        let clock = FakeClock::new(vec![epoch()]);
+        clock.sleeps_left.set(2);
+        let sanity = wait_until_sane(&clock, None, floor(), Duration::from_secs(3600));
+        assert_eq!(sanity, Sanity::ShutDown);
+        assert_eq!(clock.slept.borrow().len(), 2);
+    }
+}
@@ -0,0 +1,189 @@
+//! Keeping the terrain settings from contradicting each other
+//!
+//! Some combinations of `layer_height`, `min_soil_cutoff` and `max_water_level` make maps that
+//! aren't worth posting, like ones with no soil at all, or with all of it under water. Ranges and
+//! `evolution` can wander into those even when the configured values are fine, so the rules are
+//! checked on the values rolled for each image. A value which breaks one is clamped to the
+//! nearest one which doesn't, and the adjustment is logged and kept with the image's parameters,
+//! along with the value asked for. `--check-config` warns about the extremes of the configured
+//! ranges breaking them.
+//!
+//! The rules follow from what the settings mean: soil only goes up to the soil cutoff, in a layer
+//! up to `layer_height` thick, and water fills everything empty below the water level. Settings
+//! left to cubeglobe's defaults aren't known here, so rules involving them are skipped.
+
+/// The settings the rules are about
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TerrainLevels {
+    pub layer_height: Option<usize>,
+    pub min_soil_cutoff: Option<usize>,
+    pub max_water_level: Option<usize>,
+}
+
+/// A setting changed to keep it consistent with the others
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Adjustment {
+    pub key: String,
+    pub requested: usize,
+    pub effective: usize,
+    pub reason: String,
+}
+
+/// One relationship between the settings
+struct Rule {
+    /// Setting which is changed if the rule is broken
+    key: &'static str,
+    reason: &'static str,
+    /// The value `key` has to be changed to, if the rule is broken
+    fix: fn(&TerrainLevels) -> Option<usize>,
+}
+
+/// Checked in order, each on the values the ones before it left
+const RULES: &[Rule] = &[
+    Rule {
+        key: "min_soil_cutoff",
+        reason: "with a soil cutoff of 0, soil never appears",
+        fix: cutoff_above_ground,
+    },
+    Rule {
+        key: "layer_height",
+        reason: "the soil layer can't be thicker than the height soil goes up to",
+        fix: layer_within_cutoff,
+    },
+    Rule {
+        key: "max_water_level",
+        reason: "water at or above the soil cutoff covers all of the soil",
+        fix: water_below_cutoff,
+    },
+];
+
+fn cutoff_above_ground(levels: &TerrainLevels) -> Option<usize> {
+    match levels.min_soil_cutoff {
+        Some(0) => Some(1),
+        _ => None,
+    }
+}
+
+fn layer_within_cutoff(levels: &TerrainLevels) -> Option<usize> {
+    match (levels.layer_height, levels.min_soil_cutoff) {
+        (Some(height), Some(cutoff)) if height > cutoff => Some(cutoff),
+        _ => None,
+    }
+}
+
+fn water_below_cutoff(levels: &TerrainLevels) -> Option<usize> {
+    match (levels.max_water_level, levels.min_soil_cutoff) {
+        (Some(level), Some(cutoff)) if level >= cutoff => Some(cutoff.saturating_sub(1)),
+        _ => None,
+    }
+}
+
+impl TerrainLevels {
+    fn setting(&mut self, key: &str) -> &mut Option<usize> {
+        match key {
+            "layer_height" => &mut self.layer_height,
+            "min_soil_cutoff" => &mut self.min_soil_cutoff,
+            "max_water_level" => &mut self.max_water_level,
+            _ => unreachable!("no terrain setting {}", key),
+        }
+    }
+}
+
+/// `levels` made consistent, with what had to be changed for that
+pub fn validate_params(mut levels: TerrainLevels) -> (TerrainLevels, Vec<Adjustment>) {
+    let mut adjustments = Vec::new();
+    for rule in RULES {
+        let effective = match (rule.fix)(&levels) {
+            Some(effective) => effective,
+            None => continue,
+        };
+        let setting = levels.setting(rule.key);
+        if let Some(requested) = setting.replace(effective) {
+            adjustments.push(Adjustment {
+                key: rule.key.to_string(),
+                requested,
+                effective,
+                reason: rule.reason.to_string(),
+            });
+        }
+    }
+    (levels, adjustments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(
+        layer_height: Option<usize>,
+        min_soil_cutoff: Option<usize>,
+        max_water_level: Option<usize>,
+    ) -> TerrainLevels {
+        TerrainLevels {
+            layer_height,
+            min_soil_cutoff,
+            max_water_level,
+        }
+    }
+
+    #[test]
+    fn validate_params_table() {
+        // Settings asked for, settings made consistent, and each change as the key, the value
+        // asked for and the value used
+        let cases: &[(TerrainLevels, TerrainLevels, &[(&str, usize, usize)])] = &[
+            (levels(None, None, None), levels(None, None, None), &[]),
+            (levels(Some(9), Some(30), Some(15)), levels(Some(9), Some(30), Some(15)), &[]),
+            (
+                levels(None, Some(0), None),
+                levels(None, Some(1), None),
+                &[("min_soil_cutoff", 0, 1)],
+            ),
+            (
+                levels(Some(40), Some(30), None),
+                levels(Some(30), Some(30), None),
+                &[("layer_height", 40, 30)],
+            ),
+            (levels(Some(30), Some(30), None), levels(Some(30), Some(30), None), &[]),
+            (
+                levels(None, Some(30), Some(30)),
+                levels(None, Some(30), Some(29)),
+                &[("max_water_level", 30, 29)],
+            ),
+            (
+                levels(None, Some(30), Some(100)),
+                levels(None, Some(30), Some(29)),
+                &[("max_water_level", 100, 29)],
+            ),
+            (levels(None, Some(1), Some(0)), levels(None, Some(1), Some(0)), &[]),
+            (
+                levels(None, Some(1), Some(1)),
+                levels(None, Some(1), Some(0)),
+                &[("max_water_level", 1, 0)],
+            ),
+            // Every rule, each on what the one before left
+            (
+                levels(Some(9), Some(0), Some(5)),
+                levels(Some(1), Some(1), Some(0)),
+                &[("min_soil_cutoff", 0, 1), ("layer_height", 9, 1), ("max_water_level", 5, 0)],
+            ),
+            // Rules about settings left to cubeglobe's defaults are skipped
+            (levels(Some(50), None, Some(50)), levels(Some(50), None, Some(50)), &[]),
+        ];
+
+        for &(requested, expected, changes) in cases {
+            let (effective, adjustments) = validate_params(requested);
+            assert_eq!(effective, expected, "{:?}", requested);
+            let made: Vec<(&str, usize, usize)> = adjustments
+                .iter()
+                .map(|adjustment| {
+                    (adjustment.key.as_str(), adjustment.requested, adjustment.effective)
+                })
+                .collect();
+            assert_eq!(made, changes, "{:?}", requested);
+            assert!(adjustments.iter().all(|adjustment| !adjustment.reason.is_empty()));
+
+            // Consistent settings stay as they are
+            assert_eq!(validate_params(effective), (effective, Vec::new()));
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! High-contrast variant attachment
+//!
+//! The terrain's greens and blues are hard to tell apart for some followers. With `[bot.contrast]`,
+//! a second version of the image is attached after it, made when posting from the saved image:
+//! either with the colors in `remap` replaced by others, picked to tell apart better, or, with no
+//! `remap`, as a grayscale with its brightness spread over the whole range and its contrast
+//! boosted. Animations get a variant of their first frame. Transparency is kept as it is.
+//!
+//! `remap` and `boosted_grayscale` only depend on their arguments, so palettes can be tried out
+//! on saved images without running the bot.
+
+use std::collections::BTreeMap;
+
+use image::{self, DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+
+use background::parse_color;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ContrastConfig {
+    /// Colors to replace, as `"#rrggbb" = "#rrggbb"`. A boosted grayscale if empty.
+    #[serde(default)]
+    pub remap: BTreeMap<String, String>,
+
+    /// How far each channel of a pixel may be off a color in `remap` for it to still be
+    /// replaced, as tiles are shaded
+    #[serde(default = "default_tolerance")]
+    pub tolerance: u8,
+
+    /// How much the grayscale's contrast is raised, around its middle gray
+    #[serde(default = "default_boost")]
+    pub boost: f64,
+
+    /// Alt text of the variant
+    #[serde(default = "default_alt_text")]
+    pub alt_text: String,
+}
+
+fn default_tolerance() -> u8 {
+    12
+}
+
+fn default_boost() -> f64 {
+    1.5
+}
+
+fn default_alt_text() -> String {
+    "A high-contrast version of the first image, for easier telling apart of water, grass and \
+     other terrain."
+        .to_string()
+}
+
+impl ContrastConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (from, to) in &self.remap {
+            parse_color(from).map_err(|e| format!("remap: {}", e))?;
+            parse_color(to).map_err(|e| format!("remap.{:?}: {}", from, e))?;
+        }
+        if !self.boost.is_finite() || self.boost <= 0.0 {
+            return Err(format!("boost must be above 0, got {}", self.boost));
+        }
+        if self.alt_text.trim().is_empty() {
+            return Err("alt_text must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    /// `remap` as parsed colors
+    fn table(&self) -> Vec<([u8; 3], [u8; 3])> {
+        self.remap
+            .iter()
+            .map(|(from, to)| {
+                (
+                    parse_color(from).expect("checked by validate"),
+                    parse_color(to).expect("checked by validate"),
+                )
+            })
+            .collect()
+    }
+
+    /// Make the variant of `image`, as PNG
+    pub fn render(&self, image: &[u8]) -> Result<Vec<u8>, String> {
+        let decoded =
+            image::load_from_memory(image).map_err(|e| format!("unable to decode: {}", e))?;
+        let rgb = decoded.to_rgb();
+        let variant = if self.remap.is_empty() {
+            boosted_grayscale(&rgb, self.boost)
+        } else {
+            remap(&rgb, &self.table(), self.tolerance)
+        };
+
+        // The alpha channel is put back as it was
+        let mut rgba = decoded.to_rgba();
+        for (pixel, varied) in rgba.pixels_mut().zip(variant.pixels()) {
+            pixel.data[..3].copy_from_slice(&varied.data);
+        }
+
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(rgba)
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .map_err(|e| format!("unable to encode: {}", e))?;
+        Ok(png)
+    }
+}
+
+/// `image` with every pixel within `tolerance` of a color in `table` replaced by what it maps
+/// to, keeping the pixel's shading relative to that color
+///
+/// The first matching entry wins. Other pixels are left as they are.
+pub fn remap(image: &RgbImage, table: &[([u8; 3], [u8; 3])], tolerance: u8) -> RgbImage {
+    let mut remapped = image.clone();
+    for pixel in remapped.pixels_mut() {
+        let original = pixel.data;
+        let entry = table.iter().find(|&&(from, _)| {
+            from.iter()
+                .zip(original.iter())
+                .all(|(&a, &b)| (i16::from(a) - i16::from(b)).abs() <= i16::from(tolerance))
+        });
+        if let Some(&(from, to)) = entry {
+            let mut mapped = [0u8; 3];
+            for channel in 0..3 {
+                let shade = i16::from(original[channel]) - i16::from(from[channel]);
+                mapped[channel] = (i16::from(to[channel]) + shade).max(0).min(255) as u8;
+            }
+            *pixel = Rgb { data: mapped };
+        }
+    }
+    remapped
+}
+
+/// `image` as grayscale, with its brightness stretched to the whole range, then its contrast
+/// raised by `boost` around the middle
+pub fn boosted_grayscale(image: &RgbImage, boost: f64) -> RgbImage {
+    let luminance = |pixel: &Rgb<u8>| {
+        let [r, g, b] = pixel.data;
+        0.2126 * f64::from(r) + 0.7152 * f64::from(g) + 0.0722 * f64::from(b)
+    };
+
+    let (mut darkest, mut brightest) = (255.0f64, 0.0f64);
+    for pixel in image.pixels() {
+        let value = luminance(pixel);
+        darkest = darkest.min(value);
+        brightest = brightest.max(value);
+    }
+    let range = (brightest - darkest).max(1.0);
+
+    let mut gray = image.clone();
+    for pixel in gray.pixels_mut() {
+        let stretched = (luminance(pixel) - darkest) / range;
+        let boosted = ((stretched - 0.5) * boost + 0.5).max(0.0).min(1.0);
+        let value = (boosted * 255.0).round() as u8;
+        *pixel = Rgb {
+            data: [value, value, value],
+        };
+    }
+    gray
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn config(remap: &[(&str, &str)]) -> ContrastConfig {
+        ContrastConfig {
+            remap: remap
+                .iter()
+                .map(|&(from, to)| (from.to_string(), to.to_string()))
+                .collect(),
+            tolerance: default_tolerance(),
+            boost: default_boost(),
+            alt_text: default_alt_text(),
+        }
+    }
+
+    fn row(colors: &[[u8; 3]]) -> RgbImage {
+        let mut image = RgbImage::new(colors.len() as u32, 1);
+        for (x, &color) in colors.iter().enumerate() {
+            image.put_pixel(x as u32, 0, Rgb { data: color });
+        }
+        image
+    }
+
+    fn colors(image: &RgbImage) -> Vec<[u8; 3]> {
+        image.pixels().map(|pixel| pixel.data).collect()
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(config(&[("#00ff00", "#ffcc00")]).validate().is_ok());
+        for remap in &[("green", "#ffcc00"), ("#00ff00", "#fc0")] {
+            assert!(config(&[*remap]).validate().is_err(), "{:?}", remap);
+        }
+        for &boost in &[0.0, -1.0, ::std::f64::INFINITY] {
+            let mut config = config(&[]);
+            config.boost = boost;
+            assert!(config.validate().is_err(), "{}", boost);
+        }
+    }
+
+    #[test]
+    fn remaps_colors_and_their_shades() {
+        let table = [([0, 200, 0], [255, 200, 0]), ([0, 0, 200], [0, 0, 100])];
+        let image = row(&[[0, 200, 0], [5, 190, 0], [0, 0, 210], [0, 100, 0], [0, 200, 250]]);
+        assert_eq!(
+            colors(&remap(&image, &table, 12)),
+            vec![[255, 200, 0], [255, 190, 0], [0, 0, 110], [0, 100, 0], [0, 200, 250]]
+        );
+        // Shading can't go past the ends of the range
+        let bright = row(&[[10, 210, 10]]);
+        let table = [([0, 200, 0], [250, 250, 250])];
+        assert_eq!(colors(&remap(&bright, &table, 12)), vec![[255, 255, 255]]);
+    }
+
+    #[test]
+    fn first_matching_entry_wins() {
+        let table = [([0, 100, 0], [1, 1, 1]), ([0, 105, 0], [2, 2, 2])];
+        assert_eq!(colors(&remap(&row(&[[0, 104, 0]]), &table, 5)), vec![[1, 5, 1]]);
+    }
+
+    #[test]
+    fn stretches_and_boosts_grayscale() {
+        let image = row(&[[50, 50, 50], [75, 75, 75], [150, 150, 150]]);
+        assert_eq!(
+            colors(&boosted_grayscale(&image, 1.0)),
+            vec![[0, 0, 0], [64, 64, 64], [255, 255, 255]]
+        );
+        let image = row(&[[50, 50, 50], [75, 75, 75], [125, 125, 125], [150, 150, 150]]);
+        assert_eq!(
+            colors(&boosted_grayscale(&image, 2.0)),
+            vec![[0, 0, 0], [0, 0, 0], [255, 255, 255], [255, 255, 255]]
+        );
+        // A flat image doesn't divide by zero, and comes out as the darkest
+        let flat = boosted_grayscale(&row(&[[80, 80, 80]; 2]), 1.0);
+        assert_eq!(colors(&flat), vec![[0, 0, 0]; 2]);
+    }
+
+    #[test]
+    fn keeps_transparency() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba { data: [0, 200, 0, 255] });
+        image.put_pixel(1, 0, Rgba { data: [0, 200, 0, 0] });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .expect("Unable to encode PNG");
+
+        let variant = config(&[("#00c800", "#ffcc00")])
+            .render(&png)
+            .expect("Unable to render variant");
+        let variant = image::load_from_memory(&variant)
+            .expect("Unable to decode variant")
+            .to_rgba();
+        assert_eq!(variant.get_pixel(0, 0).data, [255, 204, 0, 255]);
+        assert_eq!(variant.get_pixel(1, 0).data, [255, 204, 0, 0]);
+    }
+}
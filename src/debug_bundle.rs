@@ -0,0 +1,333 @@
+//! Debug bundles, for bug reports
+//!
+//! A bundle is a directory with what it takes to look into a problem after the fact: the
+//! config with its secrets redacted, the state file, the pending image and its `.pending.toml`,
+//! a listing of the images directory, the last events the bot emitted, version information, and
+//! the instance documents of the instances it posts to. `--debug-bundle DIR` writes one and
+//! exits, which has no events to include, and with `bundle_after_failures`, the bot writes one to
+//! `images/bundles/` once that many failures came in a row.
+//!
+//! Every file is checked for the configured secrets once written, and any file containing one
+//! is removed again, so a bundle can be attached to a bug report as it is.
+
+use std::env::consts::{ARCH, OS};
+use std::fs::{copy, create_dir_all, read, read_dir, remove_file, File};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use serde_json;
+
+use approval::ApprovalFiles;
+use archive;
+use build_info::BuildInfo;
+use events::EventLog;
+use formats;
+use {read_config, BotConfig, State, BUNDLES_DIR};
+
+/// What goes into bundles, besides the state and events at the time
+pub struct Sources<'a> {
+    pub config_path: &'a Path,
+    pub matches: &'a ArgMatches<'a>,
+    /// Secrets of the config the bot started with, which reloads don't change
+    pub secrets: Vec<String>,
+    pub instance_bases: &'a [String],
+}
+
+impl<'a> Sources<'a> {
+    /// Write a bundle to `images/bundles/` if `state` just reached `bundle_after_failures`
+    ///
+    /// Problems are only logged, as the bot has bigger ones at that point.
+    pub fn write_if_due(&self, config: &BotConfig, state: &State, events: &EventLog) {
+        if config.bundle_after_failures != Some(state.failures) {
+            return;
+        }
+
+        let dir = state
+            .paths
+            .images
+            .join(BUNDLES_DIR)
+            .join(Utc::now().format("%Y%m%dT%H%M%SZ").to_string());
+        eprintln!(
+            "{} failures in a row, writing a debug bundle to {}",
+            state.failures,
+            dir.display()
+        );
+        if let Err(e) = self.write(&dir, state, events) {
+            eprintln!("Unable to write debug bundle: {:#}", e);
+        }
+    }
+
+    /// Write a bundle to `dir`, which must not exist yet or be empty
+    ///
+    /// Parts which can't be gathered are listed in `problems.txt` instead.
+    pub fn write(&self, dir: &Path, state: &State, events: &EventLog) -> Result<(), Error> {
+        if dir.exists() && read_dir(dir)?.next().is_some() {
+            return Err(Error::msg(format!("{} is not empty", dir.display())));
+        }
+        create_dir_all(dir).with_context(|| format!("unable to create {}", dir.display()))?;
+
+        let mut problems = Vec::new();
+        let mut note = |part: &str, result: Result<(), Error>| {
+            if let Err(e) = result {
+                problems.push(format!("{}: {:#}", part, e));
+            }
+        };
+
+        note("version", write_file(&dir.join("version.txt"), &version()));
+        match read_config(self.config_path, self.matches) {
+            Ok(config) => {
+                note(
+                    "config",
+                    config
+                        .to_redacted_toml()
+                        .and_then(|config| write_file(&dir.join("config.toml"), &config)),
+                );
+                note(
+                    "images",
+                    list_images(&state.paths.images, &config.bot.filename_template())
+                        .and_then(|list| write_file(&dir.join("images.txt"), &list)),
+                );
+            }
+            Err(e) => note("config", Err(e)),
+        }
+        note("state", copy_file(&state.paths.state, &dir.join("state.toml")));
+        if let Some(ref filename) = state.filename {
+            note(
+                "pending image",
+                copy_file(&state.paths.images.join(filename), &dir.join(filename)),
+            );
+            let sidecar = ApprovalFiles::new(&state.paths.images, filename).pending;
+            if sidecar.exists() {
+                let name = sidecar.file_name().expect("sidecar path has a file name");
+                note("pending image sidecar", copy_file(&sidecar, &dir.join(name)));
+            }
+        }
+
+        let mut lines = String::new();
+        for record in events.recent() {
+            lines.push_str(&serde_json::to_string(&record)?);
+            lines.push('\n');
+        }
+        note("events", write_file(&dir.join("events.jsonl"), &lines));
+
+        for base in self.instance_bases {
+            let host = base
+                .trim_start_matches("https://")
+                .trim_start_matches("http://")
+                .trim_end_matches('/')
+                .replace('/', "_");
+            let path = dir.join(format!("instance-{}.json", host));
+            note(
+                &format!("instance {}", base),
+                formats::fetch_instance(base).and_then(|body| write_file(&path, &body)),
+            );
+        }
+
+        if !problems.is_empty() {
+            write_file(&dir.join("problems.txt"), &(problems.join("\n") + "\n"))?;
+        }
+
+        let leaked = remove_leaked_secrets(dir, &self.secrets)?;
+        if !leaked.is_empty() {
+            return Err(Error::msg(format!(
+                "removed {} from the bundle, as they contained secrets",
+                leaked.join(", ")
+            )));
+        }
+        eprintln!("Wrote debug bundle to {}", dir.display());
+        Ok(())
+    }
+}
+
+fn version() -> String {
+    format!(
+        "{}platform: {}-{}\nwritten: {}\n",
+        BuildInfo::current().report(),
+        ARCH,
+        OS,
+        Utc::now().to_rfc3339()
+    )
+}
+
+/// Every file in the images directory, one per line with what it is, its size and when it was
+/// last changed
+fn list_images(images: &Path, template: &str) -> Result<String, Error> {
+    let mut list = String::new();
+    for entry in archive::scan(images, template)? {
+        let modified = entry
+            .modified
+            .map_or_else(|| "?".to_string(), |time| DateTime::<Utc>::from(time).to_rfc3339());
+        list.push_str(&format!(
+            "{}\t{:?}\t{}\t{}\n",
+            entry.name, entry.kind, entry.size, modified
+        ));
+    }
+    Ok(list)
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), Error> {
+    File::create(path)?.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<(), Error> {
+    copy(from, to).with_context(|| format!("unable to copy {}", from.display()))?;
+    Ok(())
+}
+
+/// Remove the files in `dir` that contain any of `secrets`, returning their names
+fn remove_leaked_secrets(dir: &Path, secrets: &[String]) -> Result<Vec<String>, Error> {
+    let mut leaked = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let contents = read(&path)?;
+        let found = secrets.iter().any(|secret| {
+            contents
+                .windows(secret.len())
+                .any(|window| window == secret.as_bytes())
+        });
+        if found {
+            remove_file(&path)?;
+            leaked.push(path.file_name().map_or_else(String::new, |name| {
+                name.to_string_lossy().into_owned()
+            }));
+        }
+    }
+    Ok(leaked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::App;
+    use std::fs::{read_to_string, write};
+    use std::path::PathBuf;
+    use tempfile::{tempdir, TempDir};
+    use StatePaths;
+
+    const TOKEN: &str = "token-aaaa-1111";
+    const SECRETS: &[&str] = &[
+        TOKEN,
+        "client-secret-bbbb-2222",
+        "fallback-token-cccc-3333",
+        "bearer-dddd-4444",
+        "webhook-secret-eeee-5555",
+    ];
+
+    /// A temporary directory with a config holding `SECRETS`, and a state with image 4 pending
+    fn setup() -> (TempDir, PathBuf, State) {
+        let root = tempdir().expect("Unable to create temporary directory");
+        let images = root.path().join("images");
+        create_dir_all(&images).expect("Unable to create images directory");
+        let state_path = root.path().join("state");
+
+        let config_path = root.path().join("config.toml");
+        let config = format!(
+            "[bot]\n\
+             map_size = 16\n\
+             images_dir = '{}'\n\
+             state_path = '{}'\n\
+             [credentials]\n\
+             base = 'https://main.example'\n\
+             client_id = 'id'\n\
+             client_secret = '{}'\n\
+             redirect = 'urn:ietf:wg:oauth:2.0:oob'\n\
+             token = '{}'\n\
+             [credentials_fallback]\n\
+             base = 'https://backup.example'\n\
+             client_id = 'id'\n\
+             client_secret = ''\n\
+             redirect = 'urn:ietf:wg:oauth:2.0:oob'\n\
+             token = '{}'\n\
+             [webhook]\n\
+             url = 'https://hook.example'\n\
+             bearer_token = '{}'\n\
+             secret = '{}'\n",
+            images.display(),
+            state_path.display(),
+            SECRETS[1],
+            SECRETS[0],
+            SECRETS[2],
+            SECRETS[3],
+            SECRETS[4]
+        );
+        write(&config_path, config).expect("Unable to write config");
+        write(&state_path, "id = 4\nphase = \"Generated\"\nfilename = \"4.png\"\n")
+            .expect("Unable to write state");
+        write(images.join("4.png"), b"\x89PNG\r\n\x1a\nimage").expect("Unable to write image");
+
+        let state = State::get_state(StatePaths {
+            state: state_path,
+            images,
+        });
+        (root, config_path, state)
+    }
+
+    fn write_bundle(config_path: &Path, state: &State, dir: &Path) -> Result<(), Error> {
+        let matches = App::new("test").get_matches_from(vec!["test"]);
+        let config = read_config(config_path, &matches).expect("Invalid config");
+        let secrets: Vec<String> = config.secrets().into_iter().map(String::from).collect();
+        assert_eq!(secrets.len(), SECRETS.len());
+
+        let sources = Sources {
+            config_path,
+            matches: &matches,
+            secrets,
+            instance_bases: &[],
+        };
+        sources.write(dir, state, &EventLog::new(false))
+    }
+
+    /// Names of the files in `dir` which contain one of `SECRETS`
+    fn files_with_secrets(dir: &Path) -> Vec<String> {
+        let mut found = Vec::new();
+        for entry in read_dir(dir).expect("Unable to list bundle") {
+            let path = entry.expect("Unable to list bundle").path();
+            let contents = String::from_utf8_lossy(&read(&path).expect("Unable to read file"))
+                .into_owned();
+            if SECRETS.iter().any(|secret| contents.contains(secret)) {
+                found.push(path.display().to_string());
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn bundle_has_no_secrets() {
+        let (root, config_path, state) = setup();
+        let dir = root.path().join("bundle");
+        write_bundle(&config_path, &state, &dir).expect("Unable to write bundle");
+
+        let config = read_to_string(dir.join("config.toml")).expect("No config in bundle");
+        assert!(config.contains("<redacted>"));
+        assert!(dir.join("state.toml").exists());
+        assert!(dir.join("4.png").exists());
+        assert_eq!(files_with_secrets(&dir), Vec::<String>::new());
+    }
+
+    #[test]
+    fn files_with_secrets_are_removed() {
+        let (root, config_path, state) = setup();
+        write(state.paths.images.join("4.png"), format!("leaked {}", TOKEN))
+            .expect("Unable to write image");
+        let dir = root.path().join("bundle");
+
+        let error = write_bundle(&config_path, &state, &dir).unwrap_err();
+        assert!(error.to_string().contains("4.png"));
+        assert!(!dir.join("4.png").exists());
+        assert!(dir.join("config.toml").exists());
+        assert_eq!(files_with_secrets(&dir), Vec::<String>::new());
+    }
+
+    #[test]
+    fn refuses_non_empty_directory() {
+        let (root, config_path, state) = setup();
+        let dir = root.path().join("bundle");
+        create_dir_all(&dir).expect("Unable to create bundle directory");
+        write(dir.join("other"), "").expect("Unable to write file");
+        assert!(write_bundle(&config_path, &state, &dir).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Short flavor text describing a generated map
+//!
+//! Descriptions are built from a template, where each placeholder is either one of the
+//! generation parameters or a phrase picked from a phrase bank. Which bank a phrase comes from
+//! depends on the parameters, e.g. `{water}` picks from `water_high` for maps with a lot of
+//! water.
+
+use std::collections::BTreeMap;
+
+use rand::Rng;
+
+use {fill_template, template_placeholders, GenerationParams};
+
+/// Placeholders which are filled in with a generation parameter
+const VALUE_PLACEHOLDERS: &[&str] = &[
+    "map_size",
+    "frequency",
+    "layer_height",
+    "soil_cutoff",
+    "water_level",
+];
+
+/// Placeholders which are filled in with a phrase from one of their banks
+const PHRASE_PLACEHOLDERS: &[&str] = &["water", "hills"];
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Placement {
+    /// Description goes after the regular post body
+    Append,
+    /// Description is posted instead of the regular post body
+    Replace,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DescriptionConfig {
+    #[serde(default = "default_placement")]
+    pub placement: Placement,
+
+    #[serde(default = "default_template")]
+    pub template: String,
+
+    /// Phrase banks, overriding the built-in ones with the same name
+    #[serde(default)]
+    pub phrases: BTreeMap<String, Vec<String>>,
+}
+
+fn default_placement() -> Placement {
+    Placement::Append
+}
+fn default_template() -> String {
+    "{water}, {hills}.".to_string()
+}
+
+impl Default for DescriptionConfig {
+    fn default() -> DescriptionConfig {
+        DescriptionConfig {
+            placement: default_placement(),
+            template: default_template(),
+            phrases: BTreeMap::new(),
+        }
+    }
+}
+
+impl DescriptionConfig {
+    /// Check that the template only uses known placeholders, and that no phrase bank is empty
+    pub fn validate(&self) -> Result<(), String> {
+        for name in template_placeholders(&self.template)? {
+            if !VALUE_PLACEHOLDERS.contains(&name) && !PHRASE_PLACEHOLDERS.contains(&name) {
+                return Err(format!("unknown placeholder {{{}}} in template", name));
+            }
+        }
+
+        for (bank, phrases) in &self.phrases {
+            if phrases.is_empty() {
+                return Err(format!("phrase bank {} is empty", bank));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get a phrase bank, preferring the configured one over the built-in one
+    fn bank(&self, name: &str) -> Vec<String> {
+        match self.phrases.get(name) {
+            Some(phrases) => phrases.clone(),
+            None => default_bank(name).iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+fn default_bank(name: &str) -> &'static [&'static str] {
+    match name {
+        "water_low" => &["A dry highland", "A parched plateau", "An arid upland"],
+        "water_mid" => &["A lakeland", "A land of scattered ponds", "A river country"],
+        "water_high" => &["A drowned archipelago", "A flooded lowland", "A land of islets"],
+        "hills_gentle" => &["gentle hills", "soft slopes", "low mounds"],
+        "hills_rolling" => &["rolling hills", "uneven ground", "winding ridges"],
+        "hills_steep" => &["steep peaks", "jagged crags", "sharp ridges"],
+        _ => &[],
+    }
+}
+
+/// Which of the `water_*` banks to use, based on how high water may rise relative to the soil
+fn water_bank(params: &GenerationParams) -> &'static str {
+    match (params.max_water_level, params.min_soil_cutoff) {
+        (Some(water), Some(soil)) if soil > 0 => {
+            let ratio = water as f64 / soil as f64;
+            if ratio < 0.3 {
+                "water_low"
+            } else if ratio > 0.6 {
+                "water_high"
+            } else {
+                "water_mid"
+            }
+        }
+        _ => "water_mid",
+    }
+}
+
+/// Which of the `hills_*` banks to use, based on the noise frequency
+fn hills_bank(params: &GenerationParams) -> &'static str {
+    match params.frequency {
+        Some(frequency) if frequency < 0.015 => "hills_gentle",
+        Some(frequency) if frequency > 0.03 => "hills_steep",
+        _ => "hills_rolling",
+    }
+}
+
+/// Build a description of a map generated with `params`
+pub fn describe<R: Rng>(
+    params: &GenerationParams,
+    config: &DescriptionConfig,
+    rng: &mut R,
+) -> String {
+    fn or_default<T: ToString>(value: Option<T>) -> String {
+        value
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| "default".to_string())
+    }
+
+    fill_template(&config.template, |name| {
+        let bank = match name {
+            "map_size" => return Some(params.map_size.to_string()),
+            "frequency" => {
+                return Some(or_default(params.frequency.map(|f| format!("{:.3}", f))))
+            }
+            "layer_height" => return Some(or_default(params.layer_height)),
+            "soil_cutoff" => return Some(or_default(params.min_soil_cutoff)),
+            "water_level" => return Some(or_default(params.max_water_level)),
+            "water" => water_bank(params),
+            "hills" => hills_bank(params),
+            _ => return None,
+        };
+
+        let phrases = config.bank(bank);
+        if phrases.is_empty() {
+            None
+        } else {
+            let index = rng.gen_range(0, phrases.len());
+            Some(phrases[index].clone())
+        }
+    })
+}
@@ -0,0 +1,206 @@
+//! Weekly digest
+//!
+//! Once a week, the bot posts a contact sheet of up to nine of the week's images, with links to
+//! the posts they came from. It goes out between regular posts, at the first chance after the
+//! configured time, and leaves the regular schedule alone.
+
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::Error;
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc, Weekday};
+use elefren::status_builder::Visibility;
+use elefren::{Mastodon, MastodonClient, MediaBuilder, StatusBuilder};
+use image::{self, imageops, DynamicImage, FilterType, ImageOutputFormat, Rgba, RgbaImage};
+
+use fill_template;
+use length::mastodon_char_count;
+
+/// Images per row of the contact sheet, and at most as many rows
+const COLUMNS: u32 = 3;
+/// Size of each image's cell on the contact sheet, in pixels
+const CELL_SIZE: u32 = 320;
+/// Statuses are kept under Mastodon's default limit, counted its way
+const MAX_CHARS: usize = 500;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct DigestConfig {
+    /// Day of the week the digest is posted on, like "sun" or "sunday"
+    #[serde(default = "default_weekday")]
+    pub weekday: Weekday,
+
+    /// Hour of the day, in UTC, from which the digest is posted
+    #[serde(default = "default_hour")]
+    pub hour: u32,
+
+    /// Text of the digest. {count} is replaced with the number of images on the sheet.
+    #[serde(default = "default_text")]
+    pub text: String,
+}
+
+fn default_weekday() -> Weekday {
+    Weekday::Sun
+}
+
+fn default_hour() -> u32 {
+    18
+}
+
+fn default_text() -> String {
+    "This week's worlds ({count}):".to_string()
+}
+
+/// A posted image which could go into the digest
+#[derive(Deserialize, Serialize, Clone)]
+pub struct DigestEntry {
+    /// Name of the image in the images directory
+    pub filename: String,
+    /// Address of the post, if known
+    pub url: Option<String>,
+    pub posted: DateTime<Utc>,
+}
+
+/// ISO week `now` is in, as `YYYY-Www`
+pub fn week_of(now: DateTime<Utc>) -> String {
+    now.format("%G-W%V").to_string()
+}
+
+/// Whether it's time for this week's digest
+///
+/// `last_week` is the last week a digest was attempted for, whether or not that worked out.
+pub fn is_due(config: &DigestConfig, last_week: Option<&str>, now: DateTime<Utc>) -> bool {
+    let (day, due_day) = (
+        now.weekday().num_days_from_monday(),
+        config.weekday.num_days_from_monday(),
+    );
+    let reached = day > due_day || (day == due_day && now.hour() >= config.hour);
+    reached && last_week != Some(week_of(now).as_str())
+}
+
+/// Drop entries too old to go into a digest any more
+pub fn prune(entries: &mut Vec<DigestEntry>, now: DateTime<Utc>) {
+    entries.retain(|entry| now - entry.posted < Duration::weeks(1));
+}
+
+/// Pick up to a sheet's worth of the past week's entries, spread out over the week
+fn pick(entries: &[DigestEntry], now: DateTime<Utc>) -> Vec<&DigestEntry> {
+    let week: Vec<&DigestEntry> = entries
+        .iter()
+        .filter(|entry| now - entry.posted < Duration::weeks(1))
+        .collect();
+    let max = (COLUMNS * COLUMNS) as usize;
+    if week.len() <= max {
+        return week;
+    }
+
+    (0..max).map(|i| week[i * week.len() / max]).collect()
+}
+
+/// Lay out `images` in rows of `COLUMNS`, each scaled down to fit its cell
+fn contact_sheet(images: &[DynamicImage]) -> DynamicImage {
+    let rows = (images.len() as u32 + COLUMNS - 1) / COLUMNS;
+    let columns = COLUMNS.min(images.len() as u32);
+    let mut sheet = RgbaImage::from_pixel(
+        columns * CELL_SIZE,
+        rows * CELL_SIZE,
+        Rgba { data: [0, 0, 0, 0] },
+    );
+
+    for (i, image) in images.iter().enumerate() {
+        let thumbnail = image.resize(CELL_SIZE, CELL_SIZE, FilterType::Triangle).to_rgba();
+        // Centered in the cell
+        let x = (i as u32 % COLUMNS) * CELL_SIZE + (CELL_SIZE - thumbnail.width()) / 2;
+        let y = (i as u32 / COLUMNS) * CELL_SIZE + (CELL_SIZE - thumbnail.height()) / 2;
+        imageops::overlay(&mut sheet, &thumbnail, x, y);
+    }
+
+    DynamicImage::ImageRgba8(sheet)
+}
+
+/// Split `lines` into statuses of at most `MAX_CHARS`
+fn split_statuses(lines: &[String]) -> Vec<String> {
+    let mut statuses: Vec<String> = Vec::new();
+    for line in lines {
+        let fits = statuses.last().map_or(false, |status| {
+            mastodon_char_count(status) + 1 + mastodon_char_count(line) <= MAX_CHARS
+        });
+        if fits {
+            let status = statuses.last_mut().expect("checked above");
+            status.push('\n');
+            status.push_str(line);
+        } else {
+            statuses.push(line.clone());
+        }
+    }
+    statuses
+}
+
+/// Post the digest of the week before `now`
+///
+/// Links to the posts go into the digest itself if they fit, and into replies to it otherwise.
+/// Images which can no longer be read are left out. Returns the number of images on the sheet,
+/// which is 0, with nothing posted, if there were none.
+pub fn post_digest(
+    config: &DigestConfig,
+    masto: &Mastodon,
+    images_dir: &Path,
+    entries: &[DigestEntry],
+    now: DateTime<Utc>,
+) -> Result<usize, Error> {
+    let mut images = Vec::new();
+    let mut links = Vec::new();
+    for entry in pick(entries, now) {
+        match image::open(images_dir.join(&entry.filename)) {
+            Ok(image) => {
+                images.push(image);
+                links.extend(entry.url.clone());
+            }
+            Err(e) => eprintln!("Unable to read {}, leaving it out: {}", entry.filename, e),
+        }
+    }
+    if images.is_empty() {
+        return Ok(0);
+    }
+
+    let mut data = Vec::new();
+    contact_sheet(&images).write_to(&mut data, ImageOutputFormat::PNG)?;
+    let attachment = masto.media(MediaBuilder {
+        description: Some(format!(
+            "A grid of {} procedurally generated landscapes posted this week.",
+            images.len()
+        )),
+        mimetype: Some("image/png".to_string()),
+        filename: Some(format!("digest-{}.png", week_of(now))),
+        ..MediaBuilder::from_reader(Cursor::new(data))
+    })?;
+
+    let text = fill_template(&config.text, |name| match name {
+        "count" => Some(images.len().to_string()),
+        _ => None,
+    });
+    let mut lines = vec![text];
+    lines.extend(links);
+    let mut statuses = split_statuses(&lines).into_iter();
+
+    let first = statuses.next().expect("at least the text");
+    let mut previous = masto.new_status(
+        StatusBuilder::new()
+            .status(first)
+            .media_ids(vec![attachment.id])
+            .visibility(Visibility::Public)
+            .build()?,
+    )?;
+    eprintln!("Posted the weekly digest at: {}", previous.uri);
+
+    for reply in statuses {
+        previous = masto.new_status(
+            StatusBuilder::new()
+                .status(reply)
+                .in_reply_to(previous.id.clone())
+                .visibility(Visibility::Unlisted)
+                .build()?,
+        )?;
+    }
+
+    Ok(images.len())
+}
@@ -0,0 +1,370 @@
+//! Posting more often when followers engage, and less often when they don't
+//!
+//! An experiment, off unless `[bot.adaptive]` is set. The bot keeps the Mastodon statuses of its
+//! last `posts` posts, and once each is `settle_hours` old, looks up how many favourites and
+//! boosts it got, once, keeping the count in the state file. At most `MAX_LOOKUPS` statuses are
+//! looked up each cycle, while waiting for the next post.
+//!
+//! Whenever a new count comes in, the interval between posts moves a `step` of the way towards
+//! `min_sleep_time` if the counts average `high` or more, or towards `max_sleep_time` if they
+//! average `low` or less, and stays where it is in between. The interval in effect is logged
+//! each cycle, kept in the state file, and emitted as an `IntervalAdapted` event when it changes.
+//! Jitter is applied to it as it is to `sleep_time`.
+//!
+//! `enabled = false` goes back to `sleep_time` right away, keeping the counts. `min_sleep_time`
+//! can't be less than the minimum interval, which is still enforced before every post.
+
+use chrono::{DateTime, Duration, Utc};
+use elefren::Data as MastoData;
+
+use build_info;
+use federation;
+
+/// Statuses looked up each cycle at most, to stay well within the instance's rate limits
+const MAX_LOOKUPS: usize = 5;
+/// Times looking up a status may fail before it's left out
+const LOOKUP_ATTEMPTS: u32 = 3;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AdaptiveConfig {
+    /// With false, `sleep_time` is used as it is
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// Shortest and longest the interval between posts can get, in seconds
+    pub min_sleep_time: i64,
+    pub max_sleep_time: i64,
+
+    /// Posts averaged over
+    #[serde(default = "default_posts")]
+    pub posts: usize,
+
+    /// Favourites and boosts per post, on average, from which posts come more often
+    pub high: f64,
+
+    /// Favourites and boosts per post, on average, up to which posts come less often
+    pub low: f64,
+
+    /// How far towards `min_sleep_time` or `max_sleep_time` the interval moves each time, from 0
+    /// to 1
+    #[serde(default = "default_step")]
+    pub step: f64,
+
+    /// Hours after posting before a status's count is looked up, to give it time to gather some
+    #[serde(default = "default_settle_hours")]
+    pub settle_hours: i64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_posts() -> usize {
+    10
+}
+
+fn default_step() -> f64 {
+    0.25
+}
+
+fn default_settle_hours() -> i64 {
+    24
+}
+
+impl AdaptiveConfig {
+    /// Check the settings, with `min_interval` as the minimum interval in seconds
+    pub fn validate(&self, min_interval: i64) -> Result<(), String> {
+        if self.min_sleep_time <= 0 || self.max_sleep_time < self.min_sleep_time {
+            return Err(
+                "min_sleep_time must be positive, and no more than max_sleep_time".to_string()
+            );
+        }
+        if self.min_sleep_time < min_interval {
+            return Err(format!(
+                "min_sleep_time must be at least the minimum interval, {} seconds",
+                min_interval
+            ));
+        }
+        if self.posts == 0 {
+            return Err("posts must be at least 1".to_string());
+        }
+        if !(self.low <= self.high) {
+            return Err("low must be no more than high".to_string());
+        }
+        if !(self.step > 0.0 && self.step <= 1.0) {
+            return Err(format!("step must be more than 0 and at most 1, got {}", self.step));
+        }
+        if self.settle_hours < 0 {
+            return Err("settle_hours must not be negative".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The interval to use after `current`, given the `average` count over the last posts, if any
+pub fn adapt(current: i64, average: Option<f64>, config: &AdaptiveConfig) -> i64 {
+    let current = current.max(config.min_sleep_time).min(config.max_sleep_time);
+    let target = match average {
+        Some(average) if average >= config.high => config.min_sleep_time,
+        Some(average) if average <= config.low => config.max_sleep_time,
+        _ => return current,
+    };
+
+    // Rounded towards the target, so small steps still get there
+    let moved = current as f64 + (target - current) as f64 * config.step;
+    let moved = if target < current {
+        moved.floor()
+    } else {
+        moved.ceil()
+    };
+    (moved as i64).max(config.min_sleep_time).min(config.max_sleep_time)
+}
+
+/// One of the last posts
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ScoredPost {
+    pub status_id: String,
+    pub posted: DateTime<Utc>,
+    /// Favourites and boosts, once looked up
+    #[serde(default)]
+    pub score: Option<u64>,
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+/// What the interval is adapted from, kept in the state file
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Engagement {
+    /// The interval in effect, in seconds, once it's been adapted
+    #[serde(default)]
+    pub sleep_time: Option<i64>,
+
+    /// The last posts, oldest first
+    #[serde(default)]
+    pub posts: Vec<ScoredPost>,
+}
+
+impl Engagement {
+    /// Add a post made as `status_id` at `posted`, keeping the last `keep`
+    pub fn record(&mut self, status_id: String, posted: DateTime<Utc>, keep: usize) {
+        self.posts.push(ScoredPost {
+            status_id,
+            posted,
+            score: None,
+            attempts: 0,
+        });
+        if self.posts.len() > keep {
+            let excess = self.posts.len() - keep;
+            self.posts.drain(..excess);
+        }
+    }
+
+    /// Average count over the posts which have one, with how many that is
+    pub fn average(&self) -> Option<(f64, usize)> {
+        let scores: Vec<u64> = self.posts.iter().filter_map(|post| post.score).collect();
+        if scores.is_empty() {
+            return None;
+        }
+        let total: u64 = scores.iter().sum();
+        Some((total as f64 / scores.len() as f64, scores.len()))
+    }
+
+    /// Look up the counts of posts old enough to have one, up to `MAX_LOOKUPS` of them,
+    /// returning how many came in
+    ///
+    /// Deleted statuses, and ones which can't be looked up `LOOKUP_ATTEMPTS` times, are left out.
+    pub fn look_up(
+        &mut self,
+        config: &AdaptiveConfig,
+        account: &MastoData,
+        now: DateTime<Utc>,
+    ) -> usize {
+        let settled = now - Duration::hours(config.settle_hours);
+        let client = build_info::http_client();
+        let mut looked_up = 0;
+        let mut scored = 0;
+        let mut gone = Vec::new();
+        for post in &mut self.posts {
+            if post.score.is_some() || post.posted > settled {
+                continue;
+            }
+            if looked_up == MAX_LOOKUPS {
+                break;
+            }
+            looked_up += 1;
+            match federation::look_up(&client, account, &post.status_id) {
+                Ok(Some(counts)) => {
+                    post.score = Some(counts.favourites_count + counts.reblogs_count);
+                    scored += 1;
+                }
+                Ok(None) => {
+                    eprintln!("Status {} is gone, leaving it out of the average", post.status_id);
+                    gone.push(post.status_id.clone());
+                }
+                Err(e) => {
+                    post.attempts += 1;
+                    if post.attempts < LOOKUP_ATTEMPTS {
+                        eprintln!("Unable to look up status {}: {}", post.status_id, e);
+                    } else {
+                        eprintln!(
+                            "Unable to look up status {} {} times, leaving it out: {}",
+                            post.status_id, LOOKUP_ATTEMPTS, e
+                        );
+                        gone.push(post.status_id.clone());
+                    }
+                }
+            }
+        }
+        self.posts.retain(|post| !gone.contains(&post.status_id));
+        scored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use test_support::MockServer;
+    use toml;
+
+    fn config(extra: &str) -> AdaptiveConfig {
+        toml::from_str(&format!(
+            "min_sleep_time = 3600\nmax_sleep_time = 7200\nhigh = 10.0\nlow = 2.0\n{}",
+            extra
+        )).expect("Unable to parse adaptive config")
+    }
+
+    fn account(base: &str) -> MastoData {
+        MastoData {
+            base: base.to_string().into(),
+            client_id: "id".into(),
+            client_secret: "secret".into(),
+            redirect: "urn:ietf:wg:oauth:2.0:oob".into(),
+            token: "token".into(),
+        }
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(config("").validate(600).is_ok());
+        let cases = &[
+            ("", 4000),
+            ("posts = 0", 600),
+            ("step = 0.0", 600),
+            ("step = 1.5", 600),
+            ("settle_hours = -1", 600),
+        ];
+        for &(extra, min_interval) in cases {
+            assert!(config(extra).validate(min_interval).is_err(), "{:?} accepted", extra);
+        }
+        let reversed: AdaptiveConfig = toml::from_str(
+            "min_sleep_time = 7200\nmax_sleep_time = 3600\nhigh = 10.0\nlow = 2.0",
+        ).expect("Unable to parse adaptive config");
+        assert!(reversed.validate(600).is_err());
+        let crossed: AdaptiveConfig = toml::from_str(
+            "min_sleep_time = 3600\nmax_sleep_time = 7200\nhigh = 1.0\nlow = 2.0",
+        ).expect("Unable to parse adaptive config");
+        assert!(crossed.validate(600).is_err());
+    }
+
+    #[test]
+    fn moves_towards_the_bounds() {
+        let config = config("");
+        let cases: &[(i64, Option<f64>, i64)] = &[
+            // High engagement shortens the interval by a quarter of the way
+            (7200, Some(12.0), 6300),
+            (6300, Some(10.0), 5625),
+            // Low engagement stretches it
+            (3600, Some(1.0), 4500),
+            (4500, Some(2.0), 5175),
+            // In between, or without counts, it stays
+            (5000, Some(5.0), 5000),
+            (5000, None, 5000),
+            // Out of bounds, as after the bounds changed, it's brought back in first
+            (10_000, None, 7200),
+            (60, Some(5.0), 3600),
+        ];
+        for &(current, average, adapted) in cases {
+            assert_eq!(adapt(current, average, &config), adapted, "from {}", current);
+        }
+    }
+
+    #[test]
+    fn small_steps_still_arrive() {
+        let config = config("step = 0.0001");
+        assert_eq!(adapt(3601, Some(20.0), &config), 3600);
+        assert_eq!(adapt(7199, Some(0.0), &config), 7200);
+        let mut interval = 7200;
+        for _ in 0..100 {
+            interval = adapt(interval, Some(20.0), &config);
+        }
+        assert_eq!(interval, 7100);
+    }
+
+    #[test]
+    fn keeps_the_last_posts() {
+        let mut engagement = Engagement::default();
+        assert_eq!(engagement.average(), None);
+        let posted = Utc.ymd(2026, 10, 1).and_hms(12, 0, 0);
+        for id in 1..5 {
+            engagement.record(id.to_string(), posted, 3);
+        }
+        let ids: Vec<&str> = engagement.posts.iter().map(|post| post.status_id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "3", "4"]);
+        assert_eq!(engagement.average(), None);
+
+        engagement.posts[0].score = Some(4);
+        engagement.posts[2].score = Some(8);
+        assert_eq!(engagement.average(), Some((6.0, 2)));
+    }
+
+    #[test]
+    fn looks_up_settled_posts_a_few_at_a_time() {
+        let counts = r#"{"id": "1", "reblogs_count": 2, "favourites_count": 5}"#;
+        let server = MockServer::start(vec![
+            (200, counts.to_string()),
+            (404, r#"{"error":"Record not found"}"#.to_string()),
+            (500, r#"{"error":"oops"}"#.to_string()),
+            (200, counts.to_string()),
+            (200, counts.to_string()),
+        ]);
+        let config = config("");
+        let now = Utc.ymd(2026, 10, 17).and_hms(12, 0, 0);
+        let mut engagement = Engagement::default();
+        for id in 1..8 {
+            engagement.record(id.to_string(), now - Duration::days(2), 10);
+        }
+        // Too recent to have settled
+        engagement.record("8".to_string(), now - Duration::hours(1), 10);
+
+        assert_eq!(engagement.look_up(&config, &account(&server.url), now), 3);
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/api/v1/statuses/1",
+                "/api/v1/statuses/2",
+                "/api/v1/statuses/3",
+                "/api/v1/statuses/4",
+                "/api/v1/statuses/5",
+            ]
+        );
+        let scores: Vec<(&str, Option<u64>, u32)> = engagement
+            .posts
+            .iter()
+            .map(|post| (post.status_id.as_str(), post.score, post.attempts))
+            .collect();
+        assert_eq!(
+            scores,
+            vec![
+                ("1", Some(7), 0),
+                ("3", None, 1),
+                ("4", Some(7), 0),
+                ("5", Some(7), 0),
+                ("6", None, 0),
+                ("7", None, 0),
+                ("8", None, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Error types
+//!
+//! Functions doing the actual work return these, so callers can tell what went wrong and react
+//! to it, like waiting out a `DiskError` or retrying a transient `PostingError`. `main` turns
+//! them into `anyhow::Error`, adding what it was doing at the time as context. Underlying errors
+//! are kept as sources wherever there is one. `ImagingError` is the library's, as turning
+//! rendered surfaces into images is shared with it.
+
+use std::error::Error as StdError;
+use std::io;
+use std::path::PathBuf;
+
+use elefren;
+use reqwest;
+
+/// Problems with the disk the images are saved to
+///
+/// These are expected to be transient, so the main loop waits and tries again instead of giving
+/// up.
+#[derive(Error, Debug)]
+pub enum DiskError {
+    #[error("only {available} bytes free in the images directory, need at least {required}")]
+    LowSpace { available: u64, required: u64 },
+    #[error("unable to create images directory {}: {source}", path.display())]
+    CreateDir { path: PathBuf, source: io::Error },
+    #[error("unable to write image file: {0}")]
+    Write(#[source] io::Error),
+    #[error("unable to check free space: {0}")]
+    Check(#[source] io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Invalid filename_template: {0}")]
+    FilenameTemplate(String),
+    #[error("Invalid frequency: {0}")]
+    Frequency(String),
+    #[error("Invalid description config: {0}")]
+    Description(String),
+    #[error("Invalid alt_text: {0}")]
+    AltText(String),
+    #[error("Invalid locales: {0}")]
+    Locales(String),
+    #[error("Invalid overlay config: {0}")]
+    Overlay(String),
+    #[error("Invalid gap notice: {0}")]
+    GapNotice(String),
+    #[error("Invalid {key}: {problem}")]
+    Value { key: &'static str, problem: String },
+    #[error("Trial render with the configured generator settings failed: {0}")]
+    TrialRender(String),
+    #[error(
+        "Images are estimated to need {estimate} bytes of memory, more than max_pipeline_bytes \
+         ({max}). {suggestion}"
+    )]
+    TooMuchMemory {
+        estimate: u64,
+        max: u64,
+        suggestion: &'static str,
+    },
+    #[error(
+        "A map_size of {map_size} would render images of about {width}x{height} pixels with this \
+         tileset, more than max_surface_pixels ({max}). Try a smaller map_size."
+    )]
+    SurfaceTooLarge {
+        map_size: usize,
+        width: u64,
+        height: u64,
+        max: u64,
+    },
+}
+
+/// Generating an image kept failing until the regeneration budget ran out
+#[derive(Error, Debug)]
+#[error("gave up after {attempts} attempts in {seconds} seconds, last error: {source}")]
+pub struct BudgetExhausted {
+    pub attempts: u32,
+    pub seconds: u64,
+    pub source: Box<dyn StdError + Send + Sync>,
+}
+
+/// Something was asked of the state that its current phase doesn't allow
+#[derive(Error, Debug)]
+#[error("function called while in incorrect state: {0}")]
+pub struct StateError(pub String);
+
+#[derive(Error, Debug)]
+pub enum PostingError {
+    #[error("Elefren returned an error: {0}")]
+    ElefrenError(#[from] elefren::Error),
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("{backend} responded with status {status}: {message}")]
+    Rejected {
+        backend: String,
+        status: u16,
+        message: String,
+    },
+    #[error("Unable to fit image within size limit: {0}")]
+    ImageTooLarge(String),
+    #[error("Status refused as a duplicate of a recent one: {0}")]
+    DuplicateStatus(String),
+    #[error(
+        "Instance refused the status's poll, remove [bot.poll] if it doesn't allow polls on \
+         statuses with images: {0}"
+    )]
+    PollRefused(String),
+    /// The instance no longer knows media uploaded by an earlier attempt, see `MastodonPoster`
+    #[error("Instance no longer knows the uploaded media, status {status}: {message}")]
+    UnknownMedia { status: u16, message: String },
+    /// The instance said it posted the status, but looking it up again says otherwise
+    #[error("Unable to verify the posted status: {0}")]
+    Unverified(String),
+    /// The attempt took longer than `max_attempt_secs`, and was left to finish in the background
+    #[error("Gave up on the attempt after {0} seconds")]
+    TimedOut(u64),
+    /// The attempt's worker thread panicked, with the panic message printed as it did
+    #[error("The attempt panicked partway")]
+    Panicked,
+}
+
+impl PostingError {
+    /// Whether trying again later might succeed
+    ///
+    /// Elefren errors are all treated as transient, so the main account keeps being retried as
+    /// it always has been.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            PostingError::ElefrenError(_)
+            | PostingError::Http(_)
+            | PostingError::Unverified(_)
+            | PostingError::TimedOut(_)
+            | PostingError::Panicked => true,
+            PostingError::Rejected { status, .. } => status >= 500 || status == 408 || status == 429,
+            // Uploaded again on the next attempt
+            PostingError::UnknownMedia { .. } => true,
+            // Already tried again with a varied body, see `MastodonPoster`
+            PostingError::ImageTooLarge(_)
+            | PostingError::DuplicateStatus(_)
+            | PostingError::PollRefused(_) => false,
+        }
+    }
+
+    /// HTTP status code the error carries, if any
+    pub fn status(&self) -> Option<u16> {
+        match *self {
+            PostingError::ElefrenError(elefren::Error::Client(status))
+            | PostingError::ElefrenError(elefren::Error::Server(status)) => Some(status.as_u16()),
+            PostingError::ElefrenError(elefren::Error::Http(ref e)) | PostingError::Http(ref e) => {
+                e.status().map(|status| status.as_u16())
+            }
+            PostingError::Rejected { status, .. } | PostingError::UnknownMedia { status, .. } => {
+                Some(status)
+            }
+            PostingError::DuplicateStatus(_) | PostingError::PollRefused(_) => Some(422),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    fn io_error(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, message)
+    }
+
+    #[test]
+    fn messages_include_details() {
+        let cases: Vec<(String, &[&str])> = vec![
+            (
+                DiskError::LowSpace {
+                    available: 1024,
+                    required: 4096,
+                }.to_string(),
+                &["1024", "4096"][..],
+            ),
+            (
+                DiskError::CreateDir {
+                    path: PathBuf::from("/srv/images"),
+                    source: io_error("read-only file system"),
+                }.to_string(),
+                &["/srv/images", "read-only file system"][..],
+            ),
+            (DiskError::Write(io_error("disk full")).to_string(), &["disk full"][..]),
+            (
+                ConfigError::Value {
+                    key: "sleep_time",
+                    problem: "must be positive".to_string(),
+                }.to_string(),
+                &["sleep_time", "must be positive"][..],
+            ),
+            (
+                ConfigError::TooMuchMemory {
+                    estimate: 3000,
+                    max: 2000,
+                    suggestion: "Try a smaller map_size.",
+                }.to_string(),
+                &["3000", "2000", "Try a smaller map_size."][..],
+            ),
+            (
+                StateError("image 5 is not generated".to_string()).to_string(),
+                &["image 5 is not generated"][..],
+            ),
+            (
+                BudgetExhausted {
+                    attempts: 3,
+                    seconds: 42,
+                    source: Box::new(io_error("tiles missing")),
+                }.to_string(),
+                &["3 attempts", "42 seconds", "tiles missing"][..],
+            ),
+            (
+                PostingError::Rejected {
+                    backend: "bluesky".to_string(),
+                    status: 413,
+                    message: "blob too big".to_string(),
+                }.to_string(),
+                &["bluesky", "413", "blob too big"][..],
+            ),
+            (PostingError::TimedOut(90).to_string(), &["90 seconds"][..]),
+        ];
+        for (message, details) in cases {
+            for detail in details {
+                assert!(message.contains(detail), "{:?} not in {:?}", detail, message);
+            }
+        }
+    }
+
+    #[test]
+    fn sources_are_kept() {
+        let error = DiskError::CreateDir {
+            path: PathBuf::from("images"),
+            source: io_error("permission denied"),
+        };
+        let source = error.source().expect("No source");
+        assert_eq!(source.to_string(), "permission denied");
+
+        let error = DiskError::Check(io_error("no such device"));
+        assert_eq!(error.source().expect("No source").to_string(), "no such device");
+    }
+
+    #[test]
+    fn transient_posting_errors() {
+        let rejected = |status| PostingError::Rejected {
+            backend: "webhook".to_string(),
+            status,
+            message: String::new(),
+        };
+        let cases: &[(PostingError, bool)] = &[
+            (rejected(500), true),
+            (rejected(503), true),
+            (rejected(408), true),
+            (rejected(429), true),
+            (rejected(400), false),
+            (rejected(401), false),
+            (rejected(413), false),
+            (PostingError::ElefrenError(elefren::Error::Client(StatusCode::NOT_FOUND)), true),
+            (PostingError::Unverified("gone".to_string()), true),
+            (PostingError::TimedOut(90), true),
+            (PostingError::Panicked, true),
+            (
+                PostingError::UnknownMedia {
+                    status: 422,
+                    message: "not found".to_string(),
+                },
+                true,
+            ),
+            (PostingError::ImageTooLarge("too big".to_string()), false),
+            (PostingError::DuplicateStatus("duplicate".to_string()), false),
+            (PostingError::PollRefused("no polls".to_string()), false),
+        ];
+        for (error, transient) in cases {
+            assert_eq!(error.is_transient(), *transient, "{}", error);
+        }
+    }
+
+    #[test]
+    fn posting_error_statuses() {
+        let cases: &[(PostingError, Option<u16>)] = &[
+            (
+                PostingError::ElefrenError(elefren::Error::Client(StatusCode::NOT_FOUND)),
+                Some(404),
+            ),
+            (
+                PostingError::ElefrenError(elefren::Error::Server(StatusCode::BAD_GATEWAY)),
+                Some(502),
+            ),
+            (
+                PostingError::Rejected {
+                    backend: "webhook".to_string(),
+                    status: 418,
+                    message: String::new(),
+                },
+                Some(418),
+            ),
+            (PostingError::DuplicateStatus(String::new()), Some(422)),
+            (PostingError::PollRefused(String::new()), Some(422)),
+            (PostingError::TimedOut(90), None),
+            (PostingError::Panicked, None),
+            (
+                PostingError::UnknownMedia {
+                    status: 404,
+                    message: String::new(),
+                },
+                Some(404),
+            ),
+            (PostingError::Unverified(String::new()), None),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status(), *status, "{}", error);
+        }
+    }
+}
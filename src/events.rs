@@ -0,0 +1,395 @@
+//! Machine-readable event log
+//!
+//! When enabled, every lifecycle event is written to stdout as a single line of JSON, so that it
+//! can be fed into log aggregators. Human-readable messages keep going to stderr regardless.
+//!
+//! The structs and enums here are the schema of that output. Fields may be added, but existing
+//! ones should not be renamed or removed.
+//!
+//! The last `RECENT_EVENTS` events are kept in memory either way, for debug bundles.
+
+use std::collections::VecDeque;
+use std::io::{stdout, Write};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use cubeglobe_bot::MapStats;
+use serde_json;
+
+use GenerationParams;
+
+/// Events kept for debug bundles, see `EventLog::recent`
+const RECENT_EVENTS: usize = 200;
+
+/// A single lifecycle event
+///
+/// Serialized with an `event` field holding the snake_case variant name, alongside the variant's
+/// own fields.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Started generating image `id`
+    GenerationStarted { id: u32 },
+
+    /// Finished generating and encoding image `id`
+    GenerationFinished {
+        id: u32,
+        duration_ms: u64,
+        params: GenerationParams,
+        #[serde(default)]
+        stats: Option<MapStats>,
+        /// Times the image was generated again before this one, see `RegenBudget`
+        #[serde(default)]
+        regenerations: u32,
+    },
+
+    /// Generating image `id` failed, and it is generated again if the budget allows
+    GenerationFailed {
+        id: u32,
+        /// Regenerations before this attempt
+        regenerations: u32,
+        error: String,
+    },
+
+    /// Ran the PNG optimizer on image `id`
+    Optimized {
+        id: u32,
+        original_bytes: usize,
+        optimized_bytes: usize,
+        duration_ms: u64,
+        /// Whether optimization failed and the unoptimized image is used instead
+        fallback: bool,
+        /// oxipng preset used, see `optimize_level`
+        #[serde(default)]
+        preset: Option<u8>,
+    },
+
+    /// Uploaded image `id` to `backend`, for backends which upload it separately
+    MediaUploaded {
+        id: u32,
+        backend: String,
+        bytes: usize,
+        duration_ms: u64,
+    },
+
+    /// `backend` refused the status for image `id` as a duplicate of a recent one, and it was
+    /// tried again with `duplicate_body_suffix` added
+    DuplicateStatus { id: u32, backend: String },
+
+    /// Attempted to post image `id` to `backend`
+    UploadAttempt {
+        id: u32,
+        #[serde(default)]
+        backend: String,
+        attempt: usize,
+        success: bool,
+        /// HTTP status returned by the instance, if the failure carried one
+        http_status: Option<u16>,
+        error: Option<String>,
+        /// Whether the attempt was abandoned for taking longer than `max_attempt_secs`, rather
+        /// than failing
+        #[serde(default)]
+        timed_out: bool,
+    },
+
+    /// About to post image `id`, with this text, before backends fit it to their limits
+    PostDrafted {
+        id: u32,
+        body: String,
+        alt_text: String,
+        /// Parameters line added to the end of the alt text, see `alt_text_params`
+        alt_params: Option<String>,
+        language: Option<String>,
+        /// Visibility of the Mastodon status
+        visibility: String,
+        format: String,
+        width: Option<u32>,
+        height: Option<u32>,
+        bytes: usize,
+        /// Whether a poll is attached, see `poll`
+        #[serde(default)]
+        poll: bool,
+        /// Backend the post was drafted for, if it's changed for that one, see `backends`
+        #[serde(default)]
+        backend: Option<String>,
+    },
+
+    /// State moved to a new phase
+    StateChanged { id: u32, phase: String },
+
+    /// Looked up the Mastodon status of image `id` again a while after posting it, see
+    /// `federation_check`
+    FederationChecked {
+        id: u32,
+        status_id: String,
+        /// Whether the status still exists, if looking it up worked
+        exists: Option<bool>,
+        reblogs: Option<u64>,
+        favourites: Option<u64>,
+        /// Whether `verify_via` found the status, if it's set and looking it up there worked
+        federated: Option<bool>,
+        error: Option<String>,
+    },
+
+    /// The poll on the Mastodon status of image `id` closed, with these results, see `poll`
+    PollClosed {
+        id: u32,
+        poll_id: String,
+        /// Votes in total
+        votes: u64,
+        options: Vec<PollVotes>,
+    },
+
+    /// The interval between posts changed with the last posts' engagement, see `adaptive`
+    IntervalAdapted {
+        /// Seconds between posts from now on, and before
+        sleep_time: i64,
+        previous: i64,
+        /// Favourites and boosts per post, on average
+        average: Option<f64>,
+    },
+}
+
+/// Votes for one option of a poll
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PollVotes {
+    pub title: String,
+    /// Missing if the instance hides the results
+    pub votes: Option<u64>,
+}
+
+/// An event along with the time it happened, as written out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Record {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Writes events to stdout, if enabled, and keeps the most recent ones
+///
+/// Can be shared with background threads, like those of `federation_check`.
+pub struct EventLog {
+    enabled: bool,
+    recent: Mutex<VecDeque<Record>>,
+}
+
+impl EventLog {
+    pub fn new(enabled: bool) -> EventLog {
+        EventLog {
+            enabled,
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_EVENTS)),
+        }
+    }
+
+    /// Log `event`, timestamped with the current time
+    pub fn emit(&self, event: Event) {
+        let record = Record {
+            timestamp: Utc::now(),
+            event,
+        };
+        {
+            let mut recent = self.recent.lock().expect("event log lock poisoned");
+            if recent.len() == RECENT_EVENTS {
+                recent.pop_front();
+            }
+            recent.push_back(record.clone());
+        }
+
+        if !self.enabled {
+            return;
+        }
+
+        match serde_json::to_string(&record) {
+            Ok(line) => {
+                let out = stdout();
+                let mut out = out.lock();
+                // If stdout is gone there is nobody to complain to, so errors are dropped
+                let _ = writeln!(out, "{}", line).and_then(|_| out.flush());
+            }
+            Err(e) => eprintln!("Failed to serialize event: {}", e),
+        }
+    }
+
+    /// The last events emitted, oldest first, whether or not they were written out
+    pub fn recent(&self) -> Vec<Record> {
+        self.recent
+            .lock()
+            .expect("event log lock poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use consistency::Adjustment;
+
+    fn params() -> GenerationParams {
+        GenerationParams {
+            map_size: 16,
+            frequency: Some(0.03),
+            layer_height: Some(2),
+            min_soil_cutoff: None,
+            max_water_level: Some(3),
+            rotation: 90,
+            name_seed: 42,
+            adjustments: vec![Adjustment {
+                key: "max_water_level".to_string(),
+                requested: 5,
+                effective: 3,
+                reason: "above the layer height".to_string(),
+            }],
+        }
+    }
+
+    fn every_event() -> Vec<(&'static str, Event)> {
+        vec![
+            ("generation_started", Event::GenerationStarted { id: 1 }),
+            (
+                "generation_finished",
+                Event::GenerationFinished {
+                    id: 1,
+                    duration_ms: 1500,
+                    params: params(),
+                    stats: Some(MapStats {
+                        water_pct: 12.5,
+                        min_height: 1,
+                        max_height: 9,
+                        mean_height: 4.25,
+                        block_types: 6,
+                    }),
+                    regenerations: 2,
+                },
+            ),
+            (
+                "generation_failed",
+                Event::GenerationFailed {
+                    id: 1,
+                    regenerations: 0,
+                    error: "Rendering panicked".to_string(),
+                },
+            ),
+            (
+                "optimized",
+                Event::Optimized {
+                    id: 1,
+                    original_bytes: 4000,
+                    optimized_bytes: 3000,
+                    duration_ms: 200,
+                    fallback: false,
+                    preset: Some(2),
+                },
+            ),
+            (
+                "media_uploaded",
+                Event::MediaUploaded {
+                    id: 1,
+                    backend: "mastodon".to_string(),
+                    bytes: 3000,
+                    duration_ms: 800,
+                },
+            ),
+            (
+                "duplicate_status",
+                Event::DuplicateStatus {
+                    id: 1,
+                    backend: "mastodon".to_string(),
+                },
+            ),
+            (
+                "upload_attempt",
+                Event::UploadAttempt {
+                    id: 1,
+                    backend: "mastodon".to_string(),
+                    attempt: 3,
+                    success: false,
+                    http_status: Some(503),
+                    error: Some("Service Unavailable".to_string()),
+                    timed_out: true,
+                },
+            ),
+            (
+                "post_drafted",
+                Event::PostDrafted {
+                    id: 1,
+                    body: "A new landscape".to_string(),
+                    alt_text: "An isometric landscape".to_string(),
+                    alt_params: Some("Size 16".to_string()),
+                    language: Some("en".to_string()),
+                    visibility: "public".to_string(),
+                    format: "png".to_string(),
+                    width: Some(800),
+                    height: None,
+                    bytes: 3000,
+                    poll: true,
+                    backend: Some("mastodon".to_string()),
+                },
+            ),
+            (
+                "state_changed",
+                Event::StateChanged {
+                    id: 1,
+                    phase: "posted".to_string(),
+                },
+            ),
+            (
+                "federation_checked",
+                Event::FederationChecked {
+                    id: 1,
+                    status_id: "1001".to_string(),
+                    exists: Some(true),
+                    reblogs: Some(4),
+                    favourites: None,
+                    federated: Some(false),
+                    error: None,
+                },
+            ),
+            (
+                "poll_closed",
+                Event::PollClosed {
+                    id: 1,
+                    poll_id: "77".to_string(),
+                    votes: 10,
+                    options: vec![
+                        PollVotes {
+                            title: "Yes".to_string(),
+                            votes: Some(7),
+                        },
+                        PollVotes {
+                            title: "No".to_string(),
+                            votes: None,
+                        },
+                    ],
+                },
+            ),
+            (
+                "interval_adapted",
+                Event::IntervalAdapted {
+                    sleep_time: 7200,
+                    previous: 3600,
+                    average: Some(2.5),
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn every_event_round_trips() {
+        for (name, event) in every_event() {
+            let record = Record {
+                timestamp: Utc.ymd(2026, 10, 1).and_hms(12, 0, 0),
+                event,
+            };
+            let line = serde_json::to_string(&record).expect("Unable to serialize event");
+            let value: serde_json::Value =
+                serde_json::from_str(&line).expect("Unable to parse event");
+            assert_eq!(value["event"], name, "{}", line);
+            let parsed: Record = serde_json::from_str(&line).expect("Unable to deserialize event");
+            assert_eq!(parsed, record, "{}", line);
+        }
+    }
+}
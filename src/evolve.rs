@@ -0,0 +1,254 @@
+//! World evolution
+//!
+//! With `evolution` on, the frequency and water level aren't picked afresh for every post, but
+//! drift from the last post's by a random amount of at most the configured `drift`, so that
+//! consecutive posts look like a slowly changing world. Values which would drift past the
+//! configured range bounce back off its ends. The last post's parameters are kept in the state;
+//! if the range changed since, they are first clamped into the new one.
+
+use rand::Rng;
+
+use range::ParamRange;
+use GenerationParams;
+
+/// How far parameters can drift from one post to the next
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+pub struct DriftConfig {
+    /// Largest change of the frequency, as a fraction of the previous frequency
+    #[serde(default = "default_frequency")]
+    pub frequency: f64,
+
+    /// Largest change of the water level, in blocks
+    #[serde(default = "default_water_level")]
+    pub water_level: usize,
+}
+
+impl Default for DriftConfig {
+    fn default() -> DriftConfig {
+        DriftConfig {
+            frequency: default_frequency(),
+            water_level: default_water_level(),
+        }
+    }
+}
+
+fn default_frequency() -> f64 {
+    0.1
+}
+
+fn default_water_level() -> usize {
+    1
+}
+
+impl DriftConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.frequency.is_finite() || self.frequency < 0.0 {
+            return Err(format!(
+                "frequency must not be negative, got {}",
+                self.frequency
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Drift the frequency and water level of `rolled` from those of `previous`
+///
+/// `rolled` holds freshly rolled parameters, which are kept where there is nothing to drift
+/// from, or no range to drift within.
+pub fn evolve<R: Rng>(
+    rolled: &mut GenerationParams,
+    previous: &GenerationParams,
+    frequency: Option<&ParamRange<f64>>,
+    water_level: Option<&ParamRange<usize>>,
+    drift: &DriftConfig,
+    rng: &mut R,
+) {
+    if let (Some(previous), Some(range)) = (previous.frequency, frequency) {
+        let step = previous * drift.frequency * rng.gen_range(-1.0, 1.0);
+        rolled.frequency = Some(drift_frequency(previous, step, range));
+    }
+    if let (Some(previous), Some(range)) = (previous.max_water_level, water_level) {
+        let max_step = drift.water_level as i64;
+        let step = rng.gen_range(-max_step, max_step + 1);
+        rolled.max_water_level = Some(drift_water_level(previous, step, range));
+    }
+}
+
+/// `previous` moved by `step` within `range`, see `reflect`
+pub fn drift_frequency(previous: f64, step: f64, range: &ParamRange<f64>) -> f64 {
+    let (min, max) = (range.min(), range.max());
+    let clamped = previous.max(min).min(max);
+    reflect(clamped + step, min, max)
+}
+
+/// `previous` moved by `step` within `range`, see `reflect`
+pub fn drift_water_level(previous: usize, step: i64, range: &ParamRange<usize>) -> usize {
+    let (min, max) = (range.min() as i64, range.max() as i64);
+    let clamped = (previous as i64).max(min).min(max);
+    reflect(clamped as f64 + step as f64, min as f64, max as f64).round() as usize
+}
+
+/// `value` folded back into `min..=max`, as if it had bounced off the ends on its way there
+///
+/// An empty or reversed range gives `min`.
+pub fn reflect(value: f64, min: f64, max: f64) -> f64 {
+    let span = max - min;
+    if !(span > 0.0) {
+        return min;
+    }
+
+    let mut offset = (value - min) % (2.0 * span);
+    if offset < 0.0 {
+        offset += 2.0 * span;
+    }
+    if offset > span {
+        offset = 2.0 * span - offset;
+    }
+    min + offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const FREQUENCY: ParamRange<f64> = ParamRange::Uniform {
+        min: 0.01,
+        max: 0.04,
+    };
+    const WATER_LEVEL: ParamRange<usize> = ParamRange::Uniform { min: 2, max: 6 };
+
+    fn assert_close(actual: f64, expected: f64, case: &str) {
+        assert!((actual - expected).abs() < 1e-9, "{}: {} != {}", case, actual, expected);
+    }
+
+    #[test]
+    fn reflects_off_bounds() {
+        let cases: &[(f64, f64)] = &[
+            (3.0, 3.0),
+            // The bounds themselves are in range
+            (2.0, 2.0),
+            (6.0, 6.0),
+            (7.0, 5.0),
+            (1.0, 3.0),
+            // Far enough to bounce off both ends
+            (11.0, 3.0),
+            (-3.0, 5.0),
+            (10.0, 2.0),
+            (14.0, 6.0),
+        ];
+        for &(value, expected) in cases {
+            assert_close(reflect(value, 2.0, 6.0), expected, &value.to_string());
+        }
+    }
+
+    #[test]
+    fn empty_range_gives_min() {
+        assert_close(reflect(5.0, 3.0, 3.0), 3.0, "empty");
+        assert_close(reflect(5.0, 3.0, 1.0), 3.0, "reversed");
+    }
+
+    #[test]
+    fn frequency_bounces_at_bounds() {
+        let cases: &[(f64, f64, f64)] = &[
+            (0.02, 0.001, 0.021),
+            (0.04, 0.0, 0.04),
+            (0.04, 0.01, 0.03),
+            (0.01, 0.0, 0.01),
+            (0.01, -0.005, 0.015),
+            (0.035, 0.01, 0.035),
+            (0.01, 0.13, 0.02),
+        ];
+        for &(previous, step, expected) in cases {
+            let case = format!("{} + {}", previous, step);
+            assert_close(drift_frequency(previous, step, &FREQUENCY), expected, &case);
+        }
+    }
+
+    #[test]
+    fn water_level_bounces_at_bounds() {
+        let cases: &[(usize, i64, usize)] = &[
+            (4, 1, 5),
+            (6, 0, 6),
+            (6, 1, 5),
+            (6, 2, 4),
+            (2, 0, 2),
+            (2, -1, 3),
+            (2, -3, 5),
+            (3, 9, 4),
+        ];
+        for &(previous, step, expected) in cases {
+            let drifted = drift_water_level(previous, step, &WATER_LEVEL);
+            assert_eq!(drifted, expected, "{} + {}", previous, step);
+        }
+    }
+
+    #[test]
+    fn narrowed_range_clamps_previous_value() {
+        // As if the bounds were changed in the config since the last post
+        assert_close(drift_frequency(0.08, 0.0, &FREQUENCY), 0.04, "above");
+        assert_close(drift_frequency(0.001, 0.0, &FREQUENCY), 0.01, "below");
+        assert_close(drift_frequency(0.08, -0.01, &FREQUENCY), 0.03, "above, drifting");
+        assert_eq!(drift_water_level(10, 0, &WATER_LEVEL), 6);
+        assert_eq!(drift_water_level(0, 0, &WATER_LEVEL), 2);
+        assert_eq!(drift_water_level(0, -1, &WATER_LEVEL), 3);
+    }
+
+    #[test]
+    fn fixed_range_pins_value() {
+        assert_close(drift_frequency(0.03, 0.01, &ParamRange::Fixed(0.02)), 0.02, "fixed");
+        assert_eq!(drift_water_level(3, 1, &ParamRange::Fixed(5)), 5);
+    }
+
+    #[test]
+    fn evolution_stays_in_range() {
+        let drift = DriftConfig {
+            frequency: 0.5,
+            water_level: 3,
+        };
+        let mut rng = StdRng::from_seed([7; 32]);
+        let mut previous = GenerationParams {
+            map_size: 16,
+            frequency: Some(0.04),
+            layer_height: None,
+            min_soil_cutoff: None,
+            max_water_level: Some(6),
+            rotation: 0,
+            name_seed: 0,
+            adjustments: Vec::new(),
+        };
+        for _ in 0..1000 {
+            let mut rolled = previous.clone();
+            evolve(&mut rolled, &previous, Some(&FREQUENCY), Some(&WATER_LEVEL), &drift, &mut rng);
+            let frequency = rolled.frequency.unwrap();
+            let water_level = rolled.max_water_level.unwrap();
+            assert!(frequency >= 0.01 && frequency <= 0.04, "{}", frequency);
+            assert!(water_level >= 2 && water_level <= 6, "{}", water_level);
+            previous = rolled;
+        }
+    }
+
+    #[test]
+    fn nothing_to_drift_keeps_rolled_values() {
+        let mut rng = StdRng::from_seed([7; 32]);
+        let previous = GenerationParams {
+            map_size: 16,
+            frequency: None,
+            layer_height: None,
+            min_soil_cutoff: None,
+            max_water_level: Some(4),
+            rotation: 0,
+            name_seed: 0,
+            adjustments: Vec::new(),
+        };
+        let mut rolled = previous.clone();
+        rolled.frequency = Some(0.02);
+        rolled.max_water_level = Some(3);
+        let drift = DriftConfig::default();
+        evolve(&mut rolled, &previous, Some(&FREQUENCY), None, &drift, &mut rng);
+        assert_eq!(rolled.frequency, Some(0.02));
+        assert_eq!(rolled.max_water_level, Some(3));
+    }
+}
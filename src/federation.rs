@@ -0,0 +1,329 @@
+//! Checking that posts federate
+//!
+//! An instance can accept a status and then never deliver it, if its queues are stuck. With
+//! `[federation_check]`, the bot looks each Mastodon status up again `after_minutes` after
+//! posting it, and logs whether it still exists and how many boosts and favourites it got. With
+//! `verify_via`, it also looks the status's address up in the search of that instance, which
+//! fetches it from ours if it hasn't arrived yet, to confirm other instances can see it.
+//! Mastodon only fetches remote statuses for signed-in users, so `verify_token` is an access
+//! token of an account there.
+//!
+//! The checks run on threads of their own and only log, and emit a `FederationChecked` event, so
+//! they can't hold up posting or change the state. A run which exits before a check is due, like
+//! one with `--posts`, skips it.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Error;
+use elefren::Data as MastoData;
+use reqwest::{Client, StatusCode};
+use serde_json;
+
+use build_info;
+use events::{Event, EventLog};
+use posting::Progress;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct FederationCheckConfig {
+    /// Minutes after posting to look the status up
+    #[serde(default = "default_after_minutes")]
+    pub after_minutes: u64,
+
+    /// Base URL of another instance to look the status up on
+    #[serde(default)]
+    pub verify_via: Option<String>,
+
+    /// Access token of an account on `verify_via`
+    #[serde(default)]
+    pub verify_token: Option<String>,
+}
+
+fn default_after_minutes() -> u64 {
+    5
+}
+
+/// The parts of a status the check looks at
+#[derive(Deserialize)]
+pub struct StatusCounts {
+    #[serde(default)]
+    pub reblogs_count: u64,
+    #[serde(default)]
+    pub favourites_count: u64,
+}
+
+#[derive(Deserialize)]
+struct SearchResults {
+    #[serde(default)]
+    statuses: Vec<serde_json::Value>,
+}
+
+/// Schedules the checks, see the module documentation
+pub struct FederationChecker {
+    config: FederationCheckConfig,
+    account: MastoData,
+    fallback: Option<MastoData>,
+    events: Arc<EventLog>,
+}
+
+impl FederationChecker {
+    pub fn new(
+        config: FederationCheckConfig,
+        account: MastoData,
+        fallback: Option<MastoData>,
+        events: Arc<EventLog>,
+    ) -> FederationChecker {
+        FederationChecker {
+            config,
+            account,
+            fallback,
+            events,
+        }
+    }
+
+    /// Check on the Mastodon status of image `id` once `after_minutes` have passed, going by
+    /// how posting it went
+    pub fn check_later(&self, id: u32, progress: Option<&Progress>) {
+        let progress = match progress {
+            Some(progress) => progress,
+            None => return,
+        };
+        let receipt = match progress.receipt {
+            Some(ref receipt) => receipt,
+            None => return,
+        };
+        let status_id = receipt.status_id.clone();
+        let account = match (progress.fallback, &self.fallback) {
+            (true, &Some(ref fallback)) => fallback.clone(),
+            _ => self.account.clone(),
+        };
+        let url = receipt.link().map(str::to_string);
+        let config = self.config.clone();
+        let events = Arc::clone(&self.events);
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(config.after_minutes * 60));
+            events.emit(check(&config, &account, id, status_id, url));
+        });
+    }
+}
+
+/// Look the status up, and log what came of it
+fn check(
+    config: &FederationCheckConfig,
+    account: &MastoData,
+    id: u32,
+    status_id: String,
+    url: Option<String>,
+) -> Event {
+    let client = build_info::http_client();
+    let mut errors = Vec::new();
+
+    let (exists, reblogs, favourites) = match look_up(&client, account, &status_id) {
+        Ok(Some(counts)) => {
+            eprintln!(
+                "Status {} is up {} minutes after posting, with {} boosts and {} favourites",
+                status_id, config.after_minutes, counts.reblogs_count, counts.favourites_count
+            );
+            (
+                Some(true),
+                Some(counts.reblogs_count),
+                Some(counts.favourites_count),
+            )
+        }
+        Ok(None) => {
+            eprintln!(
+                "WARNING: Status {} is gone {} minutes after posting",
+                status_id, config.after_minutes
+            );
+            (Some(false), None, None)
+        }
+        Err(e) => {
+            eprintln!("Unable to look up status {}: {:#}", status_id, e);
+            errors.push(format!("{:#}", e));
+            (None, None, None)
+        }
+    };
+
+    let federated = match (&config.verify_via, &url) {
+        (&Some(ref remote), &Some(ref url)) => {
+            let token = config.verify_token.as_ref().map(String::as_str);
+            match resolve(&client, remote, token, url) {
+                Ok(true) => {
+                    eprintln!("{} found status {}", remote, url);
+                    Some(true)
+                }
+                Ok(false) => {
+                    eprintln!(
+                        "WARNING: {} can't find status {}, it may not have federated",
+                        remote, url
+                    );
+                    Some(false)
+                }
+                Err(e) => {
+                    eprintln!("Unable to look up status {} on {}: {:#}", url, remote, e);
+                    errors.push(format!("{}: {:#}", remote, e));
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    Event::FederationChecked {
+        id,
+        status_id,
+        exists,
+        reblogs,
+        favourites,
+        federated,
+        error: if errors.is_empty() {
+            None
+        } else {
+            Some(errors.join("; "))
+        },
+    }
+}
+
+/// Fetch the status from our instance, or `None` if it's gone
+pub fn look_up(
+    client: &Client,
+    account: &MastoData,
+    status_id: &str,
+) -> Result<Option<StatusCounts>, Error> {
+    let url = format!(
+        "{}/api/v1/statuses/{}",
+        account.base.trim_end_matches('/'),
+        status_id
+    );
+    let mut response = client.get(&url).bearer_auth(&account.token).send()?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(response.error_for_status()?.json()?))
+}
+
+/// Whether searching for the status's address on `remote` finds it
+fn resolve(client: &Client, remote: &str, token: Option<&str>, url: &str) -> Result<bool, Error> {
+    let mut request = client
+        .get(&format!("{}/api/v2/search", remote.trim_end_matches('/')))
+        .query(&[("q", url), ("type", "statuses"), ("resolve", "true")]);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let results: SearchResults = request.send()?.error_for_status()?.json()?;
+    Ok(!results.statuses.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::MockServer;
+
+    const STATUS: &str = r#"{"id": "101", "reblogs_count": 3, "favourites_count": 7}"#;
+
+    fn account(base: &str) -> MastoData {
+        MastoData {
+            base: base.to_string().into(),
+            client_id: "id".into(),
+            client_secret: "secret".into(),
+            redirect: "urn:ietf:wg:oauth:2.0:oob".into(),
+            token: "token".into(),
+        }
+    }
+
+    fn config(verify_via: Option<&str>) -> FederationCheckConfig {
+        FederationCheckConfig {
+            after_minutes: default_after_minutes(),
+            verify_via: verify_via.map(str::to_string),
+            verify_token: verify_via.map(|_| "remote-token".to_string()),
+        }
+    }
+
+    fn checked(
+        exists: Option<bool>,
+        counts: Option<(u64, u64)>,
+        federated: Option<bool>,
+        error: Option<&str>,
+    ) -> Event {
+        Event::FederationChecked {
+            id: 5,
+            status_id: "101".to_string(),
+            exists,
+            reblogs: counts.map(|counts| counts.0),
+            favourites: counts.map(|counts| counts.1),
+            federated,
+            error: error.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn reads_counts() {
+        let server = MockServer::start(vec![(200, STATUS.to_string())]);
+        let event = check(&config(None), &account(&server.url), 5, "101".to_string(), None);
+        assert_eq!(event, checked(Some(true), Some((3, 7)), None, None));
+
+        let request = &server.requests()[0];
+        assert_eq!(request.path, "/api/v1/statuses/101");
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+    }
+
+    #[test]
+    fn notices_deleted_status() {
+        let server = MockServer::start(vec![(404, r#"{"error":"Record not found"}"#.to_string())]);
+        let event = check(&config(None), &account(&server.url), 5, "101".to_string(), None);
+        assert_eq!(event, checked(Some(false), None, None, None));
+    }
+
+    #[test]
+    fn verifies_on_other_instance() {
+        let cases = &[
+            (r#"{"accounts": [], "statuses": [{"id": "9"}], "hashtags": []}"#, true),
+            (r#"{"accounts": [], "statuses": [], "hashtags": []}"#, false),
+        ];
+        for &(results, found) in cases {
+            let home = MockServer::start(vec![(200, STATUS.to_string())]);
+            let remote = MockServer::start(vec![(200, results.to_string())]);
+            let event = check(
+                &config(Some(&remote.url)),
+                &account(&home.url),
+                5,
+                "101".to_string(),
+                Some("https://example.com/@bot/101".to_string()),
+            );
+            assert_eq!(event, checked(Some(true), Some((3, 7)), Some(found), None));
+
+            let request = &remote.requests()[0];
+            assert!(request.path.starts_with("/api/v2/search?"), "{}", request.path);
+            assert!(request.path.contains("resolve=true"), "{}", request.path);
+            assert_eq!(request.header("authorization"), Some("Bearer remote-token"));
+            home.requests();
+        }
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let home = MockServer::start(vec![(500, r#"{"error":"oops"}"#.to_string())]);
+        let remote = MockServer::start(vec![(503, r#"{"error":"unavailable"}"#.to_string())]);
+        let event = check(
+            &config(Some(&remote.url)),
+            &account(&home.url),
+            5,
+            "101".to_string(),
+            Some("https://example.com/@bot/101".to_string()),
+        );
+        match event {
+            Event::FederationChecked {
+                exists: None,
+                federated: None,
+                error: Some(error),
+                ..
+            } => {
+                assert!(error.contains("500"), "{}", error);
+                assert!(error.contains(&remote.url), "{}", error);
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+}
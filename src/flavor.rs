@@ -0,0 +1,182 @@
+//! Server software the Mastodon API is spoken to
+//!
+//! Pleroma and GoToSocial implement the same API, but not quite the same way. With `auto`, the
+//! instance's nodeinfo is looked up at startup to tell which one it is.
+
+use anyhow::Error;
+
+use build_info;
+use length::Counting;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceFlavor {
+    Mastodon,
+    Pleroma,
+    Gotosocial,
+    /// Look it up from nodeinfo
+    Auto,
+}
+
+impl Default for InstanceFlavor {
+    fn default() -> InstanceFlavor {
+        InstanceFlavor::Auto
+    }
+}
+
+#[derive(Deserialize)]
+struct NodeinfoLinks {
+    links: Vec<NodeinfoLink>,
+}
+
+#[derive(Deserialize)]
+struct NodeinfoLink {
+    href: String,
+}
+
+#[derive(Deserialize)]
+struct Nodeinfo {
+    software: Software,
+}
+
+#[derive(Deserialize)]
+struct Software {
+    name: String,
+}
+
+impl InstanceFlavor {
+    /// Look up what `auto` stands for on the instance at `base`, or return the configured flavor
+    ///
+    /// If the lookup fails, the instance is taken to be Mastodon.
+    pub fn resolve(self, base: &str) -> InstanceFlavor {
+        if self != InstanceFlavor::Auto {
+            return self;
+        }
+
+        match detect(base) {
+            Ok(flavor) => {
+                eprintln!("{} looks like {:?}", base, flavor);
+                flavor
+            }
+            Err(e) => {
+                eprintln!(
+                    "Unable to tell what software {} runs, assuming Mastodon: {}",
+                    base, e
+                );
+                InstanceFlavor::Mastodon
+            }
+        }
+    }
+
+    /// Longest status the instance accepts by default, counted by `body_counting`
+    pub fn max_chars(self) -> Option<usize> {
+        match self {
+            InstanceFlavor::Mastodon | InstanceFlavor::Auto => Some(500),
+            InstanceFlavor::Pleroma | InstanceFlavor::Gotosocial => Some(5000),
+        }
+    }
+
+    /// How the instance counts the length of a status
+    pub fn body_counting(self) -> Counting {
+        match self {
+            InstanceFlavor::Mastodon | InstanceFlavor::Auto => Counting::Mastodon,
+            InstanceFlavor::Pleroma | InstanceFlavor::Gotosocial => Counting::Chars,
+        }
+    }
+
+    /// Longest image description the instance accepts by default, in characters
+    pub fn max_alt_chars(self) -> Option<usize> {
+        match self {
+            InstanceFlavor::Mastodon | InstanceFlavor::Gotosocial | InstanceFlavor::Auto => {
+                Some(1500)
+            }
+            InstanceFlavor::Pleroma => Some(5000),
+        }
+    }
+
+    /// Whether the instance dedupes status creation on the `Idempotency-Key` header
+    pub fn supports_idempotency(self) -> bool {
+        self != InstanceFlavor::Gotosocial
+    }
+}
+
+/// Ask the instance at `base` which software it runs
+///
+/// Software other than Pleroma (or its fork Akkoma) and GoToSocial is taken to be Mastodon.
+fn detect(base: &str) -> Result<InstanceFlavor, Error> {
+    let client = build_info::http_client();
+    let url = format!("{}/.well-known/nodeinfo", base.trim_end_matches('/'));
+    let links: NodeinfoLinks = client.get(&url).send()?.error_for_status()?.json()?;
+    // Listed oldest schema first
+    let link = links
+        .links
+        .last()
+        .ok_or_else(|| Error::msg("nodeinfo lists no documents"))?;
+    let nodeinfo: Nodeinfo = client.get(&link.href).send()?.error_for_status()?.json()?;
+
+    Ok(match nodeinfo.software.name.to_lowercase().as_str() {
+        "pleroma" | "akkoma" => InstanceFlavor::Pleroma,
+        "gotosocial" => InstanceFlavor::Gotosocial,
+        _ => InstanceFlavor::Mastodon,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::MockServer;
+
+    /// What `auto` resolves to for an instance whose nodeinfo names `software`
+    fn resolve_software(software: &str) -> InstanceFlavor {
+        let document = MockServer::start(vec![(
+            200,
+            format!(r#"{{"version": "2.0", "software": {{"name": "{}"}}}}"#, software),
+        )]);
+        let links = MockServer::start(vec![(
+            200,
+            format!(
+                r#"{{"links": [
+                    {{"rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                      "href": "{}/nodeinfo/2.0"}}
+                ]}}"#,
+                document.url
+            ),
+        )]);
+        let flavor = InstanceFlavor::Auto.resolve(&links.url);
+
+        assert_eq!(links.requests()[0].path, "/.well-known/nodeinfo");
+        assert_eq!(document.requests()[0].path, "/nodeinfo/2.0");
+        flavor
+    }
+
+    #[test]
+    fn auto_reads_nodeinfo() {
+        let cases: &[(&str, InstanceFlavor)] = &[
+            ("mastodon", InstanceFlavor::Mastodon),
+            ("pleroma", InstanceFlavor::Pleroma),
+            ("akkoma", InstanceFlavor::Pleroma),
+            ("gotosocial", InstanceFlavor::Gotosocial),
+            ("GoToSocial", InstanceFlavor::Gotosocial),
+            // Anything else speaking the API is taken to be Mastodon
+            ("hometown", InstanceFlavor::Mastodon),
+        ];
+        for &(software, flavor) in cases {
+            assert_eq!(resolve_software(software), flavor, "{}", software);
+        }
+    }
+
+    #[test]
+    fn auto_assumes_mastodon_without_nodeinfo() {
+        let server = MockServer::start(vec![(404, r#"{"error":"Not found"}"#.to_string())]);
+        assert_eq!(InstanceFlavor::Auto.resolve(&server.url), InstanceFlavor::Mastodon);
+        server.requests();
+    }
+
+    #[test]
+    fn configured_flavor_isnt_looked_up() {
+        // Nothing listens here, so a lookup would fail and give Mastodon
+        let base = "http://127.0.0.1:9";
+        assert_eq!(InstanceFlavor::Pleroma.resolve(base), InstanceFlavor::Pleroma);
+        assert_eq!(InstanceFlavor::Gotosocial.resolve(base), InstanceFlavor::Gotosocial);
+    }
+}
@@ -0,0 +1,253 @@
+//! Checking that instances accept the images the bot makes
+//!
+//! Mastodon lists the media types it accepts in its instance document, under
+//! `configuration.media_attachments.supported_mime_types`, and refuses uploads of any other.
+//! The formats the bot would post are checked against that at startup, and again whenever the
+//! config is reloaded, as admins do change these settings. An instance which doesn't take GIFs
+//! gets stills instead of animations, or with `strict_format`, the bot refuses the config.
+//! Instances which don't list their media types, like older Mastodon versions, are assumed to
+//! take both.
+
+use anyhow::Error;
+use serde_json;
+
+use build_info;
+use errors::ConfigError;
+use posting::ImageFormat;
+use BotConfig;
+
+#[derive(Deserialize)]
+struct Instance {
+    #[serde(default)]
+    configuration: Option<Configuration>,
+}
+
+#[derive(Deserialize)]
+struct Configuration {
+    #[serde(default)]
+    media_attachments: Option<MediaAttachments>,
+}
+
+#[derive(Deserialize)]
+struct MediaAttachments {
+    #[serde(default)]
+    supported_mime_types: Option<Vec<String>>,
+}
+
+/// Media types listed in `body`, an instance document, if it lists any
+pub fn supported_mime_types(body: &str) -> Option<Vec<String>> {
+    serde_json::from_str::<Instance>(body)
+        .ok()?
+        .configuration?
+        .media_attachments?
+        .supported_mime_types
+}
+
+/// How posting works out with an instance
+#[derive(Debug, PartialEq)]
+pub struct Negotiation {
+    /// Whether to post animations
+    pub animate: bool,
+    /// Problem to warn about, if any
+    pub warning: Option<String>,
+}
+
+/// Work out how to post to an instance accepting the `supported` media types, if it lists them
+///
+/// `animate` is whether the config asks for animations. Without `strict`, a format the instance
+/// doesn't take is a warning, and animations turn into stills; with it, it's an error.
+pub fn negotiate(
+    animate: bool,
+    strict: bool,
+    supported: Option<&[String]>,
+) -> Result<Negotiation, String> {
+    let supported = match supported {
+        Some(supported) => supported,
+        None => {
+            return Ok(Negotiation {
+                animate,
+                warning: None,
+            })
+        }
+    };
+    let accepts = |format: ImageFormat| supported.iter().any(|mime| mime == format.mimetype());
+
+    if !accepts(ImageFormat::Png) {
+        let problem = "does not accept PNG images, so uploads will fail".to_string();
+        if strict {
+            return Err(problem);
+        }
+        return Ok(Negotiation {
+            animate: animate && accepts(ImageFormat::Gif),
+            warning: Some(problem),
+        });
+    }
+
+    if animate && !accepts(ImageFormat::Gif) {
+        let problem = "does not accept GIF images".to_string();
+        if strict {
+            return Err(format!("{}, which animate needs", problem));
+        }
+        return Ok(Negotiation {
+            animate: false,
+            warning: Some(format!("{}, posting stills instead of animations", problem)),
+        });
+    }
+
+    Ok(Negotiation {
+        animate,
+        warning: None,
+    })
+}
+
+/// Fetch the instance document of the instance at `base`, as it is sent
+pub fn fetch_instance(base: &str) -> Result<String, Error> {
+    let url = format!("{}/api/v1/instance", base.trim_end_matches('/'));
+    Ok(build_info::http_client().get(&url).send()?.error_for_status()?.text()?)
+}
+
+/// Fetch the media types the instance at `base` accepts, if it lists them
+fn fetch(base: &str) -> Result<Option<Vec<String>>, Error> {
+    Ok(supported_mime_types(&fetch_instance(base)?))
+}
+
+/// Check the formats `config` posts in against the instances at `bases`, and adjust it to them
+///
+/// Instances which can't be reached are skipped with a warning, as they can't be told apart
+/// from ones that are only down for the moment.
+pub fn check(config: &mut BotConfig, bases: &[String]) -> Result<(), ConfigError> {
+    for base in bases {
+        let supported = match fetch(base) {
+            Ok(supported) => supported,
+            Err(e) => {
+                eprintln!("Unable to look up the media types {} accepts: {}", base, e);
+                continue;
+            }
+        };
+
+        let listed = supported.as_ref().map(Vec::as_slice);
+        let negotiation = negotiate(config.animate, config.strict_format, listed).map_err(
+            |problem| ConfigError::Value {
+                key: "strict_format",
+                problem: format!("{} {}", base, problem),
+            },
+        )?;
+        if let Some(warning) = negotiation.warning {
+            eprintln!("WARNING: {} {}", base, warning);
+        }
+        let posting = if negotiation.animate { "animations" } else { "stills" };
+        match supported {
+            Some(supported) => {
+                eprintln!("{} accepts {}; posting {}", base, supported.join(", "), posting)
+            }
+            None => eprintln!(
+                "{} doesn't list the media types it accepts; posting {}",
+                base, posting
+            ),
+        }
+        config.animate = negotiation.animate;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::MockServer;
+    use toml;
+
+    const PNG_AND_GIF: &str = r#"{
+        "uri": "example.com",
+        "configuration": {
+            "media_attachments": {
+                "supported_mime_types": ["image/jpeg", "image/png", "image/gif", "video/mp4"]
+            }
+        }
+    }"#;
+    const PNG_ONLY: &str = r#"{
+        "configuration": {"media_attachments": {"supported_mime_types": ["image/png"]}}
+    }"#;
+
+    fn types(types: &[&str]) -> Vec<String> {
+        types.iter().map(|mime| mime.to_string()).collect()
+    }
+
+    fn ok(animate: bool) -> Result<Negotiation, String> {
+        Ok(Negotiation {
+            animate,
+            warning: None,
+        })
+    }
+
+    fn config(extra: &str) -> BotConfig {
+        toml::from_str(&format!("map_size = 16\nanimate = true\n{}", extra))
+            .expect("Invalid config")
+    }
+
+    #[test]
+    fn reads_media_types() {
+        assert_eq!(
+            supported_mime_types(PNG_AND_GIF),
+            Some(types(&["image/jpeg", "image/png", "image/gif", "video/mp4"]))
+        );
+        // Older versions don't list them
+        let unlisted = &[
+            r#"{"uri": "example.com"}"#,
+            r#"{"configuration": {}}"#,
+            r#"{"configuration": {"media_attachments": {}}}"#,
+            "<html>not an instance</html>",
+        ];
+        for body in unlisted {
+            assert_eq!(supported_mime_types(body), None, "{}", body);
+        }
+    }
+
+    #[test]
+    fn negotiates_formats() {
+        let both = types(&["image/png", "image/gif"]);
+        let png = types(&["image/png"]);
+        let gif = types(&["image/gif"]);
+        assert_eq!(negotiate(true, false, None), ok(true));
+        assert_eq!(negotiate(true, true, Some(&both[..])), ok(true));
+        assert_eq!(negotiate(false, true, Some(&png[..])), ok(false));
+
+        let stills = negotiate(true, false, Some(&png[..])).expect("Refused without strict");
+        assert!(!stills.animate);
+        assert!(stills.warning.expect("No warning").contains("stills"));
+        let error = negotiate(true, true, Some(&png[..])).unwrap_err();
+        assert!(error.contains("animate"), "{}", error);
+
+        let no_png = negotiate(true, false, Some(&gif[..])).expect("Refused without strict");
+        assert!(no_png.animate);
+        assert!(no_png.warning.expect("No warning").contains("PNG"));
+        assert!(negotiate(false, true, Some(&gif[..])).is_err());
+        assert!(negotiate(false, true, Some(&[][..])).is_err());
+    }
+
+    #[test]
+    fn check_turns_animations_into_stills() {
+        let server = MockServer::start(vec![(200, PNG_ONLY.to_string())]);
+        let mut config = config("");
+        check(&mut config, &[server.url.clone()]).expect("Refused without strict");
+        assert!(!config.animate);
+        assert_eq!(server.requests()[0].path, "/api/v1/instance");
+    }
+
+    #[test]
+    fn check_refuses_with_strict_format() {
+        let server = MockServer::start(vec![(200, PNG_ONLY.to_string())]);
+        let mut config = config("strict_format = true\n");
+        match check(&mut config, &[server.url.clone()]) {
+            Err(ConfigError::Value { key, .. }) => assert_eq!(key, "strict_format"),
+            _ => panic!("Not refused"),
+        }
+    }
+
+    #[test]
+    fn check_skips_unreachable_instances() {
+        let server = MockServer::start(vec![(503, r#"{"error":"unavailable"}"#.to_string())]);
+        let mut config = config("strict_format = true\n");
+        check(&mut config, &[server.url.clone()]).expect("Refused unreachable instance");
+        assert!(config.animate);
+    }
+}
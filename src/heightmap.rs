@@ -0,0 +1,194 @@
+//! Heightmap attachment
+//!
+//! A second, flat image of the terrain, for those curious about what the generator made: every
+//! column of the map is drawn as a square, colored by its height, from the lowest possible to
+//! the highest. It's posted after the landscape itself, with alt text of its own explaining how
+//! to read it.
+
+use cubeglobe::map::Map;
+use cubeglobe_bot::column_heights;
+use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+
+use background::parse_color;
+
+/// Largest accepted `scale`, which keeps a heightmap of the largest map within a few megapixels
+const MAX_SCALE: u32 = 8;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HeightmapConfig {
+    /// Colors from the lowest to the highest terrain, as `#rrggbb`, spread evenly between the
+    /// two. Grayscale from black to white if empty.
+    #[serde(default)]
+    pub gradient: Vec<String>,
+
+    /// Side of the square each column is drawn as, in pixels
+    #[serde(default = "default_scale")]
+    pub scale: u32,
+
+    /// Alt text of the heightmap
+    #[serde(default = "default_alt_text")]
+    pub alt_text: String,
+}
+
+fn default_scale() -> u32 {
+    1
+}
+
+fn default_alt_text() -> String {
+    "A heightmap of the landscape in the first image, seen from straight above. Each pixel is \
+     one column of blocks; the darker it is, the lower the terrain, and the lighter, the higher."
+        .to_string()
+}
+
+impl HeightmapConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.gradient.len() == 1 {
+            return Err("gradient needs at least two colors, or none for grayscale".to_string());
+        }
+        for color in &self.gradient {
+            parse_color(color)?;
+        }
+        if self.scale == 0 || self.scale > MAX_SCALE {
+            return Err(format!(
+                "scale must be from 1 to {}, got {}",
+                MAX_SCALE, self.scale
+            ));
+        }
+        if self.alt_text.trim().is_empty() {
+            return Err("alt_text must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    /// Draw the heightmap of `map`
+    ///
+    /// Gives `None`, with a warning, if the map has no columns to draw.
+    pub fn render(&self, map: &Map) -> Option<DynamicImage> {
+        let heights = column_heights(map);
+        let (len_x, len_y) = (map.len_x() as u32, map.len_y() as u32);
+        if heights.is_empty() || heights.len() != (len_x * len_y) as usize {
+            eprintln!("WARNING: Unable to work out the heights of the map, posting no heightmap");
+            return None;
+        }
+
+        // Relative to the height of the map rather than the highest column, so that heightmaps
+        // of different posts compare
+        let top = map.len_z().max(1) as f64;
+        let level = |x: u32, y: u32| {
+            let column = (y / self.scale * len_x + x / self.scale) as usize;
+            heights[column] as f64 / top
+        };
+        let (width, height) = (len_x * self.scale, len_y * self.scale);
+
+        if self.gradient.is_empty() {
+            let image = GrayImage::from_fn(width, height, |x, y| Luma {
+                data: [(level(x, y) * 255.0).round() as u8],
+            });
+            return Some(DynamicImage::ImageLuma8(image));
+        }
+
+        let stops: Vec<[u8; 3]> = self
+            .gradient
+            .iter()
+            .map(|color| parse_color(color).expect("checked by validate"))
+            .collect();
+        let image = RgbImage::from_fn(width, height, |x, y| Rgb {
+            data: gradient_color(&stops, level(x, y)),
+        });
+        Some(DynamicImage::ImageRgb8(image))
+    }
+}
+
+/// Color at `position`, from 0 to 1, along the gradient through `stops`
+fn gradient_color(stops: &[[u8; 3]], position: f64) -> [u8; 3] {
+    let scaled = position.max(0.0).min(1.0) * (stops.len() - 1) as f64;
+    let lower = (scaled.floor() as usize).min(stops.len() - 2);
+    let fraction = scaled - lower as f64;
+
+    let (from, to) = (stops[lower], stops[lower + 1]);
+    let mut color = [0u8; 3];
+    for (channel, value) in color.iter_mut().enumerate() {
+        let (from, to) = (f64::from(from[channel]), f64::from(to[channel]));
+        *value = (from + (to - from) * fraction).round() as u8;
+    }
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubeglobe::map::Block;
+    use image::GenericImageView;
+
+    fn config(gradient: &[&str], scale: u32) -> HeightmapConfig {
+        HeightmapConfig {
+            gradient: gradient.iter().map(|color| color.to_string()).collect(),
+            scale,
+            alt_text: default_alt_text(),
+        }
+    }
+
+    /// A 2 by 1 map, 4 blocks high, with columns 2 and 4 blocks high
+    fn map() -> Map {
+        let mut map = Map::new(2, 1, 4);
+        for z in 0..2 {
+            map.set(0, 0, z, Block::Water);
+        }
+        for z in 0..4 {
+            map.set(1, 0, z, Block::Water);
+        }
+        map
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(config(&[], 1).validate().is_ok());
+        assert!(config(&["#000000", "#ffffff"], MAX_SCALE).validate().is_ok());
+        let invalid: &[(&[&str], u32)] = &[
+            (&["#000000"], 1),
+            (&["#000000", "white"], 1),
+            (&[], 0),
+            (&[], MAX_SCALE + 1),
+        ];
+        for &(gradient, scale) in invalid {
+            assert!(config(gradient, scale).validate().is_err(), "{:?} {}", gradient, scale);
+        }
+        let mut blank = config(&[], 1);
+        blank.alt_text = " ".to_string();
+        assert!(blank.validate().is_err());
+    }
+
+    #[test]
+    fn follows_gradient() {
+        let stops = [[0, 0, 0], [200, 100, 0], [200, 200, 200]];
+        assert_eq!(gradient_color(&stops, 0.0), [0, 0, 0]);
+        assert_eq!(gradient_color(&stops, 0.25), [100, 50, 0]);
+        assert_eq!(gradient_color(&stops, 0.5), [200, 100, 0]);
+        assert_eq!(gradient_color(&stops, 1.0), [200, 200, 200]);
+        // Out of range positions are clamped
+        assert_eq!(gradient_color(&stops, -1.0), [0, 0, 0]);
+        assert_eq!(gradient_color(&stops, 2.0), [200, 200, 200]);
+    }
+
+    #[test]
+    fn draws_heights_in_grayscale() {
+        let image = config(&[], 2).render(&map()).expect("No heightmap");
+        assert_eq!(image.dimensions(), (4, 2));
+        let gray = image.to_luma();
+        for y in 0..2 {
+            assert_eq!(gray.get_pixel(1, y).data, [128]);
+            assert_eq!(gray.get_pixel(2, y).data, [255]);
+        }
+    }
+
+    #[test]
+    fn draws_heights_along_gradient() {
+        let image = config(&["#000000", "#ff0000"], 1)
+            .render(&map())
+            .expect("No heightmap");
+        let rgb = image.to_rgb();
+        assert_eq!(rgb.dimensions(), (2, 1));
+        assert_eq!(rgb.get_pixel(0, 0).data, [128, 0, 0]);
+        assert_eq!(rgb.get_pixel(1, 0).data, [255, 0, 0]);
+    }
+}
@@ -0,0 +1,347 @@
+//! Hook commands
+//!
+//! The `[hooks]` section names shell commands to run at points in an image's life, to wire the
+//! bot into things it doesn't know about itself, like copying images to a website:
+//!
+//! * `post_generate`: once an image has been generated and saved
+//! * `pre_post`: before every attempt at posting it
+//! * `post_success`: once it has been posted everywhere
+//! * `post_failure`: after every failed attempt
+//!
+//! Commands run with `sh -c`, or `cmd /C` on Windows, with `HOOK`, `IMAGE_ID` and, where they
+//! apply, `IMAGE_PATH`, `STATUS_URL` and `ERROR` set in their environment. Whatever they print
+//! goes into the bot's log. A command still running after `timeout_secs` is killed. Failing
+//! hooks are logged and otherwise ignored, except `pre_post`, whose failure calls off the
+//! attempt unless `pre_post_aborts` is off.
+
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a running hook is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for the rest of a hook's output once it has exited, in case it left
+/// something running in the background that holds on to it
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct HooksConfig {
+    pub post_generate: Option<String>,
+    pub pre_post: Option<String>,
+    pub post_success: Option<String>,
+    pub post_failure: Option<String>,
+
+    /// Seconds a hook may run before it is killed
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// Whether a failing `pre_post` hook calls off the posting attempt, which then counts as a
+    /// failed one
+    #[serde(default = "default_pre_post_aborts")]
+    pub pre_post_aborts: bool,
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+fn default_pre_post_aborts() -> bool {
+    true
+}
+
+/// The points hooks can run at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    PostGenerate,
+    PrePost,
+    PostSuccess,
+    PostFailure,
+}
+
+impl Hook {
+    /// Key of the hook in the config, also passed to it as `HOOK`
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PostGenerate => "post_generate",
+            Hook::PrePost => "pre_post",
+            Hook::PostSuccess => "post_success",
+            Hook::PostFailure => "post_failure",
+        }
+    }
+}
+
+/// What a hook is told about the image
+pub struct HookContext<'a> {
+    pub image_id: u32,
+    pub image_path: Option<PathBuf>,
+    pub status_url: Option<&'a str>,
+    pub error: Option<&'a str>,
+}
+
+impl<'a> HookContext<'a> {
+    fn vars(&self, hook: Hook) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("HOOK", hook.name().to_string()),
+            ("IMAGE_ID", self.image_id.to_string()),
+        ];
+        if let Some(ref path) = self.image_path {
+            vars.push(("IMAGE_PATH", path.to_string_lossy().into_owned()));
+        }
+        if let Some(url) = self.status_url {
+            vars.push(("STATUS_URL", url.to_string()));
+        }
+        if let Some(error) = self.error {
+            vars.push(("ERROR", error.to_string()));
+        }
+        vars
+    }
+}
+
+impl HooksConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout_secs == 0 {
+            return Err("timeout_secs must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    fn command(&self, hook: Hook) -> Option<&str> {
+        match hook {
+            Hook::PostGenerate => self.post_generate.as_ref(),
+            Hook::PrePost => self.pre_post.as_ref(),
+            Hook::PostSuccess => self.post_success.as_ref(),
+            Hook::PostFailure => self.post_failure.as_ref(),
+        }.map(String::as_str)
+    }
+
+    /// Run `hook`, if one is configured, returning whether it succeeded
+    ///
+    /// Problems are logged here. No hook configured counts as success.
+    pub fn run(&self, hook: Hook, context: &HookContext) -> bool {
+        let command = match self.command(hook) {
+            Some(command) => command,
+            None => return true,
+        };
+        match self.execute(hook, command, context) {
+            Ok(()) => true,
+            Err(problem) => {
+                eprintln!("WARNING: {} hook failed: {}", hook.name(), problem);
+                false
+            }
+        }
+    }
+
+    /// Run `pre_post`, returning whether the posting attempt may go ahead
+    pub fn allows_posting(&self, context: &HookContext) -> bool {
+        if self.run(Hook::PrePost, context) {
+            return true;
+        }
+        if self.pre_post_aborts {
+            eprintln!("Calling off this posting attempt, as the pre_post hook failed");
+            return false;
+        }
+        true
+    }
+
+    fn execute(&self, hook: Hook, command: &str, context: &HookContext) -> Result<(), String> {
+        let mut child = shell(command)
+            .envs(context.vars(hook))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("unable to run {:?}: {}", command, e))?;
+
+        // Passed on line by line as it comes, so a hook which hangs still shows how far it got
+        let (sender, receiver) = channel();
+        let stdout = child.stdout.take().map(|out| Box::new(out) as Box<dyn Read + Send>);
+        let stderr = child.stderr.take().map(|err| Box::new(err) as Box<dyn Read + Send>);
+        for output in stdout.into_iter().chain(stderr) {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(output).lines() {
+                    match line {
+                        Ok(line) => {
+                            if sender.send(line).is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+            });
+        }
+        drop(sender);
+        let log = |line: String| eprintln!("[{} hook] {}", hook.name(), line);
+
+        let deadline = Instant::now() + Duration::from_secs(self.timeout_secs);
+        let status = loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(line) => log(line),
+                Err(RecvTimeoutError::Timeout) => {}
+                // Both outputs closed, but the hook may still be running
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(POLL_INTERVAL),
+            }
+            match child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) if Instant::now() >= deadline => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    break None;
+                }
+                Ok(None) => {}
+                Err(e) => return Err(format!("unable to wait for {:?}: {}", command, e)),
+            }
+        };
+
+        let grace = Instant::now() + OUTPUT_GRACE;
+        loop {
+            let now = Instant::now();
+            if now >= grace {
+                break;
+            }
+            match receiver.recv_timeout(grace - now) {
+                Ok(line) => log(line),
+                Err(_) => break,
+            }
+        }
+
+        match status {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => Err(format!("{:?} exited with {}", command, status)),
+            None => Err(format!(
+                "{:?} was killed after running for {} seconds",
+                command, self.timeout_secs
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_to_string;
+    use tempfile;
+
+    fn hooks(pre_post: &str, pre_post_aborts: bool) -> HooksConfig {
+        HooksConfig {
+            post_generate: None,
+            pre_post: Some(pre_post.to_string()),
+            post_success: None,
+            post_failure: None,
+            timeout_secs: default_timeout_secs(),
+            pre_post_aborts,
+        }
+    }
+
+    fn context(error: Option<&str>) -> HookContext {
+        HookContext {
+            image_id: 5,
+            image_path: Some(PathBuf::from("images/5.png")),
+            status_url: None,
+            error,
+        }
+    }
+
+    #[test]
+    fn passes_what_applies() {
+        assert_eq!(
+            context(Some("timed out")).vars(Hook::PostFailure),
+            vec![
+                ("HOOK", "post_failure".to_string()),
+                ("IMAGE_ID", "5".to_string()),
+                ("IMAGE_PATH", "images/5.png".to_string()),
+                ("ERROR", "timed out".to_string()),
+            ]
+        );
+        let bare = HookContext {
+            image_id: 6,
+            image_path: None,
+            status_url: Some("https://example.com/@bot/1"),
+            error: None,
+        };
+        assert_eq!(
+            bare.vars(Hook::PostSuccess),
+            vec![
+                ("HOOK", "post_success".to_string()),
+                ("IMAGE_ID", "6".to_string()),
+                ("STATUS_URL", "https://example.com/@bot/1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unconfigured_hooks_succeed() {
+        let hooks = hooks("true", true);
+        assert!(hooks.run(Hook::PostGenerate, &context(None)));
+        assert!(hooks.run(Hook::PostFailure, &context(None)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_with_environment() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let out = dir.path().join("out");
+        let command = format!(
+            "echo \"$HOOK $IMAGE_ID $IMAGE_PATH $ERROR\" > '{}'; echo logged",
+            out.display()
+        );
+        let mut hooks = hooks("true", true);
+        hooks.post_failure = Some(command);
+
+        assert!(hooks.run(Hook::PostFailure, &context(Some("timed out"))));
+        let written = read_to_string(&out).expect("Hook didn't run");
+        assert_eq!(written, "post_failure 5 images/5.png timed out\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_pre_post_calls_off_posting() {
+        assert!(hooks("true", true).allows_posting(&context(None)));
+        assert!(!hooks("exit 3", true).allows_posting(&context(None)));
+        assert!(hooks("exit 3", false).allows_posting(&context(None)));
+        let problem = hooks("exit 3", true)
+            .execute(Hook::PrePost, "exit 3", &context(None))
+            .unwrap_err();
+        assert!(problem.contains("exited with"), "{}", problem);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hanging_hooks_are_killed() {
+        let mut hooks = hooks("sleep 30", true);
+        hooks.timeout_secs = 1;
+        let started = Instant::now();
+        let problem = hooks
+            .execute(Hook::PrePost, "sleep 30", &context(None))
+            .unwrap_err();
+        assert!(problem.contains("killed"), "{}", problem);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn validates_timeout() {
+        let mut hooks = hooks("true", true);
+        assert!(hooks.validate().is_ok());
+        hooks.timeout_secs = 0;
+        assert!(hooks.validate().is_err());
+    }
+}
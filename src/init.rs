@@ -0,0 +1,463 @@
+//! First-run setup
+//!
+//! `cubeglobe-bot init` asks for everything a working config needs, registers the bot with the
+//! instance, and writes a commented `config.toml`. Every question can also be answered with a
+//! flag, and `--yes` takes the defaults for the rest, for scripted setups.
+
+use std::env;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Error;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elefren::scopes::Scopes;
+use elefren::Data as MastoData;
+use elefren::Registration;
+use reqwest::Url;
+use toml::Value;
+
+use {IMAGES_DIR, MAX_MAP_SIZE, STATE_PATH, TILES_PATH};
+
+const DEFAULT_SLEEP_TIME: u64 = 3 * 60 * 60;
+const DEFAULT_MAP_SIZE: u64 = 32;
+const MIN_SLEEP_TIME: u64 = 60;
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("init")
+        .about("interactively write a new config file")
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("PATH")
+                .help("where to write the config [default: config.toml]"),
+        ).arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("overwrite the output file if it exists"),
+        ).arg(
+            Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("do not ask anything, use defaults for values not given as flags"),
+        ).arg(
+            Arg::with_name("instance")
+                .long("instance")
+                .value_name("URL")
+                .help("base URL of the Mastodon instance"),
+        ).arg(
+            Arg::with_name("token")
+                .long("token")
+                .value_name("TOKEN")
+                .requires_all(&["clientid", "clientsecret"])
+                .help("existing access token, skips registering the app"),
+        ).arg(
+            Arg::with_name("clientid")
+                .long("client-id")
+                .value_name("ID")
+                .requires("token")
+                .help("client id belonging to --token"),
+        ).arg(
+            Arg::with_name("clientsecret")
+                .long("client-secret")
+                .value_name("SECRET")
+                .requires("token")
+                .help("client secret belonging to --token"),
+        ).arg(
+            Arg::with_name("sleeptime")
+                .long("sleep-time")
+                .value_name("SECONDS")
+                .help("time between posts"),
+        ).arg(
+            Arg::with_name("mapsize")
+                .long("map-size")
+                .value_name("BLOCKS")
+                .help("map size, in blocks per edge"),
+        ).arg(
+            Arg::with_name("tiles")
+                .long("tiles")
+                .value_name("PATH")
+                .help("path to the tiles configuration file"),
+        ).arg(
+            Arg::with_name("imagesdir")
+                .long("images-dir")
+                .value_name("PATH")
+                .help("directory to save generated images in"),
+        ).arg(
+            Arg::with_name("statepath")
+                .long("state-path")
+                .value_name("PATH")
+                .help("where to keep the state file"),
+        )
+}
+
+/// Answers to all the questions
+struct Answers {
+    credentials: MastoData,
+    sleep_time: u64,
+    map_size: u64,
+    tiles: String,
+    images_dir: String,
+    state_path: String,
+}
+
+/// Asks questions not already answered by flags
+struct Prompter<'a> {
+    matches: &'a ArgMatches<'a>,
+    interactive: bool,
+}
+
+impl<'a> Prompter<'a> {
+    /// Get a value from the flag `arg`, or ask for it with `question`
+    ///
+    /// A flag which fails `check` is an error, since there is nobody to ask.
+    fn ask<T, F>(&self, arg: &str, question: &str, default: Option<T>, check: F) -> Result<T, Error>
+    where
+        T: Display,
+        F: Fn(&str) -> Result<T, String>,
+    {
+        if let Some(value) = self.matches.value_of(arg) {
+            return check(value).map_err(|e| Error::msg(format!("--{}: {}", arg, e)));
+        }
+
+        if !self.interactive {
+            return default.ok_or_else(|| Error::msg(format!("--{} is required with --yes", arg)));
+        }
+
+        self.question(question, default, check)
+    }
+
+    /// Ask `question` until the answer passes `check`
+    fn question<T, F>(&self, question: &str, default: Option<T>, check: F) -> Result<T, Error>
+    where
+        T: Display,
+        F: Fn(&str) -> Result<T, String>,
+    {
+        let stdin = io::stdin();
+        loop {
+            match default {
+                Some(ref default) => eprint!("{} [{}]: ", question, default),
+                None => eprint!("{}: ", question),
+            }
+            io::stderr().flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Err(Error::msg("input ended before setup was done"));
+            }
+            let line = line.trim();
+
+            if line.is_empty() {
+                if let Some(ref default) = default {
+                    // Defaults are shown to the user, so parse them back rather than requiring Clone
+                    return check(&default.to_string()).map_err(Error::msg);
+                }
+                eprintln!("A value is required");
+                continue;
+            }
+
+            match check(line) {
+                Ok(value) => return Ok(value),
+                Err(e) => eprintln!("{}, try again", e),
+            }
+        }
+    }
+
+    /// Ask a yes or no question. Always no when not interactive.
+    fn confirm(&self, question: &str) -> Result<bool, Error> {
+        if !self.interactive {
+            return Ok(false);
+        }
+
+        eprint!("{} [y/N]: ", question);
+        io::stderr().flush()?;
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        Ok(line.trim().eq_ignore_ascii_case("y") || line.trim().eq_ignore_ascii_case("yes"))
+    }
+}
+
+fn check_url(input: &str) -> Result<String, String> {
+    let url = Url::parse(input).map_err(|e| format!("not a valid URL ({})", e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(input.trim_end_matches('/').to_string()),
+        scheme => Err(format!("expected an http or https URL, got {}", scheme)),
+    }
+}
+
+fn check_range(input: &str, min: u64, max: u64) -> Result<u64, String> {
+    match input.parse::<u64>() {
+        Ok(n) if n >= min && n <= max => Ok(n),
+        _ => Err(format!("expected a number from {} to {}", min, max)),
+    }
+}
+
+fn check_nonempty(input: &str) -> Result<String, String> {
+    if input.is_empty() {
+        Err("must not be empty".to_string())
+    } else {
+        Ok(input.to_string())
+    }
+}
+
+/// Get credentials, either from flags or by registering with the instance
+fn credentials(prompt: &Prompter) -> Result<MastoData, Error> {
+    let base: String = prompt.ask("instance", "Instance URL", None, check_url)?;
+
+    if let Some(token) = prompt.matches.value_of("token") {
+        return Ok(MastoData {
+            base: base.into(),
+            client_id: prompt.matches.value_of("clientid").unwrap_or("").to_string().into(),
+            client_secret: prompt
+                .matches
+                .value_of("clientsecret")
+                .unwrap_or("")
+                .to_string()
+                .into(),
+            redirect: "urn:ietf:wg:oauth:2.0:oob".into(),
+            token: token.to_string().into(),
+        });
+    }
+
+    if !prompt.interactive {
+        return Err(Error::msg(
+            "--token, --client-id and --client-secret are required with --yes",
+        ));
+    }
+
+    eprintln!("Registering with {}...", base);
+    let registered = Registration::new(base.as_str())
+        .client_name("cubeglobe-bot")
+        .scopes(Scopes::read_all().and(Scopes::write_all()))
+        .build()?;
+    eprintln!(
+        "Log in as the bot account and open this URL to authorize it:\n\n{}\n",
+        registered.authorize_url()?
+    );
+    let code: String = prompt.question("Paste the authorization code", None, check_nonempty)?;
+    let masto = registered.complete(&code)?;
+    eprintln!("Authorized");
+
+    Ok(masto.data)
+}
+
+/// Write the config file to `output`, readable only by us, overwriting it only with `force`
+fn write_config(output: &Path, contents: &str, force: bool) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        // Checked already, but someone may have created it while we were asking
+        options.create_new(true);
+    }
+    // The config holds the access token, so keep it to ourselves
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(output)?;
+    // The mode only applies to new files, an overwritten one keeps its permissions otherwise
+    #[cfg(unix)]
+    {
+        use std::fs::Permissions;
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_bytes())
+}
+
+fn toml_string(s: &str) -> String {
+    Value::String(s.to_string()).to_string()
+}
+
+/// Render the config file, comments and all
+fn render(answers: &Answers) -> String {
+    let creds = &answers.credentials;
+    format!(
+        r#"# Written by `cubeglobe-bot init`. See example.config.toml for every option.
+
+[bot]
+# Time between posts, in seconds
+sleep_time = {sleep_time}
+
+# Random variation of the time between posts, in seconds, either way. Can also
+# differ by way, as in {{ early = 0, late = "12%" }}; see example.config.toml.
+jitter = 600
+
+# Map size, in blocks per edge
+map_size = {map_size}
+
+# Noise function frequency. Lower values give flatter terrain. Either a single
+# value, or a range which is randomized between min and max for each map
+# generated.
+frequency = {{ min = 0.01, max = 0.04 }}
+
+# Maximum height of the soil layer. Actual height determined by noise function.
+layer_height = 9
+
+# Minimum soil level. Actual picked by RNG. There is no soil above the soil
+# level.
+min_soil_cutoff = 30
+
+# Maximum water level. Actual picked by RNG. All empty space below water level
+# is filled by water.
+max_water_level = 15
+
+# Tiles config, used unless --tiles is passed
+tiles = {tiles}
+
+# Where generated images are saved
+images_dir = {images_dir}
+
+# Where the bot keeps track of what it has posted
+state_path = {state_path}
+
+[credentials]
+token = {token}
+client_id = {client_id}
+client_secret = {client_secret}
+redirect = {redirect}
+base = {base}
+"#,
+        sleep_time = answers.sleep_time,
+        map_size = answers.map_size,
+        tiles = toml_string(&answers.tiles),
+        images_dir = toml_string(&answers.images_dir),
+        state_path = toml_string(&answers.state_path),
+        token = toml_string(&creds.token),
+        client_id = toml_string(&creds.client_id),
+        client_secret = toml_string(&creds.client_secret),
+        redirect = toml_string(&creds.redirect),
+        base = toml_string(&creds.base),
+    )
+}
+
+/// Run the wizard
+pub fn run(matches: &ArgMatches) -> Result<(), Error> {
+    let output = matches.value_of("output").unwrap_or("config.toml");
+    let force = matches.is_present("force");
+    if Path::new(output).exists() && !force {
+        return Err(Error::msg(format!(
+            "{} already exists, pass --force to overwrite it",
+            output
+        )));
+    }
+
+    let prompt = Prompter {
+        matches,
+        interactive: !matches.is_present("yes"),
+    };
+
+    let credentials = credentials(&prompt)?;
+    let answers = Answers {
+        credentials,
+        sleep_time: prompt.ask(
+            "sleeptime",
+            "Seconds between posts",
+            Some(DEFAULT_SLEEP_TIME),
+            |s| check_range(s, MIN_SLEEP_TIME, u64::from(u32::max_value())),
+        )?,
+        map_size: prompt.ask(
+            "mapsize",
+            "Map size, in blocks per edge",
+            Some(DEFAULT_MAP_SIZE),
+            |s| check_range(s, 1, MAX_MAP_SIZE as u64),
+        )?,
+        tiles: prompt.ask(
+            "tiles",
+            "Path to the tiles config",
+            Some(TILES_PATH.to_string()),
+            check_nonempty,
+        )?,
+        images_dir: prompt.ask(
+            "imagesdir",
+            "Directory for generated images",
+            Some(IMAGES_DIR.to_string()),
+            check_nonempty,
+        )?,
+        state_path: prompt.ask(
+            "statepath",
+            "Path to the state file",
+            Some(STATE_PATH.to_string()),
+            check_nonempty,
+        )?,
+    };
+
+    write_config(Path::new(output), &render(&answers), force)?;
+    eprintln!("Wrote {}", output);
+
+    if prompt.confirm("Generate and upload a test image now, without posting it?")? {
+        let status = Command::new(env::current_exe()?)
+            .args(&["--config", output, "--check-upload"])
+            .status()?;
+        if !status.success() {
+            return Err(Error::msg("upload check failed, see above"));
+        }
+    } else {
+        eprintln!(
+            "Run `cubeglobe-bot --config {} --check-upload` to check everything works",
+            output
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_to_string;
+    use tempfile::tempdir;
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata().expect("Unable to read metadata").permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn refuses_to_overwrite_without_force() {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let output = dir.path().join("config.toml");
+        write_config(&output, "old", false).expect("Unable to write config");
+        assert!(write_config(&output, "new", false).is_err());
+        assert_eq!(read_to_string(&output).unwrap(), "old");
+    }
+
+    #[test]
+    fn overwrites_with_force() {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let output = dir.path().join("config.toml");
+        write_config(&output, "a much longer old config", false).expect("Unable to write config");
+        write_config(&output, "new", true).expect("Unable to overwrite config");
+        assert_eq!(read_to_string(&output).unwrap(), "new");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn new_config_is_private() {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let output = dir.path().join("config.toml");
+        write_config(&output, "new", false).expect("Unable to write config");
+        assert_eq!(mode(&output), 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn overwritten_config_is_made_private() {
+        use std::fs::{set_permissions, write, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let output = dir.path().join("config.toml");
+        write(&output, "old").expect("Unable to write config");
+        set_permissions(&output, Permissions::from_mode(0o644)).expect("Unable to chmod");
+        write_config(&output, "new", true).expect("Unable to overwrite config");
+        assert_eq!(mode(&output), 0o600);
+    }
+}
@@ -0,0 +1,141 @@
+//! Pending image checks
+//!
+//! A crash while the pending image was being written can leave a truncated file behind, which
+//! would then be posted as is after a restart. Pending images read back from disk are checked
+//! against the size and hash recorded when they were generated, and decoded in full, before
+//! anything is uploaded.
+
+use image;
+use sha2::{Digest, Sha256};
+
+use posting::ImageFormat;
+
+/// Last chunk of every PNG: zero length, type, and the CRC of the type
+const PNG_IEND: &[u8] = &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82];
+
+/// Size and hash of an image file, as recorded in the state
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    pub bytes: u64,
+    /// SHA-256 of the file, in hex
+    pub sha256: String,
+}
+
+impl Fingerprint {
+    pub fn of(data: &[u8]) -> Fingerprint {
+        Fingerprint {
+            bytes: data.len() as u64,
+            sha256: Sha256::digest(data)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        }
+    }
+}
+
+/// Find what is wrong with `data`, an image read back from disk, if anything
+///
+/// `expected` is the fingerprint recorded when it was written, if there is one; state files
+/// from before fingerprints were recorded don't have it.
+pub fn check(
+    data: &[u8],
+    format: ImageFormat,
+    expected: Option<&Fingerprint>,
+) -> Result<(), String> {
+    if let Some(expected) = expected {
+        let actual = Fingerprint::of(data);
+        if actual.bytes != expected.bytes {
+            return Err(format!("{} bytes long, expected {}", actual.bytes, expected.bytes));
+        }
+        if actual.sha256 != expected.sha256 {
+            return Err(format!("SHA-256 is {}, expected {}", actual.sha256, expected.sha256));
+        }
+    }
+
+    if format == ImageFormat::Png && !data.ends_with(PNG_IEND) {
+        return Err("PNG does not end with an IEND chunk, it was likely cut short".to_string());
+    }
+    image::load_from_memory(data).map_err(|e| format!("unable to decode: {}", e))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+
+    fn png() -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(16, 16))
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .expect("Unable to encode PNG");
+        png
+    }
+
+    #[test]
+    fn fingerprints_size_and_hash() {
+        let fingerprint = Fingerprint::of(b"abc");
+        assert_eq!(fingerprint.bytes, 3);
+        assert_eq!(
+            fingerprint.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn intact_image_passes() {
+        let png = png();
+        let fingerprint = Fingerprint::of(&png);
+        assert_eq!(check(&png, ImageFormat::Png, Some(&fingerprint)), Ok(()));
+        // State files from before fingerprints were recorded
+        assert_eq!(check(&png, ImageFormat::Png, None), Ok(()));
+    }
+
+    #[test]
+    fn truncated_image_fails() {
+        let png = png();
+        let fingerprint = Fingerprint::of(&png);
+        let truncated = &png[..png.len() / 2];
+
+        let problem = check(truncated, ImageFormat::Png, Some(&fingerprint)).unwrap_err();
+        assert!(problem.contains("bytes long"), "{}", problem);
+        // Without a fingerprint, the missing IEND gives it away
+        let problem = check(truncated, ImageFormat::Png, None).unwrap_err();
+        assert!(problem.contains("IEND"), "{}", problem);
+    }
+
+    #[test]
+    fn wrong_hash_fails() {
+        let png = png();
+        let fingerprint = Fingerprint::of(&png);
+        // Same length, one byte different
+        let mut changed = png.clone();
+        let middle = changed.len() / 2;
+        changed[middle] ^= 0xff;
+
+        let problem = check(&changed, ImageFormat::Png, Some(&fingerprint)).unwrap_err();
+        assert!(problem.contains("SHA-256"), "{}", problem);
+    }
+
+    #[test]
+    fn damaged_data_fails_to_decode() {
+        // Ends with IEND, but the image data before it is garbage
+        let mut damaged = png();
+        let start = 8 + 25;
+        for byte in &mut damaged[start..start + 16] {
+            *byte = 0x55;
+        }
+        let problem = check(&damaged, ImageFormat::Png, None).unwrap_err();
+        assert!(problem.contains("unable to decode"), "{}", problem);
+    }
+
+    #[test]
+    fn only_pngs_need_iend() {
+        let mut gif = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(4, 4))
+            .write_to(&mut gif, ImageOutputFormat::GIF)
+            .expect("Unable to encode GIF");
+        assert_eq!(check(&gif, ImageFormat::Gif, Some(&Fingerprint::of(&gif))), Ok(()));
+    }
+}
@@ -0,0 +1,92 @@
+//! Journal of the updates that follow a post
+//!
+//! Once an image is posted, more than one file changes: the `.media.toml` next to the image, and
+//! the state file, which moves on to the next image. A crash between those writes would leave
+//! them disagreeing about whether the image was posted. So before making them, the bot writes
+//! what it's about to do to a journal next to the state file, `{state}.journal.toml`, and
+//! removes it once they're all done. A journal found at startup is replayed: every update it
+//! describes which isn't done yet is made, in the same order, so that whether one is done can be
+//! told from the ones after it. Only one post's updates are ever outstanding.
+
+use std::ffi::OsString;
+use std::fs::{read_to_string, remove_file, rename, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error};
+use chrono::{DateTime, Utc};
+use toml;
+
+use remote_media::RemoteMedia;
+
+/// The updates to make for one posted image
+#[derive(Deserialize, Serialize)]
+pub struct Intent {
+    /// Id of the image, which the state file moves on from once it's updated
+    pub id: u32,
+    pub filename: Option<String>,
+    pub status_id: Option<String>,
+    /// When the image was posted, which replaying keeps
+    pub posted: DateTime<Utc>,
+    /// What the instance made of the image, for its `.media.toml`
+    #[serde(default)]
+    pub remote_media: Option<RemoteMedia>,
+}
+
+/// Where the journal of the state file at `state` goes
+pub fn path(state: &Path) -> PathBuf {
+    let mut name = state
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_else(|| OsString::from("state"));
+    name.push(".journal.toml");
+    state.with_file_name(name)
+}
+
+/// Record `intent` before starting on it
+pub fn begin(state: &Path, intent: &Intent) -> Result<(), Error> {
+    let path = path(state);
+    write_atomically(&path, toml::to_string(intent)?.as_bytes())
+        .with_context(|| format!("unable to write journal {}", path.display()))
+}
+
+/// The intent left behind by a run which didn't finish it, if any
+pub fn pending(state: &Path) -> Result<Option<Intent>, Error> {
+    let path = path(state);
+    let contents = match read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::from(e).context(format!("unable to read {}", path.display()))),
+    };
+    let intent = toml::from_str(&contents)
+        .with_context(|| format!("unable to parse journal {}", path.display()))?;
+    Ok(Some(intent))
+}
+
+/// Clear the journal once every update it describes is done
+pub fn finish(state: &Path) -> Result<(), Error> {
+    let path = path(state);
+    match remove_file(&path) {
+        Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(Error::msg(format!("unable to remove journal {}: {}", path.display(), e)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Replace the file at `path` with `data`, so that a crash leaves either the old or the new
+/// contents, never part of them
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut scratch_name = path
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_default();
+    scratch_name.push(".tmp");
+    let scratch = path.with_file_name(scratch_name);
+
+    let mut file = File::create(&scratch)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    rename(&scratch, path)
+}
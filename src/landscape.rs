@@ -0,0 +1,351 @@
+//! Generating, rendering and encoding a landscape
+//!
+//! The bot's own pipeline goes through the same functions as `render_landscape`, adding what
+//! only the bot does: random choices of parameters, backgrounds, overlays and animations,
+//! trying several optimizer presets, and giving up on oxipng at shutdown.
+
+use std::fs::{read, read_to_string, remove_file};
+use std::io::{self, Write};
+use std::path::Path;
+
+use cubeglobe::map::generator::{Generator, TerGenTwo};
+use cubeglobe::map::Map;
+use cubeglobe::renderer::{RWops, Renderer, Surface};
+use image::{DynamicImage, GenericImageView, ImageError, ImageOutputFormat};
+use oxipng;
+
+use bmp;
+use builtin_tiles;
+use stats::MapStats;
+use tiles;
+
+/// Settings to generate a landscape with
+///
+/// Every setting is fixed; choosing them at random is up to the caller. Settings left as `None`
+/// are left to cubeglobe's defaults.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct LandscapeParams {
+    /// Length of the map's sides, in blocks
+    pub map_size: usize,
+    /// Frequency of the terrain noise; higher makes for busier terrain
+    pub frequency: Option<f64>,
+    pub layer_height: Option<usize>,
+    pub min_soil_cutoff: Option<usize>,
+    pub max_water_level: Option<usize>,
+    /// Clockwise quarter turns of the map, seen from above, before rendering
+    pub quarter_turns: u8,
+    /// oxipng preset to optimize the PNG with, from 0 to 6, or `None` to leave it as encoded
+    pub optimize_preset: Option<u8>,
+    /// Have the optimizer drop textual and other chunks that don't affect how the image looks
+    pub strip_metadata: bool,
+}
+
+impl LandscapeParams {
+    /// Settings for a `map_size` by `map_size` map, with everything else as the bot defaults to
+    pub fn new(map_size: usize) -> LandscapeParams {
+        LandscapeParams {
+            map_size,
+            frequency: None,
+            layer_height: None,
+            min_soil_cutoff: None,
+            max_water_level: None,
+            quarter_turns: 0,
+            optimize_preset: Some(4),
+            strip_metadata: false,
+        }
+    }
+}
+
+/// Where the tileset to render with comes from
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub enum TilesSource<'a> {
+    /// Plain, flat-colored tiles drawn on the spot
+    Builtin,
+    /// A cubeglobe tiles config file
+    File(&'a Path),
+}
+
+/// A rendered landscape
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RenderedImage {
+    /// The image, as PNG
+    pub png: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// Settings it was generated with
+    pub params: LandscapeParams,
+    /// Statistics of the map
+    pub stats: MapStats,
+}
+
+/// Problems turning rendered surfaces into images
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ImagingError {
+    #[error("SDL error: {0}")]
+    Sdl(String),
+    #[error("error loading image: {0}")]
+    Image(#[from] ImageError),
+    #[error("error reading rendered image: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum RenderError {
+    /// The tiles config could not be read, or has problems
+    #[error("{0}")]
+    Tiles(String),
+    /// cubeglobe failed to render the map, which it only describes for debugging
+    #[error("problem rendering map: {0}")]
+    Render(String),
+    #[error(transparent)]
+    Imaging(#[from] ImagingError),
+    #[error("problem optimizing PNG: {0}")]
+    Optimize(String),
+}
+
+/// Generate, render and encode a landscape, as the bot does for a still
+///
+/// Loading the tiles is the slow part for small maps, so rendering many landscapes with the same
+/// tileset this way does more work than it needs to.
+pub fn render_landscape(
+    params: &LandscapeParams,
+    tiles: &TilesSource,
+) -> Result<RenderedImage, RenderError> {
+    let (path, tiles_config) = match *tiles {
+        TilesSource::Builtin => (
+            Path::new(builtin_tiles::BUILTIN),
+            builtin_tiles::tiles_config().map_err(|e| RenderError::Tiles(e.to_string()))?,
+        ),
+        TilesSource::File(path) => (
+            path,
+            read_to_string(path).map_err(|e| {
+                RenderError::Tiles(format!("unable to read {}: {}", path.display(), e))
+            })?,
+        ),
+    };
+    let renderer = tiles::load_renderer(path, &tiles_config)
+        .map_err(|e| RenderError::Tiles(e.to_string()))?;
+
+    let (map, stats) = generate_map(params);
+    let surf = renderer
+        .render_map(&map)
+        .map_err(|e| RenderError::Render(format!("{:?}", e)))?;
+    let image = surface_to_image(&surf)?;
+    drop(surf);
+    let (width, height) = image.dimensions();
+
+    let mut png = Vec::new();
+    image
+        .write_to(&mut png, ImageOutputFormat::PNG)
+        .map_err(ImagingError::Image)?;
+    drop(image);
+    if let Some(preset) = params.optimize_preset {
+        png = oxipng::optimize_from_memory(&png, &optimizer_options(preset, params.strip_metadata))
+            .map_err(|e| RenderError::Optimize(e.to_string()))?;
+    }
+
+    Ok(RenderedImage {
+        png,
+        width,
+        height,
+        params: params.clone(),
+        stats,
+    })
+}
+
+/// Generate the map for `params`, turned as asked, along with its statistics
+pub fn generate_map(params: &LandscapeParams) -> (Map, MapStats) {
+    let mut generator = TerGenTwo::new().set_len(params.map_size);
+    if let Some(frequency) = params.frequency {
+        generator = generator.set_frequency(frequency);
+    }
+    if let Some(height) = params.layer_height {
+        generator = generator.set_layer_height(height);
+    }
+    if let Some(cutoff) = params.min_soil_cutoff {
+        generator = generator.set_min_soil_cutoff(cutoff);
+    }
+    if let Some(level) = params.max_water_level {
+        generator = generator.set_max_water_level(level);
+    }
+
+    let mut map = generator.generate();
+    if params.quarter_turns % 4 != 0 {
+        map = rotate_map(&map, params.quarter_turns);
+    }
+
+    let stats = MapStats::of(&map);
+    (map, stats)
+}
+
+/// Turn `map` clockwise, seen from above, by `quarter_turns` quarter turns
+///
+/// The renderer only draws from one side, so to show a map from another we turn the map itself
+/// around its vertical axis before rendering.
+pub fn rotate_map(map: &Map, quarter_turns: u8) -> Map {
+    let quarter_turns = quarter_turns % 4;
+    let (len_x, len_y, len_z) = (map.len_x(), map.len_y(), map.len_z());
+    // Odd numbers of quarter turns swap the horizontal dimensions
+    let (new_x, new_y) = if quarter_turns % 2 == 0 {
+        (len_x, len_y)
+    } else {
+        (len_y, len_x)
+    };
+
+    let mut rotated = Map::new(new_x, new_y, len_z);
+    for x in 0..len_x {
+        for y in 0..len_y {
+            let (to_x, to_y) = match quarter_turns {
+                0 => (x, y),
+                1 => (len_y - 1 - y, x),
+                2 => (len_x - 1 - x, len_y - 1 - y),
+                _ => (y, len_x - 1 - x),
+            };
+            for z in 0..len_z {
+                rotated.set(to_x, to_y, z, map.get(x, y, z));
+            }
+        }
+    }
+
+    rotated
+}
+
+/// Optimizer settings for oxipng's `preset`
+///
+/// Textual chunks (tEXt, iTXt, zTXt) are kept unless `strip_metadata` is set. Stripping still
+/// keeps the chunks that affect how the image is displayed.
+pub fn optimizer_options(preset: u8, strip_metadata: bool) -> oxipng::Options {
+    let mut options = oxipng::Options::from_preset(preset);
+    options.strip = if strip_metadata {
+        oxipng::Headers::Safe
+    } else {
+        oxipng::Headers::None
+    };
+    options
+}
+
+/// Take a surface and write to to writer `out`, as PNG
+///
+/// The BMP buffer `surface_to_image` goes through is gone by the time the PNG is encoded.
+pub fn write_surface_as_png<W: Write>(surf: &Surface, mut out: W) -> Result<(), ImagingError> {
+    let image = surface_to_image(surf)?;
+    image.write_to(&mut out, ImageOutputFormat::PNG)?;
+    Ok(())
+}
+
+/// Like `surface_to_image`, but goes through a scratch file at `scratch` instead of memory, and
+/// frees the surface before decoding
+pub fn surface_to_image_via_file(
+    surf: Surface,
+    scratch: &Path,
+) -> Result<DynamicImage, ImagingError> {
+    surf.save_bmp(scratch).map_err(ImagingError::Sdl)?;
+    drop(surf);
+
+    let image = read(scratch).and_then(|data| bmp::decode(&data));
+    let _ = remove_file(scratch);
+    Ok(image?)
+}
+
+/// Copy a surface into an image we can work with
+pub fn surface_to_image(surf: &Surface) -> Result<DynamicImage, ImagingError> {
+    let (width, height) = surf.size();
+
+    // each line is padded to multiple of four
+    let line_mem_size = (width * 3 + 3) / 4 * 4;
+
+    // header should be 54. It can theoretically be longer, but hopefully not or things will go
+    // terribly for us
+    let mem_size = line_mem_size * height + 54;
+
+    // Ugliness alert: The only way to write to memory from a Surface (instead of writing to a file)
+    // is through RWOps. We have to allocate some memory and give it a slice to write to.
+    let mut surf_bytes: Vec<u8> = vec![0; mem_size as usize];
+    {
+        // from_bytes_mut can only fail if surf_bytes len is zero
+        let mut rwops =
+            RWops::from_bytes_mut(&mut surf_bytes).expect("zero size buffer allocated for bmp");
+        surf.save_bmp_rw(&mut rwops)
+            .map_err(ImagingError::Sdl)?;
+    }
+    let image = bmp::decode(&surf_bytes)?;
+    // Freed before the caller goes on to encode the image
+    drop(surf_bytes);
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cubeglobe::map::Block;
+
+    /// Whether `map` is `len_x` by `len_y` by 1 with water at exactly `water`
+    fn has_water_at(map: &Map, (len_x, len_y): (usize, usize), water: (usize, usize)) -> bool {
+        if (map.len_x(), map.len_y(), map.len_z()) != (len_x, len_y, 1) {
+            return false;
+        }
+        (0..len_x)
+            .flat_map(|x| (0..len_y).map(move |y| (x, y)))
+            .all(|(x, y)| (map.get(x, y, 0) == Block::Water) == ((x, y) == water))
+    }
+
+    /// A 3 by 2 map with one block of water at the far end of the first row
+    fn map() -> Map {
+        let mut map = Map::new(3, 2, 1);
+        map.set(2, 0, 0, Block::Water);
+        map
+    }
+
+    #[test]
+    fn new_params_are_the_bot_defaults() {
+        let params = LandscapeParams::new(64);
+        assert_eq!(params.map_size, 64);
+        assert_eq!(params.frequency, None);
+        assert_eq!(params.quarter_turns, 0);
+        assert_eq!(params.optimize_preset, Some(4));
+        assert!(!params.strip_metadata);
+    }
+
+    #[test]
+    fn rotates_clockwise() {
+        let map = map();
+        assert!(has_water_at(&rotate_map(&map, 0), (3, 2), (2, 0)));
+        assert!(has_water_at(&rotate_map(&map, 1), (2, 3), (1, 2)));
+        assert!(has_water_at(&rotate_map(&map, 2), (3, 2), (0, 1)));
+        assert!(has_water_at(&rotate_map(&map, 3), (2, 3), (0, 0)));
+        assert!(has_water_at(&rotate_map(&map, 5), (2, 3), (1, 2)));
+    }
+
+    #[test]
+    fn turns_add_up() {
+        let mut map = map();
+        for turn in 1..=4 {
+            map = rotate_map(&map, 1);
+            let expected = rotate_map(&self::map(), turn);
+            let size = (expected.len_x(), expected.len_y());
+            let water = (0..size.0)
+                .flat_map(|x| (0..size.1).map(move |y| (x, y)))
+                .find(|&(x, y)| expected.get(x, y, 0) == Block::Water)
+                .expect("Water went missing");
+            assert!(has_water_at(&map, size, water), "{} turns", turn);
+        }
+    }
+
+    #[test]
+    fn strips_metadata_only_when_asked() {
+        match optimizer_options(2, false).strip {
+            oxipng::Headers::None => (),
+            _ => panic!("metadata stripped without being asked"),
+        }
+        match optimizer_options(2, true).strip {
+            oxipng::Headers::Safe => (),
+            _ => panic!("metadata not stripped safely"),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Post length, as instances count it
+//!
+//! Mastodon doesn't count a status's characters one by one: every URL counts as 23 characters,
+//! however long it is, a mention of a remote account only counts its `@username` part, and what
+//! is counted are grapheme clusters, what a reader would take for one character, so an emoji made
+//! of several joined together counts once. Custom emoji shortcodes get no special treatment and
+//! count by their characters. Counting with `chars()` instead gets long URLs wrong in one
+//! direction and emoji sequences in the other, so posts are either cut short for nothing or
+//! refused by the instance.
+
+use std::iter;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// What a URL counts as, however long it is
+const URL_LENGTH: usize = 23;
+
+/// Characters which, at the end of a URL, are taken to be punctuation after it
+const URL_TRAILING: &[char] = &['.', ',', ':', ';', '!', '?', '\'', '"', ')'];
+
+/// How a backend counts the length of a post body
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Counting {
+    /// Every `char` counts
+    Chars,
+    /// See `mastodon_char_count`
+    Mastodon,
+}
+
+impl Default for Counting {
+    fn default() -> Counting {
+        Counting::Chars
+    }
+}
+
+impl Counting {
+    pub fn count(self, text: &str) -> usize {
+        match self {
+            Counting::Chars => text.chars().count(),
+            Counting::Mastodon => mastodon_char_count(text),
+        }
+    }
+}
+
+/// Length of `text` by Mastodon's rules, see the module documentation
+pub fn mastodon_char_count(text: &str) -> usize {
+    countable(text).graphemes(true).count()
+}
+
+/// `text` with URLs replaced by `URL_LENGTH` placeholder characters, and the domains of
+/// mentions dropped
+fn countable(text: &str) -> String {
+    let mut countable = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut previous: Option<char> = None;
+
+    while let Some(c) = rest.chars().next() {
+        // Only at the start of a word, so a URL's own `@` isn't taken for a mention
+        let at_start = previous.map_or(true, |p| !(p.is_alphanumeric() || p == '_' || p == '/'));
+        if at_start {
+            if let Some(len) = url_len(rest) {
+                countable.extend(iter::repeat('x').take(URL_LENGTH));
+                rest = &rest[len..];
+                previous = Some('x');
+                continue;
+            }
+            if let Some((len, kept)) = mention_len(rest) {
+                countable.push_str(&rest[..kept]);
+                rest = &rest[len..];
+                previous = Some('x');
+                continue;
+            }
+        }
+
+        countable.push(c);
+        rest = &rest[c.len_utf8()..];
+        previous = Some(c);
+    }
+    countable
+}
+
+/// Length in bytes of the URL `text` starts with, if it starts with one
+fn url_len(text: &str) -> Option<usize> {
+    let lower = text.get(..8).unwrap_or(text).to_ascii_lowercase();
+    let scheme = if lower.starts_with("https://") {
+        8
+    } else if lower.starts_with("http://") {
+        7
+    } else {
+        return None;
+    };
+
+    let mut url = text.find(char::is_whitespace).map_or(text, |end| &text[..end]);
+    // A closing parenthesis only belongs to the URL if it closes one in it
+    while let Some(last) = url.chars().last() {
+        let opened = url.matches('(').count() >= url.matches(')').count();
+        if !URL_TRAILING.contains(&last) || (last == ')' && opened) {
+            break;
+        }
+        url = &url[..url.len() - last.len_utf8()];
+    }
+
+    let has_host = url[scheme..]
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_alphanumeric());
+    if has_host {
+        Some(url.len())
+    } else {
+        None
+    }
+}
+
+/// Length in bytes of the mention of a remote account `text` starts with, and of the part of it
+/// which counts, if it starts with one
+fn mention_len(text: &str) -> Option<(usize, usize)> {
+    if !text.starts_with('@') {
+        return None;
+    }
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-';
+    let username = text[1..].find(|c| !is_name_char(c)).map_or(text.len(), |end| end + 1);
+    if username == 1 || !text[username..].starts_with('@') {
+        return None;
+    }
+
+    let domain_start = username + 1;
+    let domain = text[domain_start..]
+        .find(|c: char| !(c.is_alphanumeric() || c == '.' || c == '-'))
+        .map_or(text.len(), |end| domain_start + end);
+    // The domain ends with a letter or digit, not with punctuation after it
+    let domain = text[domain_start..domain]
+        .trim_end_matches(|c| c == '.' || c == '-')
+        .len()
+        + domain_start;
+    if domain == domain_start || !text[domain_start..domain].contains('.') {
+        return None;
+    }
+    Some((domain, username))
+}
+
+/// Cut `text` down to `max` by `counting`, ending it with an ellipsis if anything was cut
+///
+/// With Mastodon's counting, whole grapheme clusters are cut, so emoji aren't split apart.
+pub fn shorten(text: &str, max: usize, counting: Counting) -> String {
+    if counting.count(text) <= max {
+        return text.to_string();
+    }
+    if max == 0 {
+        return String::new();
+    }
+
+    let mut shortened = match counting {
+        Counting::Chars => text.chars().take(max - 1).collect(),
+        // Cutting into a URL can leave it counting the same, so this goes by the count of every
+        // shorter prefix rather than by position
+        Counting::Mastodon => text
+            .grapheme_indices(true)
+            .map(|(end, _)| &text[..end])
+            .rev()
+            .find(|prefix| mastodon_char_count(prefix) < max)
+            .unwrap_or("")
+            .to_string(),
+    };
+    shortened.push('…');
+    shortened
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mastodon_counts() {
+        let long_url = format!("https://example.com/{}", "a".repeat(200));
+        let cases: &[(&str, usize)] = &[
+            ("", 0),
+            ("plain text", 10),
+            // Emoji joined with zero-width joiners, a flag, and a skin tone count once each
+            ("👨\u{200d}👩\u{200d}👧\u{200d}👦", 1),
+            ("hi 👨\u{200d}👩\u{200d}👧\u{200d}👦!", 5),
+            ("🇳🇿", 1),
+            ("👍🏽", 1),
+            (":custom_emoji:", 14),
+            // URLs count as 23, however long
+            ("https://example.com", 23),
+            (&long_url, 23),
+            ("HTTPS://EXAMPLE.COM/PATH", 23),
+            ("see http://example.com/a?b=c#d now", 31),
+            ("https://example.com/@user@other.example", 23),
+            // Punctuation after a URL isn't part of it
+            ("https://example.com/path.", 24),
+            ("https://example.com/?!", 25),
+            ("(https://example.com/path)", 25),
+            // Unless it closes a parenthesis in the URL
+            ("https://en.wikipedia.org/wiki/Foo_(bar)", 23),
+            ("(https://en.wikipedia.org/wiki/Foo_(bar))", 25),
+            // Not URLs
+            ("http://", 7),
+            ("https://.example", 16),
+            ("xhttps://example.com", 20),
+            // Remote mentions only count their username
+            ("@user@mastodon.social", 5),
+            ("hi @user@mastodon.social!", 9),
+            ("@user@example.com.", 6),
+            ("@user", 5),
+            ("@user@localhost", 15),
+            ("mail me@example.com", 19),
+            // A character running across byte 8 where the scheme is looked for
+            ("http://é.example", 23),
+            ("abcdefgé https://example.com", 32),
+            ("日本語のテキスト", 8),
+        ];
+        for &(text, expected) in cases {
+            assert_eq!(mastodon_char_count(text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn chars_count_every_char() {
+        assert_eq!(Counting::Chars.count("👨\u{200d}👩\u{200d}👧\u{200d}👦"), 7);
+        assert_eq!(Counting::Chars.count("https://example.com/path"), 24);
+    }
+
+    #[test]
+    fn shorten_keeps_whole_graphemes() {
+        assert_eq!(shorten("hello world", 5, Counting::Chars), "hell…");
+        assert_eq!(shorten("hello", 5, Counting::Chars), "hello");
+        assert_eq!(shorten("hello", 0, Counting::Mastodon), "");
+
+        let family = "👨\u{200d}👩\u{200d}👧\u{200d}👦";
+        let text = format!("ab{}cd", family);
+        assert_eq!(shorten(&text, 4, Counting::Mastodon), format!("ab{}…", family));
+    }
+
+    #[test]
+    fn shorten_doesnt_count_partial_urls_as_urls() {
+        let shortened = shorten("see https://example.com/long", 20, Counting::Mastodon);
+        assert_eq!(shortened, "see https://…");
+        assert!(mastodon_char_count(&shortened) <= 20);
+        // Short enough with the URL counted as 23
+        let text = "see https://example.com/a-path-longer-than-twenty-three";
+        assert_eq!(shorten(text, 27, Counting::Mastodon), text);
+    }
+}
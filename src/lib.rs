@@ -0,0 +1,66 @@
+//! Landscape generation, as used by cubeglobe-bot
+//!
+//! [`render_landscape`] generates an isometric landscape with
+//! [cubeglobe](https://github.com/DeeUnderscore/cubeglobe), renders it with a tileset, and
+//! encodes it as an optimized PNG, the same way the bot does for the stills it posts. It knows
+//! nothing about the bot's config file, its state, or where it posts.
+//!
+//! ```no_run
+//! extern crate cubeglobe_bot;
+//!
+//! use cubeglobe_bot::{render_landscape, LandscapeParams, TilesSource};
+//!
+//! # fn main() -> Result<(), cubeglobe_bot::RenderError> {
+//! let mut params = LandscapeParams::new(64);
+//! params.frequency = Some(0.05);
+//! params.quarter_turns = 1;
+//!
+//! let image = render_landscape(&params, &TilesSource::Builtin)?;
+//! println!(
+//!     "{}x{}, {}% water",
+//!     image.width, image.height, image.stats.water_pct
+//! );
+//! std::fs::write("landscape.png", &image.png).expect("unable to save");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The items documented here follow semver: they only change incompatibly with a new major
+//! version, or a new minor version before 1.0. Structs and enums are `#[non_exhaustive]`, so
+//! that fields and variants can be added in between. Anything hidden from the documentation is
+//! shared with the bot's own binary and can change in any release.
+
+extern crate anyhow;
+extern crate cubeglobe;
+extern crate image;
+extern crate oxipng;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate thiserror;
+extern crate toml;
+
+mod bmp;
+mod landscape;
+mod stats;
+
+#[doc(hidden)]
+pub mod builtin_tiles;
+#[doc(hidden)]
+pub mod tiles;
+
+pub use landscape::{
+    render_landscape, ImagingError, LandscapeParams, RenderError, RenderedImage, TilesSource,
+};
+pub use stats::MapStats;
+
+#[doc(hidden)]
+pub use landscape::{
+    generate_map, optimizer_options, rotate_map, surface_to_image, surface_to_image_via_file,
+    write_surface_as_png,
+};
+#[doc(hidden)]
+pub use bmp::decode as decode_bmp;
+#[doc(hidden)]
+pub use stats::{column_heights, STATS_PLACEHOLDERS};
@@ -0,0 +1,109 @@
+//! Localized post text
+//!
+//! With `[[bot.locales]]` configured, each image is posted in one of them, picked at random by
+//! weight when the image is generated. The pick is kept in the state, so retries post the same
+//! text. Anything a locale leaves out comes from the bot-wide settings.
+
+use std::collections::BTreeSet;
+
+use elefren::Language;
+use rand::Rng;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LocaleConfig {
+    /// ISO 639-1 code, sent along as the language of the post
+    pub language: String,
+
+    /// How often this locale is picked, relative to the others
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+
+    /// Post body, with the same placeholders as the alt text. The bot-wide body if not set.
+    #[serde(default)]
+    pub body: Option<String>,
+
+    /// Alt text, the bot-wide `alt_text` if not set
+    #[serde(default)]
+    pub alt_text: Option<String>,
+
+    /// Added to the end of the post body, with or without the leading `#`
+    #[serde(default)]
+    pub hashtags: Vec<String>,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+impl LocaleConfig {
+    /// The hashtags as a line of text, or `None` if there are none
+    pub fn hashtag_line(&self) -> Option<String> {
+        hashtag_line(&self.hashtags)
+    }
+}
+
+/// `hashtags` as a line of text, or `None` if there are none
+pub fn hashtag_line(hashtags: &[String]) -> Option<String> {
+    if hashtags.is_empty() {
+        return None;
+    }
+
+    let tags: Vec<String> = hashtags
+        .iter()
+        .map(|tag| format!("#{}", tag.trim_start_matches('#')))
+        .collect();
+    Some(tags.join(" "))
+}
+
+/// Check the settings of every locale, other than their templates
+pub fn validate(locales: &[LocaleConfig]) -> Result<(), String> {
+    if locales.is_empty() {
+        return Err("at least one locale is needed".to_string());
+    }
+
+    let mut seen = BTreeSet::new();
+    for locale in locales {
+        if Language::from_639_1(&locale.language).is_none() {
+            return Err(format!("unknown language code {:?}", locale.language));
+        }
+        if !seen.insert(locale.language.as_str()) {
+            return Err(format!("language {} is configured more than once", locale.language));
+        }
+        if !locale.weight.is_finite() || locale.weight <= 0.0 {
+            return Err(format!(
+                "weight of {} must be positive, got {}",
+                locale.language, locale.weight
+            ));
+        }
+        for tag in &locale.hashtags {
+            let tag = tag.trim_start_matches('#');
+            if tag.is_empty() || tag.chars().any(char::is_whitespace) {
+                return Err(format!(
+                    "hashtag {:?} of {} is not a single word",
+                    tag, locale.language
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Pick one of `locales`, each as likely as its weight
+pub fn choose<'a, R: Rng>(locales: &'a [LocaleConfig], rng: &mut R) -> Option<&'a LocaleConfig> {
+    if locales.is_empty() {
+        return None;
+    }
+    let total: f64 = locales.iter().map(|locale| locale.weight).sum();
+
+    let mut pick = rng.gen_range(0.0, total);
+    for locale in locales {
+        if pick < locale.weight {
+            return Some(locale);
+        }
+        pick -= locale.weight;
+    }
+
+    // Rounding can leave a sliver past the last one
+    locales.last()
+}
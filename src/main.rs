@@ -1,5 +1,6 @@
 extern crate clap;
 extern crate cubeglobe;
+extern crate cubeglobe_bot;
 extern crate elefren;
 #[macro_use]
 extern crate serde_derive;
@@ -10,430 +11,4384 @@ extern crate toml;
 #[macro_use]
 extern crate thiserror;
 extern crate chrono;
+extern crate crossbeam_utils;
+extern crate num_cpus;
 extern crate rand;
+extern crate fs2;
+extern crate gif;
+extern crate hmac;
 extern crate oxipng;
+extern crate reqwest;
+extern crate rusttype;
+extern crate serde_json;
+#[cfg(unix)]
+extern crate signal_hook;
+extern crate sha2;
+extern crate tar;
+extern crate unicode_segmentation;
+extern crate webp;
+extern crate zstd;
+#[cfg(feature = "avif")]
+extern crate imgref;
+#[cfg(feature = "avif")]
+extern crate ravif;
+#[cfg(feature = "avif")]
+extern crate rgb;
+#[cfg(test)]
+extern crate tempfile;
 
-use std::fs::{create_dir_all, read, read_to_string, File};
-use std::io::{BufReader, Write};
-use std::io::{Cursor, Read, Seek};
+mod adapt;
+mod animate;
+mod approval;
+mod archive;
+mod archive_copy;
+mod attempts;
+mod attribution;
+mod background;
+mod build_info;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod clock;
+mod consistency;
+mod contrast;
+#[cfg(feature = "bluesky")]
+mod bluesky;
+mod debug_bundle;
+mod describe;
+mod digest;
+mod engagement;
+mod errors;
+mod events;
+mod evolve;
+mod federation;
+mod flavor;
+mod formats;
+mod heightmap;
+mod hooks;
+mod init;
+mod integrity;
+mod journal;
+mod length;
+mod locale;
+mod maintenance;
+mod memory;
+mod migrate;
+mod milestone;
+#[cfg(feature = "matrix")]
+mod matrix;
+mod names;
+mod next_post;
+mod optimize;
+mod overlay;
+mod overrides;
+mod permissions;
+mod pin;
+mod poll;
+mod posting;
+mod quantize;
+mod recover;
+mod queue;
+mod range;
+mod regen;
+mod remote_media;
+mod repair;
+mod rotate;
+mod schedule;
+mod seed;
+mod selftest;
+mod shutdown;
+mod state;
+#[cfg(test)]
+mod test_support;
+mod thread;
+mod trigger;
+mod webhook;
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, metadata, read, read_to_string, remove_file, File};
+use std::io::{self, Cursor, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::thread::sleep;
-use std::time::Duration as StdDuration;
+use std::process::exit;
+use std::time::{Duration as StdDuration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use chrono::prelude::*;
 use chrono::Duration as ChrDuration;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use elefren::Data as MastoData;
-use elefren::{Mastodon, MastodonClient, MediaBuilder, StatusBuilder};
-use anyhow::Error;
-use image::{ImageError, ImageOutputFormat};
+use elefren::{Mastodon, MastodonBuilder, MastodonClient, MediaBuilder};
+use anyhow::{Context, Error};
+use image::{DynamicImage, ImageOutputFormat};
 use rand::{thread_rng, Rng};
+use serde::Serializer;
+
+use cubeglobe::map::Map;
+use cubeglobe::renderer::{Renderer, RendererError, Surface};
+use cubeglobe_bot::{
+    builtin_tiles, rotate_map, surface_to_image, surface_to_image_via_file, tiles,
+    write_surface_as_png, ImagingError, LandscapeParams, MapStats, STATS_PLACEHOLDERS,
+};
 
-use cubeglobe::map::generator::{Generator, TerGenTwo};
-use cubeglobe::renderer::{RWops, Renderer, RendererError, Surface};
+use adapt::{BackendOverrides, BACKENDS};
+use animate::{encode_gif, AnimationConfig};
+use approval::{Decision, TimeoutAction};
+use archive_copy::ArchiveFormat;
+use attempts::AttemptLog;
+use attribution::{Attribution, Credits};
+use background::Background;
+use build_info::BuildInfo;
+#[cfg(feature = "chaos")]
+use chaos::Chaos;
+use clock::{Sanity, SystemClock};
+use consistency::{validate_params, Adjustment, TerrainLevels};
+use contrast::ContrastConfig;
+#[cfg(feature = "bluesky")]
+use bluesky::{BlueskyConfig, BlueskyPoster};
+use describe::{describe, DescriptionConfig};
+use digest::DigestConfig;
+use engagement::AdaptiveConfig;
+use errors::{BudgetExhausted, ConfigError, DiskError, PostingError};
+use events::{Event, EventLog};
+use evolve::DriftConfig;
+use federation::{FederationCheckConfig, FederationChecker};
+use flavor::InstanceFlavor;
+use heightmap::HeightmapConfig;
+use hooks::{Hook, HooksConfig};
+use integrity::Fingerprint;
+use journal::Intent;
+use locale::LocaleConfig;
+use maintenance::{Maintenance, MaintenanceWindow};
+use memory::MemoryReport;
+use milestone::MilestoneConfig;
+#[cfg(feature = "matrix")]
+use matrix::{MatrixConfig, MatrixPoster};
+use names::NameLists;
+use next_post::Reason;
+use optimize::{AutoOptimizeConfig, Measurement, OptimizeLevel};
+use overlay::OverlayConfig;
+use overrides::Override;
+use pin::PinConfig;
+use poll::PollConfig;
+use posting::{
+    image_dimensions, Attachment, DuplicateSuffix, FallbackPoster, ImageFormat, MastodonPoster,
+    Post, PostReceipt, Poster, Progress,
+};
+use queue::QueueEmpty;
+use range::ParamRange;
+use regen::RegenBudget;
+use remote_media::RemoteMedia;
+use schedule::{FirstPostDelay, JitterSpec, OutagePolicy};
+use rotate::RotationMode;
+use seed::SeedMode;
+use shutdown::{Cancelled, Shutdown, Woken};
+use state::{Phase, State, StatePaths};
+use thread::ThreadMode;
+use trigger::Trigger;
+use webhook::{WebhookConfig, WebhookPoster};
+
+/// Lets tests measure what they allocate, see `test_support::peak_allocation`
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: test_support::CountingAllocator = test_support::CountingAllocator;
 
 const STATE_PATH: &str = "state";
 const IMAGES_DIR: &str = "images";
+const TILES_PATH: &str = "tiles.conf";
+/// Subdirectory of the images directory that images dropped during an outage are moved to
+const STALE_DIR: &str = "stale";
+/// Subdirectory of the images directory that damaged pending images are moved to
+const QUARANTINE_DIR: &str = "quarantine";
+/// Subdirectory of the images directory that automatic debug bundles are written to
+const BUNDLES_DIR: &str = "bundles";
+/// Subdirectory of the images directory that heightmaps are saved to, so they aren't taken for
+/// images of their own
+const HEIGHTMAPS_DIR: &str = "heightmaps";
+/// Subdirectory of the images directory that the instance's versions of posted images are
+/// downloaded to, see `match_remote`
+const REMOTE_DIR: &str = "remote";
+
+/// Largest accepted `map_size`
+const MAX_MAP_SIZE: usize = 512;
+/// `map_size` used for the trial render at startup
+const TRIAL_MAP_SIZE: usize = 8;
+const DEFAULT_FILENAME_TEMPLATE: &str = "{id}";
 const IMAGE_TITLE: &str = "A procedurally generated landscape composed of cuboid blocks, rendered in isometric perspective.";
 const POST_BODY: &str = "⛰️";
+const REDACTED: &str = "<redacted>";
 // 30 seconds, 1 minute, 5 minutes, 15 minutes
 const DELAYS: &[u64] = &[30, 60, 300, 900];
 
+// Exit codes for count-limited runs (`--posts`), so that wrappers can tell what happened
+const EXIT_FAILED: i32 = 1;
+const EXIT_NOTHING_DUE: i32 = 3;
+
+/// Error message of every failure injected by `--chaos`
+const CHAOS_MESSAGE: &str = "failure injected by --chaos";
+
 #[derive(Deserialize)]
 struct ConfigFile {
     bot: BotConfig,
     credentials: MastoData,
+
+    /// Account to post to instead when the main one keeps failing
+    credentials_fallback: Option<MastoData>,
+
+    #[cfg(feature = "bluesky")]
+    bluesky: Option<BlueskyConfig>,
+
+    #[cfg(feature = "matrix")]
+    matrix: Option<MatrixConfig>,
+
+    webhook: Option<WebhookConfig>,
+
+    /// Look statuses up again a while after posting them, see the `federation` module
+    federation_check: Option<FederationCheckConfig>,
+
+    /// Commands to run at points in an image's life, see the `hooks` module
+    hooks: Option<HooksConfig>,
 }
 
-#[derive(Deserialize)]
+impl ConfigFile {
+    /// Serialize the config back to TOML, with secrets redacted
+    fn to_redacted_toml(&self) -> Result<String, Error> {
+        let effective = RedactedConfigFile {
+            bot: &self.bot,
+            credentials: RedactedCredentials::from(&self.credentials),
+            credentials_fallback: self.credentials_fallback.as_ref().map(RedactedCredentials::from),
+            #[cfg(feature = "bluesky")]
+            bluesky: self.bluesky.as_ref().map(|bluesky| BlueskyConfig {
+                app_password: REDACTED.to_string(),
+                ..bluesky.clone()
+            }),
+            #[cfg(feature = "matrix")]
+            matrix: self.matrix.as_ref().map(|matrix| MatrixConfig {
+                access_token: REDACTED.to_string(),
+                ..matrix.clone()
+            }),
+            webhook: self.webhook.as_ref().map(|webhook| WebhookConfig {
+                url: webhook.url.clone(),
+                bearer_token: webhook.bearer_token.as_ref().map(|_| REDACTED.to_string()),
+                secret: webhook.secret.as_ref().map(|_| REDACTED.to_string()),
+            }),
+            federation_check: self.federation_check.as_ref().map(|check| FederationCheckConfig {
+                verify_token: check.verify_token.as_ref().map(|_| REDACTED.to_string()),
+                ..check.clone()
+            }),
+            hooks: self.hooks.as_ref(),
+        };
+
+        // Going through `Value` takes care of putting plain values before tables, which the
+        // serializer would otherwise trip over
+        Ok(toml::to_string(&toml::Value::try_from(&effective)?)?)
+    }
+
+    /// Base URLs of the Mastodon instances posted to
+    fn instance_bases(&self) -> Vec<String> {
+        Some(&self.credentials)
+            .into_iter()
+            .chain(&self.credentials_fallback)
+            .map(|credentials| credentials.base.to_string())
+            .collect()
+    }
+
+    /// Every secret `to_redacted_toml` leaves out
+    fn secrets(&self) -> Vec<&str> {
+        let mut secrets = Vec::new();
+        for credentials in Some(&self.credentials).into_iter().chain(&self.credentials_fallback) {
+            secrets.push(&*credentials.client_secret);
+            secrets.push(&*credentials.token);
+        }
+        #[cfg(feature = "bluesky")]
+        secrets.extend(self.bluesky.as_ref().map(|bluesky| &*bluesky.app_password));
+        #[cfg(feature = "matrix")]
+        secrets.extend(self.matrix.as_ref().map(|matrix| &*matrix.access_token));
+        if let Some(ref webhook) = self.webhook {
+            secrets.extend(webhook.bearer_token.as_ref().map(String::as_str));
+            secrets.extend(webhook.secret.as_ref().map(String::as_str));
+        }
+        if let Some(ref check) = self.federation_check {
+            secrets.extend(check.verify_token.as_ref().map(String::as_str));
+        }
+        secrets.retain(|secret| !secret.is_empty());
+        secrets
+    }
+}
+
+/// Same shape as `ConfigFile`, but safe to print
+#[derive(Serialize)]
+struct RedactedConfigFile<'a> {
+    bot: &'a BotConfig,
+    credentials: RedactedCredentials<'a>,
+    credentials_fallback: Option<RedactedCredentials<'a>>,
+
+    #[cfg(feature = "bluesky")]
+    bluesky: Option<BlueskyConfig>,
+
+    #[cfg(feature = "matrix")]
+    matrix: Option<MatrixConfig>,
+
+    webhook: Option<WebhookConfig>,
+
+    federation_check: Option<FederationCheckConfig>,
+
+    hooks: Option<&'a HooksConfig>,
+}
+
+#[derive(Serialize)]
+struct RedactedCredentials<'a> {
+    base: &'a str,
+    client_id: &'a str,
+    client_secret: &'a str,
+    redirect: &'a str,
+    token: &'a str,
+}
+
+impl<'a> From<&'a MastoData> for RedactedCredentials<'a> {
+    fn from(credentials: &'a MastoData) -> RedactedCredentials<'a> {
+        RedactedCredentials {
+            base: &credentials.base,
+            client_id: &credentials.client_id,
+            client_secret: REDACTED,
+            redirect: &credentials.redirect,
+            token: REDACTED,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
 struct BotConfig {
     #[serde(default = "default_sleep_time")]
     sleep_time: i64,
 
-    #[serde(default = "default_jitter")]
-    jitter: i64,
+    /// How far posts may come before or after `sleep_time` is up, see `JitterSpec`
+    #[serde(default)]
+    jitter: JitterSpec,
+
+    /// Never post sooner than this many seconds after the last post, whatever the schedule says.
+    /// A quarter of `sleep_time` if not set.
+    #[serde(default)]
+    min_interval_secs: Option<i64>,
+
+    /// After a post asked for with SIGUSR1 or `trigger`, keep counting the schedule from where it
+    /// did before instead of from that post
+    #[serde(default)]
+    trigger_keeps_schedule: bool,
+
+    /// How long a fresh deployment waits before its first post, see `FirstPostDelay`
+    #[serde(default)]
+    first_post_delay: FirstPostDelay,
+
+    /// Post more or less often with how much engagement posts get, see the `engagement` module
+    #[serde(default)]
+    adaptive: Option<AdaptiveConfig>,
+
+    /// How long to wait for a system clock showing a time before the last post or the build to
+    /// be set right, before giving up, see the `clock` module
+    #[serde(default = "default_max_clock_wait_secs")]
+    max_clock_wait_secs: u64,
+
+    /// Times the instance is down for maintenance, during which nothing is posted, see the
+    /// `maintenance` module
+    #[serde(default)]
+    maintenance_windows: Vec<MaintenanceWindow>,
+
+    /// Address of a JSON or iCalendar file of further maintenance windows, fetched hourly
+    #[serde(default)]
+    maintenance_url: Option<String>,
 
     map_size: usize,
 
+    frequency: Option<ParamRange<f64>>,
+
+    /// Deprecated in favor of `frequency`
     min_frequency: Option<f64>,
+    /// Deprecated in favor of `frequency`
     max_frequency: Option<f64>,
 
     layer_height: Option<usize>,
     min_soil_cutoff: Option<usize>,
-    max_water_level: Option<usize>,
-}
+    max_water_level: Option<ParamRange<usize>>,
 
-fn default_sleep_time() -> i64 {
-    3600
-}
-fn default_jitter() -> i64 {
-    300
-}
+    /// Have the frequency and water level drift from the last post's instead of picking them
+    /// afresh, see the `evolve` module
+    #[serde(default)]
+    evolution: bool,
 
-/// Current state of the bot
-///
-/// The bot uses this struct, backed by a toml file on disk, to keep track of its state. The bot
-/// first waits for the next posting time, then generates the image, then posts the image, then
-/// waits again. We keep track of the state so that if remote problems cause posting to fail, we
-/// attempt to retry the last image instead of generating a new one.
-#[derive(Deserialize, Serialize)]
-struct State {
-    last_post: Option<DateTime<Utc>>,
-    id: u32,
-    phase: Phase,
-}
+    /// How far `evolution` lets them drift
+    #[serde(default)]
+    drift: DriftConfig,
 
-#[derive(Deserialize, Serialize)]
-enum Phase {
-    Awaiting,
-    Generated,
-}
+    /// How the RNG every choice about an image is drawn from is seeded, see the `seed` module
+    #[serde(default)]
+    seed_mode: SeedMode,
 
-impl Default for State {
-    fn default() -> State {
-        State {
-            last_post: None,
-            id: 1,
-            phase: Phase::Awaiting,
-        }
-    }
-}
+    /// Namespace for running several bots off one images directory. Prefixes image file names
+    /// with `{bot_name}-` and makes the state file default to `state-{bot_name}`.
+    #[serde(default)]
+    bot_name: Option<String>,
 
-impl State {
-    /// Read state from file or otherwise get a new one with defaults
-    fn get_state() -> State {
-        read_to_string(STATE_PATH)
-            .ok()
-            .and_then(|ref s| toml::from_str::<State>(s).ok())
-            .unwrap_or_default()
-    }
+    #[serde(default = "default_filename_template")]
+    filename_template: String,
 
-    /// Save current state to file
-    fn persist(&self) -> Result<(), Error> {
-        let serialized = toml::to_string(self)?;
-        let mut statefile = File::create(STATE_PATH)?;
+    #[serde(default)]
+    log_format: LogFormat,
 
-        statefile.write_all(serialized.as_bytes())?;
+    /// Refuse to start if the config file is readable by other users, instead of warning
+    #[serde(default)]
+    strict_permissions: bool,
 
-        Ok(())
-    }
+    /// Tiles config to use when none is given on the command line. `builtin` selects the
+    /// built-in tiles.
+    #[serde(default = "default_tiles", serialize_with = "serialize_path_lossy")]
+    tiles: PathBuf,
 
-    /// Get the full filepath for where to save the current image file
-    fn get_filename(&self) -> Result<Box<Path>, Error> {
-        let mut pathbuf = PathBuf::new();
-        pathbuf.push(IMAGES_DIR);
-        create_dir_all(&pathbuf)?;
+    /// Where generated images are saved
+    #[serde(default = "default_images_dir", serialize_with = "serialize_path_lossy")]
+    images_dir: PathBuf,
 
-        pathbuf.push(format!("{}", self.id));
-        pathbuf.set_extension("png");
-        Ok(pathbuf.into_boxed_path())
-    }
+    /// Where the state file is kept, see `state_path()` for the default
+    #[serde(default, serialize_with = "serialize_opt_path_lossy")]
+    state_path: Option<PathBuf>,
 
-    fn get_saved_image(&self) -> Result<Vec<u8>, Error> {
-        if let Phase::Awaiting = self.phase {
-            return Err(BadStateError(
-                "Asked to load image but currently in Awaiting state".to_string(),
-            ).into());
-        }
+    /// Images generated ahead of time, posted before any are generated, see the `queue` module
+    #[serde(default, serialize_with = "serialize_opt_path_lossy")]
+    queue_dir: Option<PathBuf>,
 
-        Ok(read(self.get_filename()?)?)
-    }
+    /// Whether to generate an image or skip the slot when the queue is empty
+    #[serde(default)]
+    queue_empty: QueueEmpty,
 
-    /// Update state to indicate posting was successful
-    fn posted(self) -> State {
-        State {
-            last_post: Some(Utc::now()),
-            id: self.id + 1,
-            phase: Phase::Awaiting,
-        }
-    }
+    /// Free space required on the images volume before we start generating
+    #[serde(default = "default_min_free_bytes")]
+    min_free_bytes: u64,
 
-    /// Update state to indicate image was generated but not yet posted
-    fn generated(self) -> State {
-        State {
-            phase: Phase::Generated,
-            ..self
-        }
-    }
+    /// Alt text for the image. `{description}` is replaced with the terrain description.
+    #[serde(default = "default_alt_text")]
+    alt_text: String,
 
-    /// Post new status, with `image`
-    fn post_status<I>(&self, masto: &Mastodon, image: I) -> Result<(), PostingError>
-    where
-        I: Read + Send + 'static,
-    {
-        let attachment = masto.media(MediaBuilder {
-            description: Some(IMAGE_TITLE.to_string()),
-            mimetype: Some("image/png".to_string()),
-            filename: Some(format!("{}.png", self.id)),
-            ..MediaBuilder::from_reader(image)
-        }).map_err(PostingError::ElefrenError)?;
-        let status = masto.new_status(
-            StatusBuilder::new()
-            .status(POST_BODY.to_string())
-            .media_ids(vec![attachment.id])
-            .visibility(elefren::status_builder::Visibility::Public)
-            .build().map_err(PostingError::ElefrenError)?
-        ).map_err(PostingError::ElefrenError)?;
-
-        eprintln!("New status posted at: {}", status.uri);
+    /// Post body, with the same placeholders as the alt text. The built-in body and the
+    /// description, placed as configured, if not set.
+    #[serde(default)]
+    body: Option<String>,
 
-        Ok(())
-    }
-}
+    /// Put in place of a placeholder the pending image has no value for, like a statistic from
+    /// a state file older than statistics
+    #[serde(default)]
+    placeholder_fallback: String,
 
-/// Generate a new map and render it to a `Surface`
-fn generate_image<'a>(
-    config: &BotConfig,
-    renderer: &Renderer,
-) -> Result<Surface<'a>, RendererError> {
-    let mut generator = TerGenTwo::new().set_len(config.map_size);
-    let mut rng = thread_rng();
+    /// Word lists to name landscapes from, overriding the built-in ones, see `names`
+    #[serde(default, serialize_with = "serialize_opt_path_lossy")]
+    names_file: Option<PathBuf>,
 
-    if let Some(min) = config.min_frequency {
-        if let Some(max) = config.max_frequency {
-            generator = generator.set_frequency(rng.gen_range(min, max));
-        }
-    }
+    /// Add a line with the parameters the image was generated with to the end of the alt text
+    #[serde(default)]
+    alt_text_params: bool,
 
-    if let Some(height) = config.layer_height {
-        generator = generator.set_layer_height(height);
-    }
+    /// Languages to post in, one picked for each image. If not set, posts use `alt_text` and the
+    /// built-in body.
+    #[serde(default)]
+    locales: Option<Vec<LocaleConfig>>,
 
-    if let Some(cutoff) = config.min_soil_cutoff {
-        generator = generator.set_min_soil_cutoff(cutoff);
-    }
+    /// Add a short generated description of the terrain to the post
+    #[serde(default)]
+    describe: bool,
 
-    if let Some(level) = config.max_water_level {
-        generator = generator.set_max_water_level(level);
-    }
+    #[serde(default)]
+    description: DescriptionConfig,
 
-    let map = generator.generate();
+    /// Whether to turn the map before rendering, so similar terrain looks different
+    #[serde(default)]
+    rotation: RotationMode,
 
-    renderer.render_map(&map)
-}
+    /// Post an animation showing the map from all four sides instead of a still
+    #[serde(default)]
+    animate: bool,
 
-#[derive(Error, Debug)]
-pub enum ImageConvertError {
-    #[error("SDL error: {0}")]
-    SdlError(String),
-    #[error("Error loading image: {0}")]
-    ImageError(#[from] ImageError),
-}
+    /// Refuse the config if an instance doesn't accept the formats it posts in, instead of
+    /// falling back, see the `formats` module
+    #[serde(default)]
+    strict_format: bool,
 
-#[derive(Error, Debug)]
-#[error("function called while in incorrect state")]
-pub struct BadStateError(String);
+    #[serde(default)]
+    animation: AnimationConfig,
 
-#[derive(Error, Debug)]
-pub enum PostingError {
-    #[error("Elefren returned an arror: {0}")]
-    ElefrenError(#[from] elefren::Error),
-}
+    /// Replace the renderer's background with a color or transparency
+    #[serde(default)]
+    background: Option<Background>,
 
-/// Take a surface and write to to writer `out`, as PNG
-fn write_surface_as_png<W: Write>(surf: &Surface, mut out: W) -> Result<(), Error> {
-    let (width, height) = surf.size();
+    /// Caption drawn onto every image, off if not set
+    #[serde(default)]
+    overlay: Option<OverlayConfig>,
 
-    // each line is padded to multiple of four
-    let line_mem_size = (width * 3) + ((width * 3) % 4);
+    /// Authorship metadata written into the stills, see the `attribution` module
+    #[serde(default)]
+    attribution: Option<Attribution>,
 
-    // header should be 54. It can theoretically be longer, but hopefully not or things will go
-    // terribly for us
-    let mem_size = line_mem_size * height + 54;
+    /// Attach a heightmap of the terrain after the image, see the `heightmap` module
+    #[serde(default)]
+    heightmap: Option<HeightmapConfig>,
 
-    // Ugliness alert: The only way to write to memory from a Surface (instead of writing to a file)
-    // is through RWOps. We have to allocate some memory and give it a slice to write to.
-    let mut surf_bytes: Vec<u8> = vec![0; mem_size as usize];
-    // from_bytes_mut can only fail if surf_bytes len is zero
-    let mut rwops =
-        RWops::from_bytes_mut(&mut surf_bytes).expect("zero size buffer allocated for bmp");
-    surf.save_bmp_rw(&mut rwops)
-        .map_err(ImageConvertError::SdlError)?;
+    /// Attach a high-contrast version of the image after it, see the `contrast` module
+    #[serde(default)]
+    contrast: Option<ContrastConfig>,
 
-    rwops.seek(std::io::SeekFrom::Start(0))?;
+    /// Poll to attach to each status, see the `poll` module
+    #[serde(default)]
+    poll: Option<PollConfig>,
 
-    image::load(BufReader::new(rwops), image::ImageFormat::BMP)
-        .map_err(ImageConvertError::ImageError)?
-        .write_to(&mut out, ImageOutputFormat::PNG)
-        .map_err(ImageConvertError::ImageError)?;
-    Ok(())
-}
+    /// Different body, map size and visibility for round-numbered images, see the `milestone`
+    /// module
+    #[serde(default)]
+    milestones: Option<MilestoneConfig>,
 
-fn get_backoff(attempt: usize) -> u64 {
-    // Note: attempt is 1-indexed (first attempt is number 1)
-    if attempt > DELAYS.len() {
-        *DELAYS.last().unwrap()
-    } else {
-        DELAYS[attempt - 1]
-    }
-}
+    /// Text which marks a status as the bot's when recovering a lost state file, see the
+    /// `recover` module
+    #[serde(default)]
+    recover_signature: Option<String>,
 
-fn main() {
-    let matches = App::new("cubeglobe-bot")
-        .version("0.1.1")
-        .arg(
-            Arg::with_name("config")
-                .short("c")
-                .long("config")
-                .value_name("PATH")
-                .help("path to the main config file"),
-        ).arg(
-            Arg::with_name("tilesconfig")
-                .short("t")
-                .long("tiles")
-                .value_name("PATH")
-                .help("path to the tiles configuration file"),
-        ).arg(
-            Arg::with_name("immediate")
-                .long("immediate")
-                .help("immediately generate and post an image, and then exit"),
-        ).get_matches();
+    /// How the post is changed for each backend, by backend name, see the `adapt` module
+    #[serde(default)]
+    backends: BTreeMap<String, BackendOverrides>,
 
-    let config_path = matches.value_of("config").unwrap_or("config.toml");
-    let tiles_config_path = matches.value_of("tilesconfig").unwrap_or("tiles.conf");
+    /// Also keep each still in a smaller format next to it, see the `archive_copy` module
+    #[serde(default)]
+    archive_format: Option<ArchiveFormat>,
 
-    let config: ConfigFile =
-        toml::from_str(&read_to_string(config_path).expect("Unable to read bot config"))
-            .expect("Problem reading bot config");
+    /// Download the image as the instance serves it next to the generated one, see the
+    /// `remote_media` module
+    #[serde(default)]
+    match_remote: bool,
 
-    let fedi = Mastodon::from(config.credentials);
+    /// Add `gap_notice` to the first post after failures kept the bot from posting for this long
+    #[serde(default)]
+    gap_notice_after_hours: Option<f64>,
 
-    let renderer = Renderer::from_config_str(
-        &read_to_string(tiles_config_path).expect("Unable to read tiles config"),
-    ).expect("Problem initializing renderer");
+    /// Note added to the post body after an outage. {hours} is the length of the outage.
+    #[serde(default = "default_gap_notice")]
+    gap_notice: String,
 
-    let mut state = State::get_state();
+    /// What to do about the pending image when posting keeps failing, off if not set
+    #[serde(default)]
+    on_prolonged_outage: Option<OutagePolicy>,
 
-    // Immediate mode posts immediately and exits. We do not try to retry at all here.
-    if matches.is_present("immediate") {
-        eprintln!("Immediate post requested, generating...");
-        let surf = generate_image(&config.bot, &renderer).expect("Problem generating image");
-        let filename = state
-            .get_filename()
-            .expect("Failed to initalize the images subdirectory");
-        let mut image_data: Vec<u8> = Vec::new();
-        write_surface_as_png(&surf, image_data.by_ref()).expect("Unable to generate png");
-        
-        image_data = match oxipng::optimize_from_memory(&image_data, &oxipng::Options::from_preset(4)) {
-            Ok(new_image) => new_image,
-            Err(e) => {
-                eprintln!("Failed to optimize PNG, falling back to unoptimized: {}", e);
-                image_data 
-            }
-        };
+    /// Write a debug bundle after this many failures in a row, off if not set
+    #[serde(default)]
+    bundle_after_failures: Option<u32>,
 
-        {
-            let mut outfile = File::create(&filename).expect("Unable to create image file");
-            outfile
-                .write_all(&image_data)
-                .expect("Unable to write to file");
-        }
-        eprintln!(
-            "Generated image file: {}",
-            &filename
-                .to_str()
-                .expect("Something went terribly wrong figuring out the image filename")
-        );
+    /// Hold each generated image until it is approved, see the `approval` module
+    #[serde(default)]
+    approval_required: bool,
 
-        state = state.generated();
-        state.persist().expect("Unable to persist state");
-        state
-            .post_status(&fedi, Cursor::new(image_data))
-            .expect("Failed to post status");
+    /// Decide on images still waiting for approval after this many minutes, waiting
+    /// indefinitely if not set
+    #[serde(default)]
+    approval_timeout_minutes: Option<i64>,
 
-        state.posted().persist().expect("Unable to persist state");
-    } else {
-        let mut current_image: Option<Arc<[u8]>> = None;
-        let mut attempt: usize = 0;
+    /// What the timeout decides
+    #[serde(default)]
+    approval_timeout_action: TimeoutAction,
 
-        loop {
-            if let Phase::Awaiting = state.phase {
-                if let Some(last_post) = state.last_post {
-                    let mut rng = thread_rng();
-                    let total_to_wait = ChrDuration::seconds(
-                        config.bot.sleep_time
-                            + rng.gen_range(0 - config.bot.jitter, config.bot.jitter),
-                    );
+    /// Keep fewer copies of the image in memory, at the cost of extra disk writes. Animations are
+    /// still put together in memory.
+    #[serde(default)]
+    low_memory: bool,
 
-                    let scheduled = last_post + total_to_wait;
-                    let actual_to_wait = scheduled - Utc::now();
+    /// Times an image is generated again after failing before its slot is skipped
+    #[serde(default = "default_max_regenerations")]
+    max_regenerations: u32,
 
-                    if actual_to_wait < ChrDuration::zero() {
-                        eprintln!(
-                            "Post was due at {}, it is now later, starting new post...",
-                            scheduled
-                        );
-                    } else {
-                        eprintln!("Sleeping until {}...", scheduled);
-                        sleep(actual_to_wait.to_std().expect("Time duration too large"));
-                        eprintln!("Done sleeping, starting new post...");
-                    }
-                } else {
-                    eprintln!("State shows no previous post, starting first one...");
-                }
+    /// Failures in a row with the same parameters after which new ones are rolled, see
+    /// `create_image_within_budget`
+    #[serde(default = "default_reroll_after_failures")]
+    reroll_after_failures: u32,
 
-                let surf =
-                    generate_image(&config.bot, &renderer).expect("Problem generating image");
-                let filename = state
-                    .get_filename()
-                    .expect("Failed to initalize the images subdirectory");
-                let mut new_image = Vec::new();
-                write_surface_as_png(&surf, new_image.by_ref()).expect("Unable to generate png");
+    /// Time after which no more regenerations are started for a post, in seconds
+    #[serde(default = "default_max_generation_seconds")]
+    max_generation_seconds: u64,
 
-                new_image = match oxipng::optimize_from_memory(&new_image, &oxipng::Options::from_preset(4)) {
-                    Ok(optimized) => optimized,
-                    Err(e) => {
-                        eprintln!("Failed to optimize PNG, falling back to unoptimized: {}", e);
-                        new_image
-                    }
-                };
+    /// Time after which an attempt at posting to a backend is given up on, in seconds, however
+    /// much it is still sending
+    #[serde(default = "default_max_attempt_secs")]
+    max_attempt_secs: u64,
 
-                {
-                    let mut outfile = File::create(&filename).expect("Unable to create image file");
-                    outfile
-                        .write_all(&new_image)
-                        .expect("Unable to write to file");
-                }
-                eprintln!(
-                    "Generated image file: {}",
-                    &filename
-                        .to_str()
-                        .expect("Something went terribly wrong figuring out the image filename")
-                );
+    /// Refuse settings whose images are estimated to need more memory than this to render and
+    /// encode, see `estimate_pipeline_bytes`
+    #[serde(default)]
+    max_pipeline_bytes: Option<u64>,
 
-                current_image = Some(new_image.into());
-                state = state.generated();
-                state.persist().expect("Unable to persist state");
+    /// Refuse settings whose images are estimated to have more pixels than this, going by the
+    /// size of a trial render with the tileset
+    #[serde(default = "default_max_surface_pixels")]
+    max_surface_pixels: u64,
+
+    /// oxipng preset, from 0 to 6, or "auto" to pick one from measurements, see the `optimize`
+    /// module
+    #[serde(default)]
+    optimize_level: OptimizeLevel,
+
+    /// How "auto" picks the preset
+    #[serde(default)]
+    optimize_auto: AutoOptimizeConfig,
+
+    /// Have the optimizer drop textual and other non-essential PNG chunks
+    #[serde(default)]
+    strip_metadata: bool,
+
+    /// Reduce stills to `max_colors` colors before encoding, see the `quantize` module
+    #[serde(default)]
+    quantize: bool,
+
+    /// Colors stills are reduced to with `quantize`, at most 256
+    #[serde(default = "default_max_colors")]
+    max_colors: usize,
+
+    /// Dither when quantizing, for smoother gradients at the cost of larger files
+    #[serde(default)]
+    dither: bool,
+
+    /// Leave images with more than `max_colors` colors as they are instead of quantizing them
+    #[serde(default)]
+    quantize_lossless_only: bool,
+
+    /// Failed attempts at posting an image to the main account before switching to
+    /// `credentials_fallback` for it, if configured
+    #[serde(default = "default_fallback_after_attempts")]
+    fallback_after_attempts: u32,
+
+    /// Animations larger than this are replaced by a still of their first frame
+    #[serde(default = "default_max_upload_bytes")]
+    max_upload_bytes: usize,
+
+    /// Post to Mastodon as unlisted, then boost the status after this many minutes, so it only
+    /// shows up on public timelines then
+    #[serde(default)]
+    boost_after_minutes: Option<i64>,
+
+    /// Server software of the Mastodon accounts' instances, looked up at startup by default
+    #[serde(default)]
+    instance_flavor: InstanceFlavor,
+
+    /// Added to the body of a status the instance refused as a duplicate, before trying again
+    #[serde(default)]
+    duplicate_body_suffix: DuplicateSuffix,
+
+    /// Look each Mastodon status up again after posting it, and only count it as posted if it's
+    /// there with its media, see `MastodonPoster::verify_status`
+    #[serde(default)]
+    verify_post: bool,
+
+    /// Attempts at boosting a status before giving up on it
+    #[serde(default = "default_boost_attempts")]
+    boost_attempts: u32,
+
+    /// Pin the best post of each month to the profile, off if not set
+    #[serde(default)]
+    pin_best: Option<PinConfig>,
+
+    /// Post a weekly contact sheet of the week's images, off if not set
+    #[serde(default)]
+    digest: Option<DigestConfig>,
+
+    /// Post each image as a reply to a root status, one per month, with "monthly"
+    #[serde(default)]
+    thread_mode: ThreadMode,
+
+    /// Text of each month's root status. {month} is replaced with the month and year.
+    #[serde(default = "default_thread_root_text")]
+    thread_root_text: String,
+
+    /// Failures to inject, from `--chaos`, see the `chaos` module
+    #[cfg(feature = "chaos")]
+    #[serde(skip)]
+    chaos: Option<Arc<Chaos>>,
+
+    /// Memory use to log, from `--memory-report`, see the `memory` module
+    #[serde(skip)]
+    memory_report: Option<Arc<MemoryReport>>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    /// Only human-readable messages, on stderr
+    Human,
+    /// Additionally, JSON events on stdout (see the `events` module)
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> LogFormat {
+        LogFormat::Human
+    }
+}
+
+fn default_sleep_time() -> i64 {
+    3600
+}
+fn default_filename_template() -> String {
+    DEFAULT_FILENAME_TEMPLATE.to_string()
+}
+/// Serialize a path for display, replacing anything that is not valid UTF-8
+fn serialize_path_lossy<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+/// Like `serialize_path_lossy`, for optional paths
+fn serialize_opt_path_lossy<S: Serializer>(
+    path: &Option<PathBuf>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match *path {
+        Some(ref path) => serializer.serialize_some(&path.to_string_lossy()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn default_tiles() -> PathBuf {
+    PathBuf::from(TILES_PATH)
+}
+fn default_images_dir() -> PathBuf {
+    PathBuf::from(IMAGES_DIR)
+}
+fn default_fallback_after_attempts() -> u32 {
+    3
+}
+fn default_thread_root_text() -> String {
+    "Landscapes for {month}".to_string()
+}
+fn default_max_regenerations() -> u32 {
+    3
+}
+fn default_max_colors() -> usize {
+    quantize::MAX_PALETTE
+}
+fn default_reroll_after_failures() -> u32 {
+    2
+}
+fn default_max_generation_seconds() -> u64 {
+    600
+}
+fn default_max_clock_wait_secs() -> u64 {
+    3600
+}
+fn default_max_surface_pixels() -> u64 {
+    64_000_000
+}
+fn default_max_attempt_secs() -> u64 {
+    600
+}
+fn default_boost_attempts() -> u32 {
+    3
+}
+fn default_gap_notice() -> String {
+    "Back after a brief outage.".to_string()
+}
+fn default_max_upload_bytes() -> usize {
+    8 * 1024 * 1024
+}
+fn default_min_free_bytes() -> u64 {
+    50 * 1024 * 1024
+}
+fn default_alt_text() -> String {
+    IMAGE_TITLE.to_string()
+}
+
+impl BotConfig {
+    /// Move values from deprecated keys over to their replacements, with a warning
+    fn resolve_deprecated(&mut self) {
+        if self.min_frequency.is_none() && self.max_frequency.is_none() {
+            return;
+        }
+
+        if self.frequency.is_some() {
+            eprintln!(
+                "Warning: min_frequency and max_frequency are deprecated and ignored since \
+                 frequency is set"
+            );
+        } else {
+            eprintln!(
+                "Warning: min_frequency and max_frequency are deprecated, use \
+                 frequency = {{ min = ..., max = ... }} instead"
+            );
+            self.frequency = match (self.min_frequency, self.max_frequency) {
+                (Some(min), Some(max)) => Some(ParamRange::Uniform { min, max }),
+                (Some(value), None) | (None, Some(value)) => Some(ParamRange::Fixed(value)),
+                (None, None) => None,
+            };
+        }
+
+        self.min_frequency = None;
+        self.max_frequency = None;
+    }
+
+    /// Check for values which would otherwise only cause problems later on
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &'static str, problem: String| Err(ConfigError::Value { key, problem });
+
+        if self.map_size == 0 || self.map_size > MAX_MAP_SIZE {
+            return invalid(
+                "map_size",
+                format!("must be from 1 to {}, got {}", MAX_MAP_SIZE, self.map_size),
+            );
+        }
+        if self.sleep_time <= 0 {
+            return invalid("sleep_time", format!("must be positive, got {}", self.sleep_time));
+        }
+        if self.max_surface_pixels == 0 {
+            return invalid("max_surface_pixels", "must be positive".to_string());
+        }
+        if self.min_interval_secs.map_or(false, |secs| secs < 0) {
+            return invalid("min_interval_secs", "must not be negative".to_string());
+        }
+        if let Err(problem) = self.jitter.validate(self.sleep_time) {
+            return invalid("jitter", problem);
+        }
+        if let Err(problem) = self.first_post_delay.validate() {
+            return invalid("first_post_delay", problem);
+        }
+        if let Some(ref adaptive) = self.adaptive {
+            adaptive
+                .validate(self.min_interval().num_seconds())
+                .and_then(|_| self.jitter.validate(adaptive.min_sleep_time))
+                .map_err(|problem| ConfigError::Value { key: "adaptive", problem })?;
+        }
+        for window in &self.maintenance_windows {
+            if let Err(problem) = window.validate() {
+                return invalid("maintenance_windows", problem);
+            }
+        }
+        if let Some(ref name) = self.bot_name {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return invalid(
+                    "bot_name",
+                    format!("must be letters, digits, - and _ only, got {:?}", name),
+                );
+            }
+        }
+        if let Some(ref pin_best) = self.pin_best {
+            // Every month has these
+            if pin_best.day == 0 || pin_best.day > 28 {
+                return invalid(
+                    "pin_best.day",
+                    format!("must be from 1 to 28, got {}", pin_best.day),
+                );
+            }
+        }
+        if let Some(ref digest) = self.digest {
+            if digest.hour > 23 {
+                return invalid(
+                    "digest.hour",
+                    format!("must be from 0 to 23, got {}", digest.hour),
+                );
+            }
+            let placeholders = template_placeholders(&digest.text)
+                .map_err(|problem| ConfigError::Value { key: "digest.text", problem })?;
+            if let Some(name) = placeholders.into_iter().find(|&name| name != "count") {
+                return invalid("digest.text", format!("unknown placeholder {{{}}}", name));
+            }
+        }
+        let placeholders = template_placeholders(&self.thread_root_text)
+            .map_err(|problem| ConfigError::Value { key: "thread_root_text", problem })?;
+        if let Some(name) = placeholders.into_iter().find(|&name| name != "month") {
+            return invalid("thread_root_text", format!("unknown placeholder {{{}}}", name));
+        }
+        if let Some(ref policy) = self.on_prolonged_outage {
+            if !policy.after_hours.is_finite() || policy.after_hours <= 0.0 {
+                return invalid(
+                    "on_prolonged_outage.after_hours",
+                    format!("must be positive, got {}", policy.after_hours),
+                );
+            }
+        }
+        if self.bundle_after_failures == Some(0) {
+            return invalid("bundle_after_failures", "must be at least 1".to_string());
+        }
+        if let OptimizeLevel::Preset(preset) = self.optimize_level {
+            if preset > optimize::MAX_PRESET {
+                return invalid(
+                    "optimize_level",
+                    format!(
+                        "must be from 0 to {} or \"auto\", got {}",
+                        optimize::MAX_PRESET,
+                        preset
+                    ),
+                );
+            }
+        }
+        self.optimize_auto
+            .validate()
+            .map_err(|problem| ConfigError::Value { key: "optimize_auto", problem })?;
+        if self.max_colors < 2 || self.max_colors > quantize::MAX_PALETTE {
+            return invalid(
+                "max_colors",
+                format!("must be from 2 to {}, got {}", quantize::MAX_PALETTE, self.max_colors),
+            );
+        }
+        if self.max_generation_seconds == 0 {
+            return invalid("max_generation_seconds", "must be at least 1".to_string());
+        }
+        if self.reroll_after_failures == 0 {
+            return invalid("reroll_after_failures", "must be at least 1".to_string());
+        }
+        if self.max_attempt_secs == 0 {
+            return invalid("max_attempt_secs", "must be at least 1".to_string());
+        }
+        if let Some(minutes) = self.approval_timeout_minutes {
+            if minutes <= 0 {
+                return invalid(
+                    "approval_timeout_minutes",
+                    format!("must be positive, got {}", minutes),
+                );
+            }
+        }
+        if self.layer_height == Some(0) {
+            return invalid("layer_height", "must be at least 1".to_string());
+        }
+        if let Some(minutes) = self.boost_after_minutes {
+            // Otherwise the next post could come before the boost
+            let sleep_time = self.shortest_sleep_time();
+            let shortest = sleep_time - self.jitter.early(sleep_time);
+            if minutes <= 0 || minutes * 60 >= shortest {
+                return invalid(
+                    "boost_after_minutes",
+                    format!(
+                        "must be positive and less than the shortest time between posts \
+                         ({} minutes), got {}",
+                        shortest / 60,
+                        minutes
+                    ),
+                );
             }
+        }
 
-            if let Phase::Generated = state.phase {
-                let image_data = current_image.unwrap_or_else(|| {
-                    state
-                        .get_saved_image()
-                        .expect("Wanted to retry uploading image but was unable to open its file")
-                        .into()
-                });
+        if let Some(ref frequency) = self.frequency {
+            frequency
+                .validate_positive()
+                .map_err(ConfigError::Frequency)?;
+        }
+        if let Some(ref level) = self.max_water_level {
+            level
+                .validate()
+                .map_err(|problem| ConfigError::Value { key: "max_water_level", problem })?;
+        }
+        self.drift
+            .validate()
+            .map_err(|problem| ConfigError::Value { key: "drift", problem })?;
 
-                attempt += 1;
-                let result = state.post_status(&fedi, Cursor::new(image_data.clone())); 
+        if self.describe {
+            self.description
+                .validate()
+                .map_err(ConfigError::Description)?;
+        }
 
-                match result {
-                    Ok(_) => {
-                        attempt = 0;
-                        state = state.posted();
-                        state.persist().expect("Unable to persist state");
-                        current_image = None;
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to post: {}", e);
-                        let backoff = get_backoff(attempt);
-                        eprintln!("Retrying after {} seconds", backoff);
-                        sleep(StdDuration::from_secs(backoff));
-                        current_image = Some(image_data.clone());
-                    }
-                }
+        self.validate_text_template(&self.alt_text, &[])
+            .map_err(ConfigError::AltText)?;
+        if let Some(ref body) = self.body {
+            self.validate_text_template(body, &[])
+                .map_err(|problem| ConfigError::Value { key: "body", problem })?;
+        }
+        for (name, overrides) in &self.backends {
+            let invalid = |problem| ConfigError::Value { key: "backends", problem };
+            if !BACKENDS.contains(&name.as_str()) {
+                return Err(invalid(format!(
+                    "unknown backend {:?}, expected one of {}",
+                    name,
+                    BACKENDS.join(", ")
+                )));
+            }
+            for template in overrides.body.iter().chain(overrides.alt_text.iter()) {
+                self.validate_text_template(template, &[])
+                    .map_err(|e| invalid(format!("{}: {}", name, e)))?;
             }
         }
+        if let Some(ref path) = self.names_file {
+            NameLists::load(Some(path)).map_err(|e| ConfigError::Value {
+                key: "names_file",
+                problem: e.to_string(),
+            })?;
+        }
+
+        if let Some(ref locales) = self.locales {
+            locale::validate(locales).map_err(ConfigError::Locales)?;
+            for locale in locales {
+                for template in locale.body.iter().chain(locale.alt_text.iter()) {
+                    self.validate_text_template(template, &[])
+                        .map_err(|e| ConfigError::Locales(format!("{}: {}", locale.language, e)))?;
+                }
+            }
+        }
+
+        if let Some(ref overlay) = self.overlay {
+            overlay.validate().map_err(ConfigError::Overlay)?;
+            self.validate_text_template(&overlay.text, &["id"])
+                .map_err(ConfigError::Overlay)?;
+        }
+        if let Some(ref attribution) = self.attribution {
+            let invalid = |problem| ConfigError::Value { key: "attribution", problem };
+            attribution.validate().map_err(invalid)?;
+            for template in attribution.templates() {
+                self.validate_text_template(template, &["id", "year"]).map_err(invalid)?;
+            }
+        }
+        if let Some(ref heightmap) = self.heightmap {
+            heightmap
+                .validate()
+                .map_err(|problem| ConfigError::Value { key: "heightmap", problem })?;
+        }
+        if let Some(ref contrast) = self.contrast {
+            contrast
+                .validate()
+                .map_err(|problem| ConfigError::Value { key: "contrast", problem })?;
+        }
+        if let Some(ref poll) = self.poll {
+            poll.validate()
+                .map_err(|problem| ConfigError::Value { key: "poll", problem })?;
+        }
+        if let Some(ref archive_format) = self.archive_format {
+            archive_format
+                .validate()
+                .map_err(|problem| ConfigError::Value { key: "archive_format", problem })?;
+        }
+        if let Some(ref milestones) = self.milestones {
+            let invalid = |problem| ConfigError::Value { key: "milestones", problem };
+            milestones.validate().map_err(invalid)?;
+            if let Some(ref body) = milestones.body {
+                self.validate_text_template(body, &["id"]).map_err(invalid)?;
+            }
+            let out_of_range = |&size: &usize| size == 0 || size > MAX_MAP_SIZE;
+            if let Some(size) = milestones.map_size.filter(out_of_range) {
+                return Err(invalid(format!(
+                    "map_size must be from 1 to {}, got {}",
+                    MAX_MAP_SIZE, size
+                )));
+            }
+        }
+
+        if let Some(hours) = self.gap_notice_after_hours {
+            if hours.is_nan() || hours <= 0.0 {
+                return Err(ConfigError::GapNotice(format!(
+                    "gap_notice_after_hours must be positive, got {}",
+                    hours
+                )));
+            }
+            for name in template_placeholders(&self.gap_notice).map_err(ConfigError::GapNotice)? {
+                if name != "hours" {
+                    return Err(ConfigError::GapNotice(format!(
+                        "unknown placeholder {{{}}}",
+                        name
+                    )));
+                }
+            }
+        }
+
+        validate_filename_template(&self.filename_template)
+    }
+
+    /// Shortest time allowed between posts
+    fn min_interval(&self) -> ChrDuration {
+        ChrDuration::seconds(self.min_interval_secs.unwrap_or(self.sleep_time / 4))
+    }
+
+    /// `adaptive`, unless it's not set or switched off
+    fn adaptive(&self) -> Option<&AdaptiveConfig> {
+        self.adaptive.as_ref().filter(|adaptive| adaptive.enabled)
+    }
+
+    /// Shortest `sleep_time` can get, with `adaptive`
+    fn shortest_sleep_time(&self) -> i64 {
+        match self.adaptive() {
+            Some(adaptive) => self.sleep_time.min(adaptive.min_sleep_time),
+            None => self.sleep_time,
+        }
+    }
+
+    /// Where the state file is kept
+    ///
+    /// Unless set, this is `state`, or `state-{bot_name}` for named bots.
+    fn state_path(&self) -> PathBuf {
+        match (&self.state_path, &self.bot_name) {
+            (&Some(ref path), _) => path.clone(),
+            (&None, &Some(ref name)) => PathBuf::from(format!("{}-{}", STATE_PATH, name)),
+            (&None, &None) => PathBuf::from(STATE_PATH),
+        }
+    }
+
+    /// Map size of the image numbered `id`, which is bigger for milestones if they say so
+    fn map_size_for(&self, id: u32) -> usize {
+        match self.milestones {
+            Some(ref milestones) if milestones.is_milestone(id) => {
+                milestones.map_size.unwrap_or(self.map_size)
+            }
+            _ => self.map_size,
+        }
+    }
+
+    /// Largest map size any image can have
+    fn largest_map_size(&self) -> usize {
+        let milestone_size = self.milestones.as_ref().and_then(|milestones| milestones.map_size);
+        milestone_size.map_or(self.map_size, |size| size.max(self.map_size))
+    }
+
+    /// Template image file names are rendered from, including the `bot_name` prefix
+    fn filename_template(&self) -> String {
+        match self.bot_name {
+            Some(ref name) => format!("{}-{}", name, self.filename_template),
+            None => self.filename_template.clone(),
+        }
+    }
+
+    /// Check that `template` only uses placeholders from `allowed`, {description}, {name} and the
+    /// map statistics
+    fn validate_text_template(&self, template: &str, allowed: &[&str]) -> Result<(), String> {
+        for name in template_placeholders(template)? {
+            match name {
+                "description" if self.describe => {}
+                "description" => {
+                    return Err("{description} can only be used with describe = true".to_string())
+                }
+                "name" => {}
+                name if allowed.contains(&name) || STATS_PLACEHOLDERS.contains(&name) => {}
+                other => return Err(format!("unknown placeholder {{{}}}", other)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that a filename template only yields plain, unique file names
+///
+/// The template must contain `{id}`, so that every image gets its own file, and must not contain
+/// path separators, since it is only ever a name inside the images directory.
+///
+/// There is no `{seed}`, as cubeglobe doesn't take one, so no seed identifies a map, and no
+/// `{preset}`, as parameters are rolled from ranges rather than picked from named presets. Both
+/// are refused with a message saying so, rather than as unknown.
+fn validate_filename_template(template: &str) -> Result<(), ConfigError> {
+    if template.chars().any(std::path::is_separator) {
+        return Err(ConfigError::FilenameTemplate(
+            "must not contain path separators".to_string(),
+        ));
+    }
+
+    let placeholders = template_placeholders(template).map_err(ConfigError::FilenameTemplate)?;
+    for name in &placeholders {
+        match *name {
+            "id" | "date" => {}
+            "seed" => {
+                return Err(ConfigError::FilenameTemplate(
+                    "{seed} isn't supported, maps aren't generated from a seed".to_string(),
+                ))
+            }
+            "preset" => {
+                return Err(ConfigError::FilenameTemplate(
+                    "{preset} isn't supported, parameters don't come from presets".to_string(),
+                ))
+            }
+            other => {
+                return Err(ConfigError::FilenameTemplate(format!(
+                    "unknown placeholder {{{}}}, supported are {{id}} and {{date}}",
+                    other
+                )))
+            }
+        }
+    }
+
+    if !placeholders.contains(&"id") {
+        return Err(ConfigError::FilenameTemplate(
+            "must contain {id}, so that every image gets its own file".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// List the names of all `{placeholder}`s in a template
+fn template_placeholders(template: &str) -> Result<Vec<&str>, String> {
+    let mut placeholders = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed brace in {:?}", template))?;
+        placeholders.push(&rest[start + 1..start + end]);
+        rest = &rest[start + end + 1..];
+    }
+
+    Ok(placeholders)
+}
+
+/// Replace every `{placeholder}` in a template with what `lookup` returns for its name
+///
+/// Placeholders for which `lookup` returns `None` are left as they are.
+fn fill_template<F>(template: &str, mut lookup: F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        filled.push_str(&rest[..start]);
+        match lookup(&rest[start + 1..end]) {
+            Some(value) => filled.push_str(&value),
+            None => filled.push_str(&rest[start..end + 1]),
+        }
+        rest = &rest[end + 1..];
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+/// Fill out a filename template for image `id`, generated at `date`
+fn render_filename(template: &str, id: u32, date: DateTime<Utc>) -> String {
+    template
+        .replace("{id}", &id.to_string())
+        .replace("{date}", &date.format("%Y%m%d").to_string())
+}
+
+/// Parameters a map was generated with, after any random choices were made
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenerationParams {
+    pub map_size: usize,
+    pub frequency: Option<f64>,
+    pub layer_height: Option<usize>,
+    pub min_soil_cutoff: Option<usize>,
+    pub max_water_level: Option<usize>,
+    /// Clockwise rotation of the map, in degrees
+    #[serde(default)]
+    pub rotation: u16,
+    /// Seed the landscape's name is worked out from
+    #[serde(default)]
+    pub name_seed: u64,
+    /// Settings changed from the rolled values to keep them consistent, see the `consistency`
+    /// module
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub adjustments: Vec<Adjustment>,
+}
+
+impl GenerationParams {
+    /// Settings to generate the map with
+    fn landscape(&self) -> LandscapeParams {
+        let mut landscape = LandscapeParams::new(self.map_size);
+        landscape.frequency = self.frequency;
+        landscape.layer_height = self.layer_height;
+        landscape.min_soil_cutoff = self.min_soil_cutoff;
+        landscape.max_water_level = self.max_water_level;
+        landscape.quarter_turns = (self.rotation / 90 % 4) as u8;
+        landscape
+    }
+
+    /// Parameters of a map generated with `landscape`, to be named from `name_seed`
+    fn of(landscape: &LandscapeParams, name_seed: u64) -> GenerationParams {
+        GenerationParams {
+            map_size: landscape.map_size,
+            frequency: landscape.frequency,
+            layer_height: landscape.layer_height,
+            min_soil_cutoff: landscape.min_soil_cutoff,
+            max_water_level: landscape.max_water_level,
+            rotation: u16::from(landscape.quarter_turns % 4) * 90,
+            name_seed,
+            adjustments: Vec::new(),
+        }
+    }
+
+    /// Clamp the terrain settings to consistent values, logging what was changed
+    fn make_consistent(&mut self) {
+        let (levels, adjustments) = validate_params(TerrainLevels {
+            layer_height: self.layer_height,
+            min_soil_cutoff: self.min_soil_cutoff,
+            max_water_level: self.max_water_level,
+        });
+        for adjustment in &adjustments {
+            eprintln!(
+                "Adjusted {} from {} to {}, as {}",
+                adjustment.key, adjustment.requested, adjustment.effective, adjustment.reason
+            );
+        }
+        self.layer_height = levels.layer_height;
+        self.min_soil_cutoff = levels.min_soil_cutoff;
+        self.max_water_level = levels.max_water_level;
+        self.adjustments.extend(adjustments);
+    }
+
+    /// One line summary, like `size=96 freq=0.0430 layers=6 soil=3 water=12 rotation=90`
+    ///
+    /// People parse this out of alt texts, so the format should not change. Settings which were
+    /// left to the generator's defaults are left out.
+    pub fn summary_line(&self) -> String {
+        let mut fields = vec![format!("size={}", self.map_size)];
+        if let Some(frequency) = self.frequency {
+            fields.push(format!("freq={:.4}", frequency));
+        }
+        if let Some(height) = self.layer_height {
+            fields.push(format!("layers={}", height));
+        }
+        if let Some(cutoff) = self.min_soil_cutoff {
+            fields.push(format!("soil={}", cutoff));
+        }
+        if let Some(level) = self.max_water_level {
+            fields.push(format!("water={}", level));
+        }
+        fields.push(format!("rotation={}", self.rotation));
+
+        fields.join(" ")
+    }
+}
+
+/// A freshly generated map, with what we know about it
+struct GeneratedMap {
+    map: Map,
+    params: GenerationParams,
+    stats: MapStats,
+}
+
+/// Generate a new map and render it to a `Surface`
+fn generate_image<'a>(
+    config: &BotConfig,
+    renderer: &Renderer,
+) -> Result<(Surface<'a>, GeneratedMap), RendererError> {
+    let generated = generate_map(config, &mut thread_rng());
+    Ok((renderer.render_map(&generated.map)?, generated))
+}
+
+/// Generate a new map, turned according to the config
+///
+/// Every random choice about the map is drawn from `world_rng`, which nothing else should draw
+/// from, so that a map only depends on the config and the state of `world_rng`. Scheduling
+/// draws from an RNG of its own.
+fn generate_map<R: Rng>(config: &BotConfig, world_rng: &mut R) -> GeneratedMap {
+    generate_map_sized(config, config.map_size, world_rng)
+}
+
+/// Like `generate_map`, but with `map_size` instead of the configured size
+fn generate_map_sized<R: Rng>(
+    config: &BotConfig,
+    map_size: usize,
+    world_rng: &mut R,
+) -> GeneratedMap {
+    let mut params = roll_params(config, map_size, world_rng);
+    params.make_consistent();
+    generate_map_with(&params)
+}
+
+/// Make the random choices the config leaves open for a `map_size` map, drawing from `world_rng`
+fn roll_params<R: Rng>(
+    config: &BotConfig,
+    map_size: usize,
+    world_rng: &mut R,
+) -> GenerationParams {
+    let name_seed = world_rng.gen();
+    let mut landscape = LandscapeParams::new(map_size);
+    landscape.frequency = config
+        .frequency
+        .as_ref()
+        .map(|frequency| frequency.sample(world_rng));
+    landscape.layer_height = config.layer_height;
+    landscape.min_soil_cutoff = config.min_soil_cutoff;
+    landscape.max_water_level = config
+        .max_water_level
+        .as_ref()
+        .map(|level| level.sample(world_rng));
+    landscape.quarter_turns = config.rotation.pick(world_rng);
+    GenerationParams::of(&landscape, name_seed)
+}
+
+/// Generate a map with parameters already rolled
+///
+/// The terrain itself is up to cubeglobe, which doesn't take a seed, so the same parameters
+/// give different maps every time.
+fn generate_map_with(params: &GenerationParams) -> GeneratedMap {
+    // The same generation `render_landscape` does, so the two can't drift apart
+    let (map, stats) = cubeglobe_bot::generate_map(&params.landscape());
+    GeneratedMap {
+        map,
+        params: params.clone(),
+        stats,
+    }
+}
+
+/// Warn about the terrain settings breaking the rules of the `consistency` module at the
+/// extremes of the configured ranges
+///
+/// These only warn, as images which would break them are adjusted before they're generated.
+fn check_terrain_extremes(config: &BotConfig) {
+    let water_levels = match config.max_water_level {
+        Some(ref range) => vec![Some(range.min()), Some(range.max())],
+        None => vec![None],
+    };
+    let mut warned = Vec::new();
+    for max_water_level in water_levels {
+        let (_, adjustments) = validate_params(TerrainLevels {
+            layer_height: config.layer_height,
+            min_soil_cutoff: config.min_soil_cutoff,
+            max_water_level,
+        });
+        for adjustment in adjustments {
+            if warned.contains(&adjustment) {
+                continue;
+            }
+            eprintln!(
+                "WARNING: {} = {} will be adjusted to {}, as {}",
+                adjustment.key, adjustment.requested, adjustment.effective, adjustment.reason
+            );
+            warned.push(adjustment);
+        }
+    }
+}
+
+/// Generate and render a tiny map with the configured settings, to find problems at startup
+/// rather than when the first post is due
+///
+/// cubeglobe panics on some bad settings, so panics count as failures too. The size of the
+/// render is also used to check the size of images at the full `map_size` against
+/// `max_surface_pixels`, before SDL is ever asked for a surface that large, and the memory
+/// needed for them against `max_pipeline_bytes`.
+fn trial_render(config: &BotConfig, renderer: &Renderer) -> Result<(), ConfigError> {
+    let trial_size = TRIAL_MAP_SIZE.min(config.map_size);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let generated = generate_map_sized(config, trial_size, &mut thread_rng());
+        renderer.render_map(&generated.map).map(|surf| surf.size())
+    }));
+
+    match result {
+        Ok(Ok((width, height))) => {
+            // Both sides of the image grow about in step with the map
+            let map_size = config.largest_map_size();
+            let scale = map_size as f64 / trial_size as f64;
+            let width = (f64::from(width) * scale) as u64;
+            let height = (f64::from(height) * scale) as u64;
+            let pixels = width * height;
+            if pixels > config.max_surface_pixels {
+                return Err(ConfigError::SurfaceTooLarge {
+                    map_size,
+                    width,
+                    height,
+                    max: config.max_surface_pixels,
+                });
+            }
+
+            let max = match config.max_pipeline_bytes {
+                Some(max) => max,
+                None => return Ok(()),
+            };
+            let estimate = estimate_pipeline_bytes(config, pixels);
+            if estimate > max {
+                return Err(ConfigError::TooMuchMemory {
+                    estimate,
+                    max,
+                    suggestion: if config.low_memory {
+                        "Try a smaller map_size."
+                    } else {
+                        "Try low_memory = true, or a smaller map_size."
+                    },
+                });
+            }
+            Ok(())
+        }
+        Ok(Err(e)) => Err(ConfigError::TrialRender(format!("{:?}", e))),
+        Err(panic) => Err(ConfigError::TrialRender(
+            panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "generator panicked".to_string()),
+        )),
+    }
+}
+
+/// Rough peak memory use of rendering and encoding an image of `pixels` pixels
+///
+/// Counts the copies of the image which are held at the same time: the rendered surface, the
+/// BMP it is copied out through, the decoded image, and the encoded and optimized PNG, which are
+/// assumed not to come out any smaller than the decoded image. Animations keep every frame until
+/// they are encoded. `low_memory` avoids the BMP buffer and the PNGs in memory, and `quantize`
+/// adds another copy of the still.
+fn estimate_pipeline_bytes(config: &BotConfig, pixels: u64) -> u64 {
+    // Bytes per pixel of each copy
+    const SURFACE: u64 = 4;
+    const BMP: u64 = 3;
+    const DECODED: u64 = 4;
+    const ENCODED: u64 = 4;
+
+    let frames = if config.animate { 4 } else { 1 };
+    let converting = if config.low_memory {
+        // The surface is freed before the scratch file is decoded
+        SURFACE.max(DECODED)
+    } else {
+        SURFACE + BMP + DECODED
+    };
+    let encoding = if config.low_memory { 0 } else { 2 * ENCODED };
+    let encoding = if config.quantize { encoding + DECODED } else { encoding };
+    let peak = ((frames - 1) * DECODED + converting).max(frames * DECODED + encoding);
+
+    pixels * peak
+}
+
+/// A freshly generated image, saved to disk
+struct CreatedImage {
+    filename: PathBuf,
+    data: Vec<u8>,
+    params: GenerationParams,
+    stats: MapStats,
+    /// Terrain description, if enabled
+    description: Option<String>,
+    /// Name of the landscape
+    name: String,
+    /// Times the image was generated again before it turned out
+    regenerations: u32,
+    /// Each preset's result, if several were tried on the image
+    optimizer_trial: Vec<Measurement>,
+    /// File name of the image's heightmap in the heightmaps directory, if one was made
+    heightmap: Option<String>,
+}
+
+/// Generate images for the current state until one turns out, within the regeneration budget
+///
+/// Running out of disk space and shutting down are passed on right away. Other failures are
+/// retried until `max_regenerations` or `max_generation_seconds` is reached, giving
+/// `BudgetExhausted` then.
+///
+/// The parameters are rolled once and saved in the state, and every regeneration uses them
+/// again, so that a passing problem like an SDL error doesn't change what gets posted. Only
+/// after failing `reroll_after_failures` times in a row with the same parameters are they taken
+/// to be the problem and rolled again.
+fn create_image_within_budget(
+    config: &BotConfig,
+    renderer: &Renderer,
+    state: &mut State,
+    events: &EventLog,
+    shutdown: &Shutdown,
+) -> Result<CreatedImage, Error> {
+    let mut budget = RegenBudget::new(config.max_regenerations, config.max_generation_seconds);
+    let mut failures_with_params = 0;
+    loop {
+        let regenerations = budget.regenerations();
+        let params = state.rolled_params(config);
+        let created =
+            create_image(config, renderer, state, &params, events, shutdown, regenerations);
+        let e = match created {
+            Ok(image) => {
+                state.rolled = None;
+                return Ok(image);
+            }
+            Err(e) => e,
+        };
+        if e.downcast_ref::<Cancelled>().is_some() || e.downcast_ref::<DiskError>().is_some() {
+            return Err(e);
+        }
+        failures_with_params += 1;
+
+        events.emit(Event::GenerationFailed {
+            id: state.id,
+            regenerations,
+            error: format!("{:#}", e),
+        });
+        if failures_with_params >= config.reroll_after_failures {
+            eprintln!(
+                "Failed {} times with the same parameters, rolling new ones: {}",
+                failures_with_params,
+                params.summary_line()
+            );
+            state.rolled = None;
+            failures_with_params = 0;
+        }
+        if !budget.try_regenerate() {
+            // The slot is skipped, and the next one starts afresh
+            state.rolled = None;
+            return Err(BudgetExhausted {
+                attempts: regenerations + 1,
+                seconds: budget.elapsed().as_secs(),
+                source: e.into(),
+            }.into());
+        }
+        eprintln!("Problem generating image, generating another: {:#}", e);
+    }
+}
+
+/// Next entry in the queue in `queue_dir`, as an image to save as the file for the current state
+///
+/// Damaged entries are moved to the queue's quarantine and skipped. Returns the image and its
+/// file name in the queue, or `None` if the queue is empty. Nothing is moved yet, see
+/// `State::finish_taking_from_queue`.
+fn next_queued_image(
+    config: &BotConfig,
+    state: &State,
+    queue_dir: &Path,
+) -> Result<Option<(CreatedImage, String)>, Error> {
+    let (entry, data) = loop {
+        let entry = match queue::entries(queue_dir)?.into_iter().next() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        match entry.load_image(queue_dir) {
+            Ok(data) => break (entry, data),
+            Err(problem) => {
+                eprintln!("Queued image {} is damaged, skipping it: {}", entry.image, problem);
+                queue::quarantine(queue_dir, &entry);
+            }
+        }
+    };
+
+    let filename = state.get_filename(&config.filename_template(), entry.format())?;
+    eprintln!("Taking {} from the queue as {}", entry.image, filename.display());
+    let image = CreatedImage {
+        filename,
+        data,
+        params: entry.params,
+        stats: entry.stats,
+        description: entry.description,
+        name: entry.name,
+        regenerations: entry.regenerations,
+        optimizer_trial: Vec::new(),
+        heightmap: entry.heightmap.as_ref().and_then(|_| heightmap_name(&filename)),
+    };
+    Ok(Some((image, entry.image)))
+}
+
+/// Generate a new image for the current state, optimize it, and save it to disk
+///
+/// `regenerations` is how many times generating it was already tried. Stops with `Cancelled`
+/// between stages if shutdown is requested, without leaving any files behind.
+fn create_image(
+    config: &BotConfig,
+    renderer: &Renderer,
+    state: &State,
+    params: &GenerationParams,
+    events: &EventLog,
+    shutdown: &Shutdown,
+    regenerations: u32,
+) -> Result<CreatedImage, Error> {
+    let generate = || generate_map_with(params);
+    create_image_from(config, renderer, state, events, shutdown, regenerations, generate)
+}
+
+/// Like `create_image`, but with the map from `generate`
+fn create_image_from<F: FnOnce() -> GeneratedMap>(
+    config: &BotConfig,
+    renderer: &Renderer,
+    state: &State,
+    events: &EventLog,
+    shutdown: &Shutdown,
+    regenerations: u32,
+    generate: F,
+) -> Result<CreatedImage, Error> {
+    // No point in spending CPU time on an image we won't be able to save
+    check_free_space(&config.images_dir, config.min_free_bytes)?;
+
+    events.emit(Event::GenerationStarted { id: state.id });
+    let started = Instant::now();
+    if chaos_strikes(config, "generate") {
+        return Err(Error::msg(CHAOS_MESSAGE));
+    }
+
+    let GeneratedMap { map, params, stats } = generate();
+    let heightmap = config
+        .heightmap
+        .as_ref()
+        .and_then(|heightmap| heightmap.render(&map));
+    report_memory(config, "map generated");
+    shutdown.check()?;
+
+    let name = NameLists::load(config.names_file.as_ref().map(PathBuf::as_path))?
+        .name(params.name_seed, &state.used_names);
+
+    let description = if config.describe {
+        let mut rng = seed::choice_rng(params.name_seed, "description");
+        Some(describe(&params, &config.description, &mut rng))
+    } else {
+        None
+    };
+    let placeholder = |placeholder: &str| match placeholder {
+        "id" => Some(state.id.to_string()),
+        "year" => Some(Utc::now().format("%Y").to_string()),
+        "description" => Some(description.clone().unwrap_or_default()),
+        "name" => Some(name.clone()),
+        _ => stats.placeholder(placeholder),
+    };
+    let overlay_text = config
+        .overlay
+        .as_ref()
+        .map(|overlay| fill_template(&overlay.text, &placeholder));
+    let credits = config
+        .attribution
+        .as_ref()
+        .map(|attribution| attribution.fill(|template| fill_template(template, &placeholder)));
+
+    // An animation shows the map from each side in turn, starting with the one a still would show
+    let sides = if config.animate { 4 } else { 1 };
+    let mut frames = Vec::with_capacity(sides);
+    for quarter_turns in 0..sides {
+        shutdown.check()?;
+        let surf = renderer
+            .render_map(&rotate_map(&map, quarter_turns as u8))
+            .map_err(|e| Error::msg(format!("Problem generating image: {:?}", e)))?;
+        let mut frame = if config.low_memory {
+            let scratch = config.images_dir.join(format!(".render-{}.bmp", state.id));
+            surface_to_image_via_file(surf, &scratch)?
+        } else {
+            let image = surface_to_image(&surf)?;
+            // Not needed any more, so it doesn't stay around through the background and overlay
+            drop(surf);
+            image
+        };
+        if let Some(background) = config.background {
+            frame = background.apply(&frame);
+        }
+        if let (Some(overlay), Some(text)) = (config.overlay.as_ref(), overlay_text.as_ref()) {
+            frame = overlay.draw(&frame, text)?;
+        }
+        frames.push(frame);
+    }
+    // Everything the map is needed for is done
+    drop(map);
+    report_memory(config, "rendered");
+
+    shutdown.check()?;
+    let animation = if config.animate {
+        let animation = encode_gif(&frames, &config.animation)?;
+        report_memory(config, "animation encoded");
+        Some(animation)
+    } else {
+        None
+    };
+
+    events.emit(Event::GenerationFinished {
+        id: state.id,
+        duration_ms: started.elapsed().as_millis() as u64,
+        params: params.clone(),
+        stats: Some(stats.clone()),
+        regenerations,
+    });
+
+    shutdown.check()?;
+    let (filename, data, optimizer_trial) = match animation {
+        Some(animation) if animation.len() <= config.max_upload_bytes => {
+            drop(frames);
+            let filename = save_image_data(config, state, &animation, ImageFormat::Gif)?;
+            (filename, animation, Vec::new())
+        }
+        _ => {
+            if config.animate {
+                eprintln!(
+                    "Animation is larger than max_upload_bytes ({}), posting a still instead",
+                    config.max_upload_bytes
+                );
+            }
+
+            let still = frames.into_iter().next().expect("rendered at least one frame");
+            // From the pixels as rendered, before save_still quantizes them away
+            let archived = config.archive_format.as_ref().and_then(|archive| {
+                match archive.encode(&still, credits.as_ref()) {
+                    Ok(data) => Some((archive.format, data)),
+                    Err(e) => {
+                        eprintln!("WARNING: Unable to encode the archive copy: {:#}", e);
+                        None
+                    }
+                }
+            });
+            let saved = save_still(config, state, still, credits.as_ref(), events, shutdown)?;
+            if let Some((format, data)) = archived {
+                archive_copy::save(&saved.0, format, &data);
+            }
+            saved
+        }
+    };
+    eprintln!("Generated image file: {}", filename.display());
+    let heightmap = heightmap.and_then(|heightmap| save_heightmap(state, &filename, &heightmap));
+    report_memory(config, "saved");
+
+    Ok(CreatedImage {
+        filename,
+        data,
+        params,
+        stats,
+        description,
+        name,
+        regenerations,
+        optimizer_trial,
+        heightmap,
+    })
+}
+
+/// File name of the heightmap of the image saved as `image`
+fn heightmap_name(image: &Path) -> Option<String> {
+    let stem = image.file_stem()?.to_string_lossy();
+    Some(format!("{}.heightmap.png", stem))
+}
+
+/// Save `heightmap` for the image saved as `filename`, returning the heightmap's file name
+///
+/// The heightmap is only an extra, so problems saving it are logged, and the image is posted
+/// without it.
+fn save_heightmap(state: &State, filename: &Path, heightmap: &DynamicImage) -> Option<String> {
+    let name = heightmap_name(filename)?;
+    let dir = state.paths.images.join(HEIGHTMAPS_DIR);
+
+    let mut data = Vec::new();
+    let saved = heightmap
+        .write_to(&mut data, ImageOutputFormat::PNG)
+        .map_err(Error::from)
+        .and_then(|_| {
+            create_dir_all(&dir)?;
+            File::create(dir.join(&name))?.write_all(&data)?;
+            Ok(())
+        });
+    match saved {
+        Ok(()) => Some(name),
+        Err(e) => {
+            eprintln!("WARNING: Unable to save heightmap {}: {:#}", name, e);
+            None
+        }
+    }
+}
+
+/// Whether `--chaos` says `point` is to fail this time, see the `chaos` module
+#[cfg(feature = "chaos")]
+fn chaos_strikes(config: &BotConfig, point: &str) -> bool {
+    config.chaos.as_ref().map_or(false, |chaos| chaos.strike(point))
+}
+
+#[cfg(not(feature = "chaos"))]
+fn chaos_strikes(_config: &BotConfig, _point: &str) -> bool {
+    false
+}
+
+/// Log the memory used after `stage`, if `--memory-report` asks for it
+fn report_memory(config: &BotConfig, stage: &str) {
+    if let Some(ref report) = config.memory_report {
+        report.stage(stage);
+    }
+}
+
+/// The error of a write failing on purpose, see the `chaos` module
+fn injected_write_error() -> DiskError {
+    DiskError::Write(io::Error::new(io::ErrorKind::Other, CHAOS_MESSAGE))
+}
+
+/// Save encoded image data as the file for the current state
+fn save_image_data(
+    config: &BotConfig,
+    state: &State,
+    data: &[u8],
+    format: ImageFormat,
+) -> Result<PathBuf, Error> {
+    let filename = state.get_filename(&config.filename_template(), format)?;
+    if chaos_strikes(config, "disk") {
+        return Err(injected_write_error().into());
+    }
+    let written = File::create(&filename).and_then(|mut outfile| outfile.write_all(data));
+    if let Err(e) = written {
+        // Don't leave a partial file behind to be mistaken for a finished image
+        let _ = remove_file(&filename);
+        return Err(DiskError::Write(e).into());
+    }
+
+    Ok(filename)
+}
+
+/// Encode `still` as an optimized PNG and save it as the file for the current state
+///
+/// If more than one optimizer preset is to be tried, the smallest result is kept. Returns the
+/// filename, the PNG data, and each preset's result if there was more than one.
+fn save_still(
+    config: &BotConfig,
+    state: &State,
+    still: DynamicImage,
+    credits: Option<&Credits>,
+    events: &EventLog,
+    shutdown: &Shutdown,
+) -> Result<(PathBuf, Vec<u8>, Vec<Measurement>), Error> {
+    let (still, unquantized_bytes) = quantize_still(config, still)?;
+    let presets = state.optimize_presets(config);
+    let emit = |optimized: &OptimizedPng, preset: u8| {
+        events.emit(Event::Optimized {
+            id: state.id,
+            original_bytes: optimized.original_size,
+            optimized_bytes: optimized.data.len(),
+            duration_ms: optimized.duration.as_millis() as u64,
+            fallback: optimized.fallback,
+            preset: Some(preset),
+        })
+    };
+
+    if config.low_memory {
+        // Straight to disk, and optimized there, so the image, the unoptimized PNG and the
+        // optimized one are never all in memory at once
+        let filename = state.get_filename(&config.filename_template(), ImageFormat::Png)?;
+        if chaos_strikes(config, "disk") {
+            return Err(injected_write_error().into());
+        }
+        if let Err(e) = still.save(&filename) {
+            let _ = remove_file(&filename);
+            return Err(DiskError::Write(e).into());
+        }
+        drop(still);
+
+        // Trying several presets would take another copy of the image, so only one is run
+        let preset = presets.iter().cloned().max().unwrap_or(optimize::DEFAULT_PRESET);
+        let optimized = optimize_png_file(config, preset, &filename, shutdown).map_err(|e| {
+            let _ = remove_file(&filename);
+            e
+        })?;
+        emit(&optimized, preset);
+        if config.strip_metadata && credits.is_none() {
+            return Ok((filename, optimized.data, Vec::new()));
+        }
+        // The optimized file was read back anyway, so tagging it only takes writing it again
+        let data = tag_still(config, credits, optimized.data);
+        File::create(&filename)
+            .and_then(|mut outfile| outfile.write_all(&data))
+            .map_err(DiskError::Write)?;
+        return Ok((filename, data, Vec::new()));
+    }
+
+    let mut image_data: Vec<u8> = Vec::new();
+    still
+        .write_to(&mut image_data, ImageOutputFormat::PNG)
+        .map_err(ImagingError::Image)?;
+    drop(still);
+
+    let mut best: Option<OptimizedPng> = None;
+    let mut trial = Vec::new();
+    for (i, &preset) in presets.iter().enumerate() {
+        // The last run can have the original
+        let input = if i + 1 == presets.len() {
+            std::mem::replace(&mut image_data, Vec::new())
+        } else {
+            image_data.clone()
+        };
+        let optimized = optimize_png(config, preset, input, shutdown)?;
+        emit(&optimized, preset);
+        if presets.len() > 1 && !optimized.fallback {
+            trial.push(Measurement {
+                preset,
+                bytes: optimized.data.len(),
+                duration_ms: optimized.duration.as_millis() as u64,
+            });
+        }
+        if best.as_ref().map_or(true, |best| optimized.data.len() < best.data.len()) {
+            best = Some(optimized);
+        }
+    }
+    let optimized = best.expect("at least one preset");
+    if let Some(unquantized_bytes) = unquantized_bytes {
+        eprintln!(
+            "Quantized and optimized PNG is {} bytes, from {} bytes unquantized and unoptimized",
+            optimized.data.len(),
+            unquantized_bytes
+        );
+    }
+
+    let data = tag_still(config, credits, optimized.data);
+    let filename = save_image_data(config, state, &data, ImageFormat::Png)?;
+    Ok((filename, data, trial))
+}
+
+/// `png`, optimized, with the build it came from unless `strip_metadata` is set, and `credits`
+fn tag_still(config: &BotConfig, credits: Option<&Credits>, png: Vec<u8>) -> Vec<u8> {
+    let png = if config.strip_metadata {
+        png
+    } else {
+        build_info::tag_png(png)
+    };
+    match credits {
+        Some(credits) => attribution::tag_png(png, credits),
+        None => png,
+    }
+}
+
+/// Reduce `still` to `max_colors` colors, if `quantize` is set
+///
+/// Colors are counted before anything is changed. With few enough colors already, the image is
+/// left alone, as the optimizer gives it a palette anyway. When the image is quantized, it is
+/// also encoded as it was, to log how much quantizing saved, unless that would take another copy
+/// with `low_memory`.
+fn quantize_still(
+    config: &BotConfig,
+    still: DynamicImage,
+) -> Result<(DynamicImage, Option<usize>), ImagingError> {
+    if !config.quantize {
+        return Ok((still, None));
+    }
+
+    let rgba = still.to_rgba();
+    let colors = quantize::count_colors(&rgba);
+    if colors <= config.max_colors {
+        eprintln!("Image has {} colors, no need to quantize", colors);
+        return Ok((still, None));
+    }
+    if config.quantize_lossless_only {
+        eprintln!(
+            "Image has {} colors, more than max_colors ({}), leaving it unquantized",
+            colors, config.max_colors
+        );
+        return Ok((still, None));
+    }
+
+    let unquantized_bytes = if config.low_memory {
+        None
+    } else {
+        let mut unquantized = Vec::new();
+        still.write_to(&mut unquantized, ImageOutputFormat::PNG)?;
+        Some(unquantized.len())
+    };
+    drop(still);
+
+    eprintln!("Quantizing image from {} to {} colors", colors, config.max_colors);
+    Ok((quantize::quantize(&rgba, config.max_colors, config.dither), unquantized_bytes))
+}
+
+/// Result of running a PNG through the optimizer
+struct OptimizedPng {
+    data: Vec<u8>,
+    original_size: usize,
+    duration: StdDuration,
+    /// Optimization failed, and `data` is the original image
+    fallback: bool,
+}
+
+/// Optimizer settings for `config`, with oxipng's `preset`, see `cubeglobe_bot::optimizer_options`
+fn optimizer_options(config: &BotConfig, preset: u8) -> oxipng::Options {
+    cubeglobe_bot::optimizer_options(preset, config.strip_metadata)
+}
+
+/// Run the PNG through oxipng, falling back to the unoptimized data if that fails
+///
+/// oxipng can take minutes on large images and can't be interrupted, so it runs on a thread which
+/// is left behind if shutdown is requested.
+fn optimize_png(
+    config: &BotConfig,
+    preset: u8,
+    image_data: Vec<u8>,
+    shutdown: &Shutdown,
+) -> Result<OptimizedPng, Cancelled> {
+    let original_size = image_data.len();
+    let started = Instant::now();
+    let options = optimizer_options(config, preset);
+
+    let (result, image_data) = shutdown.run(move || {
+        let result = oxipng::optimize_from_memory(&image_data, &options);
+        (result, image_data)
+    })?;
+    let (data, fallback) = match result {
+        Ok(optimized) => (optimized, false),
+        Err(e) => {
+            eprintln!("Failed to optimize PNG, falling back to unoptimized: {}", e);
+            (image_data, true)
+        }
+    };
+
+    Ok(OptimizedPng {
+        data,
+        original_size,
+        duration: started.elapsed(),
+        fallback,
+    })
+}
+
+/// Run the PNG file at `path` through oxipng in place, leaving it as it is if that fails
+///
+/// The returned data is read back from the file afterwards. Like `optimize_png`, this gives up
+/// on oxipng if shutdown is requested.
+fn optimize_png_file(
+    config: &BotConfig,
+    preset: u8,
+    path: &Path,
+    shutdown: &Shutdown,
+) -> Result<OptimizedPng, Error> {
+    let original_size = metadata(path).map_err(DiskError::Write)?.len() as usize;
+    let started = Instant::now();
+    let options = optimizer_options(config, preset);
+
+    let in_file = oxipng::InFile::Path(path.to_path_buf());
+    let result = shutdown.run(move || {
+        oxipng::optimize(&in_file, &oxipng::OutFile::Path(None), &options)
+    })?;
+    let fallback = match result {
+        Ok(()) => false,
+        Err(e) => {
+            eprintln!("Failed to optimize PNG, falling back to unoptimized: {}", e);
+            true
+        }
+    };
+
+    Ok(OptimizedPng {
+        data: read(path).map_err(DiskError::Write)?,
+        original_size,
+        duration: started.elapsed(),
+        fallback,
+    })
+}
+
+/// Create the images directory if it does not exist yet
+fn create_images_dir(images_dir: &Path) -> Result<(), DiskError> {
+    create_dir_all(images_dir).map_err(|source| DiskError::CreateDir {
+        path: images_dir.to_path_buf(),
+        source,
+    })
+}
+
+/// Check that the images directory has at least `required` bytes of free space
+fn check_free_space(images_dir: &Path, required: u64) -> Result<(), DiskError> {
+    create_images_dir(images_dir)?;
+    let available = fs2::available_space(images_dir).map_err(DiskError::Check)?;
+
+    if available < required {
+        Err(DiskError::LowSpace {
+            available,
+            required,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Generate an image and upload it without attaching it to any status
+///
+/// This goes through the whole pipeline (generation, encoding, optimization, upload) without
+/// making a visible post and without touching the state file. Afterwards, we try to delete the
+/// uploaded media. Not all instances support that, in which case the attachment is left orphaned
+/// for the instance to clean up on its own.
+fn check_upload(config: &BotConfig, renderer: &Renderer, creds: &MastoData) -> Result<(), Error> {
+    let masto = mastodon_client(creds.clone());
+
+    let (surf, _) = generate_image(config, renderer)
+        .map_err(|e| Error::msg(format!("Problem generating image: {:?}", e)))?;
+    let mut image_data: Vec<u8> = Vec::new();
+    write_surface_as_png(&surf, image_data.by_ref())?;
+    let preset = config.optimize_level.fixed_preset();
+    let image_data = optimize_png(config, preset, image_data, &Shutdown::default())?.data;
+    eprintln!("Generated {} bytes of PNG, uploading...", image_data.len());
+
+    let attachment = masto.media(MediaBuilder {
+        description: Some(IMAGE_TITLE.to_string()),
+        mimetype: Some("image/png".to_string()),
+        filename: Some("check-upload.png".to_string()),
+        ..MediaBuilder::from_reader(Cursor::new(image_data))
+    }).map_err(PostingError::ElefrenError)?;
+
+    eprintln!("Uploaded attachment id: {}", attachment.id);
+    if attachment.url.is_empty() {
+        eprintln!("Attachment status: still processing on the instance");
+    } else {
+        eprintln!("Attachment status: processed, available at {}", attachment.url);
+    }
+
+    match delete_media(creds, &attachment.id) {
+        Ok(true) => eprintln!("Deleted attachment {}", attachment.id),
+        Ok(false) => eprintln!(
+            "Instance did not allow deleting attachment {}, leaving it orphaned",
+            attachment.id
+        ),
+        Err(e) => eprintln!(
+            "Failed to delete attachment {}, leaving it orphaned: {}",
+            attachment.id, e
+        ),
+    }
+
+    Ok(())
+}
+
+/// Elefren client for `account`, which sends the bot's User-Agent like every other request
+fn mastodon_client(account: MastoData) -> Mastodon {
+    MastodonBuilder::new()
+        .client(build_info::http_client())
+        .data(account)
+        .build()
+        .expect("client and data are both set")
+}
+
+/// Delete a media attachment that has not been attached to a status
+///
+/// Elefren has no call for this, so we make the request ourselves. Returns `Ok(false)` if the
+/// instance responded, but refused or did not know the endpoint.
+fn delete_media(creds: &MastoData, id: &str) -> Result<bool, reqwest::Error> {
+    let url = format!("{}/api/v1/media/{}", creds.base.trim_end_matches('/'), id);
+    let response = build_info::http_client()
+        .delete(&url)
+        .bearer_auth(&creds.token)
+        .send()?;
+
+    Ok(response.status().is_success())
+}
+
+/// Event describing the outcome of posting attempt number `attempt` to `backend`
+fn upload_attempt_event(
+    state: &State,
+    backend: &str,
+    attempt: usize,
+    result: &Result<(), PostingError>,
+) -> Event {
+    Event::UploadAttempt {
+        id: state.id,
+        backend: backend.to_string(),
+        attempt,
+        success: result.is_ok(),
+        http_status: result.as_ref().err().and_then(PostingError::status),
+        error: result.as_ref().err().map(|e| e.to_string()),
+        timed_out: match *result {
+            Err(PostingError::TimedOut(_)) => true,
+            _ => false,
+        },
+    }
+}
+
+/// Log everything about `post` that goes into posting it, before it is posted
+///
+/// Along with the error, this is what it takes to reproduce a failed attempt.
+fn log_draft(post: &Post, backend: Option<&str>, config: &BotConfig, events: &EventLog) {
+    let visibility = if config.boost_after_minutes.is_some() && !post.public {
+        "unlisted"
+    } else {
+        "public"
+    };
+    let dimensions = image_dimensions(&post.image);
+
+    match backend {
+        Some(backend) => eprintln!("Posting image {} to {} as:", post.id, backend),
+        None => eprintln!("Posting image {}:", post.id),
+    }
+    eprintln!("  body: {:?}", post.body);
+    eprintln!("  alt text: {:?}", post.alt_text);
+    if let Some(ref alt_params) = post.alt_params {
+        eprintln!("  alt text parameters: {:?}", alt_params);
+    }
+    if let Some(ref in_reply_to) = post.in_reply_to {
+        eprintln!("  in reply to: {}", in_reply_to);
+    }
+    eprintln!(
+        "  language: {}",
+        post.language.as_ref().map_or("not set", String::as_str)
+    );
+    eprintln!("  visibility: {}", visibility);
+    eprintln!(
+        "  image: {}, {}, {} bytes",
+        post.format.extension(),
+        dimensions.map_or("unknown size".to_string(), |(w, h)| format!("{}x{}", w, h)),
+        post.image.len()
+    );
+    for attachment in &post.attachments {
+        eprintln!(
+            "  attachment: {}, {} bytes, alt text {:?}",
+            attachment.format.extension(),
+            attachment.image.len(),
+            attachment.alt_text
+        );
+    }
+
+    events.emit(Event::PostDrafted {
+        id: post.id,
+        body: post.body.clone(),
+        alt_text: post.alt_text.clone(),
+        alt_params: post.alt_params.clone(),
+        language: post.language.clone(),
+        visibility: visibility.to_string(),
+        format: post.format.extension().to_string(),
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        bytes: post.image.len(),
+        poll: post.poll.is_some(),
+        backend: backend.map(str::to_string),
+    });
+}
+
+/// Read, fill in and validate the config file at `path`, applying command line overrides
+fn read_config(path: &Path, matches: &ArgMatches) -> Result<ConfigFile, Error> {
+    let contents =
+        read_to_string(path).with_context(|| format!("unable to read {}", path.display()))?;
+    let overrides = match matches.values_of("set") {
+        Some(values) => values.map(Override::parse).collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+    let invalid = || format!("invalid config file {}", path.display());
+    let mut config: ConfigFile = if overrides.is_empty() {
+        toml::from_str(&contents).with_context(invalid)?
+    } else {
+        let mut root: toml::Value = toml::from_str(&contents).with_context(invalid)?;
+        for assignment in &overrides {
+            assignment.apply(&mut root)?;
+        }
+        let config: ConfigFile = toml::from_str(&toml::to_string(&root)?)
+            .context("invalid config after applying --set")?;
+
+        // Checked before deprecated keys are resolved, which would clear them
+        let bot = toml::Value::try_from(&config.bot)?;
+        for assignment in &overrides {
+            assignment.check_known(&bot)?;
+            eprintln!("Overriding {} from the command line", assignment.key());
+        }
+        config
+    };
+
+    config.bot.resolve_deprecated();
+    if matches.is_present("logjson") {
+        config.bot.log_format = LogFormat::Json;
+    }
+    if matches.is_present("strictperms") {
+        config.bot.strict_permissions = true;
+    }
+    if let Some(cycles) = matches.value_of("memoryreport") {
+        let cycles = cycles
+            .parse()
+            .map_err(|e| Error::msg(format!("invalid --memory-report: {}", e)))?;
+        config.bot.memory_report = Some(Arc::new(MemoryReport::new(cycles)));
+    }
+    #[cfg(feature = "chaos")]
+    {
+        if let Some(spec) = matches.value_of("chaos") {
+            let chaos =
+                Chaos::parse(spec).map_err(|e| Error::msg(format!("invalid --chaos: {}", e)))?;
+            config.bot.chaos = Some(Arc::new(chaos));
+        }
+    }
+    config
+        .bot
+        .validate()
+        .with_context(|| format!("invalid [bot] section in {}", path.display()))?;
+    if let Some(ref hooks) = config.hooks {
+        hooks
+            .validate()
+            .map_err(|problem| ConfigError::Value { key: "hooks", problem })
+            .with_context(|| format!("invalid [hooks] section in {}", path.display()))?;
+    }
+
+    Ok(config)
+}
+
+/// Tiles config to use, from the command line or otherwise the bot config
+fn tiles_path(matches: &ArgMatches, config: &BotConfig) -> PathBuf {
+    matches
+        .value_of_os("tilesconfig")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| config.tiles.clone())
+}
+
+/// Read the tiles config at `path`, or set up the built-in tileset if that is what it names
+fn read_tiles_config(path: &Path) -> Result<String, Error> {
+    if builtin_tiles::is_builtin(path) {
+        return builtin_tiles::tiles_config().context("unable to set up the built-in tiles");
+    }
+
+    read_to_string(path)
+        .with_context(|| format!("unable to read tiles config {}", path.display()))
+}
+
+/// A reloaded bot config, and the renderer to go with it if the tiles config changed
+struct Reloaded {
+    bot: BotConfig,
+    tiles: Option<(String, Renderer)>,
+}
+
+/// Read the config again, for use from the next cycle on
+///
+/// Only the `[bot]` section is reloaded. Settings which decide where the state and images live
+/// can only change on restart. The formats are checked against the instances at
+/// `instance_bases` again.
+fn reload_config(
+    path: &Path,
+    matches: &ArgMatches,
+    current: &BotConfig,
+    current_tiles: &str,
+    current_renderer: &Renderer,
+    instance_bases: &[String],
+) -> Result<Reloaded, Error> {
+    let mut bot = read_config(path, matches)?.bot;
+    // Keeps counting cycles from where it was
+    bot.memory_report = current.memory_report.clone();
+    #[cfg(feature = "chaos")]
+    {
+        // Keeps track of which `once` failures were injected already
+        bot.chaos = current.chaos.clone();
+    }
+    if bot.images_dir != current.images_dir
+        || bot.state_path() != current.state_path()
+        || bot.bot_name != current.bot_name
+    {
+        return Err(Error::msg(
+            "images_dir, state_path and bot_name can only be changed by restarting",
+        ));
+    }
+
+    let tiles_path = tiles_path(matches, &bot);
+    let tiles_config = read_tiles_config(&tiles_path)?;
+    let tiles = if tiles_config == current_tiles {
+        None
+    } else {
+        let renderer = tiles::load_renderer(&tiles_path, &tiles_config)?;
+        Some((tiles_config, renderer))
+    };
+
+    let renderer = tiles.as_ref().map_or(current_renderer, |&(_, ref renderer)| renderer);
+    trial_render(&bot, renderer)?;
+    formats::check(&mut bot, instance_bases)?;
+
+    Ok(Reloaded { bot, tiles })
+}
+
+/// Reload the config into `config`, along with the tiles config and renderer if that changed
+///
+/// If the reload fails, everything is kept as it was. Returns whether the config was reloaded.
+fn apply_reload(
+    path: &Path,
+    matches: &ArgMatches,
+    config: &mut BotConfig,
+    tiles_config: &mut String,
+    renderer: &mut Renderer,
+    instance_bases: &[String],
+) -> bool {
+    match reload_config(path, matches, config, tiles_config, renderer, instance_bases) {
+        Ok(reloaded) => {
+            *config = reloaded.bot;
+            if let Some((tiles, new_renderer)) = reloaded.tiles {
+                *tiles_config = tiles;
+                *renderer = new_renderer;
+                eprintln!("Tiles config changed, rebuilt renderer");
+            }
+            eprintln!("Config reloaded, takes effect from this cycle on");
+            true
+        }
+        Err(e) => {
+            eprintln!("Config reload failed, keeping the old config: {:#}", e);
+            false
+        }
+    }
+}
+
+fn get_backoff(attempt: usize) -> u64 {
+    // Note: attempt is 1-indexed (first attempt is number 1)
+    if attempt > DELAYS.len() {
+        *DELAYS.last().unwrap()
+    } else {
+        DELAYS[attempt - 1]
+    }
+}
+
+/// Hold off posting until at least `min_interval_secs` have passed since the last post, or with
+/// `no_wait`, exit if that's still to come
+fn wait_for_min_interval(state: &State, config: &BotConfig, shutdown: &Shutdown, no_wait: bool) {
+    let wait = schedule::min_interval_wait(state.last_post, config.min_interval(), Utc::now());
+    if let Some(wait) = wait {
+        next_post::write(&state.paths.state, Utc::now() + wait, Reason::MinInterval);
+        if no_wait {
+            eprintln!(
+                "Last post was less than {} seconds ago, not posting for another {} seconds, \
+                 exiting",
+                config.min_interval().num_seconds(),
+                wait.num_seconds()
+            );
+            exit(EXIT_NOTHING_DUE);
+        }
+        eprintln!(
+            "Last post was less than {} seconds ago, holding off posting for {} seconds",
+            config.min_interval().num_seconds(),
+            wait.num_seconds()
+        );
+        if !shutdown.sleep(wait.to_std().expect("Time duration too large")) {
+            shut_down();
+        }
+    }
+}
+
+/// Wait for the system clock to be plausible, see the `clock` module, exiting if it isn't in time
+fn wait_for_sane_clock(state: &State, config: &BotConfig, shutdown: &Shutdown) {
+    let max_wait = StdDuration::from_secs(config.max_clock_wait_secs);
+    let clock = SystemClock(shutdown);
+    match clock::wait_until_sane(&clock, state.last_post, clock::build_floor(), max_wait) {
+        Sanity::Sane => {}
+        Sanity::GaveUp => exit(EXIT_FAILED),
+        Sanity::ShutDown => shut_down(),
+    }
+}
+
+/// Hold off posting while in a maintenance window, see the `maintenance` module
+fn wait_out_maintenance(maintenance: &mut Maintenance, config: &BotConfig, shutdown: &Shutdown) {
+    let url = config.maintenance_url.as_ref().map(String::as_str);
+    let late = config.jitter.late(config.sleep_time);
+    // Windows fetched while waiting can extend the wait
+    while let Some(until) =
+        maintenance.defer(&config.maintenance_windows, url, Utc::now(), late, &mut thread_rng())
+    {
+        if let Ok(wait) = (until - Utc::now()).to_std() {
+            if !shutdown.sleep(wait) {
+                shut_down();
+            }
+        }
+    }
+}
+
+/// Exit because shutdown was requested
+///
+/// Everything worth keeping is in the state file by the time this is called.
+fn shut_down() -> ! {
+    eprintln!("Shutdown requested, exiting");
+    exit(0)
+}
+
+fn main() {
+    let app = App::new("cubeglobe-bot")
+        .version(build_info::VERSION)
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("PATH")
+                .help("path to the main config file"),
+        ).arg(
+            Arg::with_name("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .multiple(true)
+                .number_of_values(1)
+                .help("override a [bot] config key for this run, like --set bot.map_size=48"),
+        ).arg(
+            Arg::with_name("tilesconfig")
+                .short("t")
+                .long("tiles")
+                .value_name("PATH")
+                .help(
+                    "path to the tiles configuration file, or \"builtin\" for plain built-in \
+                     tiles",
+                ),
+        ).arg(
+            Arg::with_name("immediate")
+                .long("immediate")
+                .help("immediately generate and post an image, and then exit"),
+        ).arg(
+            Arg::with_name("force")
+                .long("force")
+                .requires("immediate")
+                .help(
+                    "with --immediate, post even if the last post was less than \
+                     min_interval_secs ago",
+                ),
+        ).arg(
+            Arg::with_name("forcenew")
+                .long("force-new")
+                .requires("immediate")
+                .help(
+                    "with --immediate, generate a new image even if one is pending, moving the \
+                     pending one to images/stale/",
+                ),
+        ).arg(
+            Arg::with_name("checkupload")
+                .long("check-upload")
+                .conflicts_with("immediate")
+                .help("generate and upload an image without posting it, then exit"),
+        ).arg(
+            Arg::with_name("posts")
+                .long("posts")
+                .value_name("N")
+                .conflicts_with_all(&["immediate", "checkupload"])
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("must be a positive number".to_string()),
+                }).help("exit after N successful posts, following the usual schedule"),
+        ).arg(
+            Arg::with_name("nowait")
+                .long("no-wait")
+                .requires("posts")
+                .help("with --posts, exit with status 3 instead of waiting if no post is due yet"),
+        ).arg(
+            Arg::with_name("logjson")
+                .long("log-json")
+                .help("write lifecycle events to stdout as JSON, one per line"),
+        ).arg(
+            Arg::with_name("strictperms")
+                .long("strict-perms")
+                .help("refuse to start if the config file is readable by other users"),
+        ).arg(
+            Arg::with_name("checkconfig")
+                .long("check-config")
+                .help("check the config and tiles config, including a small trial render, and exit"),
+        ).arg(
+            Arg::with_name("printconfig")
+                .long("print-config")
+                .help("print the effective configuration, with secrets redacted, and exit"),
+        ).arg(
+            Arg::with_name("resetworld")
+                .long("reset-world")
+                .help("with evolution, start over with fresh parameters instead of drifting"),
+        ).arg(
+            Arg::with_name("recoverfromremote")
+                .long("recover-from-remote")
+                .help("rebuild the last post time and next id from the account's statuses"),
+        ).arg(
+            Arg::with_name("yes")
+                .long("yes")
+                .help("keep a recovered state without asking"),
+        ).arg(
+            Arg::with_name("memoryreport")
+                .long("memory-report")
+                .value_name("N")
+                .help("log the memory used after each stage of the first N cycles"),
+        ).arg(
+            Arg::with_name("debugbundle")
+                .long("debug-bundle")
+                .value_name("DIR")
+                .help("write what a bug report needs, with secrets redacted, to DIR and exit"),
+        ).arg(
+            Arg::with_name("buildinfo")
+                .long("build-info")
+                .help("print the version, git commit, build time and features, and exit"),
+        ).subcommand(init::subcommand())
+        .subcommand(schedule::subcommand())
+        .subcommand(repair::subcommand())
+        .subcommand(approval::subcommand())
+        .subcommand(trigger::subcommand())
+        .subcommand(attempts::subcommand())
+        .subcommand(migrate::export_subcommand())
+        .subcommand(migrate::import_subcommand())
+        .subcommand(selftest::subcommand())
+        .subcommand(queue::generate_subcommand())
+        .subcommand(queue::subcommand());
+    #[cfg(feature = "chaos")]
+    let app = app.arg(
+        Arg::with_name("chaos")
+            .long("chaos")
+            .value_name("SPEC")
+            .hidden(true)
+            .help("make things fail on purpose, like upload:0.5,disk:once, see the chaos module"),
+    );
+    let matches = app.get_matches();
+
+    if matches.is_present("buildinfo") {
+        print!("{}", BuildInfo::current().report());
+        return;
+    }
+
+    if let Some(init_matches) = matches.subcommand_matches("init") {
+        if let Err(e) = init::run(init_matches) {
+            eprintln!("Setup failed: {}", e);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    let config_path = matches
+        .value_of_os("config")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("config.toml"));
+
+    if let Some(selftest_matches) = matches.subcommand_matches("selftest") {
+        if !selftest::run(&matches, selftest_matches, &config_path) {
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    let mut config = read_config(&config_path, &matches)
+        .unwrap_or_else(|e| panic!("Problem loading bot config: {:#}", e));
+    #[cfg(feature = "chaos")]
+    {
+        if let Some(ref chaos) = config.bot.chaos {
+            eprintln!("CHAOS: failures will be injected: {}", chaos.describe());
+        }
+    }
+
+    match permissions::check_private(&config_path) {
+        Ok(None) => {}
+        Ok(Some(problem)) if config.bot.strict_permissions => {
+            eprintln!("Refusing to start: {}", problem);
+            exit(EXIT_FAILED);
+        }
+        Ok(Some(problem)) => eprintln!("WARNING: {}", problem),
+        Err(e) => eprintln!("Unable to check config file permissions: {}", e),
+    }
+
+    let effective_config = config
+        .to_redacted_toml()
+        .expect("Unable to serialize effective config");
+    if matches.is_present("printconfig") {
+        print!("{}", effective_config);
+        return;
+    }
+    eprintln!("Effective configuration:\n{}", effective_config);
+
+    let instance_bases = config.instance_bases();
+    let bundle_sources = debug_bundle::Sources {
+        config_path: &config_path,
+        matches: &matches,
+        secrets: config.secrets().into_iter().map(String::from).collect(),
+        instance_bases: &instance_bases,
+    };
+    if let Some(dir) = matches.value_of_os("debugbundle") {
+        let state = State::get_state(StatePaths::from_config(&config.bot));
+        if let Err(e) = bundle_sources.write(Path::new(dir), &state, &EventLog::new(false)) {
+            eprintln!("Unable to write debug bundle: {:#}", e);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    if let Some(simulate_matches) = matches.subcommand_matches("simulate") {
+        schedule::simulate(
+            simulate_matches,
+            config.bot.sleep_time,
+            &config.bot.jitter,
+            config.bot.first_post_delay,
+        );
+        return;
+    }
+
+    if let Some(repair_matches) = matches.subcommand_matches("repair") {
+        match repair::run(repair_matches, &config.bot) {
+            Ok(true) => return,
+            Ok(false) => exit(EXIT_FAILED),
+            Err(e) => {
+                eprintln!("Repair failed: {}", e);
+                exit(EXIT_FAILED);
+            }
+        }
+    }
+
+    if let Some(queue_matches) = matches.subcommand_matches("queue") {
+        if let Err(e) = queue::run(queue_matches, &config.bot) {
+            eprintln!("Queue command failed: {:#}", e);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    if let Some(approve_matches) = matches.subcommand_matches("approve") {
+        if let Err(e) = approval::run(approve_matches, &config.bot) {
+            eprintln!("Unable to approve: {}", e);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("stats").is_some() {
+        if let Err(e) = attempts::run(&config.bot) {
+            eprintln!("Unable to show stats: {:#}", e);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    if let Some(export_matches) = matches.subcommand_matches("export") {
+        if let Err(e) = migrate::export(export_matches, &config.bot) {
+            eprintln!("Export failed: {:#}", e);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    if let Some(import_matches) = matches.subcommand_matches("import") {
+        if let Err(e) = migrate::import(import_matches, &config.bot) {
+            eprintln!("Import failed: {:#}", e);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("trigger").is_some() {
+        if let Err(e) = trigger::run(&config.bot) {
+            eprintln!("Unable to trigger a post: {}", e);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    let tiles_config_path = tiles_path(&matches, &config.bot);
+    let mut tiles_config = read_tiles_config(&tiles_config_path)
+        .unwrap_or_else(|e| panic!("Problem with tiles config: {:#}", e));
+    let mut renderer = tiles::load_renderer(&tiles_config_path, &tiles_config).unwrap_or_else(|e| {
+        eprintln!("Problem with tiles config: {}", e);
+        exit(EXIT_FAILED);
+    });
+    if let Err(e) = trial_render(&config.bot, &renderer) {
+        eprintln!("Problem with bot config: {}", e);
+        exit(EXIT_FAILED);
+    }
+
+    if matches.is_present("checkconfig") {
+        check_terrain_extremes(&config.bot);
+        eprintln!("Config is valid");
+        return;
+    }
+
+    // Upload check does not post anything and does not touch the state
+    if matches.is_present("checkupload") {
+        eprintln!("Upload check requested, generating...");
+        check_upload(&config.bot, &renderer, &config.credentials).expect("Upload check failed");
+        return;
+    }
+
+    // Checked again on every reload, as instances' settings change too
+    if let Err(e) = formats::check(&mut config.bot, &instance_bases) {
+        eprintln!("Problem with bot config: {}", e);
+        exit(EXIT_FAILED);
+    }
+
+    let events = Arc::new(EventLog::new(config.bot.log_format == LogFormat::Json));
+
+    if let Some(generate_matches) = matches.subcommand_matches("generate") {
+        let shutdown = Shutdown::register();
+        let generated = queue::generate(
+            generate_matches,
+            &config.bot,
+            &renderer,
+            &tiles_config_path,
+            &tiles_config,
+            &events,
+            &shutdown,
+        );
+        if let Err(e) = generated {
+            eprintln!("Unable to generate images: {:#}", e);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
+    // Kept for boosting, pinning and digests, see `boost_after_minutes`, `pin_best` and `digest`
+    let boost_accounts = (config.credentials.clone(), config.credentials_fallback.clone());
+    let unlisted = config.bot.boost_after_minutes.is_some();
+    let flavor = config.bot.instance_flavor;
+    let mastodon: Box<dyn Poster> = Box::new(MastodonPoster::new(
+        flavor.resolve(&config.credentials.base),
+        mastodon_client(config.credentials),
+        unlisted,
+        config.bot.duplicate_body_suffix,
+        config.bot.verify_post,
+    ));
+    let mastodon: Box<dyn Poster> = match config.credentials_fallback {
+        Some(fallback) => Box::new(FallbackPoster::new(
+            mastodon,
+            Box::new(MastodonPoster::new(
+                flavor.resolve(&fallback.base),
+                mastodon_client(fallback),
+                unlisted,
+                config.bot.duplicate_body_suffix,
+                config.bot.verify_post,
+            )),
+            config.bot.fallback_after_attempts,
+        )),
+        None => mastodon,
+    };
+    let mut posters: Vec<Arc<dyn Poster>> = vec![mastodon.into()];
+    #[cfg(feature = "bluesky")]
+    posters.extend(
+        config
+            .bluesky
+            .map(|bluesky| Arc::new(BlueskyPoster::new(bluesky)) as Arc<dyn Poster>),
+    );
+    #[cfg(feature = "matrix")]
+    posters.extend(
+        config
+            .matrix
+            .map(|matrix| Arc::new(MatrixPoster::new(matrix)) as Arc<dyn Poster>),
+    );
+    posters.extend(
+        config
+            .webhook
+            .map(|webhook| Arc::new(WebhookPoster::new(webhook)) as Arc<dyn Poster>),
+    );
+
+    let paths = StatePaths::from_config(&config.bot);
+    let recovering =
+        matches.is_present("recoverfromremote") || recover::state_lost(&paths, &config.bot);
+    let mut state = State::get_state(paths).replay_journal(&config.bot);
+    if recovering {
+        let yes = matches.is_present("yes");
+        state = recover::run(&config.bot, &boost_accounts.0, state, yes).unwrap_or_else(|e| {
+            eprintln!("Unable to recover the state: {:#}", e);
+            exit(EXIT_FAILED);
+        });
+    }
+    let shutdown = Shutdown::register();
+    wait_for_sane_clock(&state, &config.bot, &shutdown);
+    if matches.is_present("resetworld") && state.world.take().is_some() {
+        // Parameters already rolled for the next image drifted from the old world
+        state.rolled = None;
+        state.persist().expect("Unable to persist state");
+        eprintln!("Forgot the last post's parameters, the next post starts a new world");
+    }
+    state
+        .finish_taking_from_queue(&config.bot)
+        .unwrap_or_else(|e| panic!("Problem taking image from the queue: {:#}", e));
+    let hooks = config.hooks.as_ref();
+
+    // Immediate mode posts immediately and exits. We do not try to retry at all here.
+    if matches.is_present("immediate") {
+        // A pending image is posted rather than replaced, so a run in loop mode that was retrying
+        // it doesn't lose it
+        let had_pending = match state.phase {
+            Phase::Awaiting => false,
+            Phase::AwaitingApproval | Phase::Generated => true,
+        };
+        let pending = state.take_up_pending(matches.is_present("forcenew"));
+        if had_pending && pending.is_none() {
+            events.emit(state.changed_event());
+        }
+        let image_data: Arc<[u8]> = match pending {
+            Some(data) => {
+                eprintln!("Immediate post requested, posting pending image {}...", state.id);
+                data.into()
+            }
+            None => {
+                eprintln!("Immediate post requested, generating...");
+                let params = state.rolled_params(&config.bot);
+                let created =
+                    create_image(&config.bot, &renderer, &state, &params, &events, &shutdown, 0);
+                let image = match created {
+                    Ok(image) => image,
+                    Err(ref e) if e.downcast_ref::<Cancelled>().is_some() => shut_down(),
+                    Err(e) => {
+                        eprintln!("Problem generating image: {:#}", e);
+                        exit(EXIT_FAILED);
+                    }
+                };
+
+                state = state.generated(&image, &config.bot);
+                state.persist().expect("Unable to persist state");
+                events.emit(state.changed_event());
+                state.run_hook(hooks, Hook::PostGenerate, None);
+                image.data.into()
+            }
+        };
+
+        if !matches.is_present("force") {
+            let no_wait = matches.is_present("nowait");
+            wait_for_min_interval(&state, &config.bot, &shutdown, no_wait);
+        }
+        state.note_gap(&config.bot);
+        let mut post = state.draft_post(&config.bot, image_data);
+        post.in_reply_to = state.thread_root(&config.bot, &boost_accounts.0);
+        log_draft(&post, None, &config.bot, &events);
+        let (started, timer) = (Utc::now(), Instant::now());
+        let posted = if state.pre_post_allows(hooks) {
+            state.post_everywhere(&posters, &post, 1, &config.bot, &events)
+        } else {
+            Err("the pre_post hook failed".to_string())
+        };
+        state.attempted(started, timer, &posted);
+        if let Err(error) = posted {
+            state.quarantine_if_given_up(&posters);
+            state.persist().expect("Unable to persist state");
+            state.run_hook(hooks, Hook::PostFailure, Some(&error));
+            eprintln!("Failed to post status: {}", error);
+            exit(EXIT_FAILED);
+        }
+
+        state.run_hook(hooks, Hook::PostSuccess, None);
+        state = state.complete_post(&config.bot);
+        events.emit(state.changed_event());
+    } else {
+        // With --posts, we exit after that many posts instead of looping forever. Giving up on
+        // an image that keeps failing leaves it pending for the next run.
+        let post_limit = matches
+            .value_of("posts")
+            .map(|n| n.parse::<usize>().expect("Invalid number of posts"));
+        let no_wait = matches.is_present("nowait");
+        let mut posted_count: usize = 0;
+
+        // Not with --immediate, which exits before any check would be due
+        let federation_checker = config.federation_check.clone().map(|check| {
+            FederationChecker::new(
+                check,
+                boost_accounts.0.clone(),
+                boost_accounts.1.clone(),
+                Arc::clone(&events),
+            )
+        });
+
+        let mut current_image: Option<Arc<[u8]>> = None;
+        let mut attempt: usize = 0;
+        let mut disk_attempt: usize = 0;
+        let mut maintenance = Maintenance::new();
+
+        // SIGHUP asks for the config to be read again, which we do at the top of the next cycle
+        let reload_requested = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::SIGHUP, Arc::clone(&reload_requested))
+            .expect("Unable to set up SIGHUP handler");
+
+        // SIGUSR1 or the trigger file asks for a post right away, see `trigger`
+        let trigger = Trigger::register(&state.paths.state);
+        // Where the schedule counted from before a requested post, to go back to once it's made
+        let mut kept_anchor: Option<DateTime<Utc>> = None;
+
+        loop {
+            if shutdown.is_requested() {
+                shut_down();
+            }
+            // Only waiting for the next post can be cut short
+            trigger.ignore_pending();
+            if let Some(ref report) = config.bot.memory_report {
+                report.cycle_started();
+            }
+            // Written again once this cycle knows when the next post is
+            next_post::clear(&state.paths.state);
+
+            if reload_requested.swap(false, Ordering::SeqCst) {
+                eprintln!("Got SIGHUP, reloading config...");
+                apply_reload(
+                    &config_path,
+                    &matches,
+                    &mut config.bot,
+                    &mut tiles_config,
+                    &mut renderer,
+                    &instance_bases,
+                );
+            }
+
+            if let Phase::Awaiting = state.phase {
+                wait_for_sane_clock(&state, &config.bot, &shutdown);
+                state.adapt_sleep_time(&config.bot, &boost_accounts.0, &events);
+                let sleep_time = state.sleep_time(&config.bot);
+                if let Some(last_post) = state.schedule_base() {
+                    // Never the RNG maps are generated with, see `generate_map`
+                    let mut schedule_rng = thread_rng();
+                    let scheduled = schedule::next_post(
+                        last_post,
+                        sleep_time,
+                        &config.bot.jitter,
+                        &mut schedule_rng,
+                    );
+                    next_post::write(&state.paths.state, scheduled, Reason::Schedule);
+                    let mut triggered = false;
+
+                    // Boosts due before the next post are made while waiting for it
+                    while let Some(due) = state
+                        .pending_boost
+                        .as_ref()
+                        .map(|boost| boost.due)
+                        .filter(|&due| due <= scheduled)
+                    {
+                        if let Ok(wait) = (due - Utc::now()).to_std() {
+                            if no_wait {
+                                break;
+                            }
+                            eprintln!("Sleeping until {} to boost the last post...", due);
+                            match shutdown.sleep_unless(wait, || trigger.take()) {
+                                Woken::Elapsed => {}
+                                Woken::Interrupted => {
+                                    triggered = true;
+                                    break;
+                                }
+                                Woken::ShutDown => shut_down(),
+                            }
+                        }
+                        state.boost(&config.bot, &boost_accounts.0, boost_accounts.1.as_ref());
+                    }
+
+                    // Done while waiting for the next post, so it doesn't hold that up
+                    state.pin_best_if_due(&config.bot, &boost_accounts.0, &shutdown);
+                    state.post_digest_if_due(&config.bot, &boost_accounts.0, &shutdown);
+                    state.download_remote_media();
+                    state.log_poll_results(&boost_accounts.0, boost_accounts.1.as_ref(), &events);
+                    if shutdown.is_requested() {
+                        shut_down();
+                    }
+
+                    if !triggered {
+                        let drawn = scheduled - last_post - ChrDuration::seconds(sleep_time);
+                        let deferred = maintenance.defer(
+                            &config.bot.maintenance_windows,
+                            config.bot.maintenance_url.as_ref().map(String::as_str),
+                            scheduled,
+                            config.bot.jitter.late(sleep_time),
+                            &mut schedule_rng,
+                        );
+                        let scheduled = deferred.unwrap_or(scheduled);
+                        let actual_to_wait = scheduled - Utc::now();
+                        let reason = if actual_to_wait < ChrDuration::zero() {
+                            Reason::Overdue
+                        } else if deferred.is_some() {
+                            Reason::Maintenance
+                        } else {
+                            Reason::Schedule
+                        };
+                        next_post::write(&state.paths.state, scheduled, reason);
+
+                        if actual_to_wait < ChrDuration::zero() {
+                            eprintln!(
+                                "Post was due at {}, it is now later, starting new post...",
+                                scheduled
+                            );
+                        } else if no_wait {
+                            eprintln!("Next post is not due until {}, exiting", scheduled);
+                            exit(EXIT_NOTHING_DUE);
+                        } else {
+                            eprintln!(
+                                "Sleeping until {} ({:+} seconds of jitter)...",
+                                scheduled,
+                                drawn.num_seconds()
+                            );
+                            let wait = actual_to_wait.to_std().expect("Time duration too large");
+                            match shutdown.sleep_unless(wait, || trigger.take()) {
+                                Woken::Elapsed => eprintln!("Done sleeping, starting new post..."),
+                                Woken::Interrupted => triggered = true,
+                                Woken::ShutDown => shut_down(),
+                            }
+                        }
+                    }
+                    if triggered {
+                        eprintln!("Asked to post now, starting new post...");
+                        if config.bot.trigger_keeps_schedule {
+                            kept_anchor = Some(last_post);
+                        }
+                    }
+                } else if let Some(due) = state.first_post_at(&config.bot) {
+                    next_post::write(&state.paths.state, due, Reason::Schedule);
+                    match (due - Utc::now()).to_std() {
+                        Ok(_) if no_wait => {
+                            eprintln!("First post is not due until {}, exiting", due);
+                            exit(EXIT_NOTHING_DUE);
+                        }
+                        Ok(wait) => {
+                            eprintln!("State shows no previous post, sleeping until {}...", due);
+                            match shutdown.sleep_unless(wait, || trigger.take()) {
+                                Woken::Elapsed => {
+                                    eprintln!("Done sleeping, starting first post...")
+                                }
+                                Woken::Interrupted => {
+                                    eprintln!("Asked to post now, starting first post...")
+                                }
+                                Woken::ShutDown => shut_down(),
+                            }
+                        }
+                        Err(_) => eprintln!("First post was due at {}, starting it...", due),
+                    }
+                } else {
+                    eprintln!("State shows no previous post, starting first one...");
+                }
+
+                let queued = match config.bot.queue_dir {
+                    Some(ref queue_dir) => next_queued_image(&config.bot, &state, queue_dir)
+                        .unwrap_or_else(|e| {
+                            eprintln!("Unable to take an image from the queue: {:#}", e);
+                            None
+                        }),
+                    None => None,
+                };
+                let (created, queue_image) = match queued {
+                    Some((image, queue_image)) => (Ok(image), Some(queue_image)),
+                    None if config.bot.queue_dir.is_some()
+                        && config.bot.queue_empty == QueueEmpty::Skip =>
+                    {
+                        eprintln!("WARNING: The queue is empty, skipping this post");
+                        state.slot_skipped = Some(Utc::now());
+                        state.persist().expect("Unable to persist state");
+                        continue;
+                    }
+                    None => {
+                        if config.bot.queue_dir.is_some() {
+                            eprintln!("The queue is empty, generating an image");
+                        }
+                        let created = create_image_within_budget(
+                            &config.bot,
+                            &renderer,
+                            &mut state,
+                            &events,
+                            &shutdown,
+                        );
+                        (created, None)
+                    }
+                };
+                let image = match created {
+                    Ok(image) => image,
+                    Err(e) => {
+                        if e.downcast_ref::<Cancelled>().is_some() {
+                            shut_down();
+                        }
+                        if e.downcast_ref::<BudgetExhausted>().is_some() {
+                            eprintln!("ERROR: Skipping this post: {}", e);
+                            state.skip_slot();
+                            bundle_sources.write_if_due(&config.bot, &state, &events);
+                            continue;
+                        }
+                        if e.downcast_ref::<DiskError>().is_none() {
+                            eprintln!("Problem generating image: {:#}", e);
+                            exit(EXIT_FAILED);
+                        }
+
+                        // A full disk may well clear up, so skip this round and check again
+                        disk_attempt += 1;
+                        state.failed();
+                        bundle_sources.write_if_due(&config.bot, &state, &events);
+                        if no_wait {
+                            eprintln!("Skipping generation: {}", e);
+                            eprintln!("Not waiting to check again, exiting");
+                            exit(EXIT_FAILED);
+                        }
+                        if post_limit.is_some() && disk_attempt >= DELAYS.len() {
+                            eprintln!("Skipping generation: {}", e);
+                            eprintln!("Giving up after {} attempts, exiting", disk_attempt);
+                            exit(EXIT_FAILED);
+                        }
+                        let backoff = get_backoff(disk_attempt);
+                        eprintln!("Skipping generation: {}", e);
+                        eprintln!("Checking again after {} seconds", backoff);
+                        let retry = Utc::now() + ChrDuration::seconds(backoff as i64);
+                        next_post::write(&state.paths.state, retry, Reason::Retry);
+                        if !shutdown.sleep(StdDuration::from_secs(backoff)) {
+                            shut_down();
+                        }
+                        continue;
+                    }
+                };
+                disk_attempt = 0;
+
+                state = state.generated(&image, &config.bot);
+                if let Some(queue_image) = queue_image {
+                    // Recorded before anything is moved, so a crash part way through is
+                    // finished on restart
+                    state.queue_head = Some(queue_image);
+                    state.persist().expect("Unable to persist state");
+                    if let Err(e) = state.finish_taking_from_queue(&config.bot) {
+                        panic!("Problem taking image from the queue: {:#}", e);
+                    }
+                }
+                current_image = Some(image.data.into());
+                if config.bot.approval_required {
+                    state.request_approval(&config.bot);
+                }
+                state.persist().expect("Unable to persist state");
+                events.emit(state.changed_event());
+                state.run_hook(hooks, Hook::PostGenerate, None);
+            }
+
+            if let Phase::AwaitingApproval = state.phase {
+                match state.await_approval(&config.bot, &shutdown) {
+                    Ok(Decision::Approve) => {}
+                    Ok(Decision::Reject) => current_image = None,
+                    Err(Cancelled) => shut_down(),
+                }
+                events.emit(state.changed_event());
+            }
+
+            if let Phase::Generated = state.phase {
+                let image_data = match current_image {
+                    Some(image_data) => image_data,
+                    None => {
+                        let data = state.get_saved_image().expect(
+                            "Wanted to retry uploading image but was unable to open its file",
+                        );
+                        if let Err(problem) = state.check_saved_image(&data) {
+                            eprintln!(
+                                "Pending image is damaged, generating it again: {}",
+                                problem
+                            );
+                            state.quarantine_pending();
+                            state.persist().expect("Unable to persist state");
+                            events.emit(state.changed_event());
+                            continue;
+                        }
+                        data.into()
+                    }
+                };
+
+                wait_out_maintenance(&mut maintenance, &config.bot, &shutdown);
+                wait_for_min_interval(&state, &config.bot, &shutdown, no_wait);
+                attempt += 1;
+                state.note_gap(&config.bot);
+                let mut post = state.draft_post(&config.bot, image_data.clone());
+                post.in_reply_to = state.thread_root(&config.bot, &boost_accounts.0);
+                log_draft(&post, None, &config.bot, &events);
+
+                let (started, timer) = (Utc::now(), Instant::now());
+                let posted = if state.pre_post_allows(hooks) {
+                    state.post_everywhere(&posters, &post, attempt, &config.bot, &events)
+                } else {
+                    Err("the pre_post hook failed".to_string())
+                };
+                state.attempted(started, timer, &posted);
+                match posted {
+                    Ok(()) => {
+                        attempt = 0;
+                        state.run_hook(hooks, Hook::PostSuccess, None);
+                        if let Some(ref checker) = federation_checker {
+                            checker.check_later(state.id, state.progress.get("mastodon"));
+                        }
+                        state = state.complete_post(&config.bot);
+                        report_memory(&config.bot, "posted");
+                        if let Some(anchor) = kept_anchor.take() {
+                            state.schedule_anchor = Some(anchor);
+                            state.persist().expect("Unable to persist state");
+                        }
+                        events.emit(state.changed_event());
+                        current_image = None;
+
+                        posted_count += 1;
+                        if post_limit == Some(posted_count) {
+                            eprintln!("Made {} post(s) as requested, exiting", posted_count);
+                            break;
+                        }
+                    }
+                    Err(error) => {
+                        state.failed();
+                        state.run_hook(hooks, Hook::PostFailure, Some(&error));
+                        bundle_sources.write_if_due(&config.bot, &state, &events);
+                        let set_aside = state.quarantine_if_given_up(&posters)
+                            || state.handle_outage(&config.bot);
+                        if set_aside {
+                            attempt = 0;
+                            current_image = None;
+                            events.emit(state.changed_event());
+                            continue;
+                        }
+                        if post_limit.is_some() && attempt >= DELAYS.len() {
+                            eprintln!("Giving up after {} attempts, exiting", attempt);
+                            exit(EXIT_FAILED);
+                        }
+
+                        let backoff = get_backoff(attempt);
+                        eprintln!("Retrying after {} seconds", backoff);
+                        state.attempts.backed_off(backoff);
+                        state.persist().expect("Unable to persist state");
+                        let retry = Utc::now() + ChrDuration::seconds(backoff as i64);
+                        next_post::write(&state.paths.state, retry, Reason::Retry);
+                        if !shutdown.sleep(StdDuration::from_secs(backoff)) {
+                            shut_down();
+                        }
+                        current_image = Some(image_data.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::fs::write;
+    use std::sync::Mutex;
+    use tempfile::{tempdir, TempDir};
+
+    /// Settings from a `[bot]` section with `extra` in it
+    fn bot_config(extra: &str) -> BotConfig {
+        toml::from_str(&format!("map_size = 16\n{}", extra)).expect("Invalid config")
+    }
+
+    /// A state in a temporary directory, with its images directory created
+    fn temp_state() -> (TempDir, State) {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let paths = StatePaths {
+            state: dir.path().join("state"),
+            images: dir.path().join("images"),
+        };
+        create_dir_all(&paths.images).expect("Unable to create images directory");
+        (dir, State::get_state(paths))
+    }
+
+    /// Where a crash can leave the updates after posting
+    #[derive(Clone, Copy, Debug)]
+    enum CrashAfter {
+        Journal,
+        MediaRecord,
+        AttemptLog,
+        StateFile,
+    }
+
+    /// Post image 5 and crash at `crash`, returning what was to be done
+    fn post_and_crash(state: State, config: &BotConfig, crash: CrashAfter) -> Intent {
+        let mut state = state;
+        state.id = 5;
+        state.phase = Phase::Generated;
+        state.filename = Some("5.png".to_string());
+        state.attempts.record(Utc::now(), 100, None);
+        write(state.paths.images.join("5.png"), b"\x89PNG\r\n\x1a\nimage")
+            .expect("Unable to write image");
+        state.persist().expect("Unable to persist state");
+
+        let intent = Intent {
+            id: 5,
+            filename: state.filename.clone(),
+            status_id: Some("1234".to_string()),
+            posted: Utc.ymd(2024, 5, 1).and_hms(12, 0, 0),
+            remote_media: Some(RemoteMedia {
+                url: "https://files.example/5.png".to_string(),
+                mimetype: Some("image/png".to_string()),
+                width: None,
+                height: None,
+            }),
+        };
+        journal::begin(&state.paths.state, &intent).expect("Unable to write journal");
+
+        // The same writes `apply_intent` makes, up to the crash
+        match crash {
+            CrashAfter::Journal => {}
+            CrashAfter::MediaRecord | CrashAfter::AttemptLog => {
+                state.record_remote_media(config, intent.remote_media.as_ref());
+                if let CrashAfter::AttemptLog = crash {
+                    state.attempts.write(&state.paths.images, "5.png");
+                }
+            }
+            CrashAfter::StateFile => {
+                state.apply_intent(config, &intent);
+            }
+        }
+        intent
+    }
+
+    #[test]
+    fn replaying_journal_finishes_interrupted_updates() {
+        let config = bot_config("");
+        for &crash in &[
+            CrashAfter::Journal,
+            CrashAfter::MediaRecord,
+            CrashAfter::AttemptLog,
+            CrashAfter::StateFile,
+        ] {
+            let (dir, state) = temp_state();
+            let paths = state.paths.clone();
+            let intent = post_and_crash(state, &config, crash);
+
+            // Started again
+            let state = State::get_state(paths.clone()).replay_journal(&config);
+            assert_eq!(state.id, 6, "{:?}", crash);
+            assert_eq!(state.last_post, Some(intent.posted), "{:?}", crash);
+            assert!(state.filename.is_none(), "{:?}", crash);
+            assert!(paths.images.join("5.media.toml").exists(), "{:?}", crash);
+            assert!(paths.images.join("5.attempts.toml").exists(), "{:?}", crash);
+            assert!(!journal::path(&paths.state).exists(), "{:?}", crash);
+
+            let saved = State::get_state(paths);
+            assert_eq!(saved.id, 6, "{:?}", crash);
+            assert_eq!(saved.last_post, Some(intent.posted), "{:?}", crash);
+            drop(dir);
+        }
+    }
+
+    #[test]
+    fn replaying_journal_twice_changes_nothing() {
+        let config = bot_config("");
+        let (_dir, state) = temp_state();
+        let paths = state.paths.clone();
+        let intent = post_and_crash(state, &config, CrashAfter::MediaRecord);
+        // Crashed again while replaying, after the state file was written
+        journal::begin(&paths.state, &intent).expect("Unable to write journal");
+        State::get_state(paths.clone()).apply_intent(&config, &intent);
+
+        let state = State::get_state(paths.clone()).replay_journal(&config);
+        assert_eq!(state.id, 6);
+        assert!(!journal::path(&paths.state).exists());
+    }
+
+    #[test]
+    fn without_journal_nothing_is_replayed() {
+        let config = bot_config("");
+        let (_dir, mut state) = temp_state();
+        state.id = 5;
+        state.persist().expect("Unable to persist state");
+
+        let state = state.replay_journal(&config);
+        assert_eq!(state.id, 5);
+        assert!(state.last_post.is_none());
+    }
+
+    #[test]
+    fn damaged_journal_is_ignored() {
+        let config = bot_config("");
+        let (_dir, mut state) = temp_state();
+        state.id = 5;
+        state.persist().expect("Unable to persist state");
+        let journal = journal::path(&state.paths.state);
+        write(&journal, "id = \"not").expect("Unable to write journal");
+
+        let state = state.replay_journal(&config);
+        assert_eq!(state.id, 5);
+        assert!(!journal.exists());
+    }
+
+    #[test]
+    fn validates_filename_templates() {
+        for template in &["{id}", "{date}-{id}", "landscape {id} of {date}"] {
+            assert!(validate_filename_template(template).is_ok(), "{}", template);
+        }
+        let refused: &[(&str, &str)] = &[
+            ("{date}", "must contain {id}"),
+            ("images/{id}", "path separators"),
+            ("{id}-{name}", "unknown placeholder {name}"),
+            ("{id}-{seed}", "{seed} isn't supported"),
+            ("{preset}-{id}", "{preset} isn't supported"),
+            ("{id", "unclosed brace"),
+        ];
+        for &(template, problem) in refused {
+            let error = validate_filename_template(template).unwrap_err();
+            assert!(error.to_string().contains(problem), "{}: {}", template, error);
+        }
+    }
+
+    #[test]
+    fn renders_filenames() {
+        let date = Utc.ymd(2024, 5, 1).and_hms(23, 59, 0);
+        assert_eq!(render_filename("{id}", 7, date), "7");
+        assert_eq!(render_filename("{date}-{id}", 7, date), "20240501-7");
+        assert_eq!(render_filename("{id}-{id}", 12, date), "12-12");
+    }
+
+    /// Renderer with the built-in tiles
+    fn builtin_renderer() -> Renderer {
+        let tiles_config = builtin_tiles::tiles_config().expect("Unable to draw built-in tiles");
+        tiles::load_renderer(Path::new(builtin_tiles::BUILTIN), &tiles_config)
+            .expect("Unable to load built-in tiles")
+    }
+
+    #[test]
+    fn peak_allocation_counts() {
+        let (_, peak) = test_support::peak_allocation(|| vec![1u8; 1 << 20].len());
+        assert!(peak >= 1 << 20);
+        let (_, peak) = test_support::peak_allocation(|| 1 + 1);
+        assert_eq!(peak, 0);
+    }
+
+    #[test]
+    fn encoding_stays_under_estimate() {
+        let config = bot_config("");
+        let renderer = builtin_renderer();
+        let generated = generate_map_sized(&config, 32, &mut thread_rng());
+        let surf = renderer.render_map(&generated.map).expect("Unable to render map");
+        let (width, height) = surf.size();
+        let estimate = estimate_pipeline_bytes(&config, u64::from(width) * u64::from(height));
+
+        let (png, peak) = test_support::peak_allocation(|| {
+            let mut png = Vec::new();
+            write_surface_as_png(&surf, &mut png).expect("Unable to encode surface");
+            png
+        });
+        assert!(!png.is_empty());
+        // The surface isn't counted, it's SDL's own
+        assert!(peak as u64 <= estimate, "peak {} over estimate {}", peak, estimate);
+    }
+
+    #[test]
+    fn estimate_follows_settings() {
+        let pixels = 1_000_000;
+        let plain = estimate_pipeline_bytes(&bot_config(""), pixels);
+        let low_memory = estimate_pipeline_bytes(&bot_config("low_memory = true"), pixels);
+        let animated = estimate_pipeline_bytes(&bot_config("animate = true"), pixels);
+        assert!(low_memory < plain);
+        assert!(animated > plain);
+        assert_eq!(estimate_pipeline_bytes(&bot_config(""), 2 * pixels), 2 * plain);
+    }
+
+    #[test]
+    fn trial_render_refuses_settings_over_memory_cap() {
+        let renderer = builtin_renderer();
+        let mut capped = bot_config("max_pipeline_bytes = 1000000");
+        capped.map_size = MAX_MAP_SIZE;
+        match trial_render(&capped, &renderer) {
+            Err(ConfigError::TooMuchMemory { estimate, max, .. }) => {
+                assert!(estimate > max);
+                assert_eq!(max, 1_000_000);
+            }
+            _ => panic!("Settings over max_pipeline_bytes accepted"),
+        }
+        assert!(trial_render(&bot_config(""), &renderer).is_ok());
+    }
+
+    #[test]
+    fn trial_render_refuses_surfaces_over_cap() {
+        let renderer = builtin_renderer();
+        match trial_render(&bot_config("max_surface_pixels = 1000"), &renderer) {
+            Err(ConfigError::SurfaceTooLarge { map_size, width, height, max }) => {
+                assert_eq!(map_size, 16);
+                assert!(width * height > max);
+                assert_eq!(max, 1000);
+            }
+            _ => panic!("Settings over max_surface_pixels accepted"),
+        }
+        assert!(trial_render(&bot_config(""), &renderer).is_ok());
+    }
+
+    /// A 3x2 PNG
+    fn small_png() -> Arc<[u8]> {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(image::RgbImage::new(3, 2))
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .expect("Unable to encode PNG");
+        png.into()
+    }
+
+    /// The `PostDrafted` event `log_draft` emits for `post`
+    fn drafted(post: &Post, backend: Option<&str>, config: &BotConfig) -> Event {
+        let events = EventLog::new(false);
+        log_draft(post, backend, config, &events);
+        let mut recent = events.recent();
+        assert_eq!(recent.len(), 1);
+        recent.remove(0).event
+    }
+
+    #[test]
+    fn draft_has_everything_posted() {
+        let mut post = test_support::post(5);
+        post.image = small_png();
+        post.alt_params = Some("frequency 0.02".to_string());
+        post.language = Some("en".to_string());
+        let bytes = post.image.len();
+
+        match drafted(&post, None, &bot_config("")) {
+            Event::PostDrafted {
+                id,
+                body,
+                alt_text,
+                alt_params,
+                language,
+                visibility,
+                format,
+                width,
+                height,
+                bytes: drafted_bytes,
+                poll,
+                backend,
+            } => {
+                assert_eq!(id, 5);
+                assert_eq!(body, "Landscape 5");
+                assert_eq!(alt_text, "An isometric landscape");
+                assert_eq!(alt_params.as_ref().map(String::as_str), Some("frequency 0.02"));
+                assert_eq!(language.as_ref().map(String::as_str), Some("en"));
+                assert_eq!(visibility, "public");
+                assert_eq!(format, "png");
+                assert_eq!((width, height), (Some(3), Some(2)));
+                assert_eq!(drafted_bytes, bytes);
+                assert!(!poll);
+                assert_eq!(backend, None);
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn draft_visibility_follows_boosting() {
+        let boosted = bot_config("boost_after_minutes = 30");
+        let mut post = test_support::post(5);
+        let visibility = |event: Event| match event {
+            Event::PostDrafted { visibility, .. } => visibility,
+            other => panic!("Unexpected event {:?}", other),
+        };
+
+        assert_eq!(visibility(drafted(&post, None, &boosted)), "unlisted");
+        // Milestones are posted publicly even when boosting
+        post.public = true;
+        assert_eq!(visibility(drafted(&post, None, &boosted)), "public");
+    }
+
+    #[test]
+    fn draft_names_backend_and_unknown_sizes() {
+        // Not really an image, so its size can't be told
+        let post = test_support::post(5);
+        match drafted(&post, Some("bluesky"), &bot_config("")) {
+            Event::PostDrafted {
+                width,
+                height,
+                backend,
+                ..
+            } => {
+                assert_eq!((width, height), (None, None));
+                assert_eq!(backend.as_ref().map(String::as_str), Some("bluesky"));
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn damaged_image_is_quarantined_under_the_same_id() {
+        let (dir, mut state) = temp_state();
+        let images = dir.path().join("images");
+        write(images.join("5.png"), b"\x89PNG cut short").expect("Unable to write image");
+        state.id = 5;
+        state.phase = Phase::Generated;
+        state.filename = Some("5.png".to_string());
+        state.fingerprint = Some(Fingerprint::of(b"\x89PNG as it was written"));
+
+        let data = state.get_saved_image().expect("Unable to read image");
+        let problem = state.check_saved_image(&data).unwrap_err();
+        assert!(problem.contains("bytes long"), "{}", problem);
+
+        state.quarantine_pending();
+        assert_eq!(state.id, 5);
+        assert!(match state.phase {
+            Phase::Awaiting => true,
+            _ => false,
+        });
+        assert_eq!(state.filename, None);
+        assert_eq!(state.fingerprint, None);
+        assert!(!images.join("5.png").exists());
+        assert!(images.join(QUARANTINE_DIR).join("5.png").exists());
+    }
+
+    /// Config generating into the images directory of the state in `dir`
+    fn generating_config(dir: &Path, extra: &str) -> BotConfig {
+        bot_config(&format!(
+            "images_dir = '{}'\n{}",
+            dir.join("images").display(),
+            extra
+        ))
+    }
+
+    /// Parameters no roll is going to come up with by chance
+    fn preset_params() -> GenerationParams {
+        GenerationParams {
+            map_size: 16,
+            frequency: Some(0.03),
+            layer_height: None,
+            min_soil_cutoff: None,
+            max_water_level: Some(3),
+            rotation: 90,
+            name_seed: 42,
+            adjustments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn daily_seed_rolls_the_same_image_whatever_the_schedule() {
+        let schedules = [
+            "sleep_time = 3600\njitter = 600",
+            "sleep_time = 86400\njitter = { early = 0, late = '10%' }",
+        ];
+        let generation = "seed_mode = 'daily'\ndescribe = true\n\
+                          frequency = { min = 0.01, max = 0.04 }\nrotation = 'random'\n\
+                          [[locales]]\nlanguage = 'en'\n[[locales]]\nlanguage = 'de'\n";
+        let mut dirs = Vec::new();
+        let mut rolled = Vec::new();
+        for schedule in &schedules {
+            let (dir, mut state) = temp_state();
+            let extra = format!("{}\n{}", schedule, generation);
+            let config = generating_config(dir.path(), &extra);
+            let params = state.rolled_params(&config);
+            dirs.push(dir);
+            rolled.push((state, config, params));
+        }
+        // cubeglobe doesn't take a seed, so both are given the same terrain
+        let shared = generate_map_with(&rolled[0].2);
+
+        let renderer = builtin_renderer();
+        let events = EventLog::new(false);
+        let images: Vec<_> = rolled
+            .into_iter()
+            .map(|(state, config, params)| {
+                let generate = || GeneratedMap {
+                    map: rotate_map(&shared.map, 0),
+                    params: params.clone(),
+                    stats: shared.stats.clone(),
+                };
+                let shutdown = Shutdown::default();
+                let image =
+                    create_image_from(&config, &renderer, &state, &events, &shutdown, 0, generate)
+                        .expect("Unable to create image");
+                let state = state.generated(&image, &config);
+                let fingerprint = Fingerprint::of(&image.data);
+                (params, image.name, image.description, state.locale, fingerprint)
+            })
+            .collect();
+        assert_eq!(images[0], images[1]);
+    }
+
+    #[test]
+    fn rolled_params_survive_a_restart() {
+        let (dir, mut state) = temp_state();
+        let config = generating_config(dir.path(), "");
+        let rolled = state.rolled_params(&config);
+        assert_eq!(state.rolled_params(&config), rolled);
+
+        let paths = StatePaths {
+            state: dir.path().join("state"),
+            images: dir.path().join("images"),
+        };
+        let mut restarted = State::get_state(paths);
+        assert_eq!(restarted.rolled_params(&config), rolled);
+    }
+
+    #[test]
+    fn generates_with_the_rolled_params() {
+        let (dir, mut state) = temp_state();
+        let config = generating_config(dir.path(), "");
+        state.rolled = Some(preset_params());
+        let created = create_image_within_budget(
+            &config,
+            &builtin_renderer(),
+            &mut state,
+            &EventLog::new(false),
+            &Shutdown::default(),
+        ).expect("Unable to create image");
+
+        assert_eq!(created.params, preset_params());
+        let name = NameLists::load(None)
+            .expect("Unable to load names")
+            .name(42, &state.used_names);
+        assert_eq!(created.name, name);
+        // The next image rolls its own
+        assert_eq!(state.rolled, None);
+    }
+
+    // Other unix file systems, like APFS, refuse names which aren't valid UTF-8
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn works_in_a_non_utf8_images_dir() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let images = dir.path().join(OsStr::from_bytes(b"images-\xff"));
+        let paths = StatePaths {
+            state: dir.path().join("state"),
+            images: images.clone(),
+        };
+        let mut state = State::get_state(paths.clone());
+        let mut config = generating_config(dir.path(), "");
+        config.images_dir = images.clone();
+        state.rolled = Some(preset_params());
+
+        let created = create_image_within_budget(
+            &config,
+            &builtin_renderer(),
+            &mut state,
+            &EventLog::new(false),
+            &Shutdown::default(),
+        ).expect("Unable to create image");
+        assert_eq!(created.filename.parent(), Some(images.as_path()));
+        let state = state.generated(&created, &config);
+        state.persist().expect("Unable to save state");
+
+        let restarted = State::get_state(paths);
+        assert!(match restarted.phase {
+            Phase::Generated => true,
+            _ => false,
+        });
+        let data = restarted.get_saved_image().expect("Unable to read pending image");
+        assert_eq!(data, created.data);
+    }
+
+    /// Saved parameters after generating with `preset_params` fails every time
+    fn params_after_failures(reroll_after_failures: u32) -> GenerationParams {
+        let (dir, mut state) = temp_state();
+        let config = generating_config(
+            dir.path(),
+            &format!(
+                "names_file = '{}'\nmax_regenerations = 2\nreroll_after_failures = {}\n",
+                dir.path().join("missing.toml").display(),
+                reroll_after_failures
+            ),
+        );
+        state.rolled = Some(preset_params());
+        state.persist().expect("Unable to persist state");
+
+        let events = EventLog::new(false);
+        let created = create_image_within_budget(
+            &config,
+            &builtin_renderer(),
+            &mut state,
+            &events,
+            &Shutdown::default(),
+        );
+        assert!(created.is_err());
+        let failures = events
+            .recent()
+            .iter()
+            .filter(|record| match record.event {
+                Event::GenerationFailed { .. } => true,
+                _ => false,
+            }).count();
+        assert_eq!(failures, 3);
+        // Skipping the slot drops them in memory, the state file has the last ones used
+        assert_eq!(state.rolled, None);
+        let saved = read_to_string(dir.path().join("state")).expect("Unable to read state");
+        toml::from_str::<State>(&saved)
+            .expect("Invalid state")
+            .rolled
+            .expect("No parameters saved")
+    }
+
+    #[test]
+    fn regenerations_reuse_rolled_params() {
+        assert_eq!(params_after_failures(5), preset_params());
+    }
+
+    #[test]
+    fn repeated_failures_roll_new_params() {
+        assert_ne!(params_after_failures(1), preset_params());
+    }
+
+    #[test]
+    fn missing_values_fall_back() {
+        let (_dir, mut state) = temp_state();
+        state.id = 5;
+        state.name = Some("The Misty Steppes of Narlun".to_string());
+        let template = "#{id} {name}: {water_pct}% water, {mean_height} high. {description}";
+
+        let config = bot_config("placeholder_fallback = '?'\n");
+        assert_eq!(
+            state.fill_text(template, &config),
+            "#5 The Misty Steppes of Narlun: ?% water, ? high. ?"
+        );
+        // Empty by default
+        assert_eq!(
+            state.fill_text(template, &bot_config("")),
+            "#5 The Misty Steppes of Narlun: % water,  high. "
+        );
+
+        state.stats = Some(MapStats {
+            water_pct: 12.4,
+            min_height: 1,
+            max_height: 9,
+            mean_height: 4.3,
+            block_types: 3,
+        });
+        state.description = Some("Calm.".to_string());
+        assert_eq!(
+            state.fill_text(template, &config),
+            "#5 The Misty Steppes of Narlun: 12% water, 4.3 high. Calm."
+        );
+    }
+
+    /// A state with image 5 pending, saved with `data` as it was written
+    fn pending_state(data: &[u8]) -> (TempDir, State) {
+        let (dir, mut state) = temp_state();
+        write(dir.path().join("images").join("5.png"), data).expect("Unable to write image");
+        state.id = 5;
+        state.phase = Phase::Generated;
+        state.filename = Some("5.png".to_string());
+        state.fingerprint = Some(Fingerprint::of(data));
+        (dir, state)
+    }
+
+    #[test]
+    fn immediate_posts_pending_image() {
+        let png = small_png();
+        let (dir, mut state) = pending_state(&png);
+        assert_eq!(state.take_up_pending(false), Some(png.to_vec()));
+        assert_eq!(state.id, 5);
+        assert_eq!(state.filename, Some("5.png".to_string()));
+        assert!(dir.path().join("images").join("5.png").exists());
+    }
+
+    #[test]
+    fn immediate_with_nothing_pending_generates() {
+        let (_dir, mut state) = temp_state();
+        assert_eq!(state.take_up_pending(false), None);
+        assert_eq!(state.take_up_pending(true), None);
+    }
+
+    #[test]
+    fn immediate_regenerates_damaged_image() {
+        let png = small_png();
+        let (dir, mut state) = pending_state(&png);
+        write(dir.path().join("images").join("5.png"), &png[..png.len() / 2])
+            .expect("Unable to write image");
+
+        assert_eq!(state.take_up_pending(false), None);
+        assert_eq!(state.id, 5);
+        assert_eq!(state.filename, None);
+        let images = dir.path().join("images");
+        assert!(images.join(QUARANTINE_DIR).join("5.png").exists());
+    }
+
+    #[test]
+    fn force_new_sets_pending_image_aside() {
+        for &phase in &["Generated", "AwaitingApproval"] {
+            let (dir, mut state) = pending_state(&small_png());
+            state.phase = toml::from_str::<State>(&format!("id = 5\nphase = {:?}\n", phase))
+                .expect("Invalid state")
+                .phase;
+
+            assert_eq!(state.take_up_pending(true), None, "{}", phase);
+            // The set aside image keeps its id
+            assert_eq!(state.id, 6);
+            assert_eq!(state.filename, None);
+            let images = dir.path().join("images");
+            assert!(!images.join("5.png").exists());
+            assert!(images.join(STALE_DIR).join("5.png").exists());
+            // Persisted, so a run in loop mode carries on without it
+            let saved = read_to_string(dir.path().join("state")).expect("Unable to read state");
+            let saved = toml::from_str::<State>(&saved).expect("Invalid state");
+            assert_eq!(saved.filename, None);
+            assert!(match saved.phase {
+                Phase::Awaiting => true,
+                _ => false,
+            });
+        }
+    }
+
+    /// Posts by handing back a fixed receipt, as Mastodon would
+    struct ReceiptPoster;
+
+    impl Poster for ReceiptPoster {
+        fn name(&self) -> &str {
+            "mastodon"
+        }
+
+        fn post(&self, _post: &Post, progress: &mut Progress) -> Result<(), PostingError> {
+            progress.receipt = Some(PostReceipt {
+                status_id: "110".to_string(),
+                url: "https://example.org/@cubeglobe/110".to_string(),
+                created_at: Utc.ymd(2024, 5, 1).and_hms(12, 0, 0),
+                media_ids: vec!["109".to_string()],
+                poll: None,
+            });
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn schedule_follows_the_receipt() {
+        let config = bot_config("");
+        let png = small_png();
+        let (_dir, mut state) = pending_state(&png);
+        let posters: Vec<Arc<dyn Poster>> = vec![Arc::new(ReceiptPoster)];
+        let post = state.draft_post(&config, png);
+        state
+            .post_everywhere(&posters, &post, 1, &config, &EventLog::new(false))
+            .expect("Unable to post");
+
+        let receipt = state.receipt().expect("No receipt");
+        assert_eq!(receipt.status_id, "110");
+        assert_eq!(
+            state.hook_context(None).status_url,
+            Some("https://example.org/@cubeglobe/110")
+        );
+        let state = state.complete_post(&config);
+        assert_eq!(state.id, 6);
+        assert_eq!(state.last_post, Some(Utc.ymd(2024, 5, 1).and_hms(12, 0, 0)));
+    }
+
+    #[test]
+    fn no_gap_notice_for_a_long_interval() {
+        let config = bot_config("sleep_time = 604800\ngap_notice_after_hours = 1");
+        let png = small_png();
+        let (_dir, mut state) = pending_state(&png);
+        state.last_post = Some(Utc::now() - ChrDuration::days(7) - ChrDuration::hours(1));
+        state.note_gap(&config);
+        assert_eq!(state.gap_notice, None);
+        assert_eq!(state.draft_post(&config, png).body, POST_BODY);
+    }
+
+    #[test]
+    fn gap_notice_is_kept_for_the_image() {
+        let config = bot_config("gap_notice_after_hours = 1\ngap_notice = 'Down for {hours}h'");
+        let png = small_png();
+        let (_dir, mut state) = pending_state(&png);
+        state.last_post = Some(Utc::now() - ChrDuration::hours(4) - ChrDuration::minutes(30));
+        state.failures = 3;
+        state.note_gap(&config);
+        let notice = state.gap_notice.clone().expect("No notice after an outage");
+        assert!(notice.starts_with("Down for "), "{}", notice);
+
+        // Later attempts post the same note, however long the outage went on
+        state.last_post = Some(Utc::now() - ChrDuration::days(2));
+        state.note_gap(&config);
+        assert_eq!(state.gap_notice.as_ref(), Some(&notice));
+        let body = state.draft_post(&config, png).body;
+        assert!(body.ends_with(&format!("\n\n{}", notice)), "{}", body);
+
+        let state = state.posted(&config, Utc::now());
+        assert_eq!(state.gap_notice, None);
+    }
+
+    /// Image `id` generated and drafted with milestones every 100 images, and its own body on
+    /// Bluesky
+    fn milestone_post(id: u32) -> (State, Post, Post) {
+        let (dir, mut state) = temp_state();
+        let config = generating_config(
+            dir.path(),
+            "boost_after_minutes = 30\n\
+             [milestones]\n\
+             every = 100\n\
+             body = 'This is landscape number {id}!'\n\
+             map_size = 24\n\
+             [backends.bluesky]\n\
+             body = 'Landscape {id}'\n",
+        );
+        state.id = id;
+        let created = create_image_within_budget(
+            &config,
+            &builtin_renderer(),
+            &mut state,
+            &EventLog::new(false),
+            &Shutdown::default(),
+        ).expect("Unable to create image");
+        assert_eq!(created.params.map_size, if id % 100 == 0 { 24 } else { 16 });
+        let state = state.generated(&created, &config);
+        state.persist().expect("Unable to persist state");
+
+        // As after a restart
+        let state = State::get_state(state.paths.clone());
+        let post = state.draft_post(&config, small_png());
+        let bluesky = state.adapt_post(&post, &config, "bluesky").unwrap_or_else(|| post.clone());
+        (state, post, bluesky)
+    }
+
+    #[test]
+    fn milestones_are_kept_and_apply_to_every_backend() {
+        let (state, post, bluesky) = milestone_post(100);
+        assert!(state.milestone);
+        assert_eq!(post.body, "This is landscape number 100!");
+        assert!(post.public);
+        assert_eq!(bluesky.body, "This is landscape number 100!");
+        assert!(bluesky.public);
+
+        let (state, post, bluesky) = milestone_post(101);
+        assert!(!state.milestone);
+        assert!(!post.public);
+        assert_eq!(bluesky.body, "Landscape 101");
+        assert!(!bluesky.public);
+    }
+
+    #[test]
+    fn kept_anchor_is_scheduled_from_until_the_next_post() {
+        let (_dir, mut state) = temp_state();
+        let anchor = Utc.ymd(2026, 10, 17).and_hms(9, 0, 0);
+        let triggered = Utc.ymd(2026, 10, 17).and_hms(10, 15, 0);
+        state.last_post = Some(triggered);
+        assert_eq!(state.schedule_base(), Some(triggered));
+        state.schedule_anchor = Some(anchor);
+        assert_eq!(state.schedule_base(), Some(anchor));
+
+        let next = Utc.ymd(2026, 10, 17).and_hms(12, 0, 0);
+        let state = state.posted(&bot_config(""), next);
+        assert_eq!(state.schedule_anchor, None);
+        assert_eq!(state.schedule_base(), Some(next));
+    }
+
+    /// Backend which keeps what it's asked to post, failing the first `failures` times
+    struct DraftPoster {
+        name: &'static str,
+        failures: Mutex<u32>,
+        drafts: Mutex<Vec<Post>>,
+    }
+
+    impl DraftPoster {
+        fn new(name: &'static str, failures: u32) -> Arc<DraftPoster> {
+            Arc::new(DraftPoster {
+                name,
+                failures: Mutex::new(failures),
+                drafts: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn drafts(&self) -> Vec<Post> {
+            self.drafts.lock().expect("drafts lock poisoned").clone()
+        }
+    }
+
+    impl Poster for DraftPoster {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn post(&self, post: &Post, _progress: &mut Progress) -> Result<(), PostingError> {
+            self.drafts.lock().expect("drafts lock poisoned").push(post.clone());
+            let mut failures = self.failures.lock().expect("failures lock poisoned");
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(PostingError::Rejected {
+                    backend: self.name.to_string(),
+                    status: 503,
+                    message: "unavailable".to_string(),
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn each_backend_gets_its_own_draft() {
+        let config = bot_config(
+            "body = 'Landscape {id}'\n\
+             alt_text = 'An isometric landscape'\n\
+             [backends.bluesky]\n\
+             body = 'Landscape {id}, on Bluesky'\n\
+             [backends.matrix]\n\
+             alt_text = 'Landscape {id} for Matrix'\n",
+        );
+        let png = small_png();
+        let (_dir, mut state) = pending_state(&png);
+        let bluesky = DraftPoster::new("bluesky", 0);
+        let matrix = DraftPoster::new("matrix", 1);
+        let posters: Vec<Arc<dyn Poster>> = vec![bluesky.clone(), matrix.clone()];
+        let post = state.draft_post(&config, png);
+        let events = EventLog::new(false);
+
+        assert!(state.post_everywhere(&posters, &post, 1, &config, &events).is_err());
+        assert_eq!(state.posted_to, vec!["bluesky".to_string()]);
+        state
+            .post_everywhere(&posters, &post, 2, &config, &events)
+            .expect("Unable to post");
+        assert_eq!(state.posted_to, vec!["bluesky".to_string(), "matrix".to_string()]);
+
+        // Bluesky was done after the first attempt, and isn't posted to again
+        let bluesky = bluesky.drafts();
+        assert_eq!(bluesky.len(), 1);
+        assert_eq!(bluesky[0].body, "Landscape 5, on Bluesky");
+        assert_eq!(bluesky[0].alt_text, "An isometric landscape");
+        let matrix = matrix.drafts();
+        assert_eq!(matrix.len(), 2);
+        for draft in &matrix {
+            assert_eq!(draft.body, "Landscape 5");
+            assert_eq!(draft.alt_text, "Landscape 5 for Matrix");
+        }
+        assert_eq!(state.progress["matrix"].failures, 1);
+    }
+
+    /// Making many tiny images one after another doesn't leave memory behind
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cycles_dont_leak() {
+        let (dir, mut state) = temp_state();
+        let config = generating_config(dir.path(), "");
+        let renderer = builtin_renderer();
+        let mut cycle = |state: &mut State| {
+            let created = create_image_within_budget(
+                &config,
+                &renderer,
+                state,
+                &EventLog::new(false),
+                &Shutdown::default(),
+            ).expect("Unable to create image");
+            remove_file(&created.filename).expect("Unable to remove image");
+            state.id += 1;
+        };
+
+        // Allocators and SDL keep some memory around once they've needed it
+        for _ in 0..5 {
+            cycle(&mut state);
+        }
+        let before = memory::rss_kib().expect("Unable to read RSS");
+        for _ in 0..50 {
+            cycle(&mut state);
+        }
+        let after = memory::rss_kib().expect("Unable to read RSS");
+        assert!(
+            after < before + 8 * 1024,
+            "RSS grew from {} KiB to {} KiB",
+            before,
+            after
+        );
+    }
+
+    #[test]
+    fn stripping_metadata_keeps_the_attribution() {
+        let contains = |png: &[u8], needle: &[u8]| png.windows(needle.len()).any(|w| w == needle);
+        let credits = Credits {
+            creator: Some("cubeglobe".to_string()),
+            license: None,
+            source: None,
+        };
+        let png = small_png().to_vec();
+        for &(strip, software) in &[(false, true), (true, false)] {
+            let mut config = bot_config("");
+            config.strip_metadata = strip;
+            let tagged = tag_still(&config, Some(&credits), png.clone());
+            assert!(contains(&tagged, b"iTXtXML:com.adobe.xmp"));
+            assert!(contains(&tagged, b"<rdf:li>cubeglobe</rdf:li>"));
+            assert_eq!(contains(&tagged, b"tEXtSoftware"), software);
+        }
+        assert_eq!(tag_still(&bot_config("strip_metadata = true"), None, png.clone()), png);
+    }
+
+    #[test]
+    fn first_post_time_is_kept_across_restarts() {
+        let (_dir, mut state) = temp_state();
+        assert_eq!(state.first_post_at(&bot_config("")), None);
+
+        let config = bot_config("first_post_delay = 900");
+        let before = Utc::now();
+        let due = state.first_post_at(&config).expect("No first post time");
+        let delay = ChrDuration::seconds(900);
+        assert!(due >= before + delay && due <= Utc::now() + delay);
+
+        let mut restarted = State::get_state(state.paths.clone());
+        assert_eq!(restarted.first_post_at(&config), Some(due));
+        // Only the first post is put off
+        let state = restarted.posted(&config, due);
+        assert_eq!(state.first_post_at, None);
+    }
+
+    /// Write a config file to `path`, keeping the state and images at the paths given
+    fn write_config(path: &Path, images_dir: &Path, state_path: &Path, extra: &str) {
+        let contents = format!(
+            "[bot]\nmap_size = 16\ntiles = 'builtin'\nimages_dir = '{}'\nstate_path = '{}'\n{}\n\
+             [credentials]\nbase = 'https://example.org'\nclient_id = 'id'\n\
+             client_secret = 'secret'\nredirect = 'urn:ietf:wg:oauth:2.0:oob'\n\
+             token = 'token'\n",
+            images_dir.display(),
+            state_path.display(),
+            extra
+        );
+        write(path, contents).expect("Unable to write config");
+    }
+
+    #[test]
+    fn reload_refuses_to_move_the_state() {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let (path, images, state) = (
+            dir.path().join("config.toml"),
+            dir.path().join("images"),
+            dir.path().join("state"),
+        );
+        let matches = App::new("cubeglobe-bot").get_matches_from(vec!["cubeglobe-bot"]);
+        write_config(&path, &images, &state, "");
+        let current = read_config(&path, &matches).expect("Unable to read config").bot;
+        let tiles_config = builtin_tiles::tiles_config().expect("Unable to draw built-in tiles");
+        let renderer = builtin_renderer();
+
+        let moved = dir.path().join("moved");
+        let cases = &[
+            (&moved, &state, ""),
+            (&images, &moved, ""),
+            (&images, &state, "bot_name = 'other'"),
+        ];
+        for &(images_dir, state_path, extra) in cases {
+            write_config(&path, images_dir, state_path, extra);
+            let reloaded = reload_config(&path, &matches, &current, &tiles_config, &renderer, &[]);
+            match reloaded {
+                Err(e) => assert!(e.to_string().contains("restarting"), "{}", e),
+                Ok(_) => panic!("{:?}, {:?}, {:?} accepted", images_dir, state_path, extra),
+            }
+        }
+
+        write_config(&path, &images, &state, "sleep_time = 7200");
+        let reloaded = reload_config(&path, &matches, &current, &tiles_config, &renderer, &[])
+            .expect("Unable to reload config");
+        assert_eq!(reloaded.bot.sleep_time, 7200);
+        assert!(reloaded.tiles.is_none());
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_old_config() {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let (path, images, state) = (
+            dir.path().join("config.toml"),
+            dir.path().join("images"),
+            dir.path().join("state"),
+        );
+        let matches = App::new("cubeglobe-bot").get_matches_from(vec!["cubeglobe-bot"]);
+        write_config(&path, &images, &state, "sleep_time = 3600");
+        let mut config = read_config(&path, &matches).expect("Unable to read config").bot;
+        let mut tiles_config =
+            builtin_tiles::tiles_config().expect("Unable to draw built-in tiles");
+        let mut renderer = builtin_renderer();
+
+        let broken = &["sleep_time = -5", "sleep_time = 'hourly'", "min_interval_secs = -1"];
+        for extra in broken {
+            write_config(&path, &images, &state, extra);
+            assert!(
+                !apply_reload(&path, &matches, &mut config, &mut tiles_config, &mut renderer, &[]),
+                "{} accepted",
+                extra
+            );
+            assert_eq!(config.sleep_time, 3600);
+            assert_eq!(config.min_interval_secs, None);
+        }
+
+        write_config(&path, &images, &state, "sleep_time = 7200");
+        assert!(apply_reload(&path, &matches, &mut config, &mut tiles_config, &mut renderer, &[]));
+        assert_eq!(config.sleep_time, 7200);
     }
 }
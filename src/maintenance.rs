@@ -0,0 +1,676 @@
+//! Maintenance windows
+//!
+//! While the instance is down for announced maintenance, posting would only pile up failures.
+//! Windows are listed in `maintenance_windows`, either once, from `start` to `end`, or weekly,
+//! like `"tue 03:00-04:30"` or `"sat 22:00-sun 02:00 +02:00"`. A weekly window without a second
+//! day ends on the day it starts, or the next one if its end is earlier than its start. Times are
+//! UTC unless an offset follows; there's no time zone database to go by, so a window's offset
+//! doesn't change with daylight saving time.
+//!
+//! With `maintenance_url`, windows are also fetched from a JSON list of the same entries, or an
+//! iCalendar file with an event per window, once an hour. If fetching fails, the windows fetched
+//! last are kept.
+//!
+//! A post scheduled inside a window is put off until the window ends, plus up to the late
+//! jitter, and no posting attempts, first or retry, are made during one. Windows which overlap
+//! or touch count as one.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Error;
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+    Utc, Weekday,
+};
+use rand::Rng;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use serde_json;
+
+use build_info;
+
+/// How often windows are fetched from `maintenance_url`
+const REFRESH_MINUTES: i64 = 60;
+
+/// Minutes in a week
+const WEEK_MINUTES: i64 = 7 * 24 * 60;
+
+/// A configured maintenance window
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum MaintenanceWindow {
+    Once {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        /// Shown in the log instead of the times
+        #[serde(default)]
+        note: Option<String>,
+    },
+    Weekly {
+        weekly: WeeklyWindow,
+        #[serde(default)]
+        note: Option<String>,
+    },
+}
+
+impl MaintenanceWindow {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            MaintenanceWindow::Once { start, end, .. } if end <= start => Err(format!(
+                "window from {} ends at {}, before it starts",
+                start, end
+            )),
+            MaintenanceWindow::Once { .. } => Ok(()),
+            MaintenanceWindow::Weekly { ref weekly, .. } => weekly.validate(),
+        }
+    }
+
+    /// The occurrence of the window `at` falls into, if it falls into one
+    fn containing(&self, at: DateTime<Utc>) -> Option<Span> {
+        let (start, end, label) = match *self {
+            MaintenanceWindow::Once {
+                start,
+                end,
+                ref note,
+            } => {
+                let label = note
+                    .clone()
+                    .unwrap_or_else(|| format!("{} to {}", start, end));
+                (start, end, label)
+            }
+            MaintenanceWindow::Weekly {
+                ref weekly,
+                ref note,
+            } => {
+                let (start, end) = weekly.containing(at)?;
+                let label = note.clone().unwrap_or_else(|| format!("weekly {}", weekly));
+                (start, end, label)
+            }
+        };
+        if start <= at && at < end {
+            Some(Span { end, label })
+        } else {
+            None
+        }
+    }
+}
+
+/// A window recurring every week, see the module documentation
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeeklyWindow {
+    pub start_day: Weekday,
+    pub start: NaiveTime,
+    /// Day it ends on, if not worked out from the times
+    pub end_day: Option<Weekday>,
+    pub end: NaiveTime,
+    pub offset: FixedOffset,
+}
+
+/// Minutes since the start of the week, Monday 00:00
+fn week_minutes(day: Weekday, time: NaiveTime) -> i64 {
+    let midnight = NaiveTime::from_hms(0, 0, 0);
+    i64::from(day.num_days_from_monday()) * 24 * 60
+        + time.signed_duration_since(midnight).num_minutes()
+}
+
+impl WeeklyWindow {
+    pub fn validate(&self) -> Result<(), String> {
+        let length = self.length().num_minutes();
+        if length <= 0 || length >= WEEK_MINUTES {
+            return Err(format!("weekly window {} must last less than a week", self));
+        }
+        Ok(())
+    }
+
+    /// How long the window lasts
+    fn length(&self) -> Duration {
+        let start = week_minutes(self.start_day, self.start);
+        let end = match self.end_day {
+            Some(day) => week_minutes(day, self.end),
+            None if self.end > self.start => week_minutes(self.start_day, self.end),
+            None => week_minutes(self.start_day, self.end) + 24 * 60,
+        };
+        let mut length = end - start;
+        if self.end_day.is_some() && length <= 0 {
+            // Ends in the next week, like Sunday to Monday
+            length += WEEK_MINUTES;
+        }
+        Duration::minutes(length)
+    }
+
+    /// Start and end of the occurrence `at` falls into, if any
+    ///
+    /// That's either the one starting in the week of `at`, or one starting the week before and
+    /// running into it.
+    pub fn containing(&self, at: DateTime<Utc>) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let local = at.with_timezone(&self.offset).naive_local();
+        let monday =
+            local.date() - Duration::days(i64::from(local.weekday().num_days_from_monday()));
+        for weeks_back in 0..2 {
+            let day = monday - Duration::weeks(weeks_back)
+                + Duration::days(i64::from(self.start_day.num_days_from_monday()));
+            let start = self
+                .offset
+                .from_local_datetime(&day.and_time(self.start))
+                .single()?
+                .with_timezone(&Utc);
+            let end = start + self.length();
+            if start <= at && at < end {
+                return Some((start, end));
+            }
+        }
+        None
+    }
+}
+
+fn parse_time(input: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(input, "%H:%M")
+        .map_err(|_| format!("expected a time like 03:30, got {:?}", input))
+}
+
+fn parse_weekday(input: &str) -> Result<Weekday, String> {
+    Weekday::from_str(input).map_err(|_| format!("expected a weekday like tue, got {:?}", input))
+}
+
+/// Parse an offset like `+02:00`, `-0530`, `+01`, `UTC` or `Z`
+fn parse_offset(input: &str) -> Result<FixedOffset, String> {
+    if input.eq_ignore_ascii_case("utc") || input == "Z" {
+        return Ok(FixedOffset::east(0));
+    }
+    let invalid = || format!("expected an offset like +02:00, got {:?}", input);
+    let sign = match input.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return Err(invalid()),
+    };
+    let digits: String = input[1..].chars().filter(|&c| c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) || (digits.len() != 2 && digits.len() != 4) {
+        return Err(invalid());
+    }
+    let hours: i32 = digits[..2].parse().map_err(|_| invalid())?;
+    let minutes: i32 = if digits.len() == 4 {
+        digits[2..].parse().map_err(|_| invalid())?
+    } else {
+        0
+    };
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(FixedOffset::east(sign * (hours * 3600 + minutes * 60)))
+}
+
+impl WeeklyWindow {
+    /// Parse a window like `tue 03:00-04:30`, `sat 22:00-sun 02:00 +02:00`
+    pub fn parse(input: &str) -> Result<WeeklyWindow, String> {
+        let mut tokens: Vec<&str> = input.split_whitespace().collect();
+        let offset = match tokens.last() {
+            Some(last)
+                if last.starts_with('+')
+                    || last.starts_with('-')
+                    || last.eq_ignore_ascii_case("utc")
+                    || *last == "Z" =>
+            {
+                let offset = parse_offset(last)?;
+                tokens.pop();
+                offset
+            }
+            _ => FixedOffset::east(0),
+        };
+
+        let rest = tokens.join(" ");
+        let mut halves = rest.splitn(2, '-');
+        let (from, to) = match (halves.next(), halves.next()) {
+            (Some(from), Some(to)) => (from.trim(), to.trim()),
+            _ => return Err(format!("expected a window like \"tue 03:00-04:30\", got {:?}", input)),
+        };
+
+        let from: Vec<&str> = from.split_whitespace().collect();
+        let (start_day, start) = match from.as_slice() {
+            [day, time] => (parse_weekday(day)?, parse_time(time)?),
+            _ => return Err(format!("expected a day and a time, like tue 03:00, got {:?}", rest)),
+        };
+        let to: Vec<&str> = to.split_whitespace().collect();
+        let (end_day, end) = match to.as_slice() {
+            [time] => (None, parse_time(time)?),
+            [day, time] => (Some(parse_weekday(day)?), parse_time(time)?),
+            _ => return Err(format!("expected an end time, like 04:30, got {:?}", rest)),
+        };
+
+        Ok(WeeklyWindow {
+            start_day,
+            start,
+            end_day,
+            end,
+            offset,
+        })
+    }
+}
+
+fn day_name(day: Weekday) -> String {
+    day.to_string().to_lowercase()
+}
+
+impl fmt::Display for WeeklyWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}-",
+            day_name(self.start_day),
+            self.start.format("%H:%M")
+        )?;
+        if let Some(day) = self.end_day {
+            write!(f, "{} ", day_name(day))?;
+        }
+        write!(f, "{} {}", self.end.format("%H:%M"), self.offset)
+    }
+}
+
+impl<'de> Deserialize<'de> for WeeklyWindow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<WeeklyWindow, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        WeeklyWindow::parse(&input).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for WeeklyWindow {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// The part of a window from a given moment on
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub end: DateTime<Utc>,
+    /// Says which window it is in the log
+    pub label: String,
+}
+
+/// Windows from the config and `maintenance_url`, see the module documentation
+#[derive(Default)]
+pub struct Maintenance {
+    fetched: Vec<MaintenanceWindow>,
+    last_fetch: Option<DateTime<Utc>>,
+}
+
+impl Maintenance {
+    pub fn new() -> Maintenance {
+        Maintenance::default()
+    }
+
+    /// Fetch the windows from `url` if they're due to be fetched again
+    fn refresh(&mut self, url: Option<&str>, now: DateTime<Utc>) {
+        let url = match url {
+            Some(url) => url,
+            None => return,
+        };
+        let due = self
+            .last_fetch
+            .map_or(true, |last| now - last >= Duration::minutes(REFRESH_MINUTES));
+        if !due {
+            return;
+        }
+
+        // Failures count as a fetch too, so a broken URL is only tried once an hour
+        self.last_fetch = Some(now);
+        match fetch(url) {
+            Ok(windows) => {
+                if windows.len() != self.fetched.len() {
+                    eprintln!("Fetched {} maintenance windows from {}", windows.len(), url);
+                }
+                self.fetched = windows;
+            }
+            Err(e) => eprintln!(
+                "WARNING: Unable to fetch maintenance windows from {}, keeping the {} known: {:#}",
+                url,
+                self.fetched.len(),
+                e
+            ),
+        }
+    }
+
+    /// The window `at` falls into, extended by any which overlap or touch it
+    pub fn active(
+        &mut self,
+        configured: &[MaintenanceWindow],
+        url: Option<&str>,
+        at: DateTime<Utc>,
+    ) -> Option<Span> {
+        self.refresh(url, Utc::now());
+        let windows: Vec<&MaintenanceWindow> = configured.iter().chain(&self.fetched).collect();
+        let find = |at| windows.iter().filter_map(|window| window.containing(at)).next();
+
+        let mut span = find(at)?;
+        // Bounded, in case weekly windows chain all the way around
+        for _ in 0..windows.len() {
+            match find(span.end) {
+                Some(next) if next.end > span.end => span.end = next.end,
+                _ => break,
+            }
+        }
+        Some(span)
+    }
+
+    /// When to post instead of `at`, if `at` falls into a window: just after the window, plus
+    /// up to `late_jitter` seconds
+    ///
+    /// The deferral is logged, with the window that caused it.
+    pub fn defer<R: Rng>(
+        &mut self,
+        configured: &[MaintenanceWindow],
+        url: Option<&str>,
+        at: DateTime<Utc>,
+        late_jitter: i64,
+        rng: &mut R,
+    ) -> Option<DateTime<Utc>> {
+        let span = self.active(configured, url, at)?;
+        let deferred = span.end + Duration::seconds(rng.gen_range(0, late_jitter.max(0) + 1));
+        eprintln!(
+            "{} falls within maintenance window {}, which ends at {}, putting posting off until {}",
+            at, span.label, span.end, deferred
+        );
+        Some(deferred)
+    }
+}
+
+/// Fetch windows from `url`, as JSON or iCalendar
+fn fetch(url: &str) -> Result<Vec<MaintenanceWindow>, Error> {
+    let body = build_info::http_client().get(url).send()?.error_for_status()?.text()?;
+    if body.trim_start().starts_with("BEGIN:VCALENDAR") {
+        return Ok(parse_ics(&body));
+    }
+    let windows: Vec<MaintenanceWindow> = serde_json::from_str(&body)?;
+    Ok(windows
+        .into_iter()
+        .filter(|window| match window.validate() {
+            Ok(()) => true,
+            Err(problem) => {
+                eprintln!("WARNING: Skipping a fetched maintenance window: {}", problem);
+                false
+            }
+        })
+        .collect())
+}
+
+/// The events of an iCalendar file as windows
+///
+/// Only UTC and floating times, taken as UTC, and whole days are understood. Events with a
+/// `TZID`, or without both a start and an end, are skipped with a warning.
+fn parse_ics(body: &str) -> Vec<MaintenanceWindow> {
+    // Long lines are folded by starting the following ones with a space
+    let mut lines: Vec<String> = Vec::new();
+    for line in body.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(&line[1..]);
+                continue;
+            }
+        }
+        lines.push(line.to_string());
+    }
+
+    let mut windows = Vec::new();
+    let mut event: Option<(Option<DateTime<Utc>>, Option<DateTime<Utc>>, Option<String>)> = None;
+    for line in &lines {
+        let (name, value) = match line.find(':') {
+            Some(colon) => (&line[..colon], &line[colon + 1..]),
+            None => continue,
+        };
+        let (property, params) = match name.find(';') {
+            Some(semicolon) => (&name[..semicolon], &name[semicolon + 1..]),
+            None => (name, ""),
+        };
+        match property {
+            "BEGIN" if value == "VEVENT" => event = Some((None, None, None)),
+            "END" if value == "VEVENT" => match event.take() {
+                Some((Some(start), Some(end), note)) => {
+                    let window = MaintenanceWindow::Once { start, end, note };
+                    match window.validate() {
+                        Ok(()) => windows.push(window),
+                        Err(problem) => eprintln!(
+                            "WARNING: Skipping a fetched maintenance window: {}",
+                            problem
+                        ),
+                    }
+                }
+                Some(_) => eprintln!("WARNING: Skipping a fetched event without a start or end"),
+                None => {}
+            },
+            "DTSTART" | "DTEND" => {
+                let event = match event {
+                    Some(ref mut event) => event,
+                    None => continue,
+                };
+                if params.contains("TZID=") {
+                    eprintln!("WARNING: Time zone names aren't supported, skipping {}", line);
+                    continue;
+                }
+                match parse_ics_time(value) {
+                    Some(time) if property == "DTSTART" => event.0 = Some(time),
+                    Some(time) => event.1 = Some(time),
+                    None => eprintln!("WARNING: Unable to parse {}", line),
+                }
+            }
+            "SUMMARY" => {
+                if let Some(ref mut event) = event {
+                    event.2 = Some(value.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    windows
+}
+
+/// Parse an iCalendar date or date and time, like `20261020T020000Z`
+fn parse_ics_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim_end_matches('Z');
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        return Some(Utc.from_utc_datetime(&time));
+    }
+    NaiveDate::parse_from_str(value, "%Y%m%d")
+        .ok()
+        .map(|date| Utc.from_utc_datetime(&date.and_hms(0, 0, 0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(input: &str) -> WeeklyWindow {
+        let window = WeeklyWindow::parse(input).expect("Invalid window");
+        window.validate().expect("Invalid window");
+        window
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2024, 5, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(
+            WeeklyWindow::parse("tue 03:00-04:30"),
+            Ok(WeeklyWindow {
+                start_day: Weekday::Tue,
+                start: NaiveTime::from_hms(3, 0, 0),
+                end_day: None,
+                end: NaiveTime::from_hms(4, 30, 0),
+                offset: FixedOffset::east(0),
+            })
+        );
+        // The last dash is the offset's, not the range's
+        assert_eq!(
+            WeeklyWindow::parse("sat 22:00-sun 02:00 -05:00"),
+            Ok(WeeklyWindow {
+                start_day: Weekday::Sat,
+                start: NaiveTime::from_hms(22, 0, 0),
+                end_day: Some(Weekday::Sun),
+                end: NaiveTime::from_hms(2, 0, 0),
+                offset: FixedOffset::west(5 * 3600),
+            })
+        );
+        assert_eq!(
+            WeeklyWindow::parse("tue 03:00 - 04:30 UTC").map(|window| window.end),
+            Ok(NaiveTime::from_hms(4, 30, 0))
+        );
+        assert_eq!(
+            WeeklyWindow::parse("Monday 01:00-02:00 +0530").map(|window| window.offset),
+            Ok(FixedOffset::east(5 * 3600 + 30 * 60))
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_windows() {
+        for input in &[
+            "",
+            "tue",
+            "tue 03:00",
+            "tue 03:00 -05:00",
+            "tue 25:00-26:00",
+            "xyz 03:00-04:00",
+            "tue 03:00-04:00 +24:00",
+            "tue 03:00-04:00 05:00",
+            "tue 03:00-wed",
+        ] {
+            assert!(WeeklyWindow::parse(input).is_err(), "{:?}", input);
+        }
+        // Parses, but lasts a whole week
+        let window = WeeklyWindow::parse("tue 03:00-tue 03:00").expect("Unable to parse");
+        assert!(window.validate().is_err());
+        // Unlike a window ending at the time it starts the next day
+        let window = WeeklyWindow::parse("tue 03:00-03:00").expect("Unable to parse");
+        assert_eq!(window.length(), Duration::hours(24));
+    }
+
+    #[test]
+    fn displays_what_it_parses() {
+        for input in &["tue 03:00-04:30", "sat 22:00-sun 02:00 -05:00", "fri 23:30-00:15 +01:00"] {
+            let window = window(input);
+            assert_eq!(WeeklyWindow::parse(&window.to_string()), Ok(window));
+        }
+    }
+
+    #[test]
+    fn parses_offsets() {
+        let cases: &[(&str, Option<i32>)] = &[
+            ("+02:00", Some(7200)),
+            ("-0530", Some(-19800)),
+            ("+01", Some(3600)),
+            ("-05:00", Some(-18000)),
+            ("UTC", Some(0)),
+            ("utc", Some(0)),
+            ("Z", Some(0)),
+            ("02:00", None),
+            ("+2", None),
+            ("+24:00", None),
+            ("+01:60", None),
+            ("+0a:00", None),
+        ];
+        for &(input, seconds) in cases {
+            assert_eq!(
+                parse_offset(input).ok().map(|offset| offset.local_minus_utc()),
+                seconds,
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn sunday_to_monday() {
+        // 2024-05-05 is a Sunday
+        let window = window("sun 23:00-mon 01:00");
+        assert_eq!(window.length(), Duration::hours(2));
+        assert_eq!(window.containing(at(5, 23, 30)), Some((at(5, 23, 0), at(6, 1, 0))));
+        // From the week before, running into this one
+        assert_eq!(window.containing(at(6, 0, 30)), Some((at(5, 23, 0), at(6, 1, 0))));
+        assert_eq!(window.containing(at(6, 1, 0)), None);
+        assert_eq!(window.containing(at(5, 22, 59)), None);
+    }
+
+    #[test]
+    fn end_before_start_ends_next_day() {
+        // 2024-05-10 is a Friday
+        let window = window("fri 23:30-00:15");
+        assert_eq!(window.length(), Duration::minutes(45));
+        assert_eq!(window.containing(at(11, 0, 10)), Some((at(10, 23, 30), at(11, 0, 15))));
+        assert_eq!(window.containing(at(11, 0, 15)), None);
+    }
+
+    #[test]
+    fn negative_offset_across_midnight() {
+        // Monday 22:00 to Tuesday 02:00 at -05:00 is Tuesday 03:00 to 07:00 UTC
+        let window = window("mon 22:00-02:00 -05:00");
+        let expected = Some((at(7, 3, 0), at(7, 7, 0)));
+        assert_eq!(window.containing(at(7, 4, 0)), expected);
+        // Tuesday locally as well
+        assert_eq!(window.containing(at(7, 6, 59)), expected);
+        assert_eq!(window.containing(at(7, 2, 59)), None);
+        assert_eq!(window.containing(at(7, 7, 0)), None);
+    }
+
+    #[test]
+    fn touching_windows_count_as_one() {
+        let configured = vec![
+            MaintenanceWindow::Once {
+                start: at(7, 10, 0),
+                end: at(7, 11, 0),
+                note: None,
+            },
+            MaintenanceWindow::Once {
+                start: at(7, 11, 0),
+                end: at(7, 12, 0),
+                note: Some("upgrade".to_string()),
+            },
+        ];
+        let mut maintenance = Maintenance::new();
+        let span = maintenance.active(&configured, None, at(7, 10, 30));
+        assert_eq!(span.map(|span| span.end), Some(at(7, 12, 0)));
+        let span = maintenance.active(&configured, None, at(7, 11, 30));
+        assert_eq!(span.map(|span| span.label), Some("upgrade".to_string()));
+        assert!(maintenance.active(&configured, None, at(7, 12, 0)).is_none());
+    }
+
+    #[test]
+    fn parses_icalendar() {
+        let body = "BEGIN:VCALENDAR\r\n\
+                    BEGIN:VEVENT\r\n\
+                    SUMMARY:Database\r\n  upgrade\r\n\
+                    DTSTART:20261020T020000Z\r\n\
+                    DTEND:20261020T040000Z\r\n\
+                    END:VEVENT\r\n\
+                    BEGIN:VEVENT\r\n\
+                    DTSTART;TZID=Europe/Berlin:20261021T020000\r\n\
+                    DTEND;TZID=Europe/Berlin:20261021T040000\r\n\
+                    END:VEVENT\r\n\
+                    BEGIN:VEVENT\r\n\
+                    DTSTART;VALUE=DATE:20261025\r\n\
+                    DTEND;VALUE=DATE:20261026\r\n\
+                    END:VEVENT\r\n\
+                    END:VCALENDAR\r\n";
+        let windows = parse_ics(body);
+        assert_eq!(windows.len(), 2);
+        match windows[0] {
+            MaintenanceWindow::Once {
+                start,
+                end,
+                ref note,
+            } => {
+                assert_eq!(start, Utc.ymd(2026, 10, 20).and_hms(2, 0, 0));
+                assert_eq!(end, Utc.ymd(2026, 10, 20).and_hms(4, 0, 0));
+                assert_eq!(note.as_ref().map(String::as_str), Some("Database upgrade"));
+            }
+            ref other => panic!("Unexpected window {:?}", other),
+        }
+        match windows[1] {
+            MaintenanceWindow::Once { start, end, .. } => {
+                assert_eq!(start, Utc.ymd(2026, 10, 25).and_hms(0, 0, 0));
+                assert_eq!(end, Utc.ymd(2026, 10, 26).and_hms(0, 0, 0));
+            }
+            ref other => panic!("Unexpected window {:?}", other),
+        }
+    }
+}
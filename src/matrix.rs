@@ -0,0 +1,224 @@
+//! Matrix backend
+//!
+//! Uploads the image to the homeserver's media repository, then sends an `m.image` event
+//! referencing it to the configured room. Encrypted rooms are not supported.
+//!
+//! The event is sent with the image's idempotency key as its transaction id, which is kept in
+//! the state file with the uploaded image's URI. A retry after an attempt that went through
+//! without us hearing back therefore sends the same transaction again, and the homeserver
+//! answers with the event it already sent instead of sending another.
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, StatusCode};
+
+use build_info;
+use errors::PostingError;
+use posting::{image_dimensions, new_idempotency_key, parse_response, Post, Poster, Progress};
+
+const BACKEND_NAME: &str = "matrix";
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct MatrixConfig {
+    /// Base URL of the homeserver, e.g. `https://matrix.org`
+    pub homeserver: String,
+    pub access_token: String,
+    /// Room to post to, e.g. `!abcdefg:matrix.org`
+    pub room_id: String,
+}
+
+#[derive(Deserialize)]
+struct Uploaded {
+    content_uri: String,
+}
+
+#[derive(Serialize)]
+struct ImageMessage<'a> {
+    msgtype: &'a str,
+    body: &'a str,
+    url: &'a str,
+    info: ImageInfo<'a>,
+}
+
+#[derive(Serialize)]
+struct ImageInfo<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    w: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    h: Option<u32>,
+    size: usize,
+    mimetype: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Sent {
+    event_id: String,
+}
+
+pub struct MatrixPoster {
+    config: MatrixConfig,
+    client: Client,
+}
+
+impl MatrixPoster {
+    pub fn new(config: MatrixConfig) -> MatrixPoster {
+        MatrixPoster {
+            config,
+            client: build_info::http_client(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.homeserver.trim_end_matches('/'), path)
+    }
+
+    fn room_url(&self, path: &str) -> String {
+        self.url(&format!(
+            "/_matrix/client/v3/rooms/{}{}",
+            percent_encode(&self.config.room_id),
+            path
+        ))
+    }
+
+    /// Fail if the room has encryption enabled, since we would be sending plaintext events
+    fn check_unencrypted(&self) -> Result<(), PostingError> {
+        let response = self
+            .client
+            .get(&self.room_url("/state/m.room.encryption"))
+            .bearer_auth(&self.config.access_token)
+            .send()?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            status if status.is_success() => Err(PostingError::Rejected {
+                backend: BACKEND_NAME.to_string(),
+                status: status.as_u16(),
+                message: format!(
+                    "room {} is end-to-end encrypted, which is not supported",
+                    self.config.room_id
+                ),
+            }),
+            _ => parse_response::<::serde_json::Value>(BACKEND_NAME, response).map(|_| ()),
+        }
+    }
+}
+
+impl Poster for MatrixPoster {
+    fn name(&self) -> &str {
+        BACKEND_NAME
+    }
+
+    fn post(&self, post: &Post, progress: &mut Progress) -> Result<(), PostingError> {
+        self.check_unencrypted()?;
+
+        let content_uri = match progress.media_id {
+            Some(ref content_uri) => content_uri.clone(),
+            None => {
+                let filename = format!("{}.{}", post.id, post.format.extension());
+                let response = self
+                    .client
+                    .post(&self.url(&format!(
+                        "/_matrix/media/v3/upload?filename={}",
+                        percent_encode(&filename)
+                    ))).bearer_auth(&self.config.access_token)
+                    .header(CONTENT_TYPE, post.format.mimetype())
+                    .body(post.image.to_vec())
+                    .send()?;
+                let uploaded: Uploaded = parse_response(BACKEND_NAME, response)?;
+                progress.media_id = Some(uploaded.content_uri.clone());
+                uploaded.content_uri
+            }
+        };
+
+        let dimensions = image_dimensions(&post.image);
+        let key = progress
+            .idempotency_key
+            .get_or_insert_with(new_idempotency_key);
+        let transaction_id = format!("cubeglobe-{}-{}", post.id, key);
+        let response = self
+            .client
+            .put(&self.room_url(&format!("/send/m.room.message/{}", transaction_id)))
+            .bearer_auth(&self.config.access_token)
+            .json(&ImageMessage {
+                msgtype: "m.image",
+                body: &post.alt_text,
+                url: &content_uri,
+                info: ImageInfo {
+                    w: dimensions.map(|(w, _)| w),
+                    h: dimensions.map(|(_, h)| h),
+                    size: post.image.len(),
+                    mimetype: post.format.mimetype(),
+                },
+            }).send()?;
+        let sent: Sent = parse_response(BACKEND_NAME, response)?;
+
+        eprintln!(
+            "New Matrix event sent to {}: {}",
+            self.config.room_id, sent.event_id
+        );
+
+        Ok(())
+    }
+}
+
+/// Percent-encode everything but unreserved characters, for use in a URL path or query
+fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{self, MockServer};
+    use toml;
+
+    #[test]
+    fn retries_send_the_same_transaction() {
+        let server = MockServer::start(vec![
+            (404, "{}".to_string()),
+            (200, "{\"content_uri\":\"mxc://example.org/abc\"}".to_string()),
+            (502, "{}".to_string()),
+            (404, "{}".to_string()),
+            (200, "{\"event_id\":\"$event\"}".to_string()),
+        ]);
+        let poster = MatrixPoster::new(MatrixConfig {
+            homeserver: format!("{}/", server.url),
+            access_token: "token".to_string(),
+            room_id: "!room:example.org".to_string(),
+        });
+        let post = test_support::post(3);
+
+        let mut progress = Progress::default();
+        assert!(poster.post(&post, &mut progress).is_err());
+        // Kept in the state file in between, as across a restart
+        let saved = toml::to_string(&progress).expect("Unable to serialize progress");
+        let mut progress: Progress = toml::from_str(&saved).expect("Unable to read progress");
+        poster.post(&post, &mut progress).expect("Retry failed");
+
+        let requests = server.requests();
+        let sends: Vec<&str> = requests
+            .iter()
+            .filter(|request| request.method == "PUT")
+            .map(|request| request.path.as_str())
+            .collect();
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[0], sends[1]);
+        assert!(sends[0].contains("/send/m.room.message/cubeglobe-3-"));
+
+        // The image was only uploaded once
+        let uploads = requests
+            .iter()
+            .filter(|request| request.path.contains("/media/v3/upload"))
+            .count();
+        assert_eq!(uploads, 1);
+        assert!(String::from_utf8_lossy(&requests[4].body).contains("mxc://example.org/abc"));
+    }
+}
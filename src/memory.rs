@@ -0,0 +1,110 @@
+//! Watching the bot's memory use, to tell a leak from a peak
+//!
+//! `--memory-report N` logs the resident set size, as the kernel gives it in `/proc/self/status`,
+//! at the start of each of the first N cycles and after each stage of making and posting an
+//! image, with the change since the last line and since the first cycle started. Memory that's
+//! given back at the end of each cycle shows up as a peak; memory that isn't shows up as the
+//! start of each cycle creeping up. Every line starts with `MEMORY:`.
+//!
+//! The renderer is set up once and kept for the whole run, and only set up again when a reload
+//! changes the tiles config, so it isn't among what each cycle allocates. Where there's no
+//! `/proc`, nothing is logged.
+
+use std::fs::read_to_string;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Resident set size of the bot, in KiB, if it can be found out
+pub fn rss_kib() -> Option<u64> {
+    parse_rss(&read_to_string("/proc/self/status").ok()?)
+}
+
+/// Resident set size in `status`, the contents of `/proc/PID/status`
+fn parse_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line["VmRSS:".len()..].trim().trim_end_matches("kB").trim().parse().ok()
+}
+
+/// Memory use so far, for the first `cycles` cycles
+#[derive(Debug)]
+pub struct MemoryReport {
+    cycles: usize,
+    /// Cycles started so far
+    cycle: AtomicUsize,
+    /// Size at the start of the first cycle, and at the last line logged
+    sizes: Mutex<Option<(u64, u64)>>,
+}
+
+impl MemoryReport {
+    pub fn new(cycles: usize) -> MemoryReport {
+        MemoryReport {
+            cycles,
+            cycle: AtomicUsize::new(0),
+            sizes: Mutex::new(None),
+        }
+    }
+
+    /// Log the size as a new cycle starts
+    pub fn cycle_started(&self) {
+        self.cycle.fetch_add(1, Ordering::SeqCst);
+        self.log("cycle start");
+    }
+
+    /// Log the size after `stage` of the current cycle
+    pub fn stage(&self, stage: &str) {
+        self.log(stage);
+    }
+
+    fn log(&self, stage: &str) {
+        let cycle = self.cycle.load(Ordering::SeqCst);
+        if cycle == 0 || cycle > self.cycles {
+            return;
+        }
+        let rss = match rss_kib() {
+            Some(rss) => rss,
+            None => return,
+        };
+        let mut sizes = self.sizes.lock().expect("memory report lock poisoned");
+        let (first, last) = sizes.unwrap_or((rss, rss));
+        eprintln!(
+            "MEMORY: cycle {}/{}, {}: {} KiB ({:+} KiB since the last line, {:+} KiB since the \
+             first cycle started)",
+            cycle,
+            self.cycles,
+            stage,
+            rss,
+            rss as i64 - last as i64,
+            rss as i64 - first as i64
+        );
+        *sizes = Some((first, rss));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rss() {
+        let status = "Name:\tcubeglobe-bot\nVmPeak:\t  200000 kB\nVmRSS:\t   51234 kB\n";
+        assert_eq!(parse_rss(status), Some(51234));
+        assert_eq!(parse_rss("Name:\tcubeglobe-bot\n"), None);
+        assert_eq!(parse_rss("VmRSS:\tlots\n"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reports_only_the_first_cycles() {
+        let report = MemoryReport::new(1);
+        report.stage("before any cycle");
+        assert!(report.sizes.lock().expect("memory report lock poisoned").is_none());
+
+        report.cycle_started();
+        let first = *report.sizes.lock().expect("memory report lock poisoned");
+        assert!(first.is_some());
+
+        report.cycle_started();
+        report.stage("rendered");
+        assert_eq!(*report.sizes.lock().expect("memory report lock poisoned"), first);
+    }
+}
@@ -0,0 +1,667 @@
+//! Moving the bot to another host
+//!
+//! `cubeglobe-bot export --output bundle.tar.zst` packs up what the bot needs to carry on where
+//! it left off: the state file and its journal, the pending image with the files next to it and
+//! its heightmap, and the records kept next to posted images, like `.media.toml` and
+//! `.attempts.toml`, from the images directory and its stale and quarantine directories. With
+//! `--include-archive`, everything in the images directory goes in. The bundle is a
+//! zstd-compressed tarball, starting with a `manifest.toml` which has the version of the bot that
+//! made it, the id and time of the last post, and the size and SHA-256 of every file.
+//!
+//! `cubeglobe-bot import bundle.tar.zst`, run on the new host with its own config, checks every
+//! file against the manifest, and the state file against what this version can read, before
+//! anything is put in place, and then puts the files where that config has them. It refuses to
+//! import over a state file or images which are already there, unless given `--force`. The state
+//! file only names images relative to the images directory, so it needs no changes for a new
+//! layout, and the bot picks the schedule and ids up from it as if it had never moved.
+//!
+//! The config, with its credentials, isn't in the bundle. The bot should be stopped on both
+//! hosts while this runs.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, read, read_dir, read_to_string, remove_dir_all, remove_file};
+use std::fs::{rename, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Error};
+use chrono::{DateTime, Utc};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use tar;
+use toml;
+use zstd;
+
+use archive::{self, Kind};
+use build_info;
+use integrity::Fingerprint;
+use journal;
+use {BotConfig, State, StatePaths, HEIGHTMAPS_DIR, QUARANTINE_DIR, STALE_DIR};
+
+/// Version of the bundle layout, raised whenever older versions of the bot couldn't import it
+const FORMAT: u32 = 1;
+const MANIFEST: &str = "manifest.toml";
+/// Names of the state file and its journal in a bundle, and of the directory images go under
+const STATE: &str = "state.toml";
+const JOURNAL: &str = "state.journal.toml";
+const IMAGES: &str = "images";
+/// Directory in the images directory imported files wait in until every one has been checked
+const STAGING_DIR: &str = ".import";
+const ZSTD_LEVEL: i32 = 9;
+
+#[derive(Deserialize, Serialize)]
+struct Manifest {
+    format: u32,
+    version: String,
+    git_hash: String,
+    exported_at: DateTime<Utc>,
+    /// Where the state file and images were on the host the bundle was made on
+    state_path: PathBuf,
+    images_dir: PathBuf,
+    /// Id of the next image, and when the last post was made, as the state file has them
+    next_id: u32,
+    #[serde(default)]
+    last_post: Option<DateTime<Utc>>,
+    /// Whether every image is in the bundle, or only the pending one
+    archive: bool,
+    /// Every other file in the bundle, by name
+    files: BTreeMap<String, Fingerprint>,
+}
+
+/// What an import found in a bundle, once checked
+struct Unpacked {
+    manifest: Manifest,
+    state: String,
+    journal: Option<Vec<u8>>,
+    /// Images directory files, relative to it, waiting in the staging directory
+    images: Vec<PathBuf>,
+}
+
+pub fn export_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("export")
+        .about("pack the state file and images into a bundle, for moving the bot to another host")
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("FILE")
+                .required(true)
+                .help("file to write the bundle to, like bundle.tar.zst"),
+        ).arg(
+            Arg::with_name("includearchive")
+                .long("include-archive")
+                .help("include every image, not only the pending one and the others' records"),
+        )
+}
+
+pub fn import_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("import")
+        .about("put the state file and images from a bundle made by export where the config says")
+        .arg(
+            Arg::with_name("bundle")
+                .value_name("BUNDLE")
+                .required(true)
+                .help("bundle to import"),
+        ).arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("import even over a state file or images which are already there"),
+        )
+}
+
+/// Every file under `dir`, named as in a bundle under `prefix`, leaving out unfinished writes
+fn walk(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) -> Result<(), Error> {
+    let entries = read_dir(dir).with_context(|| format!("unable to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let bundled = format!("{}/{}", prefix, name);
+        if path.is_dir() {
+            if name != STAGING_DIR {
+                walk(&path, &bundled, files)?;
+            }
+        } else if path.is_file() && !name.ends_with(".tmp") && !name.starts_with(".render-") {
+            files.push((bundled, path));
+        }
+    }
+    Ok(())
+}
+
+/// The pending image and the files next to it, and the records of the others
+fn records(
+    paths: &StatePaths,
+    config: &BotConfig,
+    state: &State,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), Error> {
+    let template = config.filename_template();
+    let pending_stem = state
+        .filename
+        .as_ref()
+        .and_then(|filename| Some(Path::new(filename).file_stem()?.to_str()?.to_string()));
+    for dir in &["", STALE_DIR, QUARANTINE_DIR] {
+        let full_dir = paths.images.join(dir);
+        let entries = archive::scan(&full_dir, &template)
+            .with_context(|| format!("unable to read {}", full_dir.display()))?;
+        for entry in entries {
+            let record = entry.name.ends_with(".toml")
+                && (entry.kind == Kind::Sidecar || entry.kind == Kind::Orphan);
+            let pending = dir.is_empty()
+                && pending_stem.as_ref().map_or(false, |stem| {
+                    Some(&entry.name) == state.filename.as_ref()
+                        || entry.name.starts_with(&format!("{}.", stem))
+                });
+            if record || pending {
+                let bundled = if dir.is_empty() {
+                    format!("{}/{}", IMAGES, entry.name)
+                } else {
+                    format!("{}/{}/{}", IMAGES, dir, entry.name)
+                };
+                files.push((bundled, full_dir.join(&entry.name)));
+            }
+        }
+    }
+
+    if let Some(ref heightmap) = state.heightmap {
+        let path = paths.images.join(HEIGHTMAPS_DIR).join(heightmap);
+        if path.is_file() {
+            files.push((format!("{}/{}/{}", IMAGES, HEIGHTMAPS_DIR, heightmap), path));
+        }
+    }
+    Ok(())
+}
+
+/// Write a bundle of the bot's state and images to the file asked for
+pub fn export(matches: &ArgMatches, config: &BotConfig) -> Result<(), Error> {
+    let output = Path::new(matches.value_of("output").expect("output is required"));
+    export_to(config, output, matches.is_present("includearchive"))
+}
+
+/// Write a bundle of the bot's state and images to `output`, with every image if
+/// `include_archive`
+fn export_to(config: &BotConfig, output: &Path, include_archive: bool) -> Result<(), Error> {
+    let paths = StatePaths::from_config(config);
+    let state_text = read_to_string(&paths.state)
+        .with_context(|| format!("unable to read {}", paths.state.display()))?;
+    let state: State = toml::from_str(&state_text).context("unable to read the state file")?;
+
+    let mut files = vec![(STATE.to_string(), paths.state.clone())];
+    let journal = journal::path(&paths.state);
+    if journal.is_file() {
+        files.push((JOURNAL.to_string(), journal));
+    }
+    if include_archive {
+        if paths.images.is_dir() {
+            walk(&paths.images, IMAGES, &mut files)?;
+        }
+    } else {
+        records(&paths, config, &state, &mut files)?;
+    }
+
+    let mut fingerprints = BTreeMap::new();
+    for &(ref name, ref path) in &files {
+        let data = read(path).with_context(|| format!("unable to read {}", path.display()))?;
+        fingerprints.insert(name.clone(), Fingerprint::of(&data));
+    }
+    let manifest = Manifest {
+        format: FORMAT,
+        version: build_info::VERSION.to_string(),
+        git_hash: build_info::GIT_HASH.to_string(),
+        exported_at: Utc::now(),
+        state_path: paths.state.clone(),
+        images_dir: paths.images.clone(),
+        next_id: state.id,
+        last_post: state.last_post,
+        archive: include_archive,
+        files: fingerprints,
+    };
+    let manifest_text = toml::to_string(&manifest)?;
+
+    let outfile =
+        File::create(output).with_context(|| format!("unable to create {}", output.display()))?;
+    let mut builder = tar::Builder::new(zstd::Encoder::new(outfile, ZSTD_LEVEL)?);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_text.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.exported_at.timestamp() as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, manifest_text.as_bytes())?;
+    for &(ref name, ref path) in &files {
+        builder
+            .append_path_with_name(path, name)
+            .with_context(|| format!("unable to add {} to the bundle", path.display()))?;
+    }
+    builder.into_inner()?.finish()?;
+
+    let bytes: u64 = manifest.files.values().map(|fingerprint| fingerprint.bytes).sum();
+    eprintln!(
+        "Exported {} files ({} bytes) to {}, with image {} next",
+        files.len(),
+        bytes,
+        output.display(),
+        state.id
+    );
+    Ok(())
+}
+
+/// Whether the bot already has files where `paths` has them
+fn deployed(paths: &StatePaths, config: &BotConfig) -> Result<bool, Error> {
+    if paths.state.exists() {
+        return Ok(true);
+    }
+    let entries = archive::scan(&paths.images, &config.filename_template())?;
+    Ok(entries.iter().any(|entry| entry.kind != Kind::Foreign && entry.kind != Kind::Temp))
+}
+
+/// Where `name`, a file under `images/` in a bundle, goes, relative to the images directory
+///
+/// Anything which would end up outside of it is refused.
+fn image_path(name: &str) -> Result<PathBuf, Error> {
+    let unexpected = || Error::msg(format!("{} isn't a file a bundle should have", name));
+    let relative = Path::new(name).strip_prefix(IMAGES).map_err(|_| unexpected())?;
+    let normal = relative.components().all(|component| match component {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    if relative.as_os_str().is_empty() || !normal {
+        return Err(unexpected());
+    }
+    Ok(relative.to_path_buf())
+}
+
+/// Read every file in `bundle`, checking it against the manifest, with images going into
+/// `staging`
+fn unpack<R: Read>(bundle: &mut tar::Archive<R>, staging: &Path) -> Result<Unpacked, Error> {
+    let mut manifest: Option<Manifest> = None;
+    let mut state = None;
+    let mut journal = None;
+    let mut images = Vec::new();
+    let mut seen = BTreeSet::new();
+
+    for entry in bundle.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+
+        if name == MANIFEST {
+            let read: Manifest = toml::from_str(&String::from_utf8(data)?)
+                .context("unable to read the bundle's manifest")?;
+            if read.format > FORMAT {
+                return Err(Error::msg(format!(
+                    "the bundle was made by version {}, in a format this version can't import",
+                    read.version
+                )));
+            }
+            manifest = Some(read);
+            continue;
+        }
+        let expected = manifest
+            .as_ref()
+            .ok_or_else(|| Error::msg("the bundle doesn't start with a manifest"))?
+            .files
+            .get(&name)
+            .ok_or_else(|| Error::msg(format!("{} in the bundle isn't in its manifest", name)))?;
+        if Fingerprint::of(&data) != *expected {
+            return Err(Error::msg(format!("{} doesn't match its checksum in the manifest", name)));
+        }
+        seen.insert(name.clone());
+
+        match name.as_str() {
+            STATE => state = Some(String::from_utf8(data)?),
+            JOURNAL => journal = Some(data),
+            _ => {
+                let relative = image_path(&name)?;
+                let path = staging.join(&relative);
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)?;
+                }
+                File::create(&path)?.write_all(&data)?;
+                images.push(relative);
+            }
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| Error::msg("the bundle has no manifest"))?;
+    if let Some(missing) = manifest.files.keys().find(|name| !seen.contains(*name)) {
+        return Err(Error::msg(format!("{} is in the manifest, but not in the bundle", missing)));
+    }
+    let state = state.ok_or_else(|| Error::msg("the bundle has no state file"))?;
+    Ok(Unpacked {
+        manifest,
+        state,
+        journal,
+        images,
+    })
+}
+
+/// Put the files from the bundle asked for where `config` has them
+pub fn import(matches: &ArgMatches, config: &BotConfig) -> Result<(), Error> {
+    let bundle = Path::new(matches.value_of("bundle").expect("bundle is required"));
+    import_from(config, bundle, matches.is_present("force"))
+}
+
+/// Put the files from `bundle` where `config` has them, even over what's there with `force`
+fn import_from(config: &BotConfig, bundle: &Path, force: bool) -> Result<(), Error> {
+    let paths = StatePaths::from_config(config);
+    if !force && deployed(&paths, config)? {
+        return Err(Error::msg(format!(
+            "{} or {} already has the bot's files, pass --force to import over them",
+            paths.state.display(),
+            paths.images.display()
+        )));
+    }
+
+    let infile =
+        File::open(bundle).with_context(|| format!("unable to open {}", bundle.display()))?;
+    let staging = paths.images.join(STAGING_DIR);
+    if staging.exists() {
+        remove_dir_all(&staging)?;
+    }
+    create_dir_all(&staging)
+        .with_context(|| format!("unable to create {}", staging.display()))?;
+    let unpacked = zstd::Decoder::new(infile)
+        .map_err(Error::from)
+        .and_then(|decoder| unpack(&mut tar::Archive::new(decoder), &staging))
+        .and_then(|unpacked| {
+            let state: State = toml::from_str(&unpacked.state)
+                .context("the bundle's state file can't be read by this version")?;
+            if state.id != unpacked.manifest.next_id
+                || state.last_post != unpacked.manifest.last_post
+            {
+                return Err(Error::msg("the bundle's state file doesn't match its manifest"));
+            }
+            Ok(unpacked)
+        });
+    let unpacked = match unpacked {
+        Ok(unpacked) => unpacked,
+        Err(e) => {
+            let _ = remove_dir_all(&staging);
+            return Err(e.context(format!("unable to import {}", bundle.display())));
+        }
+    };
+
+    let manifest = &unpacked.manifest;
+    if manifest.version != build_info::VERSION {
+        eprintln!(
+            "The bundle was made by version {}, and is being imported by {}",
+            manifest.version,
+            build_info::VERSION
+        );
+    }
+    for relative in &unpacked.images {
+        let to = paths.images.join(relative);
+        if let Some(parent) = to.parent() {
+            create_dir_all(parent)?;
+        }
+        rename(staging.join(relative), &to)
+            .with_context(|| format!("unable to move {} into place", to.display()))?;
+    }
+    remove_dir_all(&staging)?;
+
+    // The state file goes last, so an import cut short doesn't leave a bot that would start
+    if let Some(parent) = paths.state.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        create_dir_all(parent)?;
+    }
+    let journal_path = journal::path(&paths.state);
+    match unpacked.journal {
+        Some(ref journal) => journal::write_atomically(&journal_path, journal)?,
+        // One left over here would be replayed onto the imported state
+        None if journal_path.exists() => remove_file(&journal_path)?,
+        None => {}
+    }
+    journal::write_atomically(&paths.state, unpacked.state.as_bytes())?;
+
+    eprintln!(
+        "Imported {} files from {}, exported from {} on {}, with image {} next",
+        manifest.files.len(),
+        bundle.display(),
+        manifest.state_path.display(),
+        manifest.exported_at,
+        manifest.next_id
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use std::io::Cursor;
+    use tempfile::{tempdir, TempDir};
+
+    const STATE_TEXT: &str = "id = 5\n\
+                              phase = \"Generated\"\n\
+                              filename = \"5.png\"\n\
+                              last_post = \"2024-05-01T12:00:00Z\"\n";
+
+    /// Settings keeping the state file and images in `dir`
+    fn config_in(dir: &Path) -> BotConfig {
+        toml::from_str(&format!(
+            "map_size = 16\nimages_dir = '{}'\nstate_path = '{}'\n",
+            dir.join("images").display(),
+            dir.join("state").display()
+        )).expect("Invalid config")
+    }
+
+    /// A bot which posted images 3 and 4, set 3 aside as stale, and has image 5 pending
+    fn deployment() -> (TempDir, BotConfig) {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let images = dir.path().join("images");
+        create_dir_all(images.join(STALE_DIR)).expect("Unable to create images directory");
+        let files: &[(&str, &[u8])] = &[
+            ("state", STATE_TEXT.as_bytes()),
+            ("images/4.png", b"posted image"),
+            ("images/4.media.toml", b"record = true\n"),
+            ("images/5.png", b"pending image"),
+            ("images/5.attempts.toml", b"attempts = []\n"),
+            ("images/stale/3.png", b"stale image"),
+            ("images/stale/3.attempts.toml", b"attempts = []\n"),
+            ("images/notes.txt", b"someone else's file"),
+        ];
+        for &(name, data) in files {
+            write(dir.path().join(name), data).expect("Unable to write file");
+        }
+        let config = config_in(dir.path());
+        (dir, config)
+    }
+
+    /// An uncompressed bundle with `manifest` and `files`
+    fn bundle(manifest: &Manifest, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let manifest = toml::to_string(manifest).expect("Unable to serialize manifest");
+        let entries = Some((MANIFEST, manifest.as_bytes()))
+            .into_iter()
+            .chain(files.iter().cloned());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, data)
+                .expect("Unable to add file");
+        }
+        builder.into_inner().expect("Unable to finish bundle")
+    }
+
+    /// A manifest listing `files`
+    fn manifest(files: &[(&str, &[u8])]) -> Manifest {
+        Manifest {
+            format: FORMAT,
+            version: build_info::VERSION.to_string(),
+            git_hash: build_info::GIT_HASH.to_string(),
+            exported_at: Utc::now(),
+            state_path: PathBuf::from("state"),
+            images_dir: PathBuf::from("images"),
+            next_id: 5,
+            last_post: None,
+            archive: false,
+            files: files
+                .iter()
+                .map(|&(name, data)| (name.to_string(), Fingerprint::of(data)))
+                .collect(),
+        }
+    }
+
+    fn unpack_bytes(bundle: Vec<u8>) -> Result<Unpacked, Error> {
+        let staging = tempdir().expect("Unable to create temporary directory");
+        unpack(&mut tar::Archive::new(Cursor::new(bundle)), staging.path())
+    }
+
+    /// Why unpacking `bundle` failed
+    fn unpack_error(bundle: Vec<u8>) -> String {
+        match unpack_bytes(bundle) {
+            Ok(_) => panic!("Unpacked a bad bundle"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn export_then_import_into_empty_directory() {
+        let (from, config) = deployment();
+        let output = from.path().join("bundle.tar.zst");
+        export_to(&config, &output, false).expect("Unable to export");
+
+        let to = tempdir().expect("Unable to create temporary directory");
+        let new_config = config_in(to.path());
+        import_from(&new_config, &output, false).expect("Unable to import");
+
+        let images = to.path().join("images");
+        assert_eq!(read_to_string(to.path().join("state")).unwrap(), STATE_TEXT);
+        assert_eq!(read(images.join("5.png")).unwrap(), b"pending image");
+        assert!(images.join("5.attempts.toml").exists());
+        assert!(images.join("4.media.toml").exists());
+        assert!(images.join(STALE_DIR).join("3.attempts.toml").exists());
+        // Only the pending image and the records of the others
+        assert!(!images.join("4.png").exists());
+        assert!(!images.join(STALE_DIR).join("3.png").exists());
+        assert!(!images.join("notes.txt").exists());
+        assert!(!images.join(STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn export_with_archive_has_every_image() {
+        let (from, config) = deployment();
+        let output = from.path().join("bundle.tar.zst");
+        export_to(&config, &output, true).expect("Unable to export");
+
+        let to = tempdir().expect("Unable to create temporary directory");
+        import_from(&config_in(to.path()), &output, false).expect("Unable to import");
+        let images = to.path().join("images");
+        assert_eq!(read(images.join("4.png")).unwrap(), b"posted image");
+        assert!(images.join(STALE_DIR).join("3.png").exists());
+        assert!(images.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn import_refuses_to_overwrite_without_force() {
+        let (from, config) = deployment();
+        let output = from.path().join("bundle.tar.zst");
+        export_to(&config, &output, false).expect("Unable to export");
+
+        let (_to, existing) = deployment();
+        write(&existing.state_path(), "id = 9\n").expect("Unable to write state");
+        let error = import_from(&existing, &output, false).unwrap_err();
+        assert!(error.to_string().contains("--force"));
+        assert_eq!(read_to_string(existing.state_path()).unwrap(), "id = 9\n");
+
+        import_from(&existing, &output, true).expect("Unable to import with force");
+        assert_eq!(read_to_string(existing.state_path()).unwrap(), STATE_TEXT);
+    }
+
+    #[test]
+    fn import_leaves_nothing_behind_when_refused() {
+        // The second image was damaged on the way, after the first was unpacked
+        let listed: &[(&str, &[u8])] = &[
+            (STATE, STATE_TEXT.as_bytes()),
+            ("images/5.png", b"pending image"),
+            ("images/5.attempts.toml", b"attempts = []\n"),
+        ];
+        let damaged: &[(&str, &[u8])] = &[
+            (STATE, STATE_TEXT.as_bytes()),
+            ("images/5.png", b"pending image"),
+            ("images/5.attempts.toml", b"attempts = [\n"),
+        ];
+        let data = zstd::stream::encode_all(&bundle(&manifest(listed), damaged)[..], 0)
+            .expect("Unable to compress bundle");
+        let from = tempdir().expect("Unable to create temporary directory");
+        let output = from.path().join("bundle.tar.zst");
+        write(&output, &data).expect("Unable to write bundle");
+
+        let to = tempdir().expect("Unable to create temporary directory");
+        let error = import_from(&config_in(to.path()), &output, false).unwrap_err();
+        assert!(format!("{:#}", error).contains("doesn't match its checksum"));
+        assert!(!to.path().join("state").exists());
+        assert!(!to.path().join("images").join("5.png").exists());
+        assert!(!to.path().join("images").join(STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn unpack_checks_checksums() {
+        let files: &[(&str, &[u8])] = &[(STATE, b"id = 5\n"), ("images/5.png", b"image")];
+        let unpacked = unpack_bytes(bundle(&manifest(files), files)).expect("Unable to unpack");
+        assert_eq!(unpacked.state, "id = 5\n");
+        assert_eq!(unpacked.images, vec![PathBuf::from("5.png")]);
+
+        let tampered: &[(&str, &[u8])] = &[(STATE, b"id = 5\n"), ("images/5.png", b"other")];
+        let error = unpack_error(bundle(&manifest(files), tampered));
+        assert!(error.contains("doesn't match its checksum"));
+    }
+
+    #[test]
+    fn unpack_checks_manifest_entries() {
+        let files: &[(&str, &[u8])] = &[(STATE, b"id = 5\n"), ("images/5.png", b"image")];
+
+        // Listed, but not there
+        let error = unpack_error(bundle(&manifest(files), &files[..1]));
+        assert!(error.contains("not in the bundle"));
+
+        // There, but not listed
+        let error = unpack_error(bundle(&manifest(&files[..1]), files));
+        assert!(error.contains("isn't in its manifest"));
+
+        // No manifest first
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(7);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, STATE, &b"id = 5\n"[..]).unwrap();
+        let error = unpack_error(builder.into_inner().unwrap());
+        assert!(error.contains("manifest"));
+    }
+
+    #[test]
+    fn unpack_refuses_newer_formats() {
+        let files: &[(&str, &[u8])] = &[(STATE, b"id = 5\n")];
+        let mut newer = manifest(files);
+        newer.format = FORMAT + 1;
+        newer.version = "99.0.0".to_string();
+        let error = unpack_error(bundle(&newer, files));
+        assert!(error.contains("made by version 99.0.0"));
+    }
+
+    #[test]
+    fn image_path_refuses_traversal() {
+        assert_eq!(image_path("images/5.png").unwrap(), PathBuf::from("5.png"));
+        assert_eq!(
+            image_path("images/stale/3.png").unwrap(),
+            PathBuf::from("stale").join("3.png")
+        );
+        for name in &[
+            "images",
+            "images/",
+            "images/../state",
+            "images/stale/../../state",
+            "../images/5.png",
+            "/images/5.png",
+            "imagesx/5.png",
+            "5.png",
+        ] {
+            assert!(image_path(name).is_err(), "{:?}", name);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Special treatment for round-numbered images
+//!
+//! With `[bot.milestones]`, images whose id is listed in `ids`, or is a multiple of `every`, are
+//! milestones. They can get their own post body, like "This is landscape number {id}!", and a
+//! bigger map, and are posted publicly even when `boost_after_minutes` has other posts go out
+//! unlisted. That goes for every account and backend they're posted to.
+//!
+//! Whether an image is a milestone is decided when it's generated and kept in the state file,
+//! like its locale, so retries after a restart treat it the same.
+
+/// Which images are milestones, and what's different about them
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MilestoneConfig {
+    /// Ids of the images which are milestones
+    #[serde(default)]
+    pub ids: Vec<u32>,
+
+    /// Every image whose id is a multiple of this is a milestone
+    #[serde(default)]
+    pub every: Option<u32>,
+
+    /// Post body template used instead of `body`, which may also use {id}
+    #[serde(default)]
+    pub body: Option<String>,
+
+    /// Map size used instead of `map_size`
+    #[serde(default)]
+    pub map_size: Option<usize>,
+
+    /// Post publicly even with `boost_after_minutes`
+    #[serde(default = "default_public")]
+    pub public: bool,
+}
+
+fn default_public() -> bool {
+    true
+}
+
+impl MilestoneConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ids.is_empty() && self.every.is_none() {
+            return Err("needs ids or every".to_string());
+        }
+        if self.every == Some(0) {
+            return Err("every must be more than 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the image numbered `id` is a milestone
+    pub fn is_milestone(&self, id: u32) -> bool {
+        self.ids.contains(&id) || self.every.map_or(false, |every| every > 0 && id % every == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    fn milestones(toml: &str) -> MilestoneConfig {
+        toml::from_str(toml).expect("Unable to parse milestones")
+    }
+
+    #[test]
+    fn picks_listed_ids_and_multiples() {
+        let config = milestones("ids = [1, 42]\nevery = 100");
+        let cases: &[(u32, bool)] = &[
+            (1, true),
+            (2, false),
+            (42, true),
+            (99, false),
+            (100, true),
+            (250, false),
+            (1000, true),
+        ];
+        for &(id, milestone) in cases {
+            assert_eq!(config.is_milestone(id), milestone, "id {}", id);
+        }
+        assert!(config.public);
+
+        let listed = milestones("ids = [500]");
+        assert!(listed.is_milestone(500));
+        assert!(!listed.is_milestone(1000));
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(milestones("every = 100").validate().is_ok());
+        assert!(milestones("ids = [7]").validate().is_ok());
+        assert!(milestones("body = 'Number {id}!'").validate().is_err());
+        assert!(milestones("every = 0").validate().is_err());
+        assert!(!milestones("every = 0").is_milestone(0));
+    }
+}
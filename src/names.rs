@@ -0,0 +1,204 @@
+//! Landscape names
+//!
+//! Every image gets a name like "The Ashen Shallows of Velmor", available to text templates as
+//! {name}. Names are worked out from a seed drawn when the map is generated, so the same seed and
+//! word lists always give the same name. The shuffling is done here rather than with `rand`, so
+//! that updating `rand` doesn't rename anything.
+
+use std::fs::read_to_string;
+use std::path::Path;
+
+use anyhow::Error;
+use toml;
+
+/// How many names are kept to check new ones against
+pub const HISTORY_LEN: usize = 1000;
+/// Tries at finding a name not in the history before settling for a repeat
+const MAX_REROLLS: u64 = 50;
+
+const ADJECTIVES: &[&str] = &[
+    "Ashen", "Amber", "Broken", "Drowned", "Emerald", "Endless", "Fallow", "Forgotten", "Gilded",
+    "Hollow", "Hushed", "Iron", "Lonely", "Misty", "Pale", "Quiet", "Rusted", "Shattered",
+    "Silent", "Sunken", "Verdant", "Weathered", "Whispering", "Windswept",
+];
+
+const NOUNS: &[&str] = &[
+    "Basin", "Bluffs", "Crags", "Dales", "Downs", "Expanse", "Fells", "Fens", "Flats", "Heights",
+    "Highlands", "Hollows", "Isles", "Marches", "Mesas", "Moors", "Reaches", "Shallows", "Shelves",
+    "Steppes", "Terraces", "Uplands", "Vales", "Wilds",
+];
+
+const SYLLABLES: &[&str] = &[
+    "al", "bar", "cor", "dun", "el", "fen", "gal", "hal", "ir", "kor", "lun", "mor", "nar", "or",
+    "pel", "quen", "ros", "sel", "tor", "ul", "vel", "wyn", "yr", "zan",
+];
+
+/// Word lists to build names from, read from `names_file` or built in
+#[derive(Deserialize, Default)]
+pub struct NameLists {
+    #[serde(default)]
+    adjectives: Vec<String>,
+    #[serde(default)]
+    nouns: Vec<String>,
+    /// Put together into the place name at the end
+    #[serde(default)]
+    syllables: Vec<String>,
+}
+
+impl NameLists {
+    /// Read lists from `path`, if given, using the built-in ones for any left out
+    pub fn load(path: Option<&Path>) -> Result<NameLists, Error> {
+        let mut lists = match path {
+            Some(path) => {
+                let contents = read_to_string(path).map_err(|e| {
+                    Error::msg(format!("unable to read {}: {}", path.display(), e))
+                })?;
+                toml::from_str(&contents)?
+            }
+            None => NameLists::default(),
+        };
+
+        let builtin = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+        if lists.adjectives.is_empty() {
+            lists.adjectives = builtin(ADJECTIVES);
+        }
+        if lists.nouns.is_empty() {
+            lists.nouns = builtin(NOUNS);
+        }
+        if lists.syllables.is_empty() {
+            lists.syllables = builtin(SYLLABLES);
+        }
+        Ok(lists)
+    }
+
+    /// Name for `seed`, trying again with the next nonce while it's one of `used`
+    pub fn name(&self, seed: u64, used: &[String]) -> String {
+        let mut name = self.name_with_nonce(seed, 0);
+        for nonce in 1..=MAX_REROLLS {
+            if !used.contains(&name) {
+                break;
+            }
+            name = self.name_with_nonce(seed, nonce);
+        }
+        name
+    }
+
+    fn name_with_nonce(&self, seed: u64, nonce: u64) -> String {
+        let mut rng = SplitMix64(seed ^ nonce.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+
+        let adjective = rng.pick(&self.adjectives);
+        let noun = rng.pick(&self.nouns);
+        let syllables = 2 + rng.below(2);
+        let place: String = (0..syllables).map(|_| rng.pick(&self.syllables).as_str()).collect();
+
+        format!("The {} {} of {}", adjective, noun, capitalize(&place))
+    }
+}
+
+/// Uppercase the first letter of `word`
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Small, fixed random number generator, see the module documentation
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Number from 0 up to, but not including, `n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a>(&mut self, words: &'a [String]) -> &'a String {
+        &words[self.below(words.len())]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    fn builtin() -> NameLists {
+        NameLists::load(None).expect("Unable to load built-in lists")
+    }
+
+    /// Catches changes to the built-in lists or the shuffling, which would rename every image
+    #[test]
+    fn names_are_pinned_to_seeds() {
+        let cases: &[(u64, &str)] = &[
+            (0, "The Forgotten Isles of Elultor"),
+            (1, "The Shattered Fens of Morkor"),
+            (42, "The Misty Steppes of Narlun"),
+            (123_456_789, "The Shattered Fells of Fenulyr"),
+            (u64::max_value(), "The Gilded Heights of Torgalul"),
+        ];
+        let lists = builtin();
+        for &(seed, name) in cases {
+            assert_eq!(lists.name(seed, &[]), name, "{}", seed);
+        }
+    }
+
+    #[test]
+    fn used_names_are_rerolled() {
+        let lists = builtin();
+        let used = vec!["The Forgotten Isles of Elultor".to_string()];
+        assert_eq!(lists.name(0, &used), "The Lonely Fens of Ultor");
+        // Only names in the history count
+        assert_eq!(lists.name(42, &used), "The Misty Steppes of Narlun");
+    }
+
+    #[test]
+    fn repeats_when_every_name_is_used() {
+        let lists = NameLists {
+            adjectives: vec!["Red".to_string()],
+            nouns: vec!["Hills".to_string()],
+            syllables: vec!["ab".to_string()],
+        };
+        let used = vec!["The Red Hills of Abab".to_string(), "The Red Hills of Ababab".to_string()];
+        let name = lists.name(7, &used);
+        assert!(used.contains(&name), "{}", name);
+    }
+
+    #[test]
+    fn lists_left_out_of_the_file_are_built_in() {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let path = dir.path().join("names.toml");
+        write(&path, "adjectives = [\"Red\"]\nnouns = [\"Hills\"]\n").expect("Unable to write");
+        let lists = NameLists::load(Some(&path)).expect("Unable to load lists");
+        assert_eq!(lists.adjectives, vec!["Red"]);
+        assert_eq!(lists.syllables.len(), SYLLABLES.len());
+
+        let name = lists.name(3, &[]);
+        assert!(name.starts_with("The Red Hills of "), "{}", name);
+    }
+
+    #[test]
+    fn bad_names_file_is_an_error() {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        assert!(NameLists::load(Some(&dir.path().join("missing.toml"))).is_err());
+        let path = dir.path().join("names.toml");
+        write(&path, "adjectives = \"Red\"\n").expect("Unable to write");
+        assert!(NameLists::load(Some(&path)).is_err());
+    }
+
+    #[test]
+    fn capitalizes_first_letter() {
+        assert_eq!(capitalize("velmor"), "Velmor");
+        assert_eq!(capitalize("ørn"), "Ørn");
+        assert_eq!(capitalize(""), "");
+    }
+}
@@ -0,0 +1,120 @@
+//! When the next post is due, for other programs to show
+//!
+//! Whenever the bot works out when it will post next, it writes that to a file next to the state
+//! file, named like it with `.next_post` added: the time in RFC 3339 on the first line, and why
+//! it's then on the second. The file is removed at the start of every cycle, which includes
+//! right after a config reload, and written again once the cycle has worked out the time, so
+//! it's missing while a post is being made. A file left over from an earlier run is removed at
+//! startup the same way.
+//!
+//! The file is only advisory. It's replaced atomically, so it's never read half written, but a
+//! failure to write it is only warned about.
+
+use std::ffi::OsString;
+use std::fs::remove_file;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use journal;
+
+/// Why the next post is due when it is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    /// That's when the schedule has it
+    Schedule,
+    /// Put off until a maintenance window ends
+    Maintenance,
+    /// It was due earlier, and is being made right away
+    Overdue,
+    /// Held off until `min_interval_secs` have passed since the last post
+    MinInterval,
+    /// A failed attempt is tried again then
+    Retry,
+}
+
+impl Reason {
+    fn name(self) -> &'static str {
+        match self {
+            Reason::Schedule => "schedule",
+            Reason::Maintenance => "maintenance",
+            Reason::Overdue => "overdue",
+            Reason::MinInterval => "min_interval",
+            Reason::Retry => "retry",
+        }
+    }
+}
+
+/// Where the next post time for the state file at `state` goes
+pub fn path(state: &Path) -> PathBuf {
+    let mut name = state
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_else(|| OsString::from("state"));
+    name.push(".next_post");
+    state.with_file_name(name)
+}
+
+/// Record that the next post is due `at`, for `reason`
+pub fn write(state: &Path, at: DateTime<Utc>, reason: Reason) {
+    let contents = format!(
+        "{}\n{}\n",
+        at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        reason.name()
+    );
+    let path = path(state);
+    if let Err(e) = journal::write_atomically(&path, contents.as_bytes()) {
+        eprintln!("WARNING: Unable to write {}: {}", path.display(), e);
+    }
+}
+
+/// Remove the record of the next post, as it's not known right now
+pub fn clear(state: &Path) {
+    let path = path(state);
+    match remove_file(&path) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("WARNING: Unable to remove {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::fs::read_to_string;
+    use tempfile;
+
+    #[test]
+    fn named_after_the_state_file() {
+        assert_eq!(
+            path(Path::new("/var/lib/bot/state-cubes")),
+            PathBuf::from("/var/lib/bot/state-cubes.next_post")
+        );
+        assert_eq!(path(Path::new("state")), PathBuf::from("state.next_post"));
+    }
+
+    #[test]
+    fn tracks_every_time_worked_out() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let state = dir.path().join("state");
+        let read = || read_to_string(path(&state)).expect("Unable to read next post");
+
+        let first = Utc.ymd(2026, 10, 17).and_hms(12, 30, 0);
+        write(&state, first, Reason::Schedule);
+        assert_eq!(read(), "2026-10-17T12:30:00Z\nschedule\n");
+
+        let later = first + ::chrono::Duration::minutes(90);
+        write(&state, later, Reason::Maintenance);
+        assert_eq!(read(), "2026-10-17T14:00:00Z\nmaintenance\n");
+        write(&state, later, Reason::MinInterval);
+        assert_eq!(read(), "2026-10-17T14:00:00Z\nmin_interval\n");
+
+        clear(&state);
+        assert!(!path(&state).exists());
+        // Nothing to clear is fine
+        clear(&state);
+        assert!(!state.exists());
+    }
+}
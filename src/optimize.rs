@@ -0,0 +1,333 @@
+//! PNG optimizer presets
+//!
+//! oxipng's higher presets can take minutes longer for a few bytes. With
+//! `optimize_level = "auto"`, the first `samples` images of each month are optimized with every
+//! preset in `presets`, keeping the smallest result, and the sizes and times are kept in the
+//! state. Then, starting from the lowest preset, the next one up is taken as long as it saves at
+//! least `min_bytes_per_second` for every extra second it takes, and the rest of the month's
+//! images are optimized with the preset arrived at.
+
+use chrono::{DateTime, Utc};
+
+/// Preset used unless configured otherwise
+pub const DEFAULT_PRESET: u8 = 4;
+/// Highest preset oxipng has
+pub const MAX_PRESET: u8 = 6;
+
+/// `optimize_level`: a preset number, or "auto"
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum OptimizeLevel {
+    Preset(u8),
+    Auto(Auto),
+}
+
+/// The word "auto"
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Auto {
+    Auto,
+}
+
+impl Default for OptimizeLevel {
+    fn default() -> OptimizeLevel {
+        OptimizeLevel::Preset(DEFAULT_PRESET)
+    }
+}
+
+impl OptimizeLevel {
+    /// Preset to use where no measurements are kept, like the upload check
+    pub fn fixed_preset(self) -> u8 {
+        match self {
+            OptimizeLevel::Preset(preset) => preset,
+            OptimizeLevel::Auto(_) => DEFAULT_PRESET,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AutoOptimizeConfig {
+    /// Images each month to try every preset on
+    #[serde(default = "default_samples")]
+    pub samples: usize,
+
+    /// Presets to choose from
+    #[serde(default = "default_presets")]
+    pub presets: Vec<u8>,
+
+    /// Bytes a higher preset has to save per extra second to be worth it
+    #[serde(default = "default_min_bytes_per_second")]
+    pub min_bytes_per_second: f64,
+}
+
+impl Default for AutoOptimizeConfig {
+    fn default() -> AutoOptimizeConfig {
+        AutoOptimizeConfig {
+            samples: default_samples(),
+            presets: default_presets(),
+            min_bytes_per_second: default_min_bytes_per_second(),
+        }
+    }
+}
+
+fn default_samples() -> usize {
+    5
+}
+
+fn default_presets() -> Vec<u8> {
+    vec![2, 4]
+}
+
+fn default_min_bytes_per_second() -> f64 {
+    1024.0
+}
+
+impl AutoOptimizeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.samples == 0 {
+            return Err("samples must be at least 1".to_string());
+        }
+        if self.presets.is_empty() {
+            return Err("presets must not be empty".to_string());
+        }
+        if let Some(preset) = self.presets.iter().find(|&&preset| preset > MAX_PRESET) {
+            return Err(format!("presets go up to {}, got {}", MAX_PRESET, preset));
+        }
+        if !(self.min_bytes_per_second >= 0.0) {
+            return Err(format!(
+                "min_bytes_per_second must not be negative, got {}",
+                self.min_bytes_per_second
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// One preset's result on one image
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Measurement {
+    pub preset: u8,
+    pub bytes: usize,
+    pub duration_ms: u64,
+}
+
+/// Measurements and the chosen preset for the current month, kept in the state
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct AutoOptimizeState {
+    /// Month the samples are from, as `YYYY-MM`
+    #[serde(default)]
+    month: String,
+    /// Measurements of every preset, one list per image
+    #[serde(default)]
+    samples: Vec<Vec<Measurement>>,
+    /// Preset chosen from the samples, or last month's until this month's are in
+    #[serde(default)]
+    chosen: Option<u8>,
+}
+
+impl AutoOptimizeState {
+    /// Presets to run on the next image: all of them while sampling, otherwise the chosen one
+    pub fn presets(&self, config: &AutoOptimizeConfig, now: DateTime<Utc>) -> Vec<u8> {
+        let sampled = self.month == month_of(now) && self.samples.len() >= config.samples;
+        match self.chosen {
+            Some(chosen) if sampled => vec![chosen],
+            _ => config.presets.clone(),
+        }
+    }
+
+    /// Record an image's `measurements`, choosing a preset once there are enough
+    ///
+    /// Logs when the chosen preset changes.
+    pub fn record(
+        &mut self,
+        config: &AutoOptimizeConfig,
+        now: DateTime<Utc>,
+        measurements: Vec<Measurement>,
+    ) {
+        let month = month_of(now);
+        if self.month != month {
+            self.month = month;
+            self.samples.clear();
+        }
+        self.samples.push(measurements);
+        if self.samples.len() < config.samples {
+            return;
+        }
+
+        let chosen = choose(config, &self.samples);
+        match self.chosen {
+            Some(previous) if previous == chosen => {
+                eprintln!("Optimizer preset {} confirmed for {}", chosen, self.month)
+            }
+            Some(previous) => eprintln!(
+                "Optimizer preset changed from {} to {} for {}",
+                previous, chosen, self.month
+            ),
+            None => eprintln!("Optimizer preset {} chosen for {}", chosen, self.month),
+        }
+        self.chosen = Some(chosen);
+    }
+}
+
+fn month_of(now: DateTime<Utc>) -> String {
+    now.format("%Y-%m").to_string()
+}
+
+/// Pick the preset to use from `samples`, see the module documentation
+fn choose(config: &AutoOptimizeConfig, samples: &[Vec<Measurement>]) -> u8 {
+    let mut presets = config.presets.clone();
+    presets.sort();
+    presets.dedup();
+
+    // Average size and seconds of each preset
+    let averages: Vec<(u8, f64, f64)> = presets
+        .into_iter()
+        .filter_map(|preset| {
+            let runs: Vec<&Measurement> = samples
+                .iter()
+                .flat_map(|image| image.iter())
+                .filter(|measurement| measurement.preset == preset)
+                .collect();
+            if runs.is_empty() {
+                return None;
+            }
+            let count = runs.len() as f64;
+            let bytes = runs.iter().map(|run| run.bytes as f64).sum::<f64>() / count;
+            let millis = runs.iter().map(|run| run.duration_ms as f64).sum::<f64>() / count;
+            let seconds = millis / 1000.0;
+            Some((preset, bytes, seconds))
+        }).collect();
+
+    let mut chosen = match averages.first() {
+        Some(&first) => first,
+        None => return config.presets.iter().cloned().max().unwrap_or(DEFAULT_PRESET),
+    };
+    for &next in &averages[1..] {
+        let saved = chosen.1 - next.1;
+        // Timings are noisy, so a higher preset can come out faster
+        let extra_seconds = (next.2 - chosen.2).max(0.001);
+        if saved / extra_seconds < config.min_bytes_per_second {
+            break;
+        }
+        chosen = next;
+    }
+    chosen.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use toml;
+
+    #[derive(Deserialize)]
+    struct Level {
+        optimize_level: OptimizeLevel,
+    }
+
+    fn config(presets: &[u8], min_bytes_per_second: f64) -> AutoOptimizeConfig {
+        AutoOptimizeConfig {
+            samples: 2,
+            presets: presets.to_vec(),
+            min_bytes_per_second,
+        }
+    }
+
+    fn image(runs: &[(u8, usize, u64)]) -> Vec<Measurement> {
+        runs.iter()
+            .map(|&(preset, bytes, duration_ms)| Measurement {
+                preset,
+                bytes,
+                duration_ms,
+            }).collect()
+    }
+
+    #[test]
+    fn parses_levels() {
+        let parse = |text: &str| toml::from_str::<Level>(text).map(|level| level.optimize_level);
+        assert_eq!(parse("optimize_level = 2").unwrap(), OptimizeLevel::Preset(2));
+        assert_eq!(parse("optimize_level = \"auto\"").unwrap(), OptimizeLevel::Auto(Auto::Auto));
+        assert!(parse("optimize_level = \"fast\"").is_err());
+        assert_eq!(OptimizeLevel::default().fixed_preset(), DEFAULT_PRESET);
+        assert_eq!(OptimizeLevel::Preset(1).fixed_preset(), 1);
+        assert_eq!(OptimizeLevel::Auto(Auto::Auto).fixed_preset(), DEFAULT_PRESET);
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(AutoOptimizeConfig::default().validate().is_ok());
+        let cases: &[(AutoOptimizeConfig, &str)] = &[
+            (
+                AutoOptimizeConfig {
+                    samples: 0,
+                    ..AutoOptimizeConfig::default()
+                },
+                "samples",
+            ),
+            (config(&[], 1.0), "presets must not be empty"),
+            (config(&[2, 7], 1.0), "got 7"),
+            (config(&[2], -1.0), "min_bytes_per_second"),
+            (config(&[2], ::std::f64::NAN), "min_bytes_per_second"),
+        ];
+        for &(ref config, expected) in cases {
+            let error = config.validate().unwrap_err();
+            assert!(error.contains(expected), "{:?} lacks {:?}", error, expected);
+        }
+    }
+
+    #[test]
+    fn takes_higher_presets_while_worth_it() {
+        // 2 -> 4 saves 5000 bytes a second, 4 -> 6 saves 100 bytes a second
+        let samples = vec![image(&[(2, 50_000, 1000), (4, 40_000, 3000), (6, 39_000, 13_000)])];
+        assert_eq!(choose(&config(&[6, 2, 4], 1024.0), &samples), 4);
+        assert_eq!(choose(&config(&[2, 4, 6], 50.0), &samples), 6);
+        assert_eq!(choose(&config(&[2, 4, 6], 10_000.0), &samples), 2);
+    }
+
+    #[test]
+    fn averages_over_images() {
+        // Saves 1500 bytes a second on average, though the first image alone saves 500
+        let samples = vec![
+            image(&[(2, 10_000, 1000), (4, 9_000, 3000)]),
+            image(&[(2, 10_000, 1000), (4, 5_000, 3000)]),
+        ];
+        assert_eq!(choose(&config(&[2, 4], 1024.0), &samples), 4);
+        assert_eq!(choose(&config(&[2, 4], 1024.0), &samples[..1]), 2);
+    }
+
+    #[test]
+    fn faster_higher_preset_is_taken_if_smaller() {
+        let samples = vec![image(&[(2, 10_000, 2000), (4, 9_990, 1000)])];
+        assert_eq!(choose(&config(&[2, 4], 1024.0), &samples), 4);
+    }
+
+    #[test]
+    fn without_measurements_uses_highest_preset() {
+        assert_eq!(choose(&config(&[2, 5, 3], 1024.0), &[]), 5);
+    }
+
+    #[test]
+    fn samples_then_chooses_each_month() {
+        let config = config(&[2, 4], 1024.0);
+        let october = Utc.ymd(2026, 10, 3).and_hms(12, 0, 0);
+        let november = Utc.ymd(2026, 11, 1).and_hms(0, 0, 0);
+        let worth_it = || image(&[(2, 50_000, 1000), (4, 40_000, 2000)]);
+        let not_worth_it = || image(&[(2, 50_000, 1000), (4, 49_900, 2000)]);
+
+        let mut state = AutoOptimizeState::default();
+        assert_eq!(state.presets(&config, october), vec![2, 4]);
+        state.record(&config, october, worth_it());
+        assert_eq!(state.presets(&config, october), vec![2, 4]);
+        state.record(&config, october, worth_it());
+        assert_eq!(state.presets(&config, october), vec![4]);
+
+        // A new month samples again, then replaces the choice
+        assert_eq!(state.presets(&config, november), vec![2, 4]);
+        state.record(&config, november, not_worth_it());
+        assert_eq!(state.chosen, Some(4));
+        assert_eq!(state.samples.len(), 1);
+        state.record(&config, november, not_worth_it());
+        assert_eq!(state.chosen, Some(2));
+        assert_eq!(state.presets(&config, november), vec![2]);
+    }
+}
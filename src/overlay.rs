@@ -0,0 +1,190 @@
+//! Caption overlay
+//!
+//! Draws a short line of text, like the bot's handle and the image id, into a corner of the
+//! image, so reposts elsewhere keep attribution.
+
+use std::fs::read;
+use std::path::PathBuf;
+
+use anyhow::Error;
+use image::{DynamicImage, GenericImageView, Rgba};
+use rusttype::{point, Font, PositionedGlyph, Scale};
+
+use background::parse_color;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Default for Corner {
+    fn default() -> Corner {
+        Corner::BottomRight
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct OverlayConfig {
+    /// Text to draw. Same placeholders as the alt text, plus {id}.
+    pub text: String,
+
+    #[serde(default)]
+    pub corner: Corner,
+
+    /// TrueType font to draw with
+    pub font: PathBuf,
+
+    /// Font size in pixels. Scaled down if the text does not fit the image.
+    #[serde(default = "default_size")]
+    pub size: f32,
+
+    /// As `#rrggbb`
+    #[serde(default = "default_color")]
+    pub color: String,
+
+    /// From 0, invisible, to 1, opaque
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+
+    /// Distance from the edges of the image, in pixels
+    #[serde(default = "default_margin")]
+    pub margin: u32,
+}
+
+fn default_size() -> f32 {
+    16.0
+}
+
+fn default_color() -> String {
+    "#ffffff".to_string()
+}
+
+fn default_opacity() -> f32 {
+    0.7
+}
+
+fn default_margin() -> u32 {
+    8
+}
+
+impl OverlayConfig {
+    /// Check the settings, and that the font can be loaded
+    pub fn validate(&self) -> Result<(), String> {
+        parse_color(&self.color)?;
+        if !(0.0..=1.0).contains(&self.opacity) {
+            return Err(format!("opacity must be from 0 to 1, got {}", self.opacity));
+        }
+        if self.size.is_nan() || self.size <= 0.0 {
+            return Err(format!("size must be positive, got {}", self.size));
+        }
+        self.load_font().map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    fn load_font(&self) -> Result<Font<'static>, Error> {
+        let data = read(&self.font).map_err(|e| {
+            Error::msg(format!("unable to read font {}: {}", self.font.display(), e))
+        })?;
+        Font::from_bytes(data).map_err(|e| {
+            Error::msg(format!("unable to load font {}: {}", self.font.display(), e))
+        })
+    }
+
+    /// Draw `text` onto `image`
+    pub fn draw(&self, image: &DynamicImage, text: &str) -> Result<DynamicImage, Error> {
+        let font = self.load_font()?;
+        let [r, g, b] = parse_color(&self.color).map_err(Error::msg)?;
+        let (width, height) = image.dimensions();
+
+        // Shrink the text until it fits, if the image is small
+        let available_width = width.saturating_sub(2 * self.margin) as f32;
+        let available_height = height.saturating_sub(2 * self.margin) as f32;
+        let (text_width, _) = measure(&font, text, Scale::uniform(self.size));
+        let mut size = self.size;
+        if text_width > available_width {
+            size *= available_width / text_width;
+        }
+        if size > available_height {
+            size = available_height;
+        }
+        if size < 1.0 {
+            eprintln!("Image too small to fit the overlay, leaving it out");
+            return Ok(image.clone());
+        }
+
+        let scale = Scale::uniform(size);
+        let (text_width, text_height) = measure(&font, text, scale);
+        let (text_width, text_height) = (text_width.ceil() as u32, text_height.ceil() as u32);
+        let x = match self.corner {
+            Corner::TopLeft | Corner::BottomLeft => self.margin,
+            Corner::TopRight | Corner::BottomRight => {
+                width.saturating_sub(self.margin + text_width)
+            }
+        };
+        let y = match self.corner {
+            Corner::TopLeft | Corner::TopRight => self.margin,
+            Corner::BottomLeft | Corner::BottomRight => {
+                height.saturating_sub(self.margin + text_height)
+            }
+        };
+
+        let ascent = font.v_metrics(scale).ascent;
+        let mut canvas = image.to_rgba();
+        for glyph in font.layout(text, scale, point(x as f32, y as f32 + ascent)) {
+            let bounds = match glyph.pixel_bounding_box() {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+            glyph.draw(|gx, gy, coverage| {
+                let px = bounds.min.x + gx as i32;
+                let py = bounds.min.y + gy as i32;
+                if px < 0 || py < 0 || px as u32 >= width || py as u32 >= height {
+                    return;
+                }
+
+                // Text over whatever is below it, which may itself be transparent
+                let alpha = coverage * self.opacity;
+                let pixel = canvas.get_pixel_mut(px as u32, py as u32);
+                let [dr, dg, db, da] = pixel.data;
+                let below = f32::from(da) / 255.0 * (1.0 - alpha);
+                let out_alpha = alpha + below;
+                if out_alpha <= 0.0 {
+                    return;
+                }
+                let blend = |src: u8, dst: u8| {
+                    ((f32::from(src) * alpha + f32::from(dst) * below) / out_alpha).round() as u8
+                };
+                *pixel = Rgba {
+                    data: [
+                        blend(r, dr),
+                        blend(g, dg),
+                        blend(b, db),
+                        (out_alpha * 255.0).round() as u8,
+                    ],
+                };
+            });
+        }
+
+        Ok(match *image {
+            DynamicImage::ImageRgba8(_) => DynamicImage::ImageRgba8(canvas),
+            _ => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb()),
+        })
+    }
+}
+
+/// Width and height of `text` laid out at `scale`, in pixels
+fn measure(font: &Font, text: &str, scale: Scale) -> (f32, f32) {
+    let v_metrics = font.v_metrics(scale);
+    let glyphs: Vec<PositionedGlyph> = font.layout(text, scale, point(0.0, 0.0)).collect();
+    let width = glyphs
+        .last()
+        .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0);
+
+    (width, v_metrics.ascent - v_metrics.descent)
+}
@@ -0,0 +1,243 @@
+//! Config overrides from the command line
+//!
+//! `--set bot.map_size=48` sets a key in the config file's TOML before it is turned into a
+//! `ConfigFile`, so overridden values are checked just like ones from the file. Only `[bot]` keys
+//! can be set, which keeps tokens and passwords out of shell history.
+
+use anyhow::Error;
+use toml;
+use toml::value::{Table, Value};
+
+/// Top-level table overrides can go into
+const SETTABLE: &str = "bot";
+
+/// A parsed `key=value` override
+pub struct Override {
+    /// Dotted key, split up, starting with `bot`
+    pub path: Vec<String>,
+    pub value: Value,
+}
+
+impl Override {
+    /// Parse `key=value`
+    ///
+    /// The value is read as TOML, so `48`, `0.01`, `true`, `[1, 2]` and
+    /// `{ min = 1, max = 2 }` all work. Anything that isn't valid TOML is taken as a string, so
+    /// strings don't need quoting.
+    pub fn parse(assignment: &str) -> Result<Override, Error> {
+        let equals = assignment
+            .find('=')
+            .ok_or_else(|| Error::msg(format!("--set {}: expected key=value", assignment)))?;
+        let (key, raw) = (assignment[..equals].trim(), assignment[equals + 1..].trim());
+
+        let path: Vec<String> = key.split('.').map(|part| part.trim().to_string()).collect();
+        if path.iter().any(String::is_empty) {
+            return Err(Error::msg(format!("--set {}: empty part in key {:?}", assignment, key)));
+        }
+        if path[0] != SETTABLE || path.len() < 2 {
+            return Err(Error::msg(format!(
+                "--set {}: only [{}] keys can be set, like {}.map_size, not {}",
+                assignment, SETTABLE, SETTABLE, key
+            )));
+        }
+
+        let value = toml::from_str::<Table>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or_else(|| Value::String(raw.to_string()));
+
+        Ok(Override { path, value })
+    }
+
+    /// Dotted key, as given
+    pub fn key(&self) -> String {
+        self.path.join(".")
+    }
+
+    /// Set the value in `root`, the whole config file, making tables along the way as needed
+    pub fn apply(&self, root: &mut Value) -> Result<(), Error> {
+        let (last, parents) = self.path.split_last().expect("checked when parsed");
+
+        let not_a_table = || Error::msg(format!("--set {}: not inside a table", self.key()));
+        let mut table = root;
+        for part in parents {
+            table = table
+                .as_table_mut()
+                .ok_or_else(not_a_table)?
+                .entry(part.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+        }
+
+        table
+            .as_table_mut()
+            .ok_or_else(not_a_table)?
+            .insert(last.clone(), self.value.clone());
+        Ok(())
+    }
+
+    /// Fail unless the key made it into `bot`, the `[bot]` table serialized back after parsing
+    ///
+    /// Unknown keys are ignored when parsing the config, so this is how they're caught.
+    pub fn check_known(&self, bot: &Value) -> Result<(), Error> {
+        let mut value = bot;
+        for part in &self.path[1..] {
+            value = value
+                .get(part.as_str())
+                .ok_or_else(|| Error::msg(format!("--set {}: unknown key", self.key())))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BotConfig;
+
+    /// The config file `root` with `assignments` applied, and its `[bot]` table parsed the way
+    /// `read_config` parses it
+    fn apply_all(root: &str, assignments: &[&str]) -> Result<(Value, BotConfig), Error> {
+        let mut root: Value = toml::from_str(root)?;
+        for assignment in assignments {
+            Override::parse(assignment)?.apply(&mut root)?;
+        }
+        let bot = toml::from_str(&toml::to_string(&root["bot"])?)?;
+        Ok((root, bot))
+    }
+
+    #[test]
+    fn parses_values_as_toml() {
+        let cases: &[(&str, Value)] = &[
+            ("bot.map_size=48", Value::Integer(48)),
+            ("bot.min_frequency = 0.01", Value::Float(0.01)),
+            ("bot.public=true", Value::Boolean(true)),
+            ("bot.language=\"en\"", Value::String("en".to_string())),
+            // Not TOML, so taken as it is
+            ("bot.language=en", Value::String("en".to_string())),
+            ("bot.body=a = b", Value::String("a = b".to_string())),
+            (
+                "bot.poll.options=[\"yes\", \"no\"]",
+                Value::Array(vec![
+                    Value::String("yes".to_string()),
+                    Value::String("no".to_string()),
+                ]),
+            ),
+        ];
+        for (assignment, value) in cases {
+            let parsed = Override::parse(assignment).expect("Unable to parse override");
+            assert_eq!(&parsed.value, value, "{}", assignment);
+        }
+    }
+
+    #[test]
+    fn splits_dotted_keys() {
+        let parsed = Override::parse(" bot . poll . multiple =true").unwrap();
+        assert_eq!(parsed.path, vec!["bot", "poll", "multiple"]);
+        assert_eq!(parsed.key(), "bot.poll.multiple");
+    }
+
+    #[test]
+    fn refuses_keys_outside_bot() {
+        for assignment in &[
+            "credentials.token=hunter2",
+            "credentials.client_secret=hunter2",
+            "fallback.token=hunter2",
+            "map_size=48",
+            "bot=3",
+            "botx.map_size=48",
+        ] {
+            let error = Override::parse(assignment).err().expect("Parsed a refused key");
+            assert!(error.to_string().contains("only [bot] keys"), "{}", assignment);
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_assignments() {
+        for assignment in &["bot.map_size", "bot..map_size=48", "bot.=48", "=48"] {
+            assert!(Override::parse(assignment).is_err(), "{}", assignment);
+        }
+    }
+
+    #[test]
+    fn sets_top_level_keys() {
+        let (_, bot) = apply_all("[bot]\nmap_size = 16\n", &["bot.map_size=48"]).unwrap();
+        assert_eq!(bot.map_size, 48);
+    }
+
+    #[test]
+    fn sets_nested_keys_and_arrays() {
+        let (root, bot) = apply_all(
+            "[bot]\nmap_size = 16\n",
+            &["bot.poll.options=[\"yes\", \"no\"]", "bot.poll.multiple=true"],
+        ).unwrap();
+        let poll = bot.poll.expect("No poll config");
+        assert_eq!(poll.options, vec!["yes", "no"]);
+        assert!(poll.multiple);
+        // Tables made along the way are real tables
+        assert!(root["bot"]["poll"].is_table());
+    }
+
+    #[test]
+    fn nested_keys_keep_their_neighbours() {
+        let root = "[bot]\nmap_size = 16\n[bot.poll]\noptions = [\"a\", \"b\"]\n";
+        let (_, bot) = apply_all(root, &["bot.poll.multiple=true"]).unwrap();
+        let poll = bot.poll.expect("No poll config");
+        assert_eq!(poll.options, vec!["a", "b"]);
+        assert!(poll.multiple);
+    }
+
+    #[test]
+    fn later_overrides_win() {
+        let (_, bot) =
+            apply_all("[bot]\nmap_size = 16\n", &["bot.map_size=32", "bot.map_size=48"]).unwrap();
+        assert_eq!(bot.map_size, 48);
+    }
+
+    #[test]
+    fn type_mismatches_name_the_key() {
+        let cases: &[&str] = &[
+            "bot.map_size=big",
+            "bot.map_size=0.5",
+            "bot.map_size=[48]",
+            "bot.poll.options=yes",
+        ];
+        for assignment in cases {
+            let error = apply_all("[bot]\nmap_size = 16\n", &[*assignment])
+                .err()
+                .expect("Applied a mismatched type");
+            let key = assignment.split('=').next().unwrap();
+            let field = key.rsplit('.').next().unwrap();
+            assert!(error.to_string().contains(field), "{}: {}", assignment, error);
+        }
+    }
+
+    #[test]
+    fn refuses_keys_inside_values() {
+        let error = apply_all("[bot]\nmap_size = 16\n", &["bot.map_size.x=1"])
+            .err()
+            .expect("Set a key inside an integer");
+        assert!(error.to_string().contains("not inside a table"));
+    }
+
+    #[test]
+    fn unknown_keys_are_caught() {
+        let (_, bot) = apply_all(
+            "[bot]\nmap_size = 16\n",
+            &["bot.mapsize=48", "bot.poll.options=[\"a\", \"b\"]", "bot.poll.colour=1"],
+        ).unwrap();
+        let bot = Value::try_from(&bot).expect("Unable to serialize config");
+
+        let known = Override::parse("bot.map_size=48").unwrap();
+        assert!(known.check_known(&bot).is_ok());
+        let nested = Override::parse("bot.poll.options=[\"a\", \"b\"]").unwrap();
+        assert!(nested.check_known(&bot).is_ok());
+        for assignment in &["bot.mapsize=48", "bot.poll.colour=1"] {
+            let error = Override::parse(assignment)
+                .unwrap()
+                .check_known(&bot)
+                .err()
+                .expect("Unknown key passed");
+            assert!(error.to_string().contains("unknown key"), "{}", assignment);
+        }
+    }
+}
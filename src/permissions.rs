@@ -0,0 +1,68 @@
+//! Config file permission check
+//!
+//! The config holds access tokens, so it should not be readable by anyone but its owner. Only
+//! Unix permissions are checked; elsewhere the check always passes.
+
+use std::io;
+use std::path::Path;
+
+/// Check that `path` is not readable by group or others
+///
+/// Returns a message explaining the problem and how to fix it, if there is one.
+#[cfg(unix)]
+pub fn check_private(path: &Path) -> Result<Option<String>, io::Error> {
+    use std::fs::metadata;
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o044 == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(format!(
+        "{} is readable by other users (mode {:03o}), but contains access tokens. \
+         Fix with: chmod 600 {}",
+        path.display(),
+        mode,
+        shell_quote(&path.to_string_lossy())
+    )))
+}
+
+#[cfg(not(unix))]
+pub fn check_private(_path: &Path) -> Result<Option<String>, io::Error> {
+    Ok(None)
+}
+
+/// `text` quoted for a POSIX shell, so the suggested command can be pasted as it is
+#[cfg(unix)]
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs::{set_permissions, write, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile;
+
+    #[test]
+    fn flags_readable_configs() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let path = dir.path().join("bot's config.toml");
+        write(&path, "").expect("Unable to write config");
+
+        let cases: &[(u32, bool)] = &[(0o600, false), (0o400, false), (0o640, true), (0o644, true)];
+        for &(mode, flagged) in cases {
+            set_permissions(&path, Permissions::from_mode(mode)).expect("Unable to set mode");
+            let problem = check_private(&path).expect("Unable to check config");
+            assert_eq!(problem.is_some(), flagged, "{:o}", mode);
+            if let Some(problem) = problem {
+                assert!(problem.contains(&format!("{:03o}", mode)), "{}", problem);
+                let quoted = shell_quote(&path.to_string_lossy());
+                assert!(problem.ends_with(&format!("chmod 600 {}", quoted)), "{}", problem);
+                assert!(quoted.ends_with("bot'\\''s config.toml'"), "{}", quoted);
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Best of the month
+//!
+//! Early each month, the bot looks at what it posted the month before, pins the post with the
+//! most favourites and boosts to its profile in place of the previous pick, and can announce it.
+//! None of this is allowed to get in the way of regular posting, so failures are only logged.
+
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use elefren::status_builder::Visibility;
+use anyhow::Error;
+use elefren::{Mastodon, MastodonClient, StatusBuilder};
+
+use fill_template;
+use shutdown::{Cancelled, Shutdown};
+
+/// Pause between looking up statuses, to stay well within the instance's rate limits
+///
+/// An hourly bot posts around 720 times a month, which takes a while to go through like this, but
+/// it happens while waiting for the next post anyway.
+const LOOKUP_DELAY: StdDuration = StdDuration::from_secs(1);
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PinConfig {
+    /// Day of the month from which the previous month's best post is picked
+    #[serde(default = "default_day")]
+    pub day: u32,
+
+    /// Status announcing the pick, if set. {url} is replaced with the picked post's address.
+    #[serde(default)]
+    pub announcement: Option<String>,
+}
+
+fn default_day() -> u32 {
+    1
+}
+
+/// A status the bot posted, kept until it can no longer be picked
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PostedStatus {
+    pub status_id: String,
+    pub posted: DateTime<Utc>,
+}
+
+/// The current pick, and the month it is the best of, as `YYYY-MM`
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Pin {
+    pub status_id: String,
+    pub month: String,
+}
+
+/// The month before the one `now` is in, as `YYYY-MM`
+pub fn previous_month(now: DateTime<Utc>) -> String {
+    let first = Utc.ymd(now.year(), now.month(), 1);
+    (first - Duration::days(1)).format("%Y-%m").to_string()
+}
+
+/// Whether it's time to pick the best of the previous month
+///
+/// `last_checked` is the last month a pick was attempted for, whether or not that worked out.
+pub fn is_due(config: &PinConfig, last_checked: Option<&str>, now: DateTime<Utc>) -> bool {
+    now.day() >= config.day && last_checked != Some(previous_month(now).as_str())
+}
+
+/// Drop posts too old to be picked any more
+pub fn prune(posted: &mut Vec<PostedStatus>, now: DateTime<Utc>) {
+    let month = previous_month(now);
+    posted.retain(|status| status.posted.format("%Y-%m").to_string() >= month);
+}
+
+/// Pin the best of last month's `posted`, unpinning `current`
+///
+/// Returns the new pick, or `None` if nothing was posted last month. Posts which can't be looked
+/// up, e.g. because they were deleted, are skipped. Stops with `Cancelled` if shutdown is
+/// requested while going through them.
+pub fn pin_best(
+    config: &PinConfig,
+    masto: &Mastodon,
+    posted: &[PostedStatus],
+    current: Option<&Pin>,
+    now: DateTime<Utc>,
+    shutdown: &Shutdown,
+) -> Result<Option<Pin>, Error> {
+    let month = previous_month(now);
+
+    let mut best = None;
+    for status in posted {
+        if status.posted.format("%Y-%m").to_string() != month {
+            continue;
+        }
+        if !shutdown.sleep(LOOKUP_DELAY) {
+            return Err(Cancelled.into());
+        }
+        let status = match masto.get_status(&status.status_id) {
+            Ok(status) => status,
+            Err(e) => {
+                eprintln!("Unable to look up status {}, skipping it: {}", status.status_id, e);
+                continue;
+            }
+        };
+
+        let score = status.favourites_count + status.reblogs_count;
+        if best.as_ref().map_or(true, |&(best_score, _)| score > best_score) {
+            best = Some((score, status));
+        }
+    }
+
+    let best = match best {
+        Some((_, status)) => status,
+        None => return Ok(None),
+    };
+
+    if let Some(current) = current {
+        if current.status_id != best.id {
+            if let Err(e) = masto.unpin(&current.status_id) {
+                // Most likely deleted, which unpins it anyway
+                eprintln!("Unable to unpin status {}: {}", current.status_id, e);
+            }
+        }
+    }
+    masto.pin(&best.id)?;
+    eprintln!("Pinned status {} as the best of {}", best.id, month);
+
+    if let Some(ref template) = config.announcement {
+        let url = best.url.clone().unwrap_or_else(|| best.uri.clone());
+        let text = fill_template(template, |name| match name {
+            "url" => Some(url.clone()),
+            _ => None,
+        });
+        let announced = StatusBuilder::new()
+            .status(text)
+            .visibility(Visibility::Public)
+            .build()
+            .and_then(|status| masto.new_status(status));
+        // The pin itself worked, so this is not worth failing over
+        if let Err(e) = announced {
+            eprintln!("Unable to post the best of the month announcement: {}", e);
+        }
+    }
+
+    Ok(Some(Pin {
+        status_id: best.id,
+        month,
+    }))
+}
@@ -0,0 +1,276 @@
+//! Polls asking followers what they make of each image
+//!
+//! With `[bot.poll]`, every Mastodon status carries a poll with the configured options. Once a
+//! poll has closed, its results are looked up the next time the bot is waiting between posts,
+//! then logged and emitted as a `PollClosed` event. Mastodon itself refuses polls on statuses
+//! with media, so this is for servers which allow both. A refusal gives up on posting the image
+//! to the instance, with an error saying to remove the section, rather than retrying it forever.
+
+use chrono::{DateTime, Duration, Utc};
+use elefren::Data as MastoData;
+
+use build_info;
+use errors::PostingError;
+use posting::parse_response;
+
+const MIN_OPTIONS: usize = 2;
+const MAX_OPTIONS: usize = 4;
+const MAX_OPTION_CHARS: usize = 50;
+/// Shortest and longest durations Mastodon allows, 5 minutes and a month
+const MIN_EXPIRES_IN: u64 = 300;
+const MAX_EXPIRES_IN: u64 = 2_629_746;
+
+/// Times looking up a closed poll's results may fail before it's given up on
+const FETCH_ATTEMPTS: u32 = 3;
+
+/// Poll attached to each status
+///
+/// The fields are named as the API names them, so this is sent as it is.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PollConfig {
+    pub options: Vec<String>,
+
+    /// Seconds the poll stays open
+    #[serde(default = "default_expires_in")]
+    pub expires_in: u64,
+
+    /// Whether more than one option can be picked
+    #[serde(default)]
+    pub multiple: bool,
+}
+
+fn default_expires_in() -> u64 {
+    86_400
+}
+
+impl PollConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.options.len() < MIN_OPTIONS || self.options.len() > MAX_OPTIONS {
+            return Err(format!(
+                "needs {} to {} options, got {}",
+                MIN_OPTIONS,
+                MAX_OPTIONS,
+                self.options.len()
+            ));
+        }
+        for option in &self.options {
+            if option.trim().is_empty() {
+                return Err("options must not be empty".to_string());
+            }
+            if option.chars().count() > MAX_OPTION_CHARS {
+                return Err(format!(
+                    "option {:?} is longer than {} characters",
+                    option, MAX_OPTION_CHARS
+                ));
+            }
+        }
+        if self.expires_in < MIN_EXPIRES_IN || self.expires_in > MAX_EXPIRES_IN {
+            return Err(format!(
+                "expires_in must be from {} to {} seconds, got {}",
+                MIN_EXPIRES_IN, MAX_EXPIRES_IN, self.expires_in
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The poll of a posted status, as the instance returns it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PostedPoll {
+    pub id: String,
+    /// When the poll closes, if it does
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A posted poll whose results are still to be logged
+#[derive(Deserialize, Serialize)]
+pub struct PendingPoll {
+    /// Id of the image the poll is about
+    pub id: u32,
+    pub poll_id: String,
+    pub closes: DateTime<Utc>,
+    /// Whether the fallback account posted it, see `FallbackPoster`
+    pub fallback: bool,
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+impl PendingPoll {
+    /// The results of `poll`, posted at `posted` for image `id`, to be logged once it closes
+    pub fn new(
+        id: u32,
+        poll: &PostedPoll,
+        config: &PollConfig,
+        posted: DateTime<Utc>,
+        fallback: bool,
+    ) -> PendingPoll {
+        PendingPoll {
+            id,
+            poll_id: poll.id.clone(),
+            closes: poll
+                .expires_at
+                .unwrap_or_else(|| posted + Duration::seconds(config.expires_in as i64)),
+            fallback,
+            attempts: 0,
+        }
+    }
+
+    /// Count a failed lookup, returning whether to try again next time
+    pub fn failed(&mut self) -> bool {
+        self.attempts += 1;
+        self.attempts < FETCH_ATTEMPTS
+    }
+}
+
+/// A poll's votes
+#[derive(Deserialize)]
+pub struct PollResults {
+    #[serde(default)]
+    pub votes_count: u64,
+    pub options: Vec<PollOption>,
+}
+
+#[derive(Deserialize)]
+pub struct PollOption {
+    pub title: String,
+    /// Missing while results are hidden
+    #[serde(default)]
+    pub votes_count: Option<u64>,
+}
+
+impl PollResults {
+    /// The votes in one line, like `love it: 12, needs mountains: 3 (15 votes)`
+    pub fn summary(&self) -> String {
+        let options: Vec<String> = self
+            .options
+            .iter()
+            .map(|option| match option.votes_count {
+                Some(votes) => format!("{}: {}", option.title, votes),
+                None => format!("{}: ?", option.title),
+            })
+            .collect();
+        format!("{} ({} votes)", options.join(", "), self.votes_count)
+    }
+}
+
+/// Look up poll `poll_id` on `account`'s instance
+pub fn fetch(account: &MastoData, poll_id: &str) -> Result<PollResults, PostingError> {
+    let url = format!(
+        "{}/api/v1/polls/{}",
+        account.base.trim_end_matches('/'),
+        poll_id
+    );
+    let response = build_info::http_client()
+        .get(&url)
+        .bearer_auth(&account.token)
+        .send()?;
+    parse_response("mastodon", response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use test_support::MockServer;
+
+    fn config(options: &[&str], expires_in: u64) -> PollConfig {
+        PollConfig {
+            options: options.iter().map(|option| option.to_string()).collect(),
+            expires_in,
+            multiple: false,
+        }
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(config(&["love it", "too much water"], 86_400).validate().is_ok());
+        let long = "x".repeat(MAX_OPTION_CHARS + 1);
+        let invalid: &[(&[&str], u64, &str)] = &[
+            (&["only one"], 86_400, "options"),
+            (&["a", "b", "c", "d", "e"], 86_400, "options"),
+            (&["a", " "], 86_400, "empty"),
+            (&["a", long.as_str()], 86_400, "longer than"),
+            (&["a", "b"], MIN_EXPIRES_IN - 1, "expires_in"),
+            (&["a", "b"], MAX_EXPIRES_IN + 1, "expires_in"),
+        ];
+        for &(options, expires_in, expected) in invalid {
+            let problem = config(options, expires_in).validate().unwrap_err();
+            assert!(problem.contains(expected), "{}", problem);
+        }
+    }
+
+    #[test]
+    fn closes_when_the_instance_says() {
+        let posted = Utc.ymd(2024, 5, 1).and_hms(12, 0, 0);
+        let closes = Utc.ymd(2024, 5, 1).and_hms(18, 0, 0);
+        let config = config(&["a", "b"], 3600);
+        let poll = |expires_at| PostedPoll {
+            id: "7".to_string(),
+            expires_at,
+        };
+
+        let pending = PendingPoll::new(5, &poll(Some(closes)), &config, posted, true);
+        assert_eq!((pending.id, pending.poll_id.as_str()), (5, "7"));
+        assert_eq!(pending.closes, closes);
+        assert!(pending.fallback);
+        // Or after expires_in, if it doesn't
+        let pending = PendingPoll::new(5, &poll(None), &config, posted, false);
+        assert_eq!(pending.closes, posted + Duration::hours(1));
+    }
+
+    #[test]
+    fn gives_up_after_a_few_lookups() {
+        let posted = Utc.ymd(2024, 5, 1).and_hms(12, 0, 0);
+        let poll = PostedPoll {
+            id: "7".to_string(),
+            expires_at: None,
+        };
+        let mut pending = PendingPoll::new(5, &poll, &config(&["a", "b"], 3600), posted, false);
+        let retries: Vec<bool> = (0..FETCH_ATTEMPTS).map(|_| pending.failed()).collect();
+        let mut expected = vec![true; FETCH_ATTEMPTS as usize - 1];
+        expected.push(false);
+        assert_eq!(retries, expected);
+    }
+
+    #[test]
+    fn fetches_and_sums_up_results() {
+        let server = MockServer::start(vec![(
+            200,
+            r#"{"id": "7", "expired": true, "votes_count": 15, "options": [
+                {"title": "love it", "votes_count": 12},
+                {"title": "needs mountains", "votes_count": 3}
+            ]}"#.to_string(),
+        )]);
+        let account = MastoData {
+            base: server.url.clone().into(),
+            client_id: "id".into(),
+            client_secret: "secret".into(),
+            redirect: "urn:ietf:wg:oauth:2.0:oob".into(),
+            token: "token".into(),
+        };
+        let results = fetch(&account, "7").expect("Unable to fetch results");
+        assert_eq!(results.summary(), "love it: 12, needs mountains: 3 (15 votes)");
+        let request = &server.requests()[0];
+        assert_eq!(request.path, "/api/v1/polls/7");
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+    }
+
+    #[test]
+    fn hidden_results_are_marked() {
+        let results = PollResults {
+            votes_count: 4,
+            options: vec![
+                PollOption {
+                    title: "love it".to_string(),
+                    votes_count: None,
+                },
+                PollOption {
+                    title: "too much water".to_string(),
+                    votes_count: None,
+                },
+            ],
+        };
+        assert_eq!(results.summary(), "love it: ?, too much water: ? (4 votes)");
+    }
+}
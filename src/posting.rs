@@ -0,0 +1,1774 @@
+//! Posting backends
+//!
+//! Every place the bot posts to implements `Poster`. The main loop tracks which backends have
+//! already posted the pending image, so when one of several fails, only that one is retried.
+
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Duration as ChrDuration, Utc};
+use elefren::entities::account::Account;
+use elefren::entities::status::Status;
+use elefren::{self, Mastodon, MastodonClient, MediaBuilder};
+use image::{self, FilterType, GenericImageView, ImageOutputFormat};
+use rand::{thread_rng, Rng};
+use reqwest::{Client, Response};
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use build_info;
+use errors::PostingError;
+use flavor::InstanceFlavor;
+use length::{shorten, Counting};
+use poll::{PollConfig, PostedPoll};
+use remote_media::RemoteMedia;
+use GenerationParams;
+
+/// Everything needed to make one post
+#[derive(Clone)]
+pub struct Post {
+    pub id: u32,
+    pub body: String,
+    pub alt_text: String,
+    /// Machine-readable line of generation parameters, added to the end of `alt_text` by
+    /// `fit_to_limits`
+    pub alt_params: Option<String>,
+    /// Image data, in `format`
+    pub image: Arc<[u8]>,
+    pub format: ImageFormat,
+    /// File holding the same data as `image`, for backends which can upload straight from it
+    pub file: Option<PathBuf>,
+    /// Parameters the image was generated with, if known
+    pub params: Option<GenerationParams>,
+    /// ISO 639-1 code of the language the text is in, if known
+    pub language: Option<String>,
+    /// Name of the landscape, if known
+    pub name: Option<String>,
+    /// Times the image was generated again before it turned out
+    pub regenerations: u32,
+    /// Id of the main Mastodon account's status to post as a reply to, see `thread_mode`
+    pub in_reply_to: Option<String>,
+    /// Further images to attach after `image`, like its heightmap. Only Mastodon posts these,
+    /// other backends leave them out.
+    pub attachments: Vec<Attachment>,
+    /// Poll to attach, see the `poll` module. Only Mastodon posts it.
+    pub poll: Option<PollConfig>,
+    /// Post publicly even if statuses are otherwise posted unlisted, to be boosted later
+    pub public: bool,
+}
+
+/// An image attached to a post besides the main one
+#[derive(Clone)]
+pub struct Attachment {
+    pub image: Arc<[u8]>,
+    pub format: ImageFormat,
+    pub alt_text: String,
+}
+
+impl Post {
+    /// Open a reader over the image data, returning it with the size of the data, if known
+    ///
+    /// Every upload attempt needs a fresh reader, as one left over from a failed attempt would
+    /// carry on from wherever that attempt stopped. Reads from `file` if it's set, which saves
+    /// holding another copy of the image while uploading, and from `image` otherwise, or if the
+    /// file can't be opened.
+    pub fn open_image(&self) -> (Box<dyn Read + Send>, Option<usize>) {
+        match self.file.as_ref().and_then(|path| File::open(path).ok()) {
+            Some(file) => {
+                let total = file.metadata().ok().map(|metadata| metadata.len() as usize);
+                (Box::new(file), total)
+            }
+            None => (
+                Box::new(Cursor::new(self.image.clone())),
+                Some(self.image.len()),
+            ),
+        }
+    }
+}
+
+/// Container the posted image is in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    /// Animated
+    Gif,
+}
+
+impl ImageFormat {
+    pub fn mimetype(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Gif => "image/gif",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Gif => "gif",
+        }
+    }
+
+    /// Guess the format from a file's extension
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("png") => Some(ImageFormat::Png),
+            Some("gif") => Some(ImageFormat::Gif),
+            _ => None,
+        }
+    }
+}
+
+/// Restrictions a backend places on posts
+#[derive(Default, Clone, Copy)]
+pub struct Limits {
+    /// Maximum length of the post body, in characters as counted by `body_counting`
+    pub max_chars: Option<usize>,
+    pub body_counting: Counting,
+    /// Maximum size of the image, in bytes
+    pub max_image_bytes: Option<usize>,
+    /// Maximum length of the image description, in characters
+    pub max_alt_chars: Option<usize>,
+}
+
+/// Backends are shared with the worker threads attempts run on, see `post_within`
+pub trait Poster: Send + Sync {
+    /// Name of the backend, used to keep track of where the pending image was already posted
+    fn name(&self) -> &str;
+
+    fn limits(&self) -> Limits {
+        Limits::default()
+    }
+
+    /// Make the post. `post` is already adapted to the backend's `limits`.
+    ///
+    /// `progress` carries over what an earlier, failed attempt at the same post got done, and
+    /// should be updated as steps succeed, so that a retry can pick up from there.
+    fn post(&self, post: &Post, progress: &mut Progress) -> Result<(), PostingError>;
+
+    /// Whether an earlier attempt which seemed to fail made the post after all, recording what
+    /// it made in `progress`
+    ///
+    /// Backends which can't tell say it didn't.
+    fn posted_after_all(
+        &self,
+        _post: &Post,
+        _progress: &mut Progress,
+    ) -> Result<bool, PostingError> {
+        Ok(false)
+    }
+}
+
+/// How far attempts at posting an image got before failing
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct Progress {
+    /// Id of the already uploaded image, for backends which upload it separately
+    #[serde(default)]
+    pub media_id: Option<String>,
+
+    /// Ids of the already uploaded `attachments`, in order
+    #[serde(default)]
+    pub attachment_ids: Vec<String>,
+
+    /// What the instance made of the uploaded image, see the `remote_media` module
+    #[serde(default)]
+    pub remote_media: Option<RemoteMedia>,
+
+    /// Failed attempts so far
+    #[serde(default)]
+    pub failures: u32,
+
+    /// Whether posting switched over to the fallback account, see `FallbackPoster`
+    #[serde(default)]
+    pub fallback: bool,
+
+    /// What the instance said about the posted status, for backends which can boost, link to
+    /// or otherwise come back to it
+    #[serde(default)]
+    pub receipt: Option<PostReceipt>,
+
+    /// Sent with every attempt at creating the status, so the instance can tell retries apart
+    /// from new posts
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+
+    /// How the image upload went, if this attempt uploaded it
+    #[serde(skip)]
+    pub last_upload: Option<UploadStats>,
+
+    /// Whether this attempt had its body changed after it was refused as a duplicate, see
+    /// `DuplicateSuffix`
+    #[serde(skip)]
+    pub varied_duplicate: bool,
+
+    /// When the first attempt which was given up on but may still go through was started, see
+    /// `post_within`
+    #[serde(default)]
+    pub abandoned_since: Option<DateTime<Utc>>,
+
+    /// When the status was first asked for, for backends which create it in a request of its
+    /// own, so that whether an attempt which failed after that made it anyway can be checked
+    #[serde(default)]
+    pub status_requested: Option<DateTime<Utc>>,
+
+    /// Times checking the main account for a status an earlier attempt made after all has
+    /// failed, see `FallbackPoster`
+    #[serde(default)]
+    pub lookup_failures: u32,
+}
+
+impl Progress {
+    /// Ids of the uploaded image and attachments, in the order they're attached
+    pub fn media_ids(&self) -> Vec<String> {
+        self.media_id
+            .iter()
+            .chain(self.attachment_ids.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+/// What creating a status got back from the instance
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PostReceipt {
+    pub status_id: String,
+    /// Web address of the status, or its URI if the instance gives none. Empty if it gives
+    /// neither, as some versions of Pleroma do.
+    pub url: String,
+    /// When the instance says it created the status
+    pub created_at: DateTime<Utc>,
+    /// Ids of the media attached to the status, in order
+    pub media_ids: Vec<String>,
+    /// The status's poll, if it has one
+    #[serde(default)]
+    pub poll: Option<PostedPoll>,
+}
+
+impl PostReceipt {
+    fn from_status(status: Status) -> PostReceipt {
+        PostReceipt {
+            url: status.url.filter(|url| !url.is_empty()).unwrap_or(status.uri),
+            created_at: status.created_at,
+            media_ids: status
+                .media_attachments
+                .into_iter()
+                .map(|attachment| attachment.id)
+                .collect(),
+            status_id: status.id,
+            poll: None,
+        }
+    }
+
+    /// The status's address, if the instance gave one
+    pub fn link(&self) -> Option<&str> {
+        if self.url.is_empty() {
+            None
+        } else {
+            Some(&self.url)
+        }
+    }
+
+    /// Where the status is, for the log
+    pub fn location(&self) -> String {
+        self.link()
+            .map_or_else(|| format!("id {}", self.status_id), str::to_string)
+    }
+}
+
+/// Size and duration of a finished image upload
+#[derive(Clone, Debug)]
+pub struct UploadStats {
+    pub bytes: usize,
+    pub duration: Duration,
+}
+
+/// How often `CountingReader` reports progress
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Passes reads through unchanged, counting the bytes and reporting progress every few seconds
+///
+/// Uploads on slow connections can take minutes, and would be silent otherwise.
+struct CountingReader<R> {
+    inner: R,
+    /// Total size, if known, for percentages
+    total: Option<usize>,
+    /// Shared with the uploader, which keeps it after the reader is handed off
+    count: Arc<AtomicUsize>,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R, total: Option<usize>, count: Arc<AtomicUsize>) -> CountingReader<R> {
+        let now = Instant::now();
+        CountingReader {
+            inner,
+            total,
+            count,
+            started: now,
+            last_report: now,
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let count = self.count.fetch_add(read, Ordering::SeqCst) + read;
+
+        if self.last_report.elapsed() >= PROGRESS_INTERVAL {
+            self.last_report = Instant::now();
+            let elapsed_ms = self.started.elapsed().as_millis().max(1) as usize;
+            let percent = self
+                .total
+                .filter(|&total| total > 0)
+                .map(|total| format!(" ({}%)", count * 100 / total))
+                .unwrap_or_default();
+            eprintln!(
+                "Uploading: {} bytes sent{}, {} KiB/s",
+                count,
+                percent,
+                count * 1000 / elapsed_ms / 1024
+            );
+        }
+
+        Ok(read)
+    }
+}
+
+/// Adapt `post` to `limits`, then post it with `poster`
+pub fn post_to(
+    poster: &dyn Poster,
+    post: &Post,
+    progress: &mut Progress,
+) -> Result<(), PostingError> {
+    poster.post(&fit_to_limits(post, poster.limits())?, progress)
+}
+
+/// Like `post_to`, but gives up on the attempt once it has taken longer than `limit`
+///
+/// Client timeouts only apply to each read and write, so a connection trickling a byte at a time
+/// can keep an upload going for hours. The attempt runs on a worker thread, which is abandoned
+/// at the limit and carries on in the background, so it could still post after all. To keep that
+/// from ending in two posts, the idempotency key is chosen before the attempt starts and kept
+/// for the next one, and backends which can't rely on it look for the status first, see
+/// `abandoned_since`.
+pub fn post_within(
+    poster: &Arc<dyn Poster>,
+    post: &Post,
+    progress: &mut Progress,
+    limit: Duration,
+) -> Result<(), PostingError> {
+    progress
+        .idempotency_key
+        .get_or_insert_with(new_idempotency_key);
+    let started = Utc::now();
+
+    let (sender, receiver) = channel();
+    let (poster, post, mut attempt) = (Arc::clone(poster), post.clone(), progress.clone());
+    thread::spawn(move || {
+        let result = post_to(poster.as_ref(), &post, &mut attempt);
+        // Nobody is listening any more if the attempt was abandoned
+        let _ = sender.send((result, attempt));
+    });
+
+    match receiver.recv_timeout(limit) {
+        Ok((result, attempt)) => {
+            *progress = attempt;
+            if result.is_ok() {
+                progress.abandoned_since = None;
+            }
+            result
+        }
+        Err(RecvTimeoutError::Timeout) => {
+            eprintln!(
+                "Attempt is still going after {} seconds, giving up on it",
+                limit.as_secs()
+            );
+            progress.abandoned_since.get_or_insert(started);
+            Err(PostingError::TimedOut(limit.as_secs()))
+        }
+        // The worker panicked, possibly after the status was made, so treat it like an attempt
+        // given up on
+        Err(RecvTimeoutError::Disconnected) => {
+            eprintln!("Attempt panicked, trying again later");
+            progress.abandoned_since.get_or_insert(started);
+            Err(PostingError::Panicked)
+        }
+    }
+}
+
+/// Shorten the body and alt text and scale down the image as needed to satisfy `limits`
+///
+/// The parameters line is added to the alt text here. It counts towards the alt text limit, but
+/// is always kept whole, with the rest of the alt text shortened to make room for it.
+pub fn fit_to_limits(post: &Post, limits: Limits) -> Result<Post, PostingError> {
+    let mut fitted = post.clone();
+
+    if let Some(max_chars) = limits.max_chars {
+        fitted.body = shorten(&fitted.body, max_chars, limits.body_counting);
+    }
+
+    if let Some(params) = fitted.alt_params.take() {
+        let available = limits
+            .max_alt_chars
+            .map(|max| max.saturating_sub(params.chars().count() + 1));
+        let alt_text = match available {
+            Some(available) => shorten(&fitted.alt_text, available, Counting::Chars),
+            None => fitted.alt_text.clone(),
+        };
+        fitted.alt_text = if alt_text.is_empty() {
+            params
+        } else {
+            format!("{}\n{}", alt_text, params)
+        };
+    } else if let Some(max_alt_chars) = limits.max_alt_chars {
+        fitted.alt_text = shorten(&fitted.alt_text, max_alt_chars, Counting::Chars);
+    }
+    if let Some(max_alt_chars) = limits.max_alt_chars {
+        for attachment in &mut fitted.attachments {
+            attachment.alt_text = shorten(&attachment.alt_text, max_alt_chars, Counting::Chars);
+        }
+    }
+
+    if let Some(max_bytes) = limits.max_image_bytes {
+        if fitted.image.len() > max_bytes {
+            // Animations come out of this as a still of their first frame
+            fitted.image = shrink_image(&fitted.image, max_bytes)?.into();
+            fitted.format = ImageFormat::Png;
+            fitted.file = None;
+        }
+    }
+
+    Ok(fitted)
+}
+
+/// Deserialize a successful JSON response, or turn an unsuccessful one into an error
+pub fn parse_response<T: DeserializeOwned>(
+    backend: &str,
+    mut response: Response,
+) -> Result<T, PostingError> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response.json()?)
+    } else {
+        Err(PostingError::Rejected {
+            backend: backend.to_string(),
+            status: status.as_u16(),
+            message: response.text().unwrap_or_default(),
+        })
+    }
+}
+
+/// Read the width and height out of a PNG's or GIF's header
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    // 6 byte signature, then width and height as little endian 16 bit numbers
+    if data.len() >= 10 && &data[..4] == b"GIF8" {
+        let read_u16 = |bytes: &[u8]| u32::from(bytes[0]) | (u32::from(bytes[1]) << 8);
+        return Some((read_u16(&data[6..8]), read_u16(&data[8..10])));
+    }
+
+    // 8 byte signature, then the IHDR chunk: 4 bytes length, 4 bytes type, then width and height
+    if data.len() < 24 || &data[12..16] != b"IHDR" {
+        return None;
+    }
+
+    let read_u32 = |bytes: &[u8]| {
+        (u32::from(bytes[0]) << 24)
+            | (u32::from(bytes[1]) << 16)
+            | (u32::from(bytes[2]) << 8)
+            | u32::from(bytes[3])
+    };
+    Some((read_u32(&data[16..20]), read_u32(&data[20..24])))
+}
+
+/// The first frame of an animation, as PNG
+pub fn first_frame(data: &[u8]) -> Result<Vec<u8>, image::ImageError> {
+    let mut still = Vec::new();
+    image::load_from_memory(data)?.write_to(&mut still, ImageOutputFormat::PNG)?;
+    Ok(still)
+}
+
+/// Scale an image down step by step until it fits within `max_bytes`, as PNG
+fn shrink_image(data: &[u8], max_bytes: usize) -> Result<Vec<u8>, PostingError> {
+    let original = image::load_from_memory(data)
+        .map_err(|e| PostingError::ImageTooLarge(e.to_string()))?;
+    let (mut width, mut height) = original.dimensions();
+
+    loop {
+        width = width * 3 / 4;
+        height = height * 3 / 4;
+        if width == 0 || height == 0 {
+            return Err(PostingError::ImageTooLarge(format!(
+                "could not get image below {} bytes",
+                max_bytes
+            )));
+        }
+
+        let mut shrunk = Vec::new();
+        original
+            .resize(width, height, FilterType::Nearest)
+            .write_to(&mut shrunk, ImageOutputFormat::PNG)
+            .map_err(|e| PostingError::ImageTooLarge(e.to_string()))?;
+
+        if shrunk.len() <= max_bytes {
+            eprintln!(
+                "Scaled image down to {}x{} ({} bytes) to fit size limit",
+                width,
+                height,
+                shrunk.len()
+            );
+            return Ok(shrunk);
+        }
+    }
+}
+
+/// What is added to a post body which an instance refused as a duplicate of a recent one
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateSuffix {
+    /// A zero-width space, which leaves the post looking the same
+    Invisible,
+    /// The image's id, in parentheses
+    Id,
+}
+
+impl Default for DuplicateSuffix {
+    fn default() -> DuplicateSuffix {
+        DuplicateSuffix::Invisible
+    }
+}
+
+impl DuplicateSuffix {
+    /// `body` with the suffix added
+    pub fn vary(self, body: &str, id: u32) -> String {
+        match self {
+            DuplicateSuffix::Invisible => format!("{}\u{200b}", body),
+            DuplicateSuffix::Id => format!("{} ({})", body, id),
+        }
+    }
+}
+
+/// Error message of a refused request, as Mastodon and compatible servers send it
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    error: String,
+}
+
+/// Whether an instance refused a status, answering with `code` and `body`, for repeating a
+/// recent status from the same account
+///
+/// Mastodon itself allows that, but anti-spam plugins and some forks refuse with 422 and an
+/// error like "Validation failed: Text has already been posted". Other 422s, like for an
+/// attachment the instance no longer knows, must not match.
+pub fn is_duplicate_status(code: u16, body: &str) -> bool {
+    if code != 422 {
+        return false;
+    }
+    let message = error_message(body);
+
+    ["duplicate", "already been posted", "already posted", "identical"]
+        .iter()
+        .any(|phrase| message.contains(phrase))
+}
+
+/// Whether an instance refused a status, answering with `code` and `body`, for having a poll
+///
+/// Mastodon refuses polls on statuses with media with its error for too many attachments,
+/// "Cannot attach more than 4 files", which can't be why for the few images the bot attaches.
+pub fn is_poll_refusal(code: u16, body: &str) -> bool {
+    if code != 422 {
+        return false;
+    }
+    let message = error_message(body);
+
+    message.contains("poll") || message.contains("more than")
+}
+
+/// Whether an instance refused a status, answering with `code` and `body`, for an attachment it
+/// doesn't know
+///
+/// Unattached media is cleaned up after a while. Mastodon then refuses the id with 422 and
+/// "Media ... not found or already attached to another post", and Pleroma and GoToSocial with
+/// 400 or 404 and a similar error. Other refusals don't mean the upload is gone.
+pub fn is_unknown_media(code: u16, body: &str) -> bool {
+    if code != 400 && code != 404 && code != 422 {
+        return false;
+    }
+    let message = error_message(body);
+
+    message.contains("media")
+        && ["not found", "does not exist", "doesn't exist"]
+            .iter()
+            .any(|phrase| message.contains(phrase))
+}
+
+/// The error in an instance's error response `body`, lowercased, or the whole body if it isn't
+/// one
+fn error_message(body: &str) -> String {
+    match serde_json::from_str::<ApiErrorBody>(body) {
+        Ok(parsed) => parsed.error,
+        Err(_) => body.to_string(),
+    }.to_lowercase()
+}
+
+/// Posts to a Mastodon (or compatible) account
+pub struct MastodonPoster {
+    masto: Mastodon,
+    /// For requests Elefren can't make
+    client: Client,
+    /// Post as unlisted, to be boosted publicly later, rather than as public
+    unlisted: bool,
+    /// Already resolved, never `Auto`
+    flavor: InstanceFlavor,
+    /// Added to the body of a status refused as a duplicate, before trying it again
+    duplicate_suffix: DuplicateSuffix,
+    /// For looking up each posted status again, with a short timeout, if `verify_post` is set
+    verify_client: Option<Client>,
+}
+
+/// How long looking up a posted status again may take, see `MastodonPoster::verify_status`
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of a status creation request
+#[derive(Serialize)]
+struct NewStatus<'a> {
+    status: &'a str,
+    media_ids: &'a [String],
+    visibility: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_reply_to_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    poll: Option<&'a PollConfig>,
+}
+
+/// A created status, with its poll, which Elefren's `Status` leaves out
+#[derive(Deserialize)]
+struct CreatedStatus {
+    #[serde(flatten)]
+    status: Status,
+    #[serde(default)]
+    poll: Option<PostedPoll>,
+}
+
+/// Random key in the form of a version 4 UUID
+pub fn new_idempotency_key() -> String {
+    let mut bytes: [u8; 16] = thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+impl MastodonPoster {
+    pub fn new(
+        flavor: InstanceFlavor,
+        masto: Mastodon,
+        unlisted: bool,
+        duplicate_suffix: DuplicateSuffix,
+        verify: bool,
+    ) -> MastodonPoster {
+        MastodonPoster {
+            masto,
+            client: build_info::http_client(),
+            unlisted,
+            flavor,
+            duplicate_suffix,
+            verify_client: if verify {
+                Some(build_info::http_client_with_timeout(VERIFY_TIMEOUT))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Upload the image as a media attachment, returning its id
+    fn upload(&self, post: &Post, progress: &mut Progress) -> Result<String, PostingError> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let (reader, total) = post.open_image();
+        let source = MediaBuilder::from_reader(CountingReader::new(reader, total, count.clone()));
+        let started = Instant::now();
+        let attachment = self.masto.media(MediaBuilder {
+            description: Some(post.alt_text.clone()),
+            mimetype: Some(post.format.mimetype().to_string()),
+            filename: Some(format!("{}.{}", post.id, post.format.extension())),
+            ..source
+        }).map_err(PostingError::ElefrenError)?;
+
+        let stats = UploadStats {
+            bytes: count.load(Ordering::SeqCst),
+            duration: started.elapsed(),
+        };
+        eprintln!(
+            "Uploaded {} bytes in {} ms",
+            stats.bytes,
+            stats.duration.as_millis()
+        );
+        progress.last_upload = Some(stats);
+        progress.remote_media = Some(RemoteMedia::from_attachment(&attachment));
+
+        Ok(attachment.id)
+    }
+
+    /// Upload those of `post.attachments` which aren't yet, keeping their ids in `progress`
+    fn upload_attachments(
+        &self,
+        post: &Post,
+        progress: &mut Progress,
+    ) -> Result<(), PostingError> {
+        for (i, attachment) in post.attachments.iter().enumerate() {
+            if i < progress.attachment_ids.len() {
+                continue;
+            }
+            let source = MediaBuilder::from_reader(Cursor::new(attachment.image.clone()));
+            let uploaded = self.masto.media(MediaBuilder {
+                description: Some(attachment.alt_text.clone()),
+                mimetype: Some(attachment.format.mimetype().to_string()),
+                filename: Some(format!(
+                    "{}-{}.{}",
+                    post.id,
+                    i + 2,
+                    attachment.format.extension()
+                )),
+                ..source
+            }).map_err(PostingError::ElefrenError)?;
+            progress.attachment_ids.push(uploaded.id);
+        }
+        Ok(())
+    }
+
+    /// Post a status with already uploaded attachments
+    ///
+    /// A request which timed out may still have gone through. The instance recognizes a retry by
+    /// its `idempotency_key` and returns the status it already made, instead of posting the image
+    /// a second time. Elefren can't send the header, so the request is made here.
+    fn create_status(
+        &self,
+        post: &Post,
+        media_ids: &[String],
+        idempotency_key: &str,
+    ) -> Result<PostReceipt, PostingError> {
+        let data = &self.masto.data;
+        let mut request = self
+            .client
+            .post(&format!("{}/api/v1/statuses", data.base.trim_end_matches('/')))
+            .bearer_auth(&data.token)
+            .json(&NewStatus {
+                status: &post.body,
+                media_ids,
+                visibility: if self.unlisted && !post.public { "unlisted" } else { "public" },
+                language: post.language.as_ref().map(String::as_str),
+                in_reply_to_id: post.in_reply_to.as_ref().map(String::as_str),
+                poll: post.poll.as_ref(),
+            });
+        if self.flavor.supports_idempotency() {
+            request = request.header("Idempotency-Key", idempotency_key);
+        }
+        let mut response = request.send()?;
+
+        // Reported the way Elefren would, so failures are handled as before
+        let code = response.status();
+        if code.is_client_error() || code.is_server_error() {
+            let text = response.text().unwrap_or_default();
+            eprintln!("Instance refused the status with {}: {}", code, text);
+            if is_duplicate_status(code.as_u16(), &text) {
+                return Err(PostingError::DuplicateStatus(text));
+            }
+            if post.poll.is_some() && is_poll_refusal(code.as_u16(), &text) {
+                return Err(PostingError::PollRefused(text));
+            }
+            if is_unknown_media(code.as_u16(), &text) {
+                return Err(PostingError::UnknownMedia {
+                    status: code.as_u16(),
+                    message: text,
+                });
+            }
+            return Err(PostingError::ElefrenError(if code.is_client_error() {
+                elefren::Error::Client(code)
+            } else {
+                elefren::Error::Server(code)
+            }));
+        }
+        let created: CreatedStatus = response.json()?;
+        Ok(PostReceipt {
+            poll: created.poll,
+            ..PostReceipt::from_status(created.status)
+        })
+    }
+
+    /// Look among the account's latest statuses for one with the image, posted since `since`
+    ///
+    /// For after an attempt was given up on which may have gone through after all, on instances
+    /// which don't take idempotency keys. Statuses are recognized by the ids of the media uploaded
+    /// for the image in `progress`, as the body may have been varied and the alt text is often
+    /// the same for every image. Without an upload on record, nothing can have been posted with
+    /// it. A minute is allowed for the instance's clock being off.
+    fn find_posted_status(
+        &self,
+        progress: &Progress,
+        since: DateTime<Utc>,
+    ) -> Result<Option<Status>, PostingError> {
+        let media_ids = progress.media_ids();
+        if media_ids.is_empty() {
+            return Ok(None);
+        }
+        let data = &self.masto.data;
+        let base = data.base.trim_end_matches('/');
+        let get = |url: String| self.client.get(&url).bearer_auth(&data.token).send();
+
+        let account: Account = parse_response(
+            self.name(),
+            get(format!("{}/api/v1/accounts/verify_credentials", base))?,
+        )?;
+        let statuses: Vec<Status> = parse_response(
+            self.name(),
+            get(format!("{}/api/v1/accounts/{}/statuses?limit=20", base, account.id))?,
+        )?;
+
+        let since = since - ChrDuration::minutes(1);
+        Ok(statuses.into_iter().find(|status| {
+            status.created_at >= since
+                && status
+                    .media_attachments
+                    .iter()
+                    .any(|attachment| media_ids.contains(&attachment.id))
+        }))
+    }
+
+    /// Like `create_status`, but if the instance refuses the status as a duplicate, try once
+    /// more right away with `duplicate_suffix` added to the body
+    fn create_varied_status(
+        &self,
+        post: &Post,
+        media_ids: &[String],
+        idempotency_key: &str,
+        progress: &mut Progress,
+    ) -> Result<PostReceipt, PostingError> {
+        match self.create_status(post, media_ids, idempotency_key) {
+            Err(PostingError::DuplicateStatus(_)) => {
+                eprintln!(
+                    "WARNING: Instance refused the status as a duplicate of a recent one, trying \
+                     again with a varied body. Consider making bodies differ, e.g. with {{name}}."
+                );
+                progress.varied_duplicate = true;
+                let varied = Post {
+                    body: self.duplicate_suffix.vary(&post.body, post.id),
+                    ..post.clone()
+                };
+                self.create_status(&varied, media_ids, idempotency_key)
+            }
+            result => result,
+        }
+    }
+
+    /// Like `create_varied_status`, but with `verify_post`, the status is looked up again
+    /// before it counts as posted
+    fn create_verified_status(
+        &self,
+        post: &Post,
+        media_ids: &[String],
+        idempotency_key: &str,
+        progress: &mut Progress,
+    ) -> Result<PostReceipt, PostingError> {
+        let receipt = self.create_varied_status(post, media_ids, idempotency_key, progress)?;
+        self.verify_status(&receipt, media_ids.len())?;
+        Ok(receipt)
+    }
+
+    /// Check that the status `receipt` is for exists, with `media` attachments
+    ///
+    /// A response mangled on its way back, by a proxy say, can look like a posted status when
+    /// there is none. Any failure, including of the lookup itself, is transient: the retry
+    /// sends the same idempotency key, so a status which did get posted isn't posted twice.
+    fn verify_status(&self, receipt: &PostReceipt, media: usize) -> Result<(), PostingError> {
+        let client = match self.verify_client {
+            Some(ref client) => client,
+            None => return Ok(()),
+        };
+        let unverified = |problem: String| {
+            PostingError::Unverified(format!("status {}: {}", receipt.status_id, problem))
+        };
+
+        let data = &self.masto.data;
+        let url = format!(
+            "{}/api/v1/statuses/{}",
+            data.base.trim_end_matches('/'),
+            receipt.status_id
+        );
+        let response = client
+            .get(&url)
+            .bearer_auth(&data.token)
+            .send()
+            .map_err(|e| unverified(e.to_string()))?;
+        let status: Status =
+            parse_response(self.name(), response).map_err(|e| unverified(e.to_string()))?;
+        if status.media_attachments.len() != media {
+            return Err(unverified(format!(
+                "it has {} media attachments, expected {}",
+                status.media_attachments.len(),
+                media
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Posts to a fallback account once the main one has failed too often
+///
+/// Once switched over, the main account is not tried again for the same image. Otherwise, a
+/// request to it which only seemed to fail could still go through after the fallback posted, and
+/// the image would end up on both. For the same reason, before switching, the main account is
+/// checked for a status an earlier attempt made after all, see `Poster::posted_after_all`.
+///
+/// The main account being down usually means that check fails too. It's tried again on each
+/// attempt, in case the main account comes back, but after failing `after_attempts` times the
+/// switch is made anyway, as waiting on the main account forever is what the fallback is for.
+pub struct FallbackPoster {
+    primary: Box<dyn Poster>,
+    fallback: Box<dyn Poster>,
+    after_attempts: u32,
+}
+
+impl FallbackPoster {
+    pub fn new(
+        primary: Box<dyn Poster>,
+        fallback: Box<dyn Poster>,
+        after_attempts: u32,
+    ) -> FallbackPoster {
+        FallbackPoster {
+            primary,
+            fallback,
+            after_attempts,
+        }
+    }
+}
+
+impl Poster for FallbackPoster {
+    /// Same as the main account, so either counts as done for the image
+    fn name(&self) -> &str {
+        self.primary.name()
+    }
+
+    fn limits(&self) -> Limits {
+        self.primary.limits()
+    }
+
+    fn post(&self, post: &Post, progress: &mut Progress) -> Result<(), PostingError> {
+        if !progress.fallback && progress.failures >= self.after_attempts {
+            // If it can't be told whether the image is on the main account already, it stays
+            // there, rather than risk posting it twice
+            match self.primary.posted_after_all(post, progress) {
+                Ok(true) => {
+                    eprintln!(
+                        "An earlier attempt posted to {} after all, not switching to the \
+                         fallback account",
+                        self.primary.name()
+                    );
+                    return Ok(());
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    progress.lookup_failures += 1;
+                    if progress.lookup_failures < self.after_attempts {
+                        eprintln!(
+                            "Unable to check whether an earlier attempt posted to {}, not \
+                             switching to the fallback account yet: {}",
+                            self.primary.name(),
+                            e
+                        );
+                        return Err(e);
+                    }
+                    eprintln!(
+                        "WARNING: Unable to check whether an earlier attempt posted to {} {} \
+                         times, switching to the fallback account anyway: {}",
+                        self.primary.name(),
+                        progress.lookup_failures,
+                        e
+                    );
+                }
+            }
+            eprintln!(
+                "Posting to {} failed {} times, switching to the fallback account for this image",
+                self.primary.name(),
+                progress.failures
+            );
+            progress.fallback = true;
+            // Anything uploaded belongs to the main account
+            progress.media_id = None;
+            progress.remote_media = None;
+            progress.attachment_ids.clear();
+        }
+
+        if progress.fallback {
+            // The status replied to belongs to the main account, which may be elsewhere
+            let unthreaded = Post {
+                in_reply_to: None,
+                ..post.clone()
+            };
+            self.fallback.post(&unthreaded, progress)?;
+            eprintln!("Posted to the fallback {} account", self.primary.name());
+            Ok(())
+        } else {
+            self.primary.post(post, progress)
+        }
+    }
+}
+
+impl Poster for MastodonPoster {
+    fn name(&self) -> &str {
+        "mastodon"
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            // The software's defaults, instances may allow more
+            max_chars: self.flavor.max_chars(),
+            body_counting: self.flavor.body_counting(),
+            max_alt_chars: self.flavor.max_alt_chars(),
+            ..Limits::default()
+        }
+    }
+
+    fn post(&self, post: &Post, progress: &mut Progress) -> Result<(), PostingError> {
+        let key = progress
+            .idempotency_key
+            .get_or_insert_with(new_idempotency_key)
+            .clone();
+
+        if let Some(since) = progress.abandoned_since {
+            if !self.flavor.supports_idempotency() {
+                if let Some(status) = self.find_posted_status(progress, since)? {
+                    eprintln!("An attempt given up on posted the status after all");
+                    progress.receipt = Some(PostReceipt::from_status(status));
+                    return Ok(());
+                }
+            }
+        }
+
+        // Attachments left out since the last attempt aren't posted
+        progress.attachment_ids.truncate(post.attachments.len());
+        if progress.media_id.is_some() && progress.attachment_ids.len() == post.attachments.len() {
+            let uploaded = progress.media_ids();
+            progress.status_requested.get_or_insert_with(Utc::now);
+            match self.create_verified_status(post, &uploaded, &key, progress) {
+                Err(ref e @ PostingError::UnknownMedia { .. }) => {
+                    eprintln!(
+                        "Instance rejected media {} from an earlier attempt, uploading again: {}",
+                        uploaded.join(", "),
+                        e
+                    );
+                    progress.media_id = None;
+                    progress.attachment_ids.clear();
+                }
+                result => {
+                    progress.receipt = Some(result?);
+                    return Ok(());
+                }
+            }
+        }
+
+        if progress.media_id.is_none() {
+            progress.media_id = Some(self.upload(post, progress)?);
+        }
+        self.upload_attachments(post, progress)?;
+        let media_ids = progress.media_ids();
+        progress.status_requested.get_or_insert_with(Utc::now);
+        progress.receipt = Some(self.create_verified_status(post, &media_ids, &key, progress)?);
+        Ok(())
+    }
+
+    /// Looks for the status among the account's latest, if an earlier attempt got as far as
+    /// asking for it, or was given up on while it may have
+    fn posted_after_all(
+        &self,
+        _post: &Post,
+        progress: &mut Progress,
+    ) -> Result<bool, PostingError> {
+        let earliest = progress
+            .status_requested
+            .into_iter()
+            .chain(progress.abandoned_since)
+            .min();
+        let since = match earliest {
+            Some(since) => since,
+            None => return Ok(false),
+        };
+        match self.find_posted_status(progress, since)? {
+            Some(status) => {
+                progress.receipt = Some(PostReceipt::from_status(status));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use elefren::{Data, MastodonBuilder};
+    use tempfile;
+    use test_support::{self, MockServer};
+    use toml;
+
+    /// Backend which fails or not as told, counting what it's asked to do
+    #[derive(Clone)]
+    struct FakePoster {
+        fails: bool,
+        /// What `posted_after_all` answers, with an error as the status it fails with
+        earlier_post: Result<bool, u16>,
+        posts: Arc<AtomicUsize>,
+        checks: Arc<AtomicUsize>,
+    }
+
+    impl FakePoster {
+        fn new(fails: bool, earlier_post: Result<bool, u16>) -> FakePoster {
+            FakePoster {
+                fails,
+                earlier_post,
+                posts: Arc::new(AtomicUsize::new(0)),
+                checks: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    fn unavailable(status: u16) -> PostingError {
+        PostingError::Rejected {
+            backend: "mastodon".to_string(),
+            status,
+            message: "unavailable".to_string(),
+        }
+    }
+
+    impl Poster for FakePoster {
+        fn name(&self) -> &str {
+            "mastodon"
+        }
+
+        fn post(&self, _post: &Post, _progress: &mut Progress) -> Result<(), PostingError> {
+            self.posts.fetch_add(1, Ordering::SeqCst);
+            if self.fails {
+                Err(unavailable(503))
+            } else {
+                Ok(())
+            }
+        }
+
+        fn posted_after_all(
+            &self,
+            _post: &Post,
+            _progress: &mut Progress,
+        ) -> Result<bool, PostingError> {
+            self.checks.fetch_add(1, Ordering::SeqCst);
+            self.earlier_post.map_err(unavailable)
+        }
+    }
+
+    /// Backend with a bug, which panics partway through posting
+    struct PanickingPoster;
+
+    impl Poster for PanickingPoster {
+        fn name(&self) -> &str {
+            "mastodon"
+        }
+
+        fn post(&self, _post: &Post, _progress: &mut Progress) -> Result<(), PostingError> {
+            panic!("bug in the backend");
+        }
+    }
+
+    #[test]
+    fn panicking_attempt_is_transient() {
+        let poster: Arc<dyn Poster> = Arc::new(PanickingPoster);
+        let mut progress = Progress::default();
+        let error = post_within(
+            &poster,
+            &test_support::post(1),
+            &mut progress,
+            Duration::from_secs(30),
+        ).unwrap_err();
+        assert!(error.is_transient());
+        assert!(progress.abandoned_since.is_some());
+        assert!(progress.idempotency_key.is_some());
+    }
+
+    /// A fallback poster switching after 2 failures, with the main account and fallback it uses
+    fn fallback_poster(
+        earlier_post: Result<bool, u16>,
+    ) -> (FallbackPoster, FakePoster, FakePoster) {
+        let primary = FakePoster::new(true, earlier_post);
+        let fallback = FakePoster::new(false, Ok(false));
+        let poster = FallbackPoster::new(Box::new(primary.clone()), Box::new(fallback.clone()), 2);
+        (poster, primary, fallback)
+    }
+
+    fn failed_twice() -> Progress {
+        Progress {
+            failures: 2,
+            media_id: Some("main-account-media".to_string()),
+            ..Progress::default()
+        }
+    }
+
+    #[test]
+    fn fallback_waits_for_failures() {
+        let (poster, primary, fallback) = fallback_poster(Ok(false));
+        let mut progress = Progress {
+            failures: 1,
+            ..Progress::default()
+        };
+        assert!(poster.post(&test_support::post(1), &mut progress).is_err());
+        assert_eq!(primary.posts.load(Ordering::SeqCst), 1);
+        assert_eq!(primary.checks.load(Ordering::SeqCst), 0);
+        assert_eq!(fallback.posts.load(Ordering::SeqCst), 0);
+        assert!(!progress.fallback);
+    }
+
+    #[test]
+    fn fallback_after_checking_main_account() {
+        let (poster, primary, fallback) = fallback_poster(Ok(false));
+        let mut progress = failed_twice();
+        poster
+            .post(&test_support::post(1), &mut progress)
+            .expect("Fallback failed");
+        assert_eq!(primary.checks.load(Ordering::SeqCst), 1);
+        assert_eq!(primary.posts.load(Ordering::SeqCst), 0);
+        assert_eq!(fallback.posts.load(Ordering::SeqCst), 1);
+        assert!(progress.fallback);
+        assert_eq!(progress.media_id, None);
+
+        // Not checked again once switched over
+        poster
+            .post(&test_support::post(1), &mut progress)
+            .expect("Fallback failed");
+        assert_eq!(primary.checks.load(Ordering::SeqCst), 1);
+        assert_eq!(fallback.posts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn no_fallback_when_main_account_posted_after_all() {
+        let (poster, primary, fallback) = fallback_poster(Ok(true));
+        let mut progress = failed_twice();
+        poster
+            .post(&test_support::post(1), &mut progress)
+            .expect("Earlier post not recognized");
+        assert_eq!(primary.posts.load(Ordering::SeqCst), 0);
+        assert_eq!(fallback.posts.load(Ordering::SeqCst), 0);
+        assert!(!progress.fallback);
+    }
+
+    #[test]
+    fn no_fallback_when_main_account_cant_be_checked() {
+        let (poster, _, fallback) = fallback_poster(Err(503));
+        let mut progress = failed_twice();
+        let error = poster
+            .post(&test_support::post(1), &mut progress)
+            .unwrap_err();
+        assert!(error.is_transient());
+        assert_eq!(fallback.posts.load(Ordering::SeqCst), 0);
+        assert!(!progress.fallback);
+        assert_eq!(progress.media_id, Some("main-account-media".to_string()));
+    }
+
+    #[test]
+    fn fallback_when_main_account_stays_unreachable() {
+        let (poster, primary, fallback) = fallback_poster(Err(503));
+        let mut progress = failed_twice();
+        assert!(poster.post(&test_support::post(1), &mut progress).is_err());
+        assert_eq!(progress.lookup_failures, 1);
+        assert_eq!(fallback.posts.load(Ordering::SeqCst), 0);
+
+        poster
+            .post(&test_support::post(1), &mut progress)
+            .expect("Fallback failed");
+        assert_eq!(primary.checks.load(Ordering::SeqCst), 2);
+        assert_eq!(primary.posts.load(Ordering::SeqCst), 0);
+        assert_eq!(fallback.posts.load(Ordering::SeqCst), 1);
+        assert!(progress.fallback);
+    }
+
+    /// Poster for the instance at `base`, with nothing left to upload for an image
+    fn mastodon_poster(base: &str, flavor: InstanceFlavor) -> (MastodonPoster, Progress) {
+        let masto = MastodonBuilder::new()
+            .data(Data {
+                base: base.to_string().into(),
+                client_id: "id".into(),
+                client_secret: "secret".into(),
+                redirect: "urn:ietf:wg:oauth:2.0:oob".into(),
+                token: "token".into(),
+            })
+            .build()
+            .expect("Unable to build client");
+        let poster = MastodonPoster::new(flavor, masto, false, DuplicateSuffix::default(), false);
+        let progress = Progress {
+            media_id: Some("uploaded".to_string()),
+            ..Progress::default()
+        };
+        (poster, progress)
+    }
+
+    #[test]
+    fn retries_send_the_same_idempotency_key() {
+        let server = MockServer::start(vec![
+            (503, r#"{"error":"unavailable"}"#.to_string()),
+            (502, r#"{"error":"bad gateway"}"#.to_string()),
+            (503, r#"{"error":"unavailable"}"#.to_string()),
+        ]);
+        let (poster, mut progress) = mastodon_poster(&server.url, InstanceFlavor::Mastodon);
+        let post = test_support::post(1);
+
+        assert!(poster.post(&post, &mut progress).is_err());
+        assert!(poster.post(&post, &mut progress).is_err());
+        // As if the bot restarted, with the progress read back from the attempt log
+        let saved = toml::to_string(&progress).expect("Unable to serialize progress");
+        let mut progress: Progress = toml::from_str(&saved).expect("Unable to read progress");
+        let (poster, _) = mastodon_poster(&server.url, InstanceFlavor::Mastodon);
+        assert!(poster.post(&post, &mut progress).is_err());
+
+        let requests = server.requests();
+        let keys: Vec<&str> = requests
+            .iter()
+            .map(|request| {
+                assert_eq!(request.path, "/api/v1/statuses");
+                request.header("idempotency-key").expect("No idempotency key")
+            })
+            .collect();
+        assert_eq!(keys.len(), 3);
+        assert!(!keys[0].is_empty());
+        assert!(keys.iter().all(|key| *key == keys[0]), "{:?}", keys);
+        assert_eq!(progress.idempotency_key.as_ref().map(String::as_str), Some(keys[0]));
+    }
+
+    #[test]
+    fn images_get_their_own_idempotency_keys() {
+        let server = MockServer::start(vec![
+            (503, r#"{"error":"unavailable"}"#.to_string()),
+            (503, r#"{"error":"unavailable"}"#.to_string()),
+        ]);
+        let (poster, mut first) = mastodon_poster(&server.url, InstanceFlavor::Mastodon);
+        let mut second = first.clone();
+        assert!(poster.post(&test_support::post(1), &mut first).is_err());
+        assert!(poster.post(&test_support::post(2), &mut second).is_err());
+
+        let requests = server.requests();
+        assert_ne!(
+            requests[0].header("idempotency-key"),
+            requests[1].header("idempotency-key")
+        );
+    }
+
+    #[test]
+    fn posted_status_recognized_by_media_id() {
+        let alt_text = &test_support::post(1).alt_text;
+        let status = |id: &str, media: &str| {
+            test_support::status_json(
+                id,
+                &format!("https://example.org/statuses/{}", id),
+                None,
+                &[test_support::attachment_json(media, alt_text)],
+            )
+        };
+        let server = MockServer::start(vec![
+            (200, test_support::account_json()),
+            (200, format!("[{}, {}]", status("2", "other"), status("1", "uploaded"))),
+        ]);
+        let (poster, mut progress) = mastodon_poster(&server.url, InstanceFlavor::Gotosocial);
+        progress.status_requested = Some(Utc.ymd(2024, 5, 1).and_hms(11, 30, 0));
+        assert!(poster
+            .posted_after_all(&test_support::post(1), &mut progress)
+            .expect("Unable to look for the status"));
+        assert_eq!(progress.receipt.expect("No receipt").status_id, "1");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn no_lookup_without_an_upload() {
+        let server = MockServer::start(vec![]);
+        let (poster, _) = mastodon_poster(&server.url, InstanceFlavor::Gotosocial);
+        let mut progress = Progress {
+            status_requested: Some(Utc.ymd(2024, 5, 1).and_hms(11, 30, 0)),
+            ..Progress::default()
+        };
+        assert!(!poster
+            .posted_after_all(&test_support::post(1), &mut progress)
+            .expect("Unable to look for the status"));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn no_idempotency_key_where_unsupported() {
+        let server = MockServer::start(vec![(503, r#"{"error":"unavailable"}"#.to_string())]);
+        let (poster, mut progress) = mastodon_poster(&server.url, InstanceFlavor::Gotosocial);
+        assert!(poster.post(&test_support::post(1), &mut progress).is_err());
+        assert_eq!(server.requests()[0].header("idempotency-key"), None);
+    }
+
+    /// Post an image to a mock instance of `flavor`, which takes the upload as `media_id` and
+    /// answers the status creation with `status`
+    fn post_to(
+        flavor: InstanceFlavor,
+        media_id: &str,
+        status: String,
+    ) -> (Progress, Vec<test_support::Request>) {
+        let post = test_support::post(1);
+        let server = MockServer::start(vec![
+            (200, test_support::attachment_json(media_id, &post.alt_text)),
+            (200, status),
+        ]);
+        let (poster, _) = mastodon_poster(&server.url, flavor);
+        let mut progress = Progress::default();
+        poster.post(&post, &mut progress).expect("Unable to post");
+        (progress, server.requests())
+    }
+
+    /// Media ids in a status creation request
+    fn requested_media(request: &test_support::Request) -> Vec<String> {
+        let body: serde_json::Value =
+            serde_json::from_slice(&request.body).expect("Status request isn't JSON");
+        body["media_ids"]
+            .as_array()
+            .expect("No media ids")
+            .iter()
+            .map(|id| id.as_str().expect("Media id isn't a string").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn posts_to_mastodon() {
+        let url = "https://example.org/@cubeglobe/110";
+        let attachment = test_support::attachment_json("109", "An isometric landscape");
+        let status = test_support::status_json(
+            "110",
+            "https://example.org/users/cubeglobe/statuses/110",
+            Some(url),
+            &[attachment],
+        );
+        let (progress, requests) = post_to(InstanceFlavor::Mastodon, "109", status);
+
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/api/v1/media");
+        assert!(String::from_utf8_lossy(&requests[0].body).contains("An isometric landscape"));
+        assert_eq!(requests[1].path, "/api/v1/statuses");
+        assert_eq!(requested_media(&requests[1]), vec!["109"]);
+        assert!(requests[1].header("idempotency-key").is_some());
+
+        assert_eq!(progress.media_id, Some("109".to_string()));
+        let remote = progress.remote_media.expect("No remote media");
+        assert_eq!((remote.width, remote.height), (Some(640), Some(480)));
+        let receipt = progress.receipt.expect("No receipt");
+        assert_eq!(receipt.status_id, "110");
+        assert_eq!(receipt.link(), Some(url));
+        assert_eq!(receipt.media_ids, vec!["109"]);
+    }
+
+    #[test]
+    fn posts_to_pleroma() {
+        // Pleroma's ids aren't numbers
+        let (media, id) = ("AbC9xYz", "9vZ2Ab4cF0gHiJkLmN");
+        let attachment = test_support::attachment_json(media, "An isometric landscape");
+        let status = test_support::status_json(
+            id,
+            "https://example.org/objects/5e3b",
+            Some("https://example.org/notice/9vZ2Ab4cF0gHiJkLmN"),
+            &[attachment],
+        );
+        let (progress, requests) = post_to(InstanceFlavor::Pleroma, media, status);
+
+        assert_eq!(requests[0].path, "/api/v1/media");
+        assert_eq!(requested_media(&requests[1]), vec![media]);
+        assert!(requests[1].header("idempotency-key").is_some());
+        assert_eq!(progress.media_id, Some(media.to_string()));
+        assert_eq!(progress.receipt.expect("No receipt").status_id, id);
+    }
+
+    #[test]
+    fn posts_to_gotosocial() {
+        let (media, id) = ("01HX7R3ZKQ0QGMW5N6J2Y3T4V5", "01HX7R40B1C2D3E4F5G6H7J8K9");
+        let url = "https://example.org/@cubeglobe/statuses/01HX7R40B1C2D3E4F5G6H7J8K9";
+        let attachment = test_support::attachment_json(media, "An isometric landscape");
+        // No uri, only a url
+        let status = test_support::status_json(id, "", Some(url), &[attachment]);
+        let (progress, requests) = post_to(InstanceFlavor::Gotosocial, media, status);
+
+        assert_eq!(requests[0].path, "/api/v1/media");
+        assert_eq!(requested_media(&requests[1]), vec![media]);
+        // GoToSocial doesn't dedupe on the header
+        assert_eq!(requests[1].header("idempotency-key"), None);
+        let receipt = progress.receipt.expect("No receipt");
+        assert_eq!(receipt.status_id, id);
+        assert_eq!(receipt.link(), Some(url));
+    }
+
+    /// Whether `haystack` has `needle` in it
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn every_open_reads_the_whole_image() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let path = dir.path().join("1.png");
+        let mut post = test_support::post(1);
+        ::std::fs::write(&path, &post.image[..]).expect("Unable to write image");
+
+        for file in vec![None, Some(path)] {
+            post.file = file;
+            for _ in 0..2 {
+                let (mut reader, total) = post.open_image();
+                let mut read = Vec::new();
+                reader.read_to_end(&mut read).expect("Unable to read image");
+                assert_eq!(&read[..], &post.image[..]);
+                assert_eq!(total, Some(post.image.len()));
+            }
+        }
+
+        // A file gone missing is read from memory instead
+        post.file = Some(dir.path().join("missing.png"));
+        let (mut reader, _) = post.open_image();
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).expect("Unable to read image");
+        assert_eq!(&read[..], &post.image[..]);
+    }
+
+    #[test]
+    fn upload_retry_sends_the_whole_image() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let path = dir.path().join("1.png");
+        let mut post = test_support::post(1);
+        ::std::fs::write(&path, &post.image[..]).expect("Unable to write image");
+        post.file = Some(path);
+
+        let attachment = test_support::attachment_json("109", &post.alt_text);
+        let server = MockServer::start(vec![
+            (503, r#"{"error":"unavailable"}"#.to_string()),
+            (200, attachment.clone()),
+            (
+                200,
+                test_support::status_json("110", "https://example.org/110", None, &[attachment]),
+            ),
+        ]);
+        let (poster, _) = mastodon_poster(&server.url, InstanceFlavor::Mastodon);
+        let mut progress = Progress::default();
+        assert!(poster.post(&post, &mut progress).is_err());
+        poster.post(&post, &mut progress).expect("Unable to post");
+
+        let requests = server.requests();
+        for upload in &requests[..2] {
+            assert_eq!(upload.path, "/api/v1/media");
+            assert!(contains(&upload.body, &post.image), "Upload without the whole image");
+        }
+    }
+
+    #[test]
+    fn limits_follow_flavor() {
+        let cases: &[(InstanceFlavor, Option<usize>, Counting, Option<usize>)] = &[
+            (InstanceFlavor::Mastodon, Some(500), Counting::Mastodon, Some(1500)),
+            (InstanceFlavor::Pleroma, Some(5000), Counting::Chars, Some(5000)),
+            (InstanceFlavor::Gotosocial, Some(5000), Counting::Chars, Some(1500)),
+        ];
+        for &(flavor, max_chars, body_counting, max_alt_chars) in cases {
+            let (poster, _) = mastodon_poster("https://example.org", flavor);
+            let limits = poster.limits();
+            assert_eq!(limits.max_chars, max_chars, "{:?}", flavor);
+            assert_eq!(limits.body_counting, body_counting, "{:?}", flavor);
+            assert_eq!(limits.max_alt_chars, max_alt_chars, "{:?}", flavor);
+        }
+    }
+
+    #[test]
+    fn recognizes_duplicate_status_refusals() {
+        let duplicates: &[&str] = &[
+            // Mastodon with an anti-spam plugin
+            r#"{"error":"Validation failed: Text has already been posted"}"#,
+            r#"{"error":"Validation failed: Text is a duplicate of a recent status"}"#,
+            r#"{"error":"Status is identical to a recent one"}"#,
+            r#"{"error":"Already posted"}"#,
+            // Not JSON, as from a proxy
+            "Duplicate status",
+        ];
+        for body in duplicates {
+            assert!(is_duplicate_status(422, body), "{}", body);
+        }
+    }
+
+    #[test]
+    fn other_refusals_arent_duplicates() {
+        let cases: &[(u16, &str)] = &[
+            (422, r#"{"error":"Validation failed: Text character limit of 500 exceeded"}"#),
+            (422, r#"{"error":"Cannot attach files that have not finished processing"}"#),
+            (422, r#"{"error":"Record not found"}"#),
+            (422, r#"{"error":"Cannot attach more than 4 files"}"#),
+            (422, ""),
+            // The wording only counts for 422s
+            (429, r#"{"error":"Text has already been posted"}"#),
+            (500, r#"{"error":"Duplicate key value violates unique constraint"}"#),
+            (409, "duplicate"),
+        ];
+        for &(code, body) in cases {
+            assert!(!is_duplicate_status(code, body), "{} {}", code, body);
+        }
+    }
+
+    #[test]
+    fn recognizes_unknown_media_refusals() {
+        let cases: &[(u16, &str, bool)] = &[
+            (422, r#"{"error":"Media 109 not found or already attached to another post"}"#, true),
+            (404, r#"{"error":"Not Found: media attachment does not exist"}"#, true),
+            (400, r#"{"error":"Bad Request: media 01HX not found"}"#, true),
+            (422, r#"{"error":"Validation failed: Text character limit of 500 exceeded"}"#, false),
+            (422, r#"{"error":"Cannot attach files that have not finished processing"}"#, false),
+            (422, r#"{"error":"Record not found"}"#, false),
+            (404, r#"{"error":"Record not found"}"#, false),
+            (422, "", false),
+            (500, r#"{"error":"Media not found"}"#, false),
+        ];
+        for &(code, body, unknown) in cases {
+            assert_eq!(is_unknown_media(code, body), unknown, "{} {}", code, body);
+        }
+    }
+
+    #[test]
+    fn stale_media_is_uploaded_again() {
+        let post = test_support::post(1);
+        let fresh = test_support::attachment_json("fresh", &post.alt_text);
+        let server = MockServer::start(vec![
+            (
+                422,
+                r#"{"error":"Media uploaded not found or already attached to another post"}"#
+                    .to_string(),
+            ),
+            (200, fresh.clone()),
+            (200, test_support::status_json("110", "https://example.org/110", None, &[fresh])),
+        ]);
+        let (poster, mut progress) = mastodon_poster(&server.url, InstanceFlavor::Mastodon);
+        poster.post(&post, &mut progress).expect("Unable to post");
+        assert_eq!(progress.media_id, Some("fresh".to_string()));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requested_media(&requests[0]), vec!["uploaded"]);
+        assert_eq!(requests[1].path, "/api/v1/media");
+        assert_eq!(requested_media(&requests[2]), vec!["fresh"]);
+        // Still the same status as far as the instance is concerned
+        assert_eq!(requests[0].header("idempotency-key"), requests[2].header("idempotency-key"));
+    }
+
+    #[test]
+    fn other_refusals_keep_the_upload() {
+        let server = MockServer::start(vec![(
+            422,
+            r#"{"error":"Validation failed: Text character limit of 500 exceeded"}"#.to_string(),
+        )]);
+        let (poster, mut progress) = mastodon_poster(&server.url, InstanceFlavor::Mastodon);
+        assert!(poster.post(&test_support::post(1), &mut progress).is_err());
+        assert_eq!(progress.media_id, Some("uploaded".to_string()));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    fn receipt_of(uri: &str, url: Option<&str>) -> PostReceipt {
+        let attachments = [
+            test_support::attachment_json("108", "An isometric landscape"),
+            test_support::attachment_json("109", "A heightmap"),
+        ];
+        let status = test_support::status_json("110", uri, url, &attachments);
+        PostReceipt::from_status(serde_json::from_str(&status).expect("Invalid status"))
+    }
+
+    #[test]
+    fn receipt_has_what_the_instance_said() {
+        let url = "https://example.org/@cubeglobe/110";
+        let receipt = receipt_of("https://example.org/users/cubeglobe/statuses/110", Some(url));
+        assert_eq!(receipt.status_id, "110");
+        assert_eq!(receipt.link(), Some(url));
+        assert_eq!(receipt.location(), url);
+        assert_eq!(receipt.created_at, Utc.ymd(2024, 5, 1).and_hms(12, 0, 0));
+        assert_eq!(receipt.media_ids, vec!["108", "109"]);
+    }
+
+    #[test]
+    fn receipt_falls_back_to_uri() {
+        let uri = "https://example.org/objects/5e3b";
+        for url in &[None, Some("")] {
+            assert_eq!(receipt_of(uri, *url).link(), Some(uri), "{:?}", url);
+        }
+        // As some versions of Pleroma give neither
+        let receipt = receipt_of("", None);
+        assert_eq!(receipt.link(), None);
+        assert_eq!(receipt.location(), "id 110");
+    }
+
+    /// Post image 1 to a mock instance with `verify_post`, which creates status 110 with one
+    /// attachment and answers the lookup with `lookup`
+    fn post_verified(lookup: (u16, String)) -> (Result<(), PostingError>, Progress, MockServer) {
+        let attachment = test_support::attachment_json("uploaded", "An isometric landscape");
+        let server = MockServer::start(vec![
+            (
+                200,
+                test_support::status_json("110", "https://example.org/110", None, &[attachment]),
+            ),
+            lookup,
+        ]);
+        let (mut poster, mut progress) = mastodon_poster(&server.url, InstanceFlavor::Mastodon);
+        poster.verify_client = Some(build_info::http_client_with_timeout(VERIFY_TIMEOUT));
+        let result = poster.post(&test_support::post(1), &mut progress);
+        (result, progress, server)
+    }
+
+    #[test]
+    fn verified_status_counts_as_posted() {
+        let attachment = test_support::attachment_json("uploaded", "An isometric landscape");
+        let status =
+            test_support::status_json("110", "https://example.org/110", None, &[attachment]);
+        let (result, progress, server) = post_verified((200, status));
+        result.expect("Unable to post");
+        assert_eq!(progress.receipt.map(|receipt| receipt.status_id), Some("110".to_string()));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].method, "GET");
+        assert_eq!(requests[1].path, "/api/v1/statuses/110");
+        assert_eq!(requests[1].header("authorization"), Some("Bearer token"));
+    }
+
+    #[test]
+    fn missing_status_is_unverified() {
+        let (result, progress, _server) =
+            post_verified((404, r#"{"error":"Record not found"}"#.to_string()));
+        match result {
+            Err(PostingError::Unverified(ref problem)) => assert!(problem.contains("110")),
+            other => panic!("Missing status not caught: {:?}", other),
+        }
+        assert!(progress.receipt.is_none());
+        assert!(progress.idempotency_key.is_some());
+    }
+
+    #[test]
+    fn status_without_the_media_is_unverified() {
+        let status = test_support::status_json("110", "https://example.org/110", None, &[]);
+        let (result, progress, _server) = post_verified((200, status));
+        match result {
+            Err(PostingError::Unverified(ref problem)) => {
+                assert!(problem.contains("0 media attachments, expected 1"))
+            }
+            other => panic!("Status without its media not caught: {:?}", other),
+        }
+        assert!(progress.receipt.is_none());
+    }
+}
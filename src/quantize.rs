@@ -0,0 +1,221 @@
+//! Palette quantization
+//!
+//! Landscapes are drawn from a handful of tiles, so most of their colors come from shading and
+//! blending at the edges. Cutting them down to `max_colors` lets the optimizer write the PNG with
+//! a palette instead of full RGB, which makes for far smaller files than optimizing alone. The
+//! palette is chosen by median cut: the colors are split into boxes along their widest channel,
+//! at the median pixel, until there are as many boxes as colors allowed, and each box is then
+//! replaced by its average.
+
+use std::collections::HashMap;
+
+use image::{DynamicImage, Rgba, RgbaImage};
+
+/// Most colors a PNG palette can hold
+pub const MAX_PALETTE: usize = 256;
+
+/// A color and how many pixels have it
+type Bucket = ([u8; 4], u64);
+
+/// Number of distinct colors, counting transparency, in `image`
+pub fn count_colors(image: &RgbaImage) -> usize {
+    histogram(image).len()
+}
+
+/// Reduce `image` to at most `max_colors` colors
+///
+/// With `dither`, the error of each pixel is spread to its neighbors (Floyd-Steinberg), which
+/// smooths gradients but makes flat areas noisy and the PNG larger.
+pub fn quantize(image: &RgbaImage, max_colors: usize, dither: bool) -> DynamicImage {
+    let palette = median_cut(histogram(image).into_iter().collect(), max_colors);
+    let mut quantized = image.clone();
+
+    if dither {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let mut errors = vec![[0.0f32; 4]; width * (height + 1) + 1];
+        for (i, pixel) in quantized.pixels_mut().enumerate() {
+            let mut wanted = [0u8; 4];
+            for channel in 0..4 {
+                let value = f32::from(pixel.data[channel]) + errors[i][channel];
+                wanted[channel] = value.max(0.0).min(255.0).round() as u8;
+            }
+            let chosen = nearest(&palette, wanted);
+
+            let x = i % width;
+            for channel in 0..4 {
+                let error = f32::from(wanted[channel]) - f32::from(chosen[channel]);
+                if x + 1 < width {
+                    errors[i + 1][channel] += error * 7.0 / 16.0;
+                }
+                if x > 0 {
+                    errors[i + width - 1][channel] += error * 3.0 / 16.0;
+                }
+                errors[i + width][channel] += error * 5.0 / 16.0;
+                if x + 1 < width {
+                    errors[i + width + 1][channel] += error / 16.0;
+                }
+            }
+            *pixel = Rgba { data: chosen };
+        }
+    } else {
+        let mut chosen: HashMap<[u8; 4], [u8; 4]> = HashMap::new();
+        for pixel in quantized.pixels_mut() {
+            let color = *chosen
+                .entry(pixel.data)
+                .or_insert_with(|| nearest(&palette, pixel.data));
+            *pixel = Rgba { data: color };
+        }
+    }
+
+    DynamicImage::ImageRgba8(quantized)
+}
+
+fn histogram(image: &RgbaImage) -> HashMap<[u8; 4], u64> {
+    let mut counts = HashMap::new();
+    for pixel in image.pixels() {
+        *counts.entry(pixel.data).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Choose a palette of at most `max_colors` colors for `colors`, see the module documentation
+fn median_cut(colors: Vec<Bucket>, max_colors: usize) -> Vec<[u8; 4]> {
+    let mut boxes = vec![colors];
+    while boxes.len() < max_colors {
+        // Split the box with the widest spread of any channel, if any has more than one color
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|&(_, colors)| colors.len() > 1)
+            .map(|(i, colors)| {
+                let (channel, range) = widest_channel(colors);
+                (i, channel, range)
+            }).max_by_key(|&(_, _, range)| range);
+        let (i, channel) = match widest {
+            Some((i, channel, _)) => (i, channel),
+            None => break,
+        };
+
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_by_key(|&(color, _)| color[channel]);
+        let total: u64 = colors.iter().map(|&(_, count)| count).sum();
+        let mut seen = 0;
+        let median = colors
+            .iter()
+            .position(|&(_, count)| {
+                seen += count;
+                seen * 2 >= total
+            }).unwrap_or(0);
+        // Both halves need at least one color
+        let split = (median + 1).min(colors.len() - 1);
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(|colors| average(colors)).collect()
+}
+
+/// Channel with the largest difference between its lowest and highest value, and that difference
+fn widest_channel(colors: &[Bucket]) -> (usize, u8) {
+    (0..4)
+        .map(|channel| {
+            let values = colors.iter().map(|&(color, _)| color[channel]);
+            let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, range)
+        }).max_by_key(|&(_, range)| range)
+        .expect("colors have four channels")
+}
+
+/// Average of `colors`, weighted by how many pixels have each
+fn average(colors: &[Bucket]) -> [u8; 4] {
+    let total: u64 = colors.iter().map(|&(_, count)| count).sum();
+    let mut average = [0u8; 4];
+    for (channel, value) in average.iter_mut().enumerate() {
+        let sum: u64 = colors
+            .iter()
+            .map(|&(color, count)| u64::from(color[channel]) * count)
+            .sum();
+        *value = ((sum + total / 2) / total.max(1)) as u8;
+    }
+    average
+}
+
+/// Color in `palette` closest to `color`
+fn nearest(palette: &[[u8; 4]], color: [u8; 4]) -> [u8; 4] {
+    *palette
+        .iter()
+        .min_by_key(|entry| {
+            entry
+                .iter()
+                .zip(color.iter())
+                .map(|(&a, &b)| {
+                    let difference = i32::from(a) - i32::from(b);
+                    difference * difference
+                }).sum::<i32>()
+        }).expect("palette is not empty")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 32 by 32 gradient, with a different color in every pixel
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(32, 32, |x, y| Rgba {
+            data: [(x * 8) as u8, (y * 8) as u8, 128, 255],
+        })
+    }
+
+    #[test]
+    fn counts_colors() {
+        assert_eq!(count_colors(&gradient()), 32 * 32);
+        let flat = RgbaImage::from_pixel(4, 4, Rgba { data: [1, 2, 3, 255] });
+        assert_eq!(count_colors(&flat), 1);
+        // Transparency counts
+        let mut two = flat.clone();
+        two.put_pixel(0, 0, Rgba { data: [1, 2, 3, 0] });
+        assert_eq!(count_colors(&two), 2);
+    }
+
+    #[test]
+    fn reduces_to_max_colors() {
+        let image = gradient();
+        for &max_colors in &[2, 16, MAX_PALETTE] {
+            for &dither in &[false, true] {
+                let quantized = quantize(&image, max_colors, dither).to_rgba();
+                let colors = count_colors(&quantized);
+                assert!(colors <= max_colors, "{} colors, {} allowed", colors, max_colors);
+                assert_eq!(quantized.dimensions(), image.dimensions());
+            }
+        }
+    }
+
+    #[test]
+    fn few_colors_are_kept() {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 128]];
+        let image = RgbaImage::from_fn(6, 6, |x, y| Rgba {
+            data: colors[((x + y) % 3) as usize],
+        });
+        assert_eq!(quantize(&image, 4, false).to_rgba(), image);
+        assert_eq!(quantize(&image, 3, true).to_rgba(), image);
+    }
+
+    #[test]
+    fn palette_is_weighted_averages() {
+        let colors = vec![([0, 0, 0, 255], 3), ([40, 0, 0, 255], 1), ([200, 0, 0, 255], 1)];
+        let mut palette = median_cut(colors.clone(), 2);
+        palette.sort();
+        assert_eq!(palette, vec![[0, 0, 0, 255], [120, 0, 0, 255]]);
+        assert_eq!(median_cut(colors, 1), vec![[48, 0, 0, 255]]);
+    }
+
+    #[test]
+    fn picks_nearest_color() {
+        let palette = [[0, 0, 0, 255], [255, 255, 255, 255], [255, 0, 0, 255]];
+        assert_eq!(nearest(&palette, [20, 10, 10, 255]), [0, 0, 0, 255]);
+        assert_eq!(nearest(&palette, [200, 40, 30, 255]), [255, 0, 0, 255]);
+        assert_eq!(nearest(&palette, [230, 200, 210, 255]), [255, 255, 255, 255]);
+        assert_eq!(widest_channel(&[([0, 10, 0, 0], 1), ([5, 90, 0, 0], 1)]), (1, 80));
+    }
+}
@@ -0,0 +1,730 @@
+//! Queue of images generated ahead of time
+//!
+//! `cubeglobe-bot generate --count 7 --queue` generates images into `queue_dir` instead of
+//! posting them, each with a `{image}.toml` sidecar holding everything the post needs besides
+//! the image: the landscape's name, the description, the generation parameters and the map's
+//! statistics. A bot with `queue_dir` set posts the first entry in the queue whenever a post is
+//! due, and only generates an image itself when the queue is empty, or skips the slot with
+//! `queue_empty = "skip"`. The queue can be filled on another machine and copied over, as long
+//! as the sidecars come along with the images.
+//!
+//! Taking an entry moves its image into the images directory, after which it is the pending
+//! image like any generated one, and retried the same way if posting fails. `cubeglobe-bot queue`
+//! lists the entries, shows one in full, or moves one to another position.
+
+use std::collections::VecDeque;
+use std::fs::{copy, create_dir_all, read, read_dir, read_to_string, remove_file, rename, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{Context, Error};
+use chrono::{DateTime, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use cubeglobe::renderer::Renderer;
+use cubeglobe_bot::tiles;
+use cubeglobe_bot::MapStats;
+use crossbeam_utils::thread;
+use num_cpus;
+use toml;
+
+use events::EventLog;
+use integrity::{self, Fingerprint};
+use optimize::AutoOptimizeState;
+use posting::ImageFormat;
+use shutdown::{Cancelled, Shutdown};
+use {
+    create_image_within_budget, heightmap_name, BotConfig, GenerationParams, State, StatePaths,
+    HEIGHTMAPS_DIR,
+};
+
+/// Subdirectory of the queue that damaged entries are moved to
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// What the bot does when a post is due and the queue is empty
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueEmpty {
+    /// Generate an image as if there was no queue
+    Generate,
+    /// Skip the slot, warning about it
+    Skip,
+}
+
+impl Default for QueueEmpty {
+    fn default() -> QueueEmpty {
+        QueueEmpty::Generate
+    }
+}
+
+/// A queued image, as described by its sidecar
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Entry {
+    /// Where in the queue the entry is, lowest first
+    pub order: u32,
+    /// File name of the image in the queue directory
+    pub image: String,
+    /// Name of the landscape
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Times the image was generated again before it turned out
+    #[serde(default)]
+    pub regenerations: u32,
+    pub generated_at: DateTime<Utc>,
+    pub params: GenerationParams,
+    pub stats: MapStats,
+    /// Size and hash of the image, checked before it is taken
+    pub fingerprint: Fingerprint,
+    /// File name of the image's heightmap in the queue's heightmaps directory, if it has one
+    #[serde(default)]
+    pub heightmap: Option<String>,
+}
+
+impl Entry {
+    fn sidecar(&self, dir: &Path) -> PathBuf {
+        sidecar_path(dir, &self.image)
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        ImageFormat::from_path(Path::new(&self.image)).unwrap_or(ImageFormat::Png)
+    }
+
+    /// Write the sidecar out, replacing any there was
+    fn write(&self, dir: &Path) -> Result<(), Error> {
+        // Going through `Value` puts nested tables last, wherever they are in the struct
+        let serialized = toml::to_string(&toml::Value::try_from(self)?)?;
+        File::create(self.sidecar(dir))?.write_all(serialized.as_bytes())?;
+        Ok(())
+    }
+
+    /// Read and check the image, see `integrity::check`
+    pub fn load_image(&self, dir: &Path) -> Result<Vec<u8>, String> {
+        let data = read(dir.join(&self.image)).map_err(|e| format!("unable to read: {}", e))?;
+        integrity::check(&data, self.format(), Some(&self.fingerprint))?;
+        Ok(data)
+    }
+}
+
+fn sidecar_path(dir: &Path, image: &str) -> PathBuf {
+    dir.join(format!("{}.toml", image))
+}
+
+/// Entries in the queue in `dir`, in the order they are to be posted
+///
+/// Sidecars which can't be read are left out with a warning. A missing directory is an empty
+/// queue.
+pub fn entries(dir: &Path) -> Result<Vec<Entry>, Error> {
+    let mut entries = Vec::new();
+    if !dir.is_dir() {
+        return Ok(entries);
+    }
+
+    for file in read_dir(dir).with_context(|| format!("unable to list {}", dir.display()))? {
+        let path = file?.path();
+        if path.extension().map_or(true, |ext| ext != "toml") || !path.is_file() {
+            continue;
+        }
+        let parsed = read_to_string(&path)
+            .map_err(Error::from)
+            .and_then(|s| toml::from_str::<Entry>(&s).map_err(Error::from));
+        match parsed {
+            Ok(entry) => entries.push(entry),
+            Err(e) => eprintln!("Skipping unreadable queue entry {}: {}", path.display(), e),
+        }
+    }
+
+    entries.sort_by(|a, b| (a.order, &a.image).cmp(&(b.order, &b.image)));
+    Ok(entries)
+}
+
+/// Move the entry's image to `target` and remove its sidecar
+///
+/// Done again after a crash part way through, so an image already at `target` is taken as
+/// moved.
+pub fn take(dir: &Path, image: &str, target: &Path) -> Result<(), Error> {
+    move_file(&dir.join(image), target)?;
+
+    let sidecar = sidecar_path(dir, image);
+    if sidecar.exists() {
+        remove_file(&sidecar)?;
+    }
+    Ok(())
+}
+
+/// Move the heightmap of the queued `image` to `target`, before the image itself is taken
+pub fn take_heightmap(dir: &Path, image: &str, target: &Path) -> Result<(), Error> {
+    let name = heightmap_name(Path::new(image))
+        .ok_or_else(|| Error::msg(format!("{} has no heightmap name", image)))?;
+    if let Some(parent) = target.parent() {
+        create_dir_all(parent)?;
+    }
+    move_file(&dir.join(HEIGHTMAPS_DIR).join(name), target)
+}
+
+/// Move `source` to `target`, unless an earlier attempt already did
+fn move_file(source: &Path, target: &Path) -> Result<(), Error> {
+    if source.exists() {
+        // The queue may well be on another filesystem, which rename can't move across
+        if rename(source, target).is_err() {
+            copy(source, target).with_context(|| {
+                format!("unable to copy {} to {}", source.display(), target.display())
+            })?;
+            remove_file(source)?;
+        }
+    } else if !target.exists() {
+        return Err(Error::msg(format!("queued file {} is gone", source.display())));
+    }
+    Ok(())
+}
+
+/// Move a damaged entry to the queue's quarantine, so it isn't tried again
+pub fn quarantine(dir: &Path, entry: &Entry) {
+    let target_dir = dir.join(QUARANTINE_DIR);
+    if let Err(e) = create_dir_all(&target_dir) {
+        eprintln!("Unable to create {}: {}", target_dir.display(), e);
+        return;
+    }
+    for name in &[entry.image.clone(), format!("{}.toml", entry.image)] {
+        let target = target_dir.join(name);
+        match rename(dir.join(name), &target) {
+            Ok(()) => eprintln!("Moved {} to {}", name, target.display()),
+            Err(e) => eprintln!("Unable to move {} to {}: {}", name, target.display(), e),
+        }
+    }
+}
+
+pub fn generate_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("generate")
+        .about("generate images without posting them")
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .value_name("N")
+                .default_value("1")
+                .validator(|v| match v.parse::<u32>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("must be a positive number".to_string()),
+                }).help("how many images to generate"),
+        ).arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .value_name("N")
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("must be a positive number".to_string()),
+                }).help("how many images to generate at once, by default one per CPU core"),
+        ).arg(
+            Arg::with_name("queue")
+                .long("queue")
+                .help(
+                    "add them to the end of the queue in queue_dir, instead of saving them to \
+                     the current directory to look at",
+                ),
+        )
+}
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    let entry = || {
+        Arg::with_name("entry")
+            .required(true)
+            .help("position in the queue, starting from 1, or file name of the image")
+    };
+
+    SubCommand::with_name("queue")
+        .about("look at and reorder the images queued in queue_dir")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("list").about("list the queued images in order"))
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("show everything kept about a queued image")
+                .arg(entry()),
+        ).subcommand(
+            SubCommand::with_name("move")
+                .about("move a queued image to another position")
+                .arg(entry())
+                .arg(
+                    Arg::with_name("position")
+                        .required(true)
+                        .validator(|v| match v.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("must be a positive number".to_string()),
+                        }).help("position to move it to, starting from 1"),
+                ),
+        )
+}
+
+fn queue_dir(config: &BotConfig) -> Result<&Path, Error> {
+    config
+        .queue_dir
+        .as_ref()
+        .map(PathBuf::as_path)
+        .ok_or_else(|| Error::msg("queue_dir is not set in the config"))
+}
+
+/// One image for `generate` to make
+struct Job {
+    id: u32,
+    /// Position in the queue, if it goes there
+    order: u32,
+}
+
+/// What the workers of `generate` share
+struct Jobs<'a> {
+    config: &'a BotConfig,
+    queue: Option<&'a Path>,
+    images: PathBuf,
+    auto_optimize: AutoOptimizeState,
+    pending: Mutex<VecDeque<Job>>,
+    /// Names of recent posts, queued images and images generated so far
+    used_names: Mutex<Vec<String>>,
+    /// First error a job ran into, after which no more are started
+    failure: Mutex<Option<Error>>,
+    done: AtomicUsize,
+}
+
+impl<'a> Jobs<'a> {
+    /// Make images until there are none left to make, or a job fails
+    fn work(&self, renderer: &Renderer, events: &EventLog, shutdown: &Shutdown) {
+        loop {
+            if self.failure.lock().expect("job lock poisoned").is_some() {
+                return;
+            }
+            let job = match self.pending.lock().expect("job lock poisoned").pop_front() {
+                Some(job) => job,
+                None => return,
+            };
+            if let Err(e) = self.run(&job, renderer, events, shutdown) {
+                self.fail(e);
+                return;
+            }
+        }
+    }
+
+    fn fail(&self, e: Error) {
+        let mut failure = self.failure.lock().expect("job lock poisoned");
+        if failure.is_none() {
+            *failure = Some(e);
+        }
+    }
+
+    fn run(
+        &self,
+        job: &Job,
+        renderer: &Renderer,
+        events: &EventLog,
+        shutdown: &Shutdown,
+    ) -> Result<(), Error> {
+        let started = Instant::now();
+        eprintln!("[{}] Generating", job.id);
+
+        let mut scratch = State::default();
+        scratch.paths = StatePaths {
+            state: PathBuf::new(),
+            images: self.images.clone(),
+        };
+        scratch.id = job.id;
+        scratch.auto_optimize = self.auto_optimize.clone();
+        scratch.used_names = self.used_names.lock().expect("job lock poisoned").clone();
+
+        let created =
+            create_image_within_budget(self.config, renderer, &mut scratch, events, shutdown);
+        let image = match created {
+            Ok(image) => image,
+            Err(ref e) if e.downcast_ref::<Cancelled>().is_some() => {
+                return Err(Error::msg("interrupted"))
+            }
+            Err(e) => return Err(e.context(format!("unable to generate image {}", job.id))),
+        };
+        self.used_names
+            .lock()
+            .expect("job lock poisoned")
+            .push(image.name.clone());
+        let filename = image
+            .filename
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .expect("Generated image has no file name");
+
+        let seconds = started.elapsed().as_millis() as f64 / 1000.0;
+        if let Some(dir) = self.queue {
+            let entry = Entry {
+                order: job.order,
+                image: filename,
+                name: image.name.clone(),
+                description: image.description.clone(),
+                regenerations: image.regenerations,
+                generated_at: Utc::now(),
+                params: image.params.clone(),
+                stats: image.stats.clone(),
+                fingerprint: Fingerprint::of(&image.data),
+                heightmap: image.heightmap.clone(),
+            };
+            entry
+                .write(dir)
+                .with_context(|| format!("unable to write sidecar of {}", entry.image))?;
+            println!("[{}] Queued {} ({}) in {:.1} s", job.id, entry.image, entry.name, seconds);
+        } else {
+            println!(
+                "[{}] Saved {} ({}) in {:.1} s",
+                job.id,
+                image.filename.display(),
+                image.name,
+                seconds
+            );
+        }
+        self.done.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Generate images as asked for by the `generate` subcommand
+///
+/// Queued images are numbered on from the end of the queue, while images to look at are
+/// numbered from 1. Names already used by recent posts or by queued images are avoided. Every
+/// image's id is picked before any is generated, so that jobs running side by side never write
+/// the same file.
+///
+/// With more than one job, each worker thread renders with a renderer of its own, loaded from
+/// `tiles_path` and `tiles_config`: cubeglobe's renderer holds SDL surfaces, which can't be
+/// shared between threads, and its types don't allow it to be. Only `renderer` is used with one
+/// job.
+pub fn generate(
+    matches: &ArgMatches,
+    config: &BotConfig,
+    renderer: &Renderer,
+    tiles_path: &Path,
+    tiles_config: &str,
+    events: &EventLog,
+    shutdown: &Shutdown,
+) -> Result<(), Error> {
+    let count: u32 = matches.value_of("count").unwrap_or("1").parse()?;
+    let asked: usize = match matches.value_of("jobs") {
+        Some(jobs) => jobs.parse()?,
+        None => num_cpus::get_physical(),
+    };
+    let workers = asked.min(count as usize).max(1);
+    let queue = if matches.is_present("queue") {
+        Some(queue_dir(config)?)
+    } else {
+        None
+    };
+    let existing = match queue {
+        Some(dir) => entries(dir)?,
+        None => Vec::new(),
+    };
+
+    let state = State::get_state(StatePaths::from_config(config));
+    let mut used_names = state.used_names;
+    used_names.extend(existing.iter().map(|entry| entry.name.clone()));
+
+    let images = queue.map_or_else(|| PathBuf::from("."), Path::to_path_buf);
+    let template = config.filename_template();
+    let mut probe = State::default();
+    probe.paths.images = images.clone();
+    let mut order = existing.last().map_or(1, |entry| entry.order + 1);
+    probe.id = order;
+    let mut pending = VecDeque::new();
+    for _ in 0..count {
+        // Reordering leaves image names out of step with positions, so one could be taken
+        while [ImageFormat::Png, ImageFormat::Gif].iter().any(|&format| {
+            probe
+                .get_filename(&template, format)
+                .map_or(false, |path| path.exists())
+        }) {
+            probe.id += 1;
+        }
+        pending.push_back(Job {
+            id: probe.id,
+            order,
+        });
+        probe.id += 1;
+        order += 1;
+    }
+
+    let jobs = Jobs {
+        config,
+        queue,
+        images,
+        auto_optimize: state.auto_optimize,
+        pending: Mutex::new(pending),
+        used_names: Mutex::new(used_names),
+        failure: Mutex::new(None),
+        done: AtomicUsize::new(0),
+    };
+    let started = Instant::now();
+    if workers == 1 {
+        jobs.work(renderer, events, shutdown);
+    } else {
+        eprintln!("Generating {} images with {} jobs", count, workers);
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|_| match tiles::load_renderer(tiles_path, tiles_config) {
+                    Ok(renderer) => jobs.work(&renderer, events, shutdown),
+                    Err(e) => jobs.fail(e),
+                });
+            }
+        }).map_err(|_| Error::msg("a generation job panicked"))?;
+    }
+
+    let done = jobs.done.load(Ordering::SeqCst);
+    let seconds = started.elapsed().as_millis() as f64 / 1000.0;
+    if done > 0 {
+        eprintln!(
+            "Generated {} of {} images in {:.1} s, {:.1} s per image",
+            done,
+            count,
+            seconds,
+            seconds / done as f64
+        );
+    }
+    match jobs.failure.into_inner().expect("job lock poisoned") {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Run the `queue` subcommand
+pub fn run(matches: &ArgMatches, config: &BotConfig) -> Result<(), Error> {
+    let dir = queue_dir(config)?;
+    let mut entries = entries(dir)?;
+
+    match matches.subcommand() {
+        ("list", _) => {
+            if entries.is_empty() {
+                println!("The queue is empty");
+            }
+            for (i, entry) in entries.iter().enumerate() {
+                println!(
+                    "{:>3}  {}  {}  generated {}  {}",
+                    i + 1,
+                    entry.image,
+                    entry.name,
+                    entry.generated_at.format("%Y-%m-%d %H:%M"),
+                    entry.params.summary_line()
+                );
+            }
+        }
+        ("show", Some(show_matches)) => {
+            let index = find(&entries, show_matches.value_of("entry").unwrap_or_default())?;
+            let entry = &entries[index];
+            println!("Position {} of {}", index + 1, entries.len());
+            print!("{}", toml::to_string(&toml::Value::try_from(entry)?)?);
+            match entry.load_image(dir) {
+                Ok(_) => println!("# image checks out"),
+                Err(problem) => println!("# image is damaged: {}", problem),
+            }
+        }
+        ("move", Some(move_matches)) => {
+            let index = find(&entries, move_matches.value_of("entry").unwrap_or_default())?;
+            let position: usize = move_matches.value_of("position").unwrap_or("1").parse()?;
+            let entry = entries.remove(index);
+            let image = entry.image.clone();
+            entries.insert((position - 1).min(entries.len()), entry);
+
+            // Renumbered from 1, which also closes any gaps left by taken entries
+            for (i, entry) in entries.iter_mut().enumerate() {
+                entry.order = i as u32 + 1;
+                entry
+                    .write(dir)
+                    .with_context(|| format!("unable to write sidecar of {}", entry.image))?;
+            }
+            let moved_to = entries.iter().position(|entry| entry.image == image).unwrap_or(0);
+            println!("Moved {} to position {}", image, moved_to + 1);
+        }
+        _ => unreachable!("clap requires a known subcommand"),
+    }
+    Ok(())
+}
+
+/// Index of the entry `arg` refers to, by position or by image file name
+fn find(entries: &[Entry], arg: &str) -> Result<usize, Error> {
+    if let Ok(position) = arg.parse::<usize>() {
+        if position >= 1 && position <= entries.len() {
+            return Ok(position - 1);
+        }
+        return Err(Error::msg(format!(
+            "no position {} in the queue, which has {} entries",
+            position,
+            entries.len()
+        )));
+    }
+    entries
+        .iter()
+        .position(|entry| entry.image == arg)
+        .ok_or_else(|| Error::msg(format!("no queued image named {}", arg)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use cubeglobe_bot::builtin_tiles;
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+    use std::fs::write;
+    use tempfile;
+
+    fn png() -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(4, 4))
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .expect("Unable to encode PNG");
+        png
+    }
+
+    /// Queue an entry for `image` at `order` in `dir`, returning it
+    fn queue(dir: &Path, image: &str, order: u32) -> Entry {
+        let data = png();
+        write(dir.join(image), &data).expect("Unable to write image");
+        let entry = Entry {
+            order,
+            image: image.to_string(),
+            name: format!("The Isles of {}", image),
+            description: None,
+            regenerations: 0,
+            generated_at: Utc.ymd(2026, 10, 1).and_hms(12, 0, 0),
+            params: GenerationParams {
+                map_size: 16,
+                frequency: Some(0.02),
+                layer_height: None,
+                min_soil_cutoff: None,
+                max_water_level: Some(4),
+                rotation: 0,
+                name_seed: 0,
+                adjustments: Vec::new(),
+            },
+            stats: MapStats {
+                water_pct: 10.0,
+                min_height: 1,
+                max_height: 9,
+                mean_height: 4.5,
+                block_types: 3,
+            },
+            fingerprint: Fingerprint::of(&data),
+            heightmap: None,
+        };
+        entry.write(dir).expect("Unable to write sidecar");
+        entry
+    }
+
+    fn images(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.image.as_str()).collect()
+    }
+
+    #[test]
+    fn entries_are_in_order() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        queue(dir.path(), "c.png", 1);
+        queue(dir.path(), "a.png", 2);
+        queue(dir.path(), "b.png", 2);
+        write(dir.path().join("broken.png.toml"), "order = ").expect("Unable to write sidecar");
+
+        let entries = entries(dir.path()).expect("Unable to list queue");
+        assert_eq!(images(&entries), vec!["c.png", "a.png", "b.png"]);
+        assert_eq!(entries[0].name, "The Isles of c.png");
+        assert_eq!(entries[0].params.max_water_level, Some(4));
+    }
+
+    #[test]
+    fn missing_queue_is_empty() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let entries = entries(&dir.path().join("queue")).expect("Unable to list queue");
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn finds_entries_by_position_or_name() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        queue(dir.path(), "a.png", 1);
+        queue(dir.path(), "b.png", 2);
+        let entries = entries(dir.path()).expect("Unable to list queue");
+
+        assert_eq!(find(&entries, "2").unwrap(), 1);
+        assert_eq!(find(&entries, "a.png").unwrap(), 0);
+        for arg in &["0", "3", "c.png"] {
+            assert!(find(&entries, arg).is_err(), "{} was found", arg);
+        }
+    }
+
+    #[test]
+    fn take_moves_image_and_removes_sidecar() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let entry = queue(dir.path(), "a.png", 1);
+        let target = dir.path().join("5.png");
+
+        take(dir.path(), &entry.image, &target).expect("Unable to take entry");
+        assert!(target.exists());
+        assert!(!dir.path().join("a.png").exists());
+        assert!(entries(dir.path()).expect("Unable to list queue").is_empty());
+
+        // As after a crash part way through
+        take(dir.path(), &entry.image, &target).expect("Unable to take entry again");
+        remove_file(&target).expect("Unable to remove image");
+        assert!(take(dir.path(), &entry.image, &target).is_err());
+    }
+
+    #[test]
+    fn damaged_entries_are_caught_and_quarantined() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let entry = queue(dir.path(), "a.png", 1);
+        assert!(entry.load_image(dir.path()).is_ok());
+
+        let data = png();
+        write(dir.path().join("a.png"), &data[..data.len() - 4]).expect("Unable to write image");
+        let problem = entry.load_image(dir.path()).unwrap_err();
+        assert!(problem.contains("bytes long"), "{}", problem);
+
+        quarantine(dir.path(), &entry);
+        assert!(entries(dir.path()).expect("Unable to list queue").is_empty());
+        let quarantined = dir.path().join(QUARANTINE_DIR);
+        assert!(quarantined.join("a.png").exists());
+        assert!(quarantined.join("a.png.toml").exists());
+    }
+
+    #[test]
+    fn jobs_side_by_side_never_collide() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let queue_dir = dir.path().join("queue");
+        create_dir_all(&queue_dir).expect("Unable to create queue");
+        queue(&queue_dir, "a.png", 1);
+        let config: BotConfig = toml::from_str(&format!(
+            "map_size = 16\nstate_path = '{}'\nimages_dir = '{}'\nqueue_dir = '{}'\n",
+            dir.path().join("state").display(),
+            dir.path().join("images").display(),
+            queue_dir.display()
+        )).expect("Invalid config");
+        let tiles_config = builtin_tiles::tiles_config().expect("Unable to draw built-in tiles");
+        let tiles_path = Path::new(builtin_tiles::BUILTIN);
+        let renderer =
+            tiles::load_renderer(tiles_path, &tiles_config).expect("Unable to load built-in tiles");
+        let matches = generate_subcommand().get_matches_from(vec![
+            "generate", "--count", "16", "--jobs", "4", "--queue",
+        ]);
+
+        generate(
+            &matches,
+            &config,
+            &renderer,
+            tiles_path,
+            &tiles_config,
+            &EventLog::new(false),
+            &Shutdown::default(),
+        ).expect("Unable to generate images");
+
+        let entries = entries(&queue_dir).expect("Unable to list queue");
+        assert_eq!(entries.len(), 17);
+        let orders: Vec<u32> = entries.iter().map(|entry| entry.order).collect();
+        assert_eq!(orders, (1..18).collect::<Vec<_>>());
+        let mut images = images(&entries);
+        images.sort();
+        images.dedup();
+        assert_eq!(images.len(), 17);
+        for entry in &entries {
+            let data = read(queue_dir.join(&entry.image)).expect("Unable to read image");
+            assert_eq!(Fingerprint::of(&data), entry.fingerprint, "{}", entry.image);
+        }
+    }
+}
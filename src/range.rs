@@ -0,0 +1,140 @@
+//! Config values which can be either fixed or picked at random for every map
+
+use std::fmt::Display;
+
+use rand::distributions::uniform::SampleUniform;
+use rand::distributions::Uniform as UniformDist;
+use rand::Rng;
+
+/// A parameter which is either a single value, or a range to pick from uniformly
+///
+/// In the config file, this is either a plain value (`frequency = 0.02`) or a table
+/// (`frequency = { min = 0.01, max = 0.04 }`).
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum ParamRange<T> {
+    Fixed(T),
+    Uniform { min: T, max: T },
+}
+
+impl<T> ParamRange<T>
+where
+    T: PartialOrd + Copy + Display,
+{
+    /// Smallest value this can produce
+    pub fn min(&self) -> T {
+        match *self {
+            ParamRange::Fixed(value) => value,
+            ParamRange::Uniform { min, .. } => min,
+        }
+    }
+
+    /// Largest value this can produce
+    pub fn max(&self) -> T {
+        match *self {
+            ParamRange::Fixed(value) => value,
+            ParamRange::Uniform { max, .. } => max,
+        }
+    }
+
+    /// Check that a range is not empty or reversed
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            ParamRange::Uniform { min, max } if !(min < max) => Err(format!(
+                "min ({}) must be less than max ({})",
+                min, max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the fixed value, or pick one from the range
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> T
+    where
+        T: SampleUniform,
+    {
+        match *self {
+            ParamRange::Fixed(value) => value,
+            ParamRange::Uniform { min, max } => rng.sample(UniformDist::new_inclusive(min, max)),
+        }
+    }
+}
+
+impl ParamRange<f64> {
+    /// Check that the range is valid, and that both ends are finite and above zero
+    pub fn validate_positive(&self) -> Result<(), String> {
+        for value in &[self.min(), self.max()] {
+            if !value.is_finite() || *value <= 0.0 {
+                return Err(format!("{} is not a positive number", value));
+            }
+        }
+
+        self.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::f64;
+    use toml;
+
+    #[derive(Deserialize)]
+    struct Settings {
+        frequency: ParamRange<f64>,
+        layer_height: ParamRange<usize>,
+    }
+
+    fn parse(text: &str) -> Result<Settings, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    #[test]
+    fn parses_values_and_ranges() {
+        let settings = parse("frequency = 0.02\nlayer_height = { min = 2, max = 6 }")
+            .expect("Unable to parse settings");
+        assert_eq!(settings.frequency, ParamRange::Fixed(0.02));
+        assert_eq!(settings.layer_height, ParamRange::Uniform { min: 2, max: 6 });
+        assert_eq!((settings.layer_height.min(), settings.layer_height.max()), (2, 6));
+
+        let mut rng = StdRng::from_seed([7; 32]);
+        assert_eq!(ParamRange::Fixed(4).sample(&mut rng), 4);
+        for _ in 0..100 {
+            let height = settings.layer_height.sample(&mut rng);
+            assert!(height >= 2 && height <= 6, "{}", height);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_settings() {
+        let cases = &[
+            "frequency = 'high'\nlayer_height = 2",
+            "frequency = { min = 0.01 }\nlayer_height = 2",
+            "frequency = [0.01, 0.04]\nlayer_height = 2",
+            "frequency = 0.02\nlayer_height = -1",
+        ];
+        for case in cases {
+            assert!(parse(case).is_err(), "{}", case);
+        }
+    }
+
+    #[test]
+    fn validates_ranges() {
+        let cases: &[(ParamRange<f64>, bool, bool)] = &[
+            (ParamRange::Fixed(0.02), true, true),
+            (ParamRange::Uniform { min: 0.01, max: 0.04 }, true, true),
+            (ParamRange::Uniform { min: 0.04, max: 0.01 }, false, false),
+            (ParamRange::Uniform { min: 0.02, max: 0.02 }, false, false),
+            (ParamRange::Fixed(0.0), true, false),
+            (ParamRange::Uniform { min: -1.0, max: 1.0 }, true, false),
+            (ParamRange::Fixed(f64::NAN), true, false),
+            (ParamRange::Uniform { min: 1.0, max: f64::INFINITY }, true, false),
+        ];
+        for &(range, valid, positive) in cases {
+            assert_eq!(range.validate().is_ok(), valid, "{:?}", range);
+            assert_eq!(range.validate_positive().is_ok(), positive, "{:?}", range);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! Rebuilding a lost state file from what was posted
+//!
+//! Without its state file, as on a new host, the bot would post right away and start its ids over,
+//! reusing the names of images already on disk. With `--recover-from-remote`, or by itself when
+//! the state file is missing but the images directory has images in it, the bot instead looks
+//! through its account's recent statuses for the latest one it made, and takes the time of the
+//! last post from it. The next id is put past every image in the images directory, and past the
+//! id in the status, if the body template has one.
+//!
+//! A status is taken for one of the bot's if it has media and its text contains
+//! `recover_signature`. Without that set, the text of the body template up to its first
+//! placeholder is used, which is the default body when there's no template.
+//!
+//! What was worked out is printed, and only kept once confirmed, or right away with `--yes`.
+
+use std::io::{self, BufRead, Write};
+
+use anyhow::{Context, Error};
+use chrono::{DateTime, Utc};
+use elefren::Data as MastoData;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use archive::{self, Kind};
+use build_info;
+use errors::PostingError;
+use posting::parse_response;
+use {BotConfig, Phase, State, StatePaths, POST_BODY, QUARANTINE_DIR, STALE_DIR};
+
+/// Statuses asked for at once, the most Mastodon returns
+const PAGE_SIZE: usize = 40;
+/// Pages looked through before giving up on finding one of the bot's statuses
+const MAX_PAGES: usize = 5;
+
+#[derive(Deserialize)]
+struct Account {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    id: String,
+    created_at: DateTime<Utc>,
+    content: String,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    media_attachments: Vec<serde_json::Value>,
+}
+
+/// What the state file is rebuilt from
+struct Recovery {
+    /// The bot's latest status, with its time and URL
+    last_post: Option<(DateTime<Utc>, String)>,
+    /// Lowest id which no image has had yet
+    id: u32,
+}
+
+/// Whether the state file seems to have been lost, as it's missing but images are there
+pub fn state_lost(paths: &StatePaths, config: &BotConfig) -> bool {
+    !paths.state.exists()
+        && archive::scan(&paths.images, &config.filename_template())
+            .map(|entries| entries.iter().any(|entry| entry.kind == Kind::Full))
+            .unwrap_or(false)
+}
+
+/// Text which marks a status as one of the bot's
+fn signature(config: &BotConfig) -> Option<String> {
+    if let Some(ref signature) = config.recover_signature {
+        return Some(signature.clone());
+    }
+    let body = config.body.as_ref().map_or(POST_BODY, String::as_str);
+    let literal = body.split('{').next().unwrap_or("").trim();
+    if literal.is_empty() {
+        None
+    } else {
+        Some(literal.to_string())
+    }
+}
+
+/// The id in `text`, a status filled in from `template`, if the template has one
+fn id_in(text: &str, template: &str) -> Option<u32> {
+    let before = &template[..template.find("{id}")?];
+    let anchor = before.rsplit('}').next().unwrap_or("");
+    let rest = if anchor.is_empty() {
+        text.trim_start()
+    } else {
+        let start = text.find(anchor)? + anchor.len();
+        &text[start..]
+    };
+    let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// Plain text of a status's HTML content
+fn plain_text(html: &str) -> String {
+    let html = html.replace("<br>", "\n").replace("<br />", "\n").replace("</p><p>", "\n\n");
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn get<T: DeserializeOwned>(account: &MastoData, path: &str) -> Result<T, Error> {
+    let url = format!("{}{}", account.base.trim_end_matches('/'), path);
+    let response = build_info::http_client()
+        .get(&url)
+        .bearer_auth(&account.token)
+        .send()
+        .map_err(PostingError::from)?;
+    Ok(parse_response("mastodon", response)?)
+}
+
+/// The latest of `account`'s statuses which is one of the bot's, going back `MAX_PAGES` pages
+fn latest_post(account: &MastoData, signature: Option<&str>) -> Result<Option<Status>, Error> {
+    let me: Account =
+        get(account, "/api/v1/accounts/verify_credentials").context("unable to look up account")?;
+    let mut max_id: Option<String> = None;
+    for _ in 0..MAX_PAGES {
+        let mut path = format!(
+            "/api/v1/accounts/{}/statuses?limit={}&exclude_reblogs=true&exclude_replies=true",
+            me.id, PAGE_SIZE
+        );
+        if let Some(ref max_id) = max_id {
+            path.push_str(&format!("&max_id={}", max_id));
+        }
+        let statuses: Vec<Status> = get(account, &path).context("unable to fetch statuses")?;
+        let last_id = match statuses.last() {
+            Some(status) => status.id.clone(),
+            None => return Ok(None),
+        };
+        let found = statuses.into_iter().find(|status| {
+            !status.media_attachments.is_empty()
+                && signature.map_or(true, |signature| {
+                    plain_text(&status.content).contains(signature)
+                })
+        });
+        if found.is_some() {
+            return Ok(found);
+        }
+        max_id = Some(last_id);
+    }
+    Ok(None)
+}
+
+/// Lowest id past every image of the bot's in the images directory, set aside ones included
+fn id_floor(paths: &StatePaths, config: &BotConfig) -> Result<u32, Error> {
+    let template = config.filename_template();
+    let mut floor = 0;
+    for dir in &[
+        paths.images.clone(),
+        paths.images.join(STALE_DIR),
+        paths.images.join(QUARANTINE_DIR),
+    ] {
+        let entries = archive::scan(dir, &template)
+            .with_context(|| format!("unable to read {}", dir.display()))?;
+        for entry in entries.iter().filter(|entry| entry.kind == Kind::Full) {
+            if let Some(id) = entry.id {
+                floor = floor.max(id + 1);
+            }
+        }
+    }
+    Ok(floor)
+}
+
+fn reconstruct(
+    config: &BotConfig,
+    account: &MastoData,
+    paths: &StatePaths,
+) -> Result<Recovery, Error> {
+    let signature = signature(config);
+    let status = latest_post(account, signature.as_ref().map(String::as_str))?;
+    let mut id = id_floor(paths, config)?;
+    if let (&Some(ref status), &Some(ref template)) = (&status, &config.body) {
+        if let Some(posted_id) = id_in(&plain_text(&status.content), template) {
+            id = id.max(posted_id + 1);
+        }
+    }
+    Ok(Recovery {
+        last_post: status.map(|status| {
+            let url = status.url.unwrap_or(status.id);
+            (status.created_at, url)
+        }),
+        id,
+    })
+}
+
+/// Ask whether to keep the recovered state. No when there's nobody to ask.
+fn confirm() -> Result<bool, Error> {
+    eprint!("Keep this? [y/N]: ");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().eq_ignore_ascii_case("y") || line.trim().eq_ignore_ascii_case("yes"))
+}
+
+/// `state` with its last post and id recovered from `account`'s statuses, once confirmed, or
+/// right away if `yes`
+pub fn run(
+    config: &BotConfig,
+    account: &MastoData,
+    mut state: State,
+    yes: bool,
+) -> Result<State, Error> {
+    match state.phase {
+        Phase::Awaiting => {}
+        _ => {
+            return Err(Error::msg(
+                "the state file has an image pending, so there's nothing to recover",
+            ))
+        }
+    }
+    eprintln!("Recovering the state from the account's statuses...");
+    let recovery = reconstruct(config, account, &state.paths)?;
+
+    match recovery.last_post {
+        Some((posted, ref url)) => eprintln!("Last post: {} ({})", posted, url),
+        None => eprintln!("Last post: none of the account's recent statuses look like the bot's"),
+    }
+    eprintln!("Next image id: {} (was {})", recovery.id.max(state.id), state.id);
+    if !yes && !confirm()? {
+        return Err(Error::msg("recovery not confirmed, pass --yes to keep it without asking"));
+    }
+
+    if let Some((posted, _)) = recovery.last_post {
+        state.last_post = Some(state.last_post.map_or(posted, |last_post| last_post.max(posted)));
+    }
+    state.id = state.id.max(recovery.id);
+    state.persist()?;
+    eprintln!("Recovered state saved to {}", state.paths.state.display());
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::fs::{create_dir_all, write};
+    use tempfile::{tempdir, TempDir};
+    use test_support::{account_json, attachment_json, status_json, MockServer};
+    use toml;
+
+    fn account(base: &str) -> MastoData {
+        MastoData {
+            base: base.to_string().into(),
+            client_id: "id".into(),
+            client_secret: "secret".into(),
+            redirect: "urn:ietf:wg:oauth:2.0:oob".into(),
+            token: "token".into(),
+        }
+    }
+
+    /// Config with its state and images in a temporary directory, with images 1 to 3 on disk
+    fn setup() -> (TempDir, BotConfig, State) {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let config: BotConfig = toml::from_str(&format!(
+            "map_size = 16\nbody = 'Landscape {{id}}: {{name}}'\nimages_dir = '{}'\n\
+             state_path = '{}'",
+            dir.path().join("images").display(),
+            dir.path().join("state").display()
+        )).expect("Invalid config");
+        create_dir_all(&config.images_dir).expect("Unable to create images directory");
+        for id in 1..4 {
+            write(config.images_dir.join(format!("{}.png", id)), b"image")
+                .expect("Unable to write image");
+        }
+        let state = State::get_state(StatePaths::from_config(&config));
+        (dir, config, state)
+    }
+
+    /// A status with `content`, with an image attached or not
+    fn status(id: &str, content: &str, image: bool) -> String {
+        let attachments = if image {
+            vec![attachment_json("100", "An isometric landscape")]
+        } else {
+            Vec::new()
+        };
+        let url = format!("https://example.org/@cubeglobe/{}", id);
+        status_json(id, "https://example.org/1", Some(&url), &attachments)
+            .replace("<p>Landscape</p>", content)
+    }
+
+    #[test]
+    fn finds_ids_in_posts() {
+        let cases = &[
+            ("Landscape 41: Foo", "Landscape {id}: {name}", Some(41)),
+            ("Foo, no. 7", "{name}, no. {id}", Some(7)),
+            ("12 Foo", "{id} {name}", Some(12)),
+            ("Landscape: Foo", "Landscape {id}: {name}", None),
+            ("Foo", "{name}", None),
+            ("Landscape 99999999999: Foo", "Landscape {id}: {name}", None),
+        ];
+        for &(text, template, id) in cases {
+            assert_eq!(id_in(text, template), id, "{:?} from {:?}", text, template);
+        }
+    }
+
+    #[test]
+    fn strips_html() {
+        assert_eq!(
+            plain_text("<p>Landscape 4 &amp; <a href=\"x\">more</a></p><p>Next<br>line</p>"),
+            "Landscape 4 & more\n\nNext\nline"
+        );
+    }
+
+    #[test]
+    fn recovers_from_the_latest_post() {
+        let (_dir, config, state) = setup();
+        let statuses = format!(
+            "[{}, {}, {}]",
+            status("13", "<p>Landscape 99: a reply</p>", false),
+            status("12", "<p>Something else</p>", true),
+            status("11", "<p>Landscape 41: <span>Foo</span></p>", true)
+        );
+        let server = MockServer::start(vec![(200, account_json()), (200, statuses)]);
+
+        let recovered =
+            run(&config, &account(&server.url), state, true).expect("Unable to recover");
+        assert_eq!(recovered.id, 42);
+        assert_eq!(recovered.last_post, Some(Utc.ymd(2024, 5, 1).and_hms(12, 0, 0)));
+        let saved = State::get_state(StatePaths::from_config(&config));
+        assert_eq!(saved.id, 42);
+
+        let requests = server.requests();
+        assert!(requests[1].path.starts_with("/api/v1/accounts/1/statuses?"));
+    }
+
+    #[test]
+    fn malformed_statuses_change_nothing() {
+        let (_dir, config, state) = setup();
+        let server = MockServer::start(vec![
+            (200, account_json()),
+            (200, r#"[{"id": "11", "content": "Landscape 41"}]"#.to_string()),
+        ]);
+
+        assert!(run(&config, &account(&server.url), state, true).is_err());
+        assert!(!config.state_path().exists());
+        server.requests();
+    }
+
+    #[test]
+    fn nothing_to_recover_with_an_image_pending() {
+        let (_dir, config, mut state) = setup();
+        state.phase = Phase::Generated;
+        assert!(run(&config, &account("http://127.0.0.1:9"), state, true).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! Regeneration budget
+//!
+//! Whenever an image is thrown away and generated again, the budget for the post is consulted
+//! first, so a broken tiles config or settings that can't work out don't keep the bot generating
+//! forever. Once it runs out, the bot gives up on the slot and waits for the next one.
+
+use std::time::{Duration, Instant};
+
+/// Regenerations left for one post
+pub struct RegenBudget {
+    max_regenerations: u32,
+    max_duration: Duration,
+    started: Instant,
+    regenerations: u32,
+}
+
+impl RegenBudget {
+    /// Start the budget for a post, counting time from now
+    pub fn new(max_regenerations: u32, max_seconds: u64) -> RegenBudget {
+        RegenBudget {
+            max_regenerations,
+            max_duration: Duration::from_secs(max_seconds),
+            started: Instant::now(),
+            regenerations: 0,
+        }
+    }
+
+    /// Count another regeneration, returning false instead if the budget doesn't allow one
+    pub fn try_regenerate(&mut self) -> bool {
+        if self.regenerations >= self.max_regenerations || self.elapsed() >= self.max_duration {
+            return false;
+        }
+        self.regenerations += 1;
+        true
+    }
+
+    /// Regenerations so far
+    pub fn regenerations(&self) -> u32 {
+        self.regenerations
+    }
+
+    /// Time spent on the post so far
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_up_to_max_regenerations() {
+        let mut budget = RegenBudget::new(3, 600);
+        assert_eq!(budget.regenerations(), 0);
+        for regenerations in 1..=3 {
+            assert!(budget.try_regenerate());
+            assert_eq!(budget.regenerations(), regenerations);
+        }
+        assert!(!budget.try_regenerate());
+        // Refusals don't count
+        assert_eq!(budget.regenerations(), 3);
+    }
+
+    #[test]
+    fn zero_allows_none() {
+        let mut budget = RegenBudget::new(0, 600);
+        assert!(!budget.try_regenerate());
+        assert_eq!(budget.regenerations(), 0);
+    }
+
+    #[test]
+    fn runs_out_of_time() {
+        let mut budget = RegenBudget::new(3, 5);
+        assert!(budget.try_regenerate());
+        // As if generating had taken a while
+        budget.started = Instant::now() - Duration::from_secs(6);
+        assert!(budget.elapsed() >= Duration::from_secs(6));
+        assert!(!budget.try_regenerate());
+        assert_eq!(budget.regenerations(), 1);
+
+        let mut budget = RegenBudget::new(3, 0);
+        assert!(!budget.try_regenerate());
+    }
+}
@@ -0,0 +1,373 @@
+//! What the instance made of the uploaded image
+//!
+//! Instances are free to re-encode uploads: strip metadata, scale large images down, or turn a
+//! GIF into a video. After posting, the details Mastodon reported for the uploaded image are kept
+//! in `{stem}.media.toml` next to the image, together with those of the image as generated, and a
+//! notice is logged when the two differ noticeably. With `match_remote`, the instance's version
+//! is also downloaded into `remote/` in the images directory, so the archive holds what followers
+//! actually saw.
+//!
+//! None of this can fail a post. A download which doesn't work out is tried again on a later
+//! cycle, up to `MAX_DOWNLOAD_ATTEMPTS` times.
+
+use std::fs::{create_dir_all, read_to_string, File};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Error;
+use elefren::entities::attachment::Attachment;
+use reqwest::Client;
+use toml;
+
+use build_info::{self, BuildInfo};
+use posting::{image_dimensions, ImageFormat};
+use REMOTE_DIR;
+
+/// How much the instance may scale the image, as a fraction of either side, before it's noted
+const DIMENSION_TOLERANCE: f64 = 0.01;
+
+/// Downloads of the instance's version tried before giving up on it
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 5;
+
+/// The uploaded image as the instance reported it
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RemoteMedia {
+    pub url: String,
+    /// Guessed from the extension of `url`, as Mastodon doesn't report it
+    #[serde(default)]
+    pub mimetype: Option<String>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+}
+
+impl RemoteMedia {
+    pub fn from_attachment(attachment: &Attachment) -> RemoteMedia {
+        let original = attachment
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.original.as_ref());
+        RemoteMedia {
+            url: attachment.url.clone(),
+            mimetype: url_extension(&attachment.url)
+                .and_then(mimetype_of)
+                .map(str::to_string),
+            width: original.map(|details| details.width as u32),
+            height: original.map(|details| details.height as u32),
+        }
+    }
+}
+
+/// The image as generated
+#[derive(Deserialize, Serialize)]
+struct LocalMedia {
+    mimetype: String,
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    height: Option<u32>,
+    bytes: usize,
+}
+
+/// Contents of `{stem}.media.toml`
+#[derive(Deserialize, Serialize)]
+struct MediaRecord {
+    /// File in `remote/` the instance's version was saved as
+    #[serde(default)]
+    downloaded: Option<String>,
+    local: LocalMedia,
+    remote: RemoteMedia,
+    /// Build of the bot which posted the image
+    #[serde(default)]
+    build: Option<BuildInfo>,
+}
+
+/// The instance's version of an image, still to be downloaded
+#[derive(Deserialize, Serialize)]
+pub struct PendingDownload {
+    /// Local image it's the version of
+    pub filename: String,
+    pub url: String,
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+fn record_name(filename: &str) -> String {
+    let stem = Path::new(filename)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| filename.to_string());
+    format!("{}.media.toml", stem)
+}
+
+fn url_extension(url: &str) -> Option<&str> {
+    let path = url.split(|c| c == '?' || c == '#').next()?;
+    let name = path.rsplit('/').next()?;
+    let dot = name.rfind('.')?;
+    Some(&name[dot + 1..])
+}
+
+fn mimetype_of(extension: &str) -> Option<&'static str> {
+    match extension.to_lowercase().as_str() {
+        "png" => Some("image/png"),
+        "gif" => Some("image/gif"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "webp" => Some("image/webp"),
+        "mp4" => Some("video/mp4"),
+        _ => None,
+    }
+}
+
+/// Whether `remote` is more than `DIMENSION_TOLERANCE` off `local`
+fn differs(local: u32, remote: u32) -> bool {
+    let local = f64::from(local.max(1));
+    (f64::from(remote) - local).abs() / local > DIMENSION_TOLERANCE
+}
+
+/// Keep what the instance reported for `image`, saved as `filename` in `images`, next to it
+///
+/// Problems writing the record are only logged.
+pub fn record(
+    images: &Path,
+    filename: &str,
+    image: &[u8],
+    format: ImageFormat,
+    remote: &RemoteMedia,
+) {
+    let dimensions = image_dimensions(image);
+    let local = LocalMedia {
+        mimetype: format.mimetype().to_string(),
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        bytes: image.len(),
+    };
+
+    if let Some(ref mimetype) = remote.mimetype {
+        if *mimetype != local.mimetype {
+            eprintln!(
+                "Notice: The instance turned {} from {} into {}",
+                filename, local.mimetype, mimetype
+            );
+        }
+    }
+    if let (Some((width, height)), Some(remote_width), Some(remote_height)) =
+        (dimensions, remote.width, remote.height)
+    {
+        if differs(width, remote_width) || differs(height, remote_height) {
+            eprintln!(
+                "Notice: The instance resized {} from {}x{} to {}x{}",
+                filename, width, height, remote_width, remote_height
+            );
+        }
+    }
+
+    let record = MediaRecord {
+        downloaded: None,
+        local,
+        remote: remote.clone(),
+        build: Some(BuildInfo::current()),
+    };
+    if let Err(e) = write_record(images, filename, &record) {
+        eprintln!(
+            "WARNING: Unable to record the instance's details of {}: {:#}",
+            filename, e
+        );
+    }
+}
+
+fn write_record(images: &Path, filename: &str, record: &MediaRecord) -> Result<(), Error> {
+    let path = images.join(record_name(filename));
+    File::create(path)?.write_all(toml::to_string(record)?.as_bytes())?;
+    Ok(())
+}
+
+/// Download what's in `pending`, dropping what's done or failed too often
+///
+/// Returns whether `pending` changed.
+pub fn download_pending(images: &Path, pending: &mut Vec<PendingDownload>) -> bool {
+    if pending.is_empty() {
+        return false;
+    }
+
+    let client = build_info::http_client();
+    for mut download in pending.drain(..).collect::<Vec<_>>() {
+        match download_one(&client, images, &download) {
+            Ok(saved) => {
+                eprintln!("Saved the instance's version of {} as {}", download.filename, saved);
+            }
+            Err(e) => {
+                download.attempts += 1;
+                if download.attempts >= MAX_DOWNLOAD_ATTEMPTS {
+                    eprintln!(
+                        "Failed to download the instance's version of {} {} times, giving up: \
+                         {:#}",
+                        download.filename, download.attempts, e
+                    );
+                } else {
+                    eprintln!(
+                        "Unable to download the instance's version of {}, retrying later: {:#}",
+                        download.filename, e
+                    );
+                    pending.push(download);
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Download the instance's version of one image, returning the name it was saved as
+fn download_one(
+    client: &Client,
+    images: &Path,
+    download: &PendingDownload,
+) -> Result<String, Error> {
+    let mut response = client.get(&download.url).send()?.error_for_status()?;
+    let mut data = Vec::new();
+    response.copy_to(&mut data)?;
+
+    let stem = Path::new(&download.filename)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| download.filename.clone());
+    let extension = url_extension(&download.url).unwrap_or("bin");
+    let name = format!("{}.{}", stem, extension);
+
+    let dir = images.join(REMOTE_DIR);
+    create_dir_all(&dir)?;
+    File::create(dir.join(&name))?.write_all(&data)?;
+
+    // Only a note, so the download counts even if the record is missing
+    let record_path = images.join(record_name(&download.filename));
+    if let Ok(contents) = read_to_string(&record_path) {
+        if let Ok(mut record) = toml::from_str::<MediaRecord>(&contents) {
+            record.downloaded = Some(name.clone());
+            if let Err(e) = write_record(images, &download.filename, &record) {
+                eprintln!("WARNING: Unable to note the download of {}: {:#}", name, e);
+            }
+        }
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use build_info::{GIT_HASH, VERSION};
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+    use std::fs::read;
+    use tempfile;
+    use test_support::MockServer;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut png, ImageOutputFormat::PNG)
+            .expect("Unable to encode PNG");
+        png
+    }
+
+    fn remote(url: &str, width: u32, height: u32) -> RemoteMedia {
+        RemoteMedia {
+            url: url.to_string(),
+            mimetype: url_extension(url).and_then(mimetype_of).map(str::to_string),
+            width: Some(width),
+            height: Some(height),
+        }
+    }
+
+    fn read_record(images: &Path, filename: &str) -> MediaRecord {
+        let contents =
+            read_to_string(images.join(record_name(filename))).expect("Unable to read record");
+        toml::from_str(&contents).expect("Invalid record")
+    }
+
+    #[test]
+    fn guesses_types_from_urls() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("https://files.example.com/media/abc.png", Some("image/png")),
+            ("https://files.example.com/media/abc.JPEG?v=2", Some("image/jpeg")),
+            ("https://files.example.com/media/abc.mp4#t=0", Some("video/mp4")),
+            ("https://files.example.com/media/abc.tiff", None),
+            ("https://files.example.com/media.d/abc", None),
+        ];
+        for &(url, expected) in cases {
+            assert_eq!(url_extension(url).and_then(mimetype_of), expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn tolerates_small_differences() {
+        assert!(!differs(1000, 1000));
+        assert!(!differs(1000, 1010));
+        assert!(differs(1000, 1011));
+        assert!(differs(1000, 500));
+        assert!(!differs(0, 1));
+    }
+
+    #[test]
+    fn names_records_after_images() {
+        assert_eq!(record_name("5.png"), "5.media.toml");
+        assert_eq!(record_name("landscape-5.gif"), "landscape-5.media.toml");
+    }
+
+    #[test]
+    fn records_both_versions() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let image = png(40, 20);
+        let remote = remote("https://files.example.com/media/abc.jpg", 20, 10);
+        record(dir.path(), "5.png", &image, ImageFormat::Png, &remote);
+
+        let record = read_record(dir.path(), "5.png");
+        assert_eq!(record.local.mimetype, "image/png");
+        assert_eq!((record.local.width, record.local.height), (Some(40), Some(20)));
+        assert_eq!(record.local.bytes, image.len());
+        assert_eq!(record.remote.mimetype, Some("image/jpeg".to_string()));
+        assert_eq!((record.remote.width, record.remote.height), (Some(20), Some(10)));
+        assert_eq!(record.downloaded, None);
+        let build = record.build.expect("No build recorded");
+        assert_eq!((build.version.as_str(), build.git_hash.as_str()), (VERSION, GIT_HASH));
+    }
+
+    #[test]
+    fn downloads_remote_version() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let server = MockServer::start(vec![(200, "remote image".to_string())]);
+        let url = format!("{}/media/abc.jpg?v=1", server.url);
+        record(dir.path(), "5.png", &png(4, 4), ImageFormat::Png, &remote(&url, 4, 4));
+
+        let mut pending = vec![PendingDownload {
+            filename: "5.png".to_string(),
+            url,
+            attempts: 0,
+        }];
+        assert!(download_pending(dir.path(), &mut pending));
+        assert!(pending.is_empty());
+
+        let saved = dir.path().join(REMOTE_DIR).join("5.jpg");
+        assert_eq!(read(saved).expect("Not downloaded"), b"remote image");
+        let record = read_record(dir.path(), "5.png");
+        assert_eq!(record.downloaded, Some("5.jpg".to_string()));
+        assert_eq!(server.requests()[0].path, "/media/abc.jpg?v=1");
+    }
+
+    #[test]
+    fn failed_downloads_are_retried_a_few_times() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let server = MockServer::start(vec![(503, "unavailable".to_string()); 2]);
+        let mut pending = vec![PendingDownload {
+            filename: "5.png".to_string(),
+            url: format!("{}/media/abc.jpg", server.url),
+            attempts: MAX_DOWNLOAD_ATTEMPTS - 2,
+        }];
+
+        assert!(download_pending(dir.path(), &mut pending));
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].attempts, MAX_DOWNLOAD_ATTEMPTS - 1);
+        assert!(download_pending(dir.path(), &mut pending));
+        assert!(pending.is_empty());
+        assert!(!download_pending(dir.path(), &mut pending));
+        assert!(!dir.path().join(REMOTE_DIR).exists());
+    }
+}
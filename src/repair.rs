@@ -0,0 +1,287 @@
+//! Images directory and state file repair
+//!
+//! Crashes at the wrong moment, or a state file that got lost, can leave the state id behind the
+//! images already on disk, or leave images behind that were never posted. The `repair`
+//! subcommand finds these, and with `--fix` sorts them out. It should not be run while the bot
+//! itself is running.
+//!
+//! With `bot_name` set, only that bot's images are looked at, so bots sharing the images
+//! directory are left alone.
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, rename};
+use std::path::PathBuf;
+
+use anyhow::Error;
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use archive::{self, Kind};
+use {BotConfig, Phase, State, StatePaths};
+
+/// Subdirectory of the images directory that leftover images are moved to
+const ORPHANED_DIR: &str = "orphaned";
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("repair")
+        .about("check the images directory against the state file, and optionally fix problems")
+        .arg(
+            Arg::with_name("fix")
+                .long("fix")
+                .help("fix the problems found, printing each change before making it"),
+        ).arg(
+            Arg::with_name("dryrun")
+                .long("dry-run")
+                .requires("fix")
+                .help("with --fix, only print the changes that would be made"),
+        )
+}
+
+/// A change to make, printed before it is made
+enum Action {
+    /// Set the state id, so new images don't reuse ids already on disk
+    BumpId(u32),
+    /// Forget the pending image, so a new one is generated
+    ResetPending,
+    /// Move an image that was never posted out of the way
+    MoveOrphan(PathBuf),
+}
+
+/// Check the images directory and state, and apply fixes if asked to
+///
+/// Returns whether everything was consistent.
+pub fn run(matches: &ArgMatches, config: &BotConfig) -> Result<bool, Error> {
+    let fix = matches.is_present("fix");
+    let dry_run = matches.is_present("dryrun");
+
+    let paths = StatePaths::from_config(config);
+    let images_dir = paths.images.clone();
+    let mut state = State::get_state(paths);
+    let pending = match state.phase {
+        Phase::Generated | Phase::AwaitingApproval => state.filename.clone(),
+        Phase::Awaiting => None,
+    };
+
+    let mut by_id: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    let mut unrecognized = Vec::new();
+    for entry in archive::scan(&images_dir, &config.filename_template())? {
+        match (entry.kind, entry.id) {
+            (Kind::Full, Some(id)) => by_id.entry(id).or_insert_with(Vec::new).push(entry.name),
+            // Other bots' files are theirs to look after
+            (Kind::Foreign, _) => match config.bot_name {
+                Some(ref bot_name) if !entry.name.starts_with(&format!("{}-", bot_name)) => {}
+                _ => unrecognized.push(entry.name),
+            },
+            _ => {}
+        }
+    }
+
+    let mut problems = 0;
+    let mut actions = Vec::new();
+
+    for name in &unrecognized {
+        println!("{}: does not match filename_template, leaving it alone", name);
+        problems += 1;
+    }
+
+    for (id, names) in &by_id {
+        if names.len() > 1 {
+            println!("Id {} is used by more than one image: {}", id, names.join(", "));
+            problems += 1;
+        }
+    }
+
+    if let Some(ref filename) = pending {
+        if !images_dir.join(filename).is_file() {
+            println!("Pending image {} is missing", filename);
+            problems += 1;
+            actions.push(Action::ResetPending);
+        }
+    }
+
+    // Images other than the pending one, which have all been posted unless they are leftovers of
+    // an interrupted generation, which would have the current id
+    let others = |id: u32| {
+        by_id.get(&id).map_or(Vec::new(), |names| {
+            names
+                .iter()
+                .filter(|name| Some(*name) != pending.as_ref())
+                .cloned()
+                .collect()
+        })
+    };
+    let max_id = by_id
+        .keys()
+        .cloned()
+        .filter(|&id| !others(id).is_empty())
+        .max();
+
+    match max_id {
+        Some(max_id) if max_id > state.id => {
+            // The state was reset or lost, so images with the current id were posted before that
+            println!(
+                "State id {} is lower than the highest image id on disk, {}",
+                state.id, max_id
+            );
+            problems += 1;
+            actions.push(Action::BumpId(max_id + 1));
+        }
+        Some(max_id) if max_id == state.id => {
+            for name in others(state.id) {
+                println!("{}: has the current id, but is not the pending image", name);
+                problems += 1;
+                actions.push(Action::MoveOrphan(images_dir.join(name)));
+            }
+        }
+        _ => {}
+    }
+
+    if problems == 0 {
+        println!("No problems found");
+        return Ok(true);
+    }
+    println!("{} problem(s) found", problems);
+
+    if !fix {
+        if !actions.is_empty() {
+            println!("Run with --fix to fix what can be fixed automatically");
+        }
+        return Ok(false);
+    }
+
+    let orphaned_dir = images_dir.join(ORPHANED_DIR);
+    let mut state_changed = false;
+    for action in actions {
+        match action {
+            Action::BumpId(id) => {
+                println!("Setting state id from {} to {}", state.id, id);
+                if !dry_run {
+                    state.id = id;
+                    state_changed = true;
+                }
+            }
+            Action::ResetPending => {
+                println!("Forgetting the pending image, a new one will be generated");
+                if !dry_run {
+                    state.phase = Phase::Awaiting;
+                    state.filename = None;
+                    state.description = None;
+                    state.name = None;
+                    state.params = None;
+                    state.stats = None;
+                    state.locale = None;
+                    state.gap_notice = None;
+                    state.posted_to.clear();
+                    state.given_up_on.clear();
+                    state.progress.clear();
+                    state_changed = true;
+                }
+            }
+            Action::MoveOrphan(path) => {
+                let target = orphaned_dir.join(path.file_name().expect("read from a directory"));
+                println!("Moving {} to {}", path.display(), target.display());
+                if !dry_run {
+                    create_dir_all(&orphaned_dir)?;
+                    rename(&path, &target)?;
+                }
+            }
+        }
+    }
+
+    if state_changed {
+        println!("Writing {}", state.paths.state.display());
+        state.persist()?;
+    }
+    if dry_run {
+        println!("Dry run, nothing was changed");
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use std::path::Path;
+    use tempfile::{tempdir, TempDir};
+    use toml;
+
+    /// Config keeping its state and images in a temporary directory, with images `ids` on disk
+    fn setup(ids: &[u32]) -> (TempDir, BotConfig) {
+        let dir = tempdir().expect("Unable to create temporary directory");
+        let config: BotConfig = toml::from_str(&format!(
+            "map_size = 16\nimages_dir = '{}'\nstate_path = '{}'",
+            dir.path().join("images").display(),
+            dir.path().join("state").display()
+        )).expect("Invalid config");
+        create_dir_all(&config.images_dir).expect("Unable to create images directory");
+        for id in ids {
+            write_image(&config.images_dir, &format!("{}.png", id));
+        }
+        (dir, config)
+    }
+
+    fn write_image(dir: &Path, name: &str) {
+        write(dir.join(name), b"image").expect("Unable to write image");
+    }
+
+    fn state(config: &BotConfig) -> State {
+        State::get_state(StatePaths::from_config(config))
+    }
+
+    fn repair(config: &BotConfig, args: &[&str]) -> bool {
+        let matches = subcommand().get_matches_from(args);
+        run(&matches, config).expect("Unable to repair")
+    }
+
+    #[test]
+    fn consistent_state_is_left_alone() {
+        let (_dir, config) = setup(&[1, 2, 3]);
+        let mut saved = state(&config);
+        saved.id = 4;
+        saved.persist().expect("Unable to save state");
+
+        assert!(repair(&config, &["repair", "--fix"]));
+        assert_eq!(state(&config).id, 4);
+    }
+
+    #[test]
+    fn unreadable_state_gets_an_unused_id() {
+        let (_dir, config) = setup(&[1, 2, 3]);
+        write(config.state_path(), "id = [").expect("Unable to write state");
+        write_image(&config.images_dir, "notes.txt");
+
+        assert!(!repair(&config, &["repair"]));
+        assert!(!repair(&config, &["repair", "--fix"]));
+        assert_eq!(state(&config).id, 4);
+        // Files the bot didn't name are only reported
+        assert!(config.images_dir.join("notes.txt").exists());
+        assert!(!repair(&config, &["repair"]));
+    }
+
+    #[test]
+    fn missing_pending_image_is_forgotten() {
+        let (_dir, config) = setup(&[1, 2, 3, 4]);
+        write_image(&config.images_dir, "5.gif");
+        let mut saved = state(&config);
+        saved.id = 5;
+        saved.phase = Phase::Generated;
+        saved.filename = Some("5.png".to_string());
+        saved.persist().expect("Unable to save state");
+
+        assert!(!repair(&config, &["repair", "--fix", "--dry-run"]));
+        assert_eq!(state(&config).filename, Some("5.png".to_string()));
+        assert!(config.images_dir.join("5.gif").exists());
+
+        assert!(!repair(&config, &["repair", "--fix"]));
+        let repaired = state(&config);
+        assert!(match repaired.phase {
+            Phase::Awaiting => true,
+            _ => false,
+        });
+        assert_eq!(repaired.filename, None);
+        assert_eq!(repaired.id, 5);
+        assert!(config.images_dir.join(ORPHANED_DIR).join("5.gif").exists());
+        assert!(repair(&config, &["repair"]));
+    }
+}
@@ -0,0 +1,65 @@
+//! Map rotation
+//!
+//! The renderer only draws from one side, so to show a map from another we turn the map itself
+//! around its vertical axis before rendering, see `cubeglobe_bot::rotate_map`.
+
+use rand::Rng;
+
+/// How the map is turned before rendering
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationMode {
+    /// Render as generated
+    None,
+    /// Pick one of the four orientations for every post
+    Random,
+}
+
+impl Default for RotationMode {
+    fn default() -> RotationMode {
+        RotationMode::None
+    }
+}
+
+impl RotationMode {
+    /// Pick a number of quarter turns according to the mode
+    pub fn pick<R: Rng>(self, rng: &mut R) -> u8 {
+        match self {
+            RotationMode::None => 0,
+            RotationMode::Random => rng.gen_range(0, 4),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use cubeglobe::map::Map;
+    use cubeglobe_bot::{
+        builtin_tiles, generate_map, rotate_map, surface_to_image, tiles, LandscapeParams, MapStats,
+    };
+    use sha2::{Digest, Sha256};
+
+    /// Hash of the pixels `map` is rendered to
+    fn image_hash(map: &Map) -> Vec<u8> {
+        let tiles_config = builtin_tiles::tiles_config().expect("Unable to draw built-in tiles");
+        let renderer = tiles::load_renderer(Path::new(builtin_tiles::BUILTIN), &tiles_config)
+            .expect("Unable to load built-in tiles");
+        let surf = renderer.render_map(map).expect("Unable to render map");
+        let image = surface_to_image(&surf).expect("Unable to convert surface");
+        Sha256::digest(&image.raw_pixels()).to_vec()
+    }
+
+    #[test]
+    fn rotation_changes_the_image_but_not_the_stats() {
+        let (map, stats) = generate_map(&LandscapeParams::new(16));
+        let unrotated = image_hash(&map);
+        for quarter_turns in 1..4 {
+            let rotated = rotate_map(&map, quarter_turns);
+            assert_eq!(MapStats::of(&rotated), stats, "{} turns", quarter_turns);
+            assert_ne!(image_hash(&rotated), unrotated, "{} turns", quarter_turns);
+        }
+        assert_eq!(image_hash(&rotate_map(&map, 4)), unrotated);
+    }
+}
@@ -0,0 +1,635 @@
+//! Post scheduling
+//!
+//! Also home to the `simulate` subcommand, which runs the schedule forward without posting
+//! anything, to preview what a `sleep_time` and `jitter` combination looks like.
+
+use std::fmt;
+
+use chrono::{DateTime, Duration, Timelike, Utc};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+/// What to do with the pending image once the bot has been failing to post for a long time
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutageAction {
+    /// Swap the pending image for a fresh one whenever it gets `after_hours` old
+    Regenerate,
+    /// Keep retrying the pending image until it goes through
+    Keep,
+    /// Drop the pending image, and pick the schedule up again from the next post
+    SkipSlot,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct OutagePolicy {
+    /// Hours since the first failure before `action` is taken
+    pub after_hours: f64,
+    pub action: OutageAction,
+}
+
+/// How far posts may come before or after `sleep_time` is up
+///
+/// In the config file, this is either a single amount for both ways (`jitter = 600`), or a table
+/// with an amount for each (`jitter = { early = 0, late = 2700 }`), where a way left out is 0.
+/// Amounts are seconds, or a percentage of `sleep_time` like `"12%"`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum JitterSpec {
+    Symmetric(JitterAmount),
+    Asymmetric {
+        #[serde(default)]
+        early: Option<JitterAmount>,
+        #[serde(default)]
+        late: Option<JitterAmount>,
+    },
+}
+
+impl JitterSpec {
+    /// Most seconds a post may come before `sleep_time` is up
+    pub fn early(&self, sleep_time: i64) -> i64 {
+        match *self {
+            JitterSpec::Symmetric(amount) => amount.seconds(sleep_time),
+            JitterSpec::Asymmetric { early, .. } => early.map_or(0, |a| a.seconds(sleep_time)),
+        }
+    }
+
+    /// Most seconds a post may come after `sleep_time` is up
+    pub fn late(&self, sleep_time: i64) -> i64 {
+        match *self {
+            JitterSpec::Symmetric(amount) => amount.seconds(sleep_time),
+            JitterSpec::Asymmetric { late, .. } => late.map_or(0, |a| a.seconds(sleep_time)),
+        }
+    }
+
+    /// Check that no amount is negative, that `late` is at least `early` if both are given, and
+    /// that posts can't come out of order
+    pub fn validate(&self, sleep_time: i64) -> Result<(), String> {
+        let amounts = match *self {
+            JitterSpec::Symmetric(amount) => vec![amount],
+            JitterSpec::Asymmetric { early, late } => early.into_iter().chain(late).collect(),
+        };
+        for amount in amounts {
+            amount.validate()?;
+        }
+
+        let (early, late) = (self.early(sleep_time), self.late(sleep_time));
+        if let JitterSpec::Asymmetric {
+            early: Some(_),
+            late: Some(_),
+        } = *self
+        {
+            if late < early {
+                return Err(format!(
+                    "late ({} seconds) must be at least early ({} seconds)",
+                    late, early
+                ));
+            }
+        }
+        if early >= sleep_time {
+            return Err(format!(
+                "must allow posts less than sleep_time ({}) early, or they could come out of \
+                 order, got {} seconds",
+                sleep_time, early
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for JitterSpec {
+    fn default() -> JitterSpec {
+        JitterSpec::Symmetric(JitterAmount::Seconds(300))
+    }
+}
+
+/// One way of a `JitterSpec`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JitterAmount {
+    Seconds(i64),
+    /// Of `sleep_time`
+    Percent(f64),
+}
+
+impl JitterAmount {
+    /// Parse a percentage like `"12%"`
+    pub fn parse(input: &str) -> Result<JitterAmount, String> {
+        let problem = || {
+            format!(
+                "expected seconds, or a percentage of sleep_time like \"12%\", got {:?}",
+                input
+            )
+        };
+        let trimmed = input.trim();
+        if !trimmed.ends_with('%') {
+            return Err(problem());
+        }
+        trimmed[..trimmed.len() - 1]
+            .trim()
+            .parse::<f64>()
+            .map(JitterAmount::Percent)
+            .map_err(|_| problem())
+    }
+
+    pub fn seconds(self, sleep_time: i64) -> i64 {
+        match self {
+            JitterAmount::Seconds(seconds) => seconds,
+            JitterAmount::Percent(percent) => (sleep_time as f64 * percent / 100.0).round() as i64,
+        }
+    }
+
+    fn validate(self) -> Result<(), String> {
+        match self {
+            JitterAmount::Seconds(seconds) if seconds < 0 => {
+                Err(format!("must not be negative, got {}", seconds))
+            }
+            JitterAmount::Percent(percent) if !percent.is_finite() || percent < 0.0 => {
+                Err(format!("must not be negative, got {}", self))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for JitterAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JitterAmount::Seconds(seconds) => write!(f, "{}", seconds),
+            JitterAmount::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for JitterAmount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<JitterAmount, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Seconds(i64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Seconds(seconds) => Ok(JitterAmount::Seconds(seconds)),
+            Raw::Text(text) => JitterAmount::parse(&text).map_err(de::Error::custom),
+        }
+    }
+}
+
+impl Serialize for JitterAmount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            JitterAmount::Seconds(seconds) => serializer.serialize_i64(seconds),
+            JitterAmount::Percent(_) => serializer.collect_str(self),
+        }
+    }
+}
+
+/// When a fresh deployment, with nothing posted yet, makes its first post
+///
+/// In the config file, this is seconds to wait after starting (`first_post_delay = 3600`), or
+/// `"random_within_interval"` for any time before `sleep_time` is up. Right away if not set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FirstPostDelay {
+    Seconds(i64),
+    RandomWithinInterval,
+}
+
+impl FirstPostDelay {
+    pub fn is_immediate(self) -> bool {
+        self == FirstPostDelay::Seconds(0)
+    }
+
+    pub fn validate(self) -> Result<(), String> {
+        match self {
+            FirstPostDelay::Seconds(seconds) if seconds < 0 => {
+                Err(format!("must not be negative, got {}", seconds))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for FirstPostDelay {
+    fn default() -> FirstPostDelay {
+        FirstPostDelay::Seconds(0)
+    }
+}
+
+impl<'de> Deserialize<'de> for FirstPostDelay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FirstPostDelay, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Seconds(i64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Seconds(seconds) => Ok(FirstPostDelay::Seconds(seconds)),
+            Raw::Text(ref text) if text == "random_within_interval" => {
+                Ok(FirstPostDelay::RandomWithinInterval)
+            }
+            Raw::Text(text) => Err(de::Error::custom(format!(
+                "expected seconds, or \"random_within_interval\", got {:?}",
+                text
+            ))),
+        }
+    }
+}
+
+impl Serialize for FirstPostDelay {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            FirstPostDelay::Seconds(seconds) => serializer.serialize_i64(seconds),
+            FirstPostDelay::RandomWithinInterval => {
+                serializer.serialize_str("random_within_interval")
+            }
+        }
+    }
+}
+
+/// When the first post of a deployment started at `started` is due, or `None` for right away
+pub fn first_post<R: Rng>(
+    started: DateTime<Utc>,
+    delay: FirstPostDelay,
+    sleep_time: i64,
+    rng: &mut R,
+) -> Option<DateTime<Utc>> {
+    let seconds = match delay {
+        FirstPostDelay::Seconds(seconds) => seconds,
+        FirstPostDelay::RandomWithinInterval => rng.gen_range(0, sleep_time),
+    };
+    if seconds > 0 {
+        Some(started + Duration::seconds(seconds))
+    } else {
+        None
+    }
+}
+
+/// When the post after one made at `last_post` is due
+pub fn next_post<R: Rng>(
+    last_post: DateTime<Utc>,
+    sleep_time: i64,
+    jitter: &JitterSpec,
+    rng: &mut R,
+) -> DateTime<Utc> {
+    let (early, late) = (jitter.early(sleep_time), jitter.late(sleep_time));
+    let jitter = if early + late > 0 {
+        rng.gen_range(-early, late)
+    } else {
+        0
+    };
+    last_post + Duration::seconds(sleep_time + jitter)
+}
+
+/// How much longer to hold off posting so that posts are at least `min_interval` apart
+///
+/// This is a safety net under the schedule, against restarts and clock trouble. The first post is
+/// always allowed.
+pub fn min_interval_wait(
+    last_post: Option<DateTime<Utc>>,
+    min_interval: Duration,
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    let earliest = last_post? + min_interval;
+    if earliest > now {
+        Some(earliest - now)
+    } else {
+        None
+    }
+}
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    let is_number = |v: String| match v.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err("must be a whole number".to_string()),
+    };
+
+    SubCommand::with_name("simulate")
+        .about("print when posts would be made over the next days, without posting anything")
+        .arg(
+            Arg::with_name("days")
+                .long("days")
+                .value_name("N")
+                .default_value("7")
+                .validator(is_number)
+                .help("how many days to simulate"),
+        ).arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("N")
+                .validator(is_number)
+                .help("seed for the random jitter, for repeatable runs"),
+        ).arg(
+            Arg::with_name("minspacing")
+                .long("min-spacing")
+                .value_name("SECONDS")
+                .validator(is_number)
+                .help("flag posts made sooner than this after the previous one"),
+        ).arg(
+            Arg::with_name("quiethours")
+                .long("quiet-hours")
+                .value_name("START-END")
+                .validator(|v| parse_hours(&v).map(|_| ()))
+                .help("flag posts made between these hours, in UTC, e.g. 22-7"),
+        )
+}
+
+/// Parse a `START-END` range of hours
+fn parse_hours(input: &str) -> Result<(u32, u32), String> {
+    let mut split = input.splitn(2, '-');
+    let mut hour = || {
+        split
+            .next()
+            .and_then(|h| h.trim().parse::<u32>().ok())
+            .filter(|&h| h < 24)
+            .ok_or_else(|| format!("expected hours from 0 to 23 like 22-7, got {:?}", input))
+    };
+    Ok((hour()?, hour()?))
+}
+
+/// Whether `hour` falls within `start` inclusive to `end` exclusive, wrapping around midnight
+fn in_hours(hour: u32, (start, end): (u32, u32)) -> bool {
+    if start <= end {
+        hour >= start && hour < end
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+/// Run the simulation and print the results
+///
+/// The simulation starts as a fresh deployment would, with `first_post_delay`. Without one, it
+/// starts as if a post had just been made.
+pub fn simulate(
+    matches: &ArgMatches,
+    sleep_time: i64,
+    jitter: &JitterSpec,
+    first_post_delay: FirstPostDelay,
+) {
+    let days: i64 = matches.value_of("days").unwrap_or("7").parse().unwrap_or(7);
+    let min_spacing = matches
+        .value_of("minspacing")
+        .and_then(|s| s.parse::<i64>().ok())
+        .map(Duration::seconds);
+    let quiet_hours = matches.value_of("quiethours").and_then(|h| parse_hours(h).ok());
+    let mut rng = match matches.value_of("seed").and_then(|s| s.parse::<u64>().ok()) {
+        Some(seed) => {
+            let mut bytes = [0; 32];
+            for (i, byte) in bytes.iter_mut().take(8).enumerate() {
+                *byte = (seed >> (i * 8)) as u8;
+            }
+            StdRng::from_seed(bytes)
+        }
+        None => StdRng::from_entropy(),
+    };
+
+    if let Err(problem) = jitter.validate(sleep_time) {
+        println!("Invalid jitter: {}", problem);
+        return;
+    }
+
+    let start = Utc::now();
+    let end = start + Duration::days(days);
+    let mut last_post = match first_post(start, first_post_delay, sleep_time, &mut rng) {
+        Some(first) => {
+            let delay = first - start;
+            println!(
+                "{}  first post, {}h{:02}m after starting",
+                first.format("%Y-%m-%d %H:%M:%S UTC"),
+                delay.num_hours(),
+                delay.num_minutes() % 60
+            );
+            first
+        }
+        None => start,
+    };
+    let mut count = 0;
+    let mut flagged = 0;
+    let mut shortest: Option<Duration> = None;
+    let mut longest: Option<Duration> = None;
+
+    loop {
+        let post = next_post(last_post, sleep_time, jitter, &mut rng);
+        if post > end {
+            break;
+        }
+
+        let spacing = post - last_post;
+        let mut flags = Vec::new();
+        if min_spacing.map_or(false, |min| spacing < min) {
+            flags.push("too soon");
+        }
+        if quiet_hours.map_or(false, |hours| in_hours(post.hour(), hours)) {
+            flags.push("quiet hours");
+        }
+
+        println!(
+            "{}  +{}h{:02}m{}",
+            post.format("%Y-%m-%d %H:%M:%S UTC"),
+            spacing.num_hours(),
+            spacing.num_minutes() % 60,
+            if flags.is_empty() {
+                String::new()
+            } else {
+                format!("  [{}]", flags.join(", "))
+            }
+        );
+
+        count += 1;
+        if !flags.is_empty() {
+            flagged += 1;
+        }
+        shortest = Some(shortest.map_or(spacing, |s| s.min(spacing)));
+        longest = Some(longest.map_or(spacing, |l| l.max(spacing)));
+        last_post = post;
+    }
+
+    println!();
+    println!("{} posts over {} days, {} flagged", count, days, flagged);
+    if let (Some(shortest), Some(longest)) = (shortest, longest) {
+        println!(
+            "Spacing between {}m and {}m",
+            shortest.num_minutes(),
+            longest.num_minutes()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use toml;
+
+    #[derive(Deserialize, Serialize)]
+    struct Config {
+        jitter: JitterSpec,
+    }
+
+    fn jitter(raw: &str) -> Result<JitterSpec, toml::de::Error> {
+        toml::from_str::<Config>(&format!("jitter = {}", raw)).map(|config| config.jitter)
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct DelayConfig {
+        first_post_delay: FirstPostDelay,
+    }
+
+    fn delay(raw: &str) -> Result<FirstPostDelay, toml::de::Error> {
+        toml::from_str::<DelayConfig>(&format!("first_post_delay = {}", raw))
+            .map(|config| config.first_post_delay)
+    }
+
+    #[test]
+    fn parses_jitter() {
+        use schedule::JitterAmount::{Percent, Seconds};
+
+        let cases: &[(&str, JitterSpec)] = &[
+            ("600", JitterSpec::Symmetric(Seconds(600))),
+            ("\"12%\"", JitterSpec::Symmetric(Percent(12.0))),
+            ("\" 2.5 % \"", JitterSpec::Symmetric(Percent(2.5))),
+            (
+                "{ early = 0, late = 2700 }",
+                JitterSpec::Asymmetric {
+                    early: Some(Seconds(0)),
+                    late: Some(Seconds(2700)),
+                },
+            ),
+            (
+                "{ late = \"10%\" }",
+                JitterSpec::Asymmetric {
+                    early: None,
+                    late: Some(Percent(10.0)),
+                },
+            ),
+        ];
+        for &(raw, expected) in cases {
+            assert_eq!(jitter(raw).expect(raw), expected);
+        }
+        for raw in &["\"12\"", "\"%\"", "\"twelve%\"", "1.5"] {
+            assert!(jitter(raw).is_err(), "{} was accepted", raw);
+        }
+    }
+
+    #[test]
+    fn serializes_jitter_as_written() {
+        for raw in &["600", "\"12%\"", "{ early = 0, late = \"10%\" }"] {
+            let spec = jitter(raw).expect(raw);
+            let serialized = toml::to_string(&Config { jitter: spec }).expect(raw);
+            let parsed = toml::from_str::<Config>(&serialized).expect(&serialized);
+            assert_eq!(parsed.jitter, spec);
+        }
+    }
+
+    #[test]
+    fn works_out_each_way() {
+        let sleep_time = 21600;
+        let cases: &[(&str, i64, i64)] = &[
+            ("600", 600, 600),
+            ("\"10%\"", 2160, 2160),
+            ("{ late = 2700 }", 0, 2700),
+            ("{ early = \"5%\", late = \"12.5%\" }", 1080, 2700),
+        ];
+        for &(raw, early, late) in cases {
+            let spec = jitter(raw).expect(raw);
+            assert_eq!((spec.early(sleep_time), spec.late(sleep_time)), (early, late), "{}", raw);
+            assert_eq!(spec.validate(sleep_time), Ok(()), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_jitter() {
+        let cases: &[(&str, &str)] = &[
+            ("-5", "negative"),
+            ("\"-5%\"", "negative"),
+            ("{ early = 0, late = -1 }", "negative"),
+            ("{ early = 600, late = 300 }", "at least early"),
+            ("3600", "out of order"),
+            ("{ early = \"100%\" }", "out of order"),
+        ];
+        for &(raw, expected) in cases {
+            let problem = jitter(raw).expect(raw).validate(3600).unwrap_err();
+            assert!(problem.contains(expected), "{}: {}", raw, problem);
+        }
+        // Late alone may be less than the early default of 0
+        assert_eq!(jitter("{ late = 0 }").unwrap().validate(3600), Ok(()));
+    }
+
+    #[test]
+    fn next_post_stays_within_jitter() {
+        let last = Utc.ymd(2026, 10, 1).and_hms(12, 0, 0);
+        let mut rng = StdRng::from_seed([7; 32]);
+        let spec = jitter("{ early = 60, late = \"50%\" }").unwrap();
+        for _ in 0..1000 {
+            let seconds = (next_post(last, 3600, &spec, &mut rng) - last).num_seconds();
+            assert!(seconds >= 3540 && seconds < 5400, "{}", seconds);
+        }
+        let none = jitter("0").unwrap();
+        assert_eq!(next_post(last, 3600, &none, &mut rng), last + Duration::hours(1));
+    }
+
+    #[test]
+    fn parses_first_post_delay() {
+        assert_eq!(delay("0").unwrap(), FirstPostDelay::default());
+        assert!(delay("0").unwrap().is_immediate());
+        assert_eq!(delay("900").unwrap(), FirstPostDelay::Seconds(900));
+        assert_eq!(
+            delay("\"random_within_interval\"").unwrap(),
+            FirstPostDelay::RandomWithinInterval
+        );
+        assert!(delay("\"later\"").is_err());
+        assert!(delay("-1").unwrap().validate().is_err());
+        assert_eq!(delay("900").unwrap().validate(), Ok(()));
+
+        for raw in &["900", "\"random_within_interval\""] {
+            let first_post_delay = delay(raw).expect(raw);
+            let serialized = toml::to_string(&DelayConfig { first_post_delay }).expect(raw);
+            assert_eq!(serialized, format!("first_post_delay = {}\n", raw));
+        }
+    }
+
+    #[test]
+    fn first_post_is_put_off_as_configured() {
+        let started = Utc.ymd(2026, 10, 1).and_hms(12, 0, 0);
+        let mut rng = StdRng::from_seed([7; 32]);
+        assert_eq!(first_post(started, FirstPostDelay::default(), 3600, &mut rng), None);
+        assert_eq!(
+            first_post(started, FirstPostDelay::Seconds(900), 3600, &mut rng),
+            Some(started + Duration::minutes(15))
+        );
+
+        let mut seen = Vec::new();
+        for _ in 0..200 {
+            // Drawing 0 posts right away
+            let random = FirstPostDelay::RandomWithinInterval;
+            if let Some(due) = first_post(started, random, 3600, &mut rng) {
+                let seconds = (due - started).num_seconds();
+                assert!(seconds > 0 && seconds < 3600, "{}", seconds);
+                seen.push(seconds);
+            }
+        }
+        seen.sort();
+        seen.dedup();
+        assert!(seen.len() > 100);
+    }
+
+    #[test]
+    fn min_interval_holds_off_only_within_the_interval() {
+        let now = Utc.ymd(2026, 10, 1).and_hms(12, 0, 0);
+        let interval = Duration::hours(1);
+        let cases: &[(Option<DateTime<Utc>>, Option<Duration>)] = &[
+            (None, None),
+            (Some(now - Duration::minutes(20)), Some(Duration::minutes(40))),
+            (Some(now - interval), None),
+            (Some(now - Duration::days(2)), None),
+        ];
+        for &(last_post, wait) in cases {
+            assert_eq!(min_interval_wait(last_post, interval, now), wait, "{:?}", last_post);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Seeding of the world RNG
+//!
+//! Every random choice the bot makes about an image, its parameters, name, locale and
+//! description, is drawn from the world RNG, which nothing else draws from. Scheduling has an RNG
+//! of its own, so how the schedule is set up never changes what gets rolled.
+//!
+//! With `seed_mode = "daily"`, the world RNG is seeded from the UTC date, so that every deployment
+//! with the same generation settings rolls the same image on the same day. The terrain itself is
+//! up to cubeglobe, which doesn't take a seed, so it still differs. Rolling again after failures,
+//! see `reroll_after_failures`, rolls the same choices until the date changes.
+
+use chrono::{DateTime, Utc};
+use rand::rngs::StdRng;
+use rand::{FromEntropy, SeedableRng};
+use sha2::{Digest, Sha256};
+
+/// How the world RNG is seeded
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SeedMode {
+    /// Afresh for every image
+    Random,
+    /// From the UTC date the image's parameters are rolled on
+    Daily,
+}
+
+impl Default for SeedMode {
+    fn default() -> SeedMode {
+        SeedMode::Random
+    }
+}
+
+impl SeedMode {
+    /// World RNG for an image rolled at `now`
+    pub fn world_rng(self, now: DateTime<Utc>) -> StdRng {
+        match self {
+            SeedMode::Random => StdRng::from_entropy(),
+            SeedMode::Daily => StdRng::from_seed(seed_of(&now.format("%Y-%m-%d").to_string())),
+        }
+    }
+}
+
+/// RNG for a choice about an image made after its parameters are rolled, like its locale
+///
+/// It is seeded from the `name_seed` drawn from the world RNG along with the parameters, so these
+/// choices follow from the world RNG too, however long after rolling they are made. Each
+/// `purpose` gets draws of its own.
+pub fn choice_rng(name_seed: u64, purpose: &str) -> StdRng {
+    StdRng::from_seed(seed_of(&format!("{}:{}", name_seed, purpose)))
+}
+
+fn seed_of(text: &str) -> [u8; 32] {
+    let mut seed = [0; 32];
+    seed.copy_from_slice(&Sha256::digest(text.as_bytes()));
+    seed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use rand::Rng;
+
+    fn draws(mut rng: StdRng) -> Vec<u64> {
+        (0..4).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn daily_seed_follows_the_date() {
+        let morning = Utc.ymd(2026, 10, 1).and_hms(6, 0, 0);
+        let evening = Utc.ymd(2026, 10, 1).and_hms(23, 59, 59);
+        let next_day = Utc.ymd(2026, 10, 2).and_hms(0, 0, 0);
+        let daily = |now| draws(SeedMode::Daily.world_rng(now));
+        assert_eq!(daily(morning), daily(evening));
+        assert_ne!(daily(morning), daily(next_day));
+        assert_ne!(draws(SeedMode::Random.world_rng(morning)), daily(morning));
+    }
+
+    #[test]
+    fn choices_follow_the_name_seed() {
+        assert_eq!(draws(choice_rng(42, "locale")), draws(choice_rng(42, "locale")));
+        assert_ne!(draws(choice_rng(42, "locale")), draws(choice_rng(43, "locale")));
+        assert_ne!(draws(choice_rng(42, "locale")), draws(choice_rng(42, "description")));
+    }
+}
@@ -0,0 +1,277 @@
+//! Installation self-test
+//!
+//! `cubeglobe-bot selftest` runs each part of the bot once, the same way it runs when posting,
+//! and reports which ones work: reading the configs, setting up the renderer, generating and
+//! encoding a small map, optimizing it, reaching the Mastodon accounts, and writing and reading
+//! a state file. Nothing is posted, and the real state file and images are left alone.
+
+use std::env::temp_dir;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+use anyhow::Error;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elefren::MastodonClient;
+use rand::thread_rng;
+
+use posting::{MastodonPoster, Poster};
+use shutdown::Shutdown;
+use {
+    generate_map_sized, mastodon_client, optimize_png, read_config, read_tiles_config, tiles,
+    tiles_path, trial_render, write_surface_as_png, State, StatePaths, TRIAL_MAP_SIZE,
+};
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("selftest")
+        .about("check that every part of the bot works, without posting anything")
+        .arg(
+            Arg::with_name("offline")
+                .long("offline")
+                .help("skip the steps which need the network"),
+        )
+}
+
+/// Tally of the steps run
+#[derive(Default)]
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    /// Run `step`, printing whether it passed and how long it took
+    fn step<T, F>(&mut self, name: &str, step: F) -> Option<T>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let started = Instant::now();
+        let result = step();
+        let ms = started.elapsed().as_millis();
+        match result {
+            Ok(value) => {
+                println!("PASS  {} ({} ms)", name, ms);
+                Some(value)
+            }
+            Err(e) => {
+                println!("FAIL  {} ({} ms): {:#}", name, ms, e);
+                self.failed += 1;
+                None
+            }
+        }
+    }
+
+    fn skip(&self, name: &str, reason: &str) {
+        println!("SKIP  {}: {}", name, reason);
+    }
+}
+
+/// Run the self-test, returning whether every step that was run passed
+///
+/// `matches` are the top-level arguments, for the config file and overrides.
+pub fn run(matches: &ArgMatches, selftest_matches: &ArgMatches, config_path: &Path) -> bool {
+    let offline = selftest_matches.is_present("offline");
+    let mut report = Report::default();
+
+    let config = match report.step("load and check config", || read_config(config_path, matches)) {
+        Some(config) => config,
+        None => {
+            report.skip("everything else", "needs the config");
+            return false;
+        }
+    };
+
+    let tiles_config_path = tiles_path(matches, &config.bot);
+    let renderer = report.step("load tiles config and set up renderer", || {
+        let tiles_config = read_tiles_config(&tiles_config_path)?;
+        let renderer = tiles::load_renderer(&tiles_config_path, &tiles_config)?;
+        trial_render(&config.bot, &renderer)?;
+        Ok(renderer)
+    });
+
+    let png = match renderer {
+        Some(renderer) => report.step("generate and encode a small map", || {
+            let generated = generate_map_sized(&config.bot, TRIAL_MAP_SIZE, &mut thread_rng());
+            let surf = renderer
+                .render_map(&generated.map)
+                .map_err(|e| Error::msg(format!("Problem generating image: {:?}", e)))?;
+            let mut data = Vec::new();
+            write_surface_as_png(&surf, &mut data)?;
+            Ok(data)
+        }),
+        None => {
+            report.skip("generate and encode a small map", "needs the renderer");
+            None
+        }
+    };
+
+    match png {
+        Some(png) => {
+            report.step("optimize the PNG", || {
+                let preset = config.bot.optimize_level.fixed_preset();
+                let optimized = optimize_png(&config.bot, preset, png, &Shutdown::default())?;
+                if optimized.fallback {
+                    return Err(Error::msg("oxipng failed, see above"));
+                }
+                println!(
+                    "      {} bytes down to {}",
+                    optimized.original_size,
+                    optimized.data.len()
+                );
+                Ok(())
+            });
+        }
+        None => report.skip("optimize the PNG", "needs the encoded map"),
+    }
+
+    let fallback = config.credentials_fallback.as_ref();
+    let accounts = Some(("credentials", &config.credentials))
+        .into_iter()
+        .chain(fallback.map(|fallback| ("credentials_fallback", fallback)));
+    for (key, credentials) in accounts {
+        let name = format!("verify {} and fetch instance limits", key);
+        if offline {
+            report.skip(&name, "--offline");
+            continue;
+        }
+        report.step(&name, || {
+            let masto = mastodon_client(credentials.clone());
+            let account = masto.verify_credentials()?;
+            let instance = masto.instance()?;
+            let flavor = config.bot.instance_flavor.resolve(&credentials.base);
+            let limits =
+                MastodonPoster::new(flavor, masto, false, Default::default(), false).limits();
+            println!(
+                "      @{} on {} ({:?}, version {}), status limit {:?}, alt text limit {:?}",
+                account.acct,
+                instance.title,
+                flavor,
+                instance.version,
+                limits.max_chars,
+                limits.max_alt_chars
+            );
+            Ok(())
+        });
+    }
+
+    report.step("write and read a state file", || {
+        let dir = temp_dir().join(format!("cubeglobe-bot-selftest-{}", process::id()));
+        create_dir_all(&dir)?;
+        let paths = StatePaths {
+            state: dir.join("state"),
+            images: dir.join("images"),
+        };
+        let result = round_trip_state(paths);
+        let _ = remove_dir_all(&dir);
+        result
+    });
+
+    if report.failed == 0 {
+        println!("All steps passed");
+    } else {
+        println!("{} step(s) failed", report.failed);
+    }
+    report.failed == 0
+}
+
+/// Persist a state to `paths` and read it back
+fn round_trip_state(paths: StatePaths) -> Result<(), Error> {
+    let mut state = State::default();
+    state.paths = paths.clone();
+    state.id = 42;
+    state.persist()?;
+
+    let read = State::get_state(paths);
+    if read.id != state.id {
+        return Err(Error::msg(format!("wrote id {}, read back {}", state.id, read.id)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, write};
+    use std::path::PathBuf;
+    use tempfile::{tempdir, TempDir};
+
+    /// A config in a temporary directory, using the tiles config at `tiles`
+    fn setup(tiles: &str) -> (TempDir, PathBuf) {
+        let root = tempdir().expect("Unable to create temporary directory");
+        let config_path = root.path().join("config.toml");
+        let config = format!(
+            "[bot]\n\
+             map_size = 16\n\
+             tiles = '{}'\n\
+             images_dir = '{}'\n\
+             state_path = '{}'\n\
+             [credentials]\n\
+             base = 'https://main.example'\n\
+             client_id = 'id'\n\
+             client_secret = 'secret'\n\
+             redirect = 'urn:ietf:wg:oauth:2.0:oob'\n\
+             token = 'token'\n",
+            tiles,
+            root.path().join("images").display(),
+            root.path().join("state").display()
+        );
+        write(&config_path, config).expect("Unable to write config");
+        (root, config_path)
+    }
+
+    /// Run the self-test offline on the config at `config_path`
+    fn run_offline(config_path: &Path) -> bool {
+        let matches = App::new("test").get_matches_from(vec!["test"]);
+        let selftest_matches = subcommand().get_matches_from(vec!["selftest", "--offline"]);
+        run(&matches, &selftest_matches, config_path)
+    }
+
+    #[test]
+    fn passes_offline_with_builtin_tiles() {
+        let (root, config_path) = setup("builtin");
+        assert!(run_offline(&config_path));
+        // The real state file is left alone
+        assert!(!root.path().join("state").exists());
+    }
+
+    #[test]
+    fn fails_without_config() {
+        let root = tempdir().expect("Unable to create temporary directory");
+        assert!(!run_offline(&root.path().join("missing.toml")));
+    }
+
+    #[test]
+    fn fails_with_missing_tiles() {
+        let (root, config_path) = setup("builtin");
+        let missing = root.path().join("missing.conf");
+        let contents = read_to_string(&config_path)
+            .unwrap()
+            .replace("tiles = 'builtin'", &format!("tiles = '{}'", missing.display()));
+        write(&config_path, contents).expect("Unable to write config");
+        assert!(!run_offline(&config_path));
+    }
+
+    #[test]
+    fn report_counts_failures() {
+        let mut report = Report::default();
+        assert_eq!(report.step("passes", || Ok(1)), Some(1));
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.step::<(), _>("fails", || Err(Error::msg("broken"))), None);
+        assert_eq!(report.failed, 1);
+        report.skip("skipped", "needs what failed");
+        assert_eq!(report.failed, 1);
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let root = tempdir().expect("Unable to create temporary directory");
+        let paths = StatePaths {
+            state: root.path().join("state"),
+            images: root.path().join("images"),
+        };
+        round_trip_state(paths).expect("State didn't round-trip");
+        assert!(read_to_string(root.path().join("state"))
+            .unwrap()
+            .contains("id = 42"));
+    }
+}
@@ -0,0 +1,236 @@
+//! Graceful shutdown
+//!
+//! SIGTERM and SIGINT ask the bot to stop. Waits are cut short, and image generation stops at the
+//! next point between stages, removing anything it had written so far. The state is left as it
+//! was before the image was started, so the next run generates it again.
+
+#[cfg(test)]
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often waits check whether shutdown was requested
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Shutdown was requested before the work was done
+#[derive(Error, Debug)]
+#[error("shutdown requested")]
+pub struct Cancelled;
+
+/// How a wait ended, see `Shutdown::sleep_unless`
+#[derive(Debug, PartialEq)]
+pub enum Woken {
+    /// The whole duration was slept
+    Elapsed,
+    /// The wait was cut short by something else than shutdown
+    Interrupted,
+    ShutDown,
+}
+
+/// Whether shutdown was requested, shared with the signal handlers
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    /// Checks to pass before shutdown counts as requested, see `after_checks`
+    #[cfg(test)]
+    checks_left: Option<Arc<AtomicUsize>>,
+}
+
+impl Shutdown {
+    /// Set up SIGTERM and SIGINT to request shutdown. Elsewhere than on Unix, signals keep their
+    /// default behavior, and shutdown is never requested.
+    pub fn register() -> Shutdown {
+        let shutdown = Shutdown::default();
+        register_signals(&shutdown.requested);
+        shutdown
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Shutdown which is requested at the check after `checks` checks, for tests to cancel work
+    /// at each of its stages in turn
+    #[cfg(test)]
+    pub fn after_checks(checks: usize) -> Shutdown {
+        Shutdown {
+            requested: Arc::default(),
+            checks_left: Some(Arc::new(AtomicUsize::new(checks))),
+        }
+    }
+
+    /// Fail if shutdown was requested, for checking between stages of work
+    pub fn check(&self) -> Result<(), Cancelled> {
+        #[cfg(test)]
+        {
+            if let Some(ref left) = self.checks_left {
+                if left.load(Ordering::SeqCst) == 0 {
+                    self.requested.store(true, Ordering::SeqCst);
+                } else {
+                    left.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        }
+        if self.is_requested() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sleep for `duration`, or until shutdown is requested
+    ///
+    /// Returns whether the whole duration was slept.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        self.sleep_unless(duration, || false) == Woken::Elapsed
+    }
+
+    /// Like `sleep`, but also cut short once `interrupted` returns true, which is asked as often
+    /// as shutdown is checked for
+    pub fn sleep_unless<F>(&self, duration: Duration, mut interrupted: F) -> Woken
+    where
+        F: FnMut() -> bool,
+    {
+        let until = Instant::now() + duration;
+        loop {
+            if self.is_requested() {
+                return Woken::ShutDown;
+            }
+            if interrupted() {
+                return Woken::Interrupted;
+            }
+            let now = Instant::now();
+            if now >= until {
+                return Woken::Elapsed;
+            }
+            thread::sleep(POLL_INTERVAL.min(until - now));
+        }
+    }
+
+    /// Run `work` on another thread, and wait for it unless shutdown is requested first
+    ///
+    /// For long steps which can't check for shutdown themselves. On shutdown, the thread is
+    /// abandoned, and ends with the process.
+    pub fn run<T, F>(&self, work: F) -> Result<T, Cancelled>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            // Nobody is listening any more if we were abandoned
+            let _ = sender.send(work());
+        });
+
+        loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(result) => return Ok(result),
+                Err(RecvTimeoutError::Timeout) => self.check()?,
+                Err(RecvTimeoutError::Disconnected) => panic!("worker thread panicked"),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn register_signals(requested: &Arc<AtomicBool>) {
+    use signal_hook;
+
+    for &signal in &[signal_hook::SIGTERM, signal_hook::SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(requested))
+            .expect("Unable to set up shutdown signal handler");
+    }
+}
+
+#[cfg(not(unix))]
+fn register_signals(_requested: &Arc<AtomicBool>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_dir};
+    use std::path::Path;
+    use tempfile;
+    use toml;
+    use {builtin_tiles, create_image, tiles, BotConfig, EventLog, State, StatePaths};
+
+    #[test]
+    fn sleep_ends_as_it_should() {
+        let shutdown = Shutdown::default();
+        let short = Duration::from_millis(10);
+        assert_eq!(shutdown.sleep_unless(short, || false), Woken::Elapsed);
+        assert!(shutdown.sleep(short));
+
+        let mut asked = 0;
+        let long = Duration::from_secs(60);
+        let woken = shutdown.sleep_unless(long, || {
+            asked += 1;
+            asked == 2
+        });
+        assert_eq!(woken, Woken::Interrupted);
+        assert_eq!(asked, 2);
+
+        shutdown.requested.store(true, Ordering::SeqCst);
+        assert_eq!(shutdown.sleep_unless(long, || true), Woken::ShutDown);
+        assert!(!shutdown.sleep(long));
+        assert!(shutdown.check().is_err());
+    }
+
+    /// Files in `dir`
+    fn files_in(dir: &Path) -> Vec<String> {
+        read_dir(dir)
+            .expect("Unable to list directory")
+            .map(|entry| entry.expect("Unable to list directory").file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn cancelling_leaves_no_files_behind() {
+        let tiles_config = builtin_tiles::tiles_config().expect("Unable to draw built-in tiles");
+        let renderer = tiles::load_renderer(Path::new(builtin_tiles::BUILTIN), &tiles_config)
+            .expect("Unable to load built-in tiles");
+
+        for extra in &["", "low_memory = true", "animate = true", "quantize = true"] {
+            let mut finished = false;
+            // Shut down at each check in turn, until there are none left to shut down at
+            for checks in 0..50 {
+                let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+                let paths = StatePaths {
+                    state: dir.path().join("state"),
+                    images: dir.path().join("images"),
+                };
+                create_dir_all(&paths.images).expect("Unable to create images directory");
+                let config: BotConfig = toml::from_str(&format!(
+                    "map_size = 16\nimages_dir = '{}'\n{}",
+                    paths.images.display(),
+                    extra
+                )).expect("Invalid config");
+                let mut state = State::get_state(paths);
+                let params = state.rolled_params(&config);
+
+                let shutdown = Shutdown::after_checks(checks);
+                let events = EventLog::new(false);
+                let created =
+                    create_image(&config, &renderer, &state, &params, &events, &shutdown, 0);
+                let files = files_in(&state.paths.images);
+                match created {
+                    Ok(image) => {
+                        let name = image.filename.file_name().expect("No file name");
+                        assert_eq!(files, vec![name.to_string_lossy().into_owned()], "{}", extra);
+                        finished = true;
+                        break;
+                    }
+                    Err(e) => {
+                        assert!(e.downcast_ref::<Cancelled>().is_some(), "{}: {:#}", extra, e);
+                        assert!(files.is_empty(), "{} after {} checks: {:?}", extra, checks, files);
+                    }
+                }
+            }
+            assert!(finished, "{} never got through", extra);
+        }
+    }
+}
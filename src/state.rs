@@ -0,0 +1,1633 @@
+//! The bot's state, and how it moves from one phase to the next
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read, read_to_string, rename};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+
+use anyhow::{Context, Error};
+use chrono::Duration as ChrDuration;
+use chrono::{DateTime, Utc};
+use elefren::Data as MastoData;
+use elefren::MastodonClient;
+use rand::thread_rng;
+use toml;
+
+use cubeglobe_bot::{MapStats, STATS_PLACEHOLDERS};
+
+use adapt::BackendOverrides;
+use approval::{self, ApprovalFiles, Decision, PendingPost};
+use archive_copy;
+use attempts::AttemptLog;
+use describe::Placement;
+use digest::{self, DigestEntry};
+use engagement::{self, Engagement};
+use errors::{DiskError, PostingError, StateError};
+use events::{Event, EventLog, PollVotes};
+use evolve;
+use hooks::{Hook, HookContext, HooksConfig};
+use integrity::{self, Fingerprint};
+use journal::{self, Intent};
+use locale::{self, LocaleConfig};
+use milestone::MilestoneConfig;
+use names;
+use optimize::{AutoOptimizeState, OptimizeLevel};
+use pin::{self, Pin, PostedStatus};
+use poll::{self, PendingPoll};
+use posting::{
+    first_frame, post_within, Attachment, ImageFormat, Post, PostReceipt, Poster, Progress,
+};
+use queue;
+use remote_media::{self, PendingDownload, RemoteMedia};
+use schedule::{self, OutageAction};
+use seed;
+use shutdown::{Cancelled, Shutdown};
+use thread::{self, ThreadMode, ThreadRoot};
+use {
+    chaos_strikes, create_images_dir, fill_template, get_backoff, log_draft, mastodon_client,
+    render_filename, roll_params, upload_attempt_event, BotConfig, CreatedImage, GenerationParams,
+    CHAOS_MESSAGE, DEFAULT_FILENAME_TEMPLATE, EXIT_FAILED, HEIGHTMAPS_DIR, POST_BODY,
+    QUARANTINE_DIR, STALE_DIR,
+};
+
+/// Current state of the bot
+///
+/// The bot uses this struct, backed by a toml file on disk, to keep track of its state. The bot
+/// first waits for the next posting time, then generates the image, then posts the image, then
+/// waits again. We keep track of the state so that if remote problems cause posting to fail, we
+/// attempt to retry the last image instead of generating a new one.
+#[derive(Deserialize, Serialize)]
+pub struct State {
+    pub last_post: Option<DateTime<Utc>>,
+    pub id: u32,
+    pub phase: Phase,
+
+    /// Name the pending image was saved under in the images directory, once it has been generated
+    ///
+    /// We keep this rather than rendering the filename template again, since the template may
+    /// depend on things like the date, which will have changed by the time we retry. Only the
+    /// name is kept, as it always comes from the template and so is valid UTF-8, unlike the
+    /// images directory, which TOML could not hold.
+    #[serde(default)]
+    pub filename: Option<String>,
+
+    /// Terrain description of the pending image, kept so that retries post the same text
+    #[serde(default)]
+    pub description: Option<String>,
+
+    /// Name of the pending image's landscape
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Times the pending image was generated again before it turned out
+    #[serde(default)]
+    pub regenerations: u32,
+
+    /// Size and hash of the pending image's file, checked before it is posted from disk
+    #[serde(default)]
+    pub fingerprint: Option<Fingerprint>,
+
+    /// Names given to recent images, so they aren't given again, up to `names::HISTORY_LEN`
+    #[serde(default)]
+    pub used_names: Vec<String>,
+
+    /// Parameters the pending image was generated with
+    #[serde(default)]
+    pub params: Option<GenerationParams>,
+
+    /// Parameters rolled for the image being generated, kept through regenerations and restarts
+    /// until it turns out, see `create_image_within_budget`
+    #[serde(default)]
+    pub rolled: Option<GenerationParams>,
+
+    /// Parameters of the last post, which the next one's drift from with `evolution`
+    #[serde(default)]
+    pub world: Option<GenerationParams>,
+
+    /// Statistics of the pending image's map
+    #[serde(default)]
+    pub stats: Option<MapStats>,
+
+    /// File name of the pending image's heightmap in the heightmaps directory, see `heightmap`
+    #[serde(default)]
+    pub heightmap: Option<String>,
+
+    /// Language code of the locale picked for the pending image, see `locales`
+    #[serde(default)]
+    pub locale: Option<String>,
+
+    /// Whether the pending image is a milestone, see `milestones`
+    #[serde(default)]
+    pub milestone: bool,
+
+    /// Note about the outage before the pending image, kept once worked out so that every
+    /// backend and retry posts the same text, see `gap_notice_after_hours`
+    #[serde(default)]
+    pub gap_notice: Option<String>,
+
+    /// Backends the pending image has already been posted to
+    #[serde(default)]
+    pub posted_to: Vec<String>,
+
+    /// Backends which failed to post the pending image in a way retrying won't fix
+    #[serde(default)]
+    pub given_up_on: Vec<String>,
+
+    /// How far posting the pending image got, by backend, so retries can pick up from there
+    #[serde(default)]
+    pub progress: BTreeMap<String, Progress>,
+
+    /// Failed posting attempts and skipped generations since the last successful post
+    #[serde(default)]
+    pub failures: u32,
+
+    /// Attempts at posting the pending image, see `attempts`
+    #[serde(default)]
+    pub attempts: AttemptLog,
+
+    /// When the first of `failures` happened
+    #[serde(default)]
+    pub first_failure: Option<DateTime<Utc>>,
+
+    /// When the pending image was generated
+    #[serde(default)]
+    pub generated_at: Option<DateTime<Utc>>,
+
+    /// Image in `queue_dir` the pending image is being taken from, until it has been moved into
+    /// the images directory
+    #[serde(default)]
+    pub queue_head: Option<String>,
+
+    /// When approval for the pending image was asked for, see `approval_required`
+    #[serde(default)]
+    pub approval_requested: Option<DateTime<Utc>>,
+
+    /// When a slot was last dropped during an outage, see `on_prolonged_outage`. The schedule
+    /// counts from here if it's later than the last post.
+    #[serde(default)]
+    pub slot_skipped: Option<DateTime<Utc>>,
+
+    /// What the schedule counts from instead of the last post, when that was asked for outside
+    /// of it, see `trigger_keeps_schedule`
+    #[serde(default)]
+    pub schedule_anchor: Option<DateTime<Utc>>,
+
+    /// When the first post is due, when there's none yet, see `first_post_delay`
+    #[serde(default)]
+    pub first_post_at: Option<DateTime<Utc>>,
+
+    /// Unlisted status still to be boosted, see `boost_after_minutes`
+    #[serde(default)]
+    pub pending_boost: Option<PendingBoost>,
+
+    /// Statuses which could still be picked as the best of the month, see `pin_best`
+    #[serde(default)]
+    pub recent_posts: Vec<PostedStatus>,
+
+    /// Currently pinned best of the month
+    #[serde(default)]
+    pub pin: Option<Pin>,
+
+    /// Images which could still go into the weekly digest, see `digest`
+    #[serde(default)]
+    pub digest_entries: Vec<DigestEntry>,
+
+    /// Last week, as `YYYY-Www`, a digest was posted for, or attempted to be
+    #[serde(default)]
+    pub digest_week: Option<String>,
+
+    /// Last month, as `YYYY-MM`, a best of the month was picked for, or attempted to be
+    #[serde(default)]
+    pub pin_checked: Option<String>,
+
+    /// Root status of the current month's thread, see `thread_mode`
+    #[serde(default)]
+    pub thread_root: Option<ThreadRoot>,
+
+    /// Optimizer measurements and the preset picked from them, see `optimize_level`
+    #[serde(default)]
+    pub auto_optimize: AutoOptimizeState,
+
+    /// The instance's versions of posted images still to be downloaded, see `match_remote`
+    #[serde(default)]
+    pub remote_downloads: Vec<PendingDownload>,
+
+    /// Posted polls whose results are still to be logged, see `poll`
+    #[serde(default)]
+    pub pending_polls: Vec<PendingPoll>,
+
+    /// Recent posts' engagement and the interval adapted from it, see `adaptive`
+    #[serde(default)]
+    pub engagement: Engagement,
+
+    #[serde(skip)]
+    pub paths: StatePaths,
+}
+
+/// Where the state file and images live, as configured
+#[derive(Clone, Default)]
+pub struct StatePaths {
+    pub state: PathBuf,
+    pub images: PathBuf,
+}
+
+impl StatePaths {
+    pub fn from_config(config: &BotConfig) -> StatePaths {
+        StatePaths {
+            state: config.state_path(),
+            images: config.images_dir.clone(),
+        }
+    }
+}
+
+/// A status posted as unlisted, to be boosted when `due`
+#[derive(Deserialize, Serialize)]
+pub struct PendingBoost {
+    pub status_id: String,
+    pub due: DateTime<Utc>,
+    /// Posted from the fallback account, which then has to boost it too
+    #[serde(default)]
+    pub fallback: bool,
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub enum Phase {
+    Awaiting,
+    /// Generated, but not to be posted until approved
+    AwaitingApproval,
+    Generated,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State {
+            last_post: None,
+            id: 1,
+            phase: Phase::Awaiting,
+            filename: None,
+            description: None,
+            name: None,
+            regenerations: 0,
+            fingerprint: None,
+            params: None,
+            rolled: None,
+            world: None,
+            stats: None,
+            heightmap: None,
+            locale: None,
+            milestone: false,
+            gap_notice: None,
+            posted_to: Vec::new(),
+            given_up_on: Vec::new(),
+            progress: BTreeMap::new(),
+            failures: 0,
+            attempts: AttemptLog::default(),
+            first_failure: None,
+            generated_at: None,
+            queue_head: None,
+            approval_requested: None,
+            slot_skipped: None,
+            schedule_anchor: None,
+            first_post_at: None,
+            pending_boost: None,
+            used_names: Vec::new(),
+            recent_posts: Vec::new(),
+            pin: None,
+            pin_checked: None,
+            thread_root: None,
+            auto_optimize: AutoOptimizeState::default(),
+            remote_downloads: Vec::new(),
+            pending_polls: Vec::new(),
+            engagement: Engagement::default(),
+            digest_entries: Vec::new(),
+            digest_week: None,
+            paths: StatePaths::default(),
+        }
+    }
+}
+
+impl State {
+    /// Read state from file or otherwise get a new one with defaults
+    pub fn get_state(paths: StatePaths) -> State {
+        let mut state = read_to_string(&paths.state)
+            .ok()
+            .and_then(|ref s| toml::from_str::<State>(s).ok())
+            .unwrap_or_default();
+        state.paths = paths;
+        state
+    }
+
+    /// Save current state to file
+    ///
+    /// Scratch states, which have no file, like the one `generate` works with, aren't saved.
+    pub fn persist(&self) -> Result<(), Error> {
+        if self.paths.state.as_os_str().is_empty() {
+            return Ok(());
+        }
+        // Going through `Value` puts nested tables last, wherever they are in the struct
+        let serialized = toml::to_string(&toml::Value::try_from(self)?)?;
+        journal::write_atomically(&self.paths.state, serialized.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Get the full filepath for where to save the current image file
+    ///
+    /// If the image has already been generated, this is the path it was saved under. Otherwise,
+    /// the filename is rendered from `template`.
+    pub fn get_filename(&self, template: &str, format: ImageFormat) -> Result<PathBuf, DiskError> {
+        if let Some(ref filename) = self.filename {
+            return Ok(self.paths.images.join(filename));
+        }
+
+        create_images_dir(&self.paths.images)?;
+        Ok(self
+            .paths
+            .images
+            .join(format!(
+                "{}.{}",
+                render_filename(template, self.id, Utc::now()),
+                format.extension()
+            )))
+    }
+
+    pub fn get_saved_image(&self) -> Result<Vec<u8>, Error> {
+        if let Phase::Awaiting = self.phase {
+            return Err(StateError(format!(
+                "asked to load the pending image of id {}, but it has not been generated yet",
+                self.id
+            )).into());
+        }
+
+        // State files from before filenames were stored can only have used the default
+        let path = self.get_filename(DEFAULT_FILENAME_TEMPLATE, ImageFormat::Png)?;
+        read(&path).with_context(|| format!("unable to read pending image {}", path.display()))
+    }
+
+    /// Check the pending image's data, as read from disk, see `integrity::check`
+    pub fn check_saved_image(&self, data: &[u8]) -> Result<(), String> {
+        integrity::check(data, self.image_format(), self.fingerprint.as_ref())
+    }
+
+    /// oxipng presets to run on the next image, see `optimize_level`
+    pub fn optimize_presets(&self, config: &BotConfig) -> Vec<u8> {
+        match config.optimize_level {
+            OptimizeLevel::Preset(preset) => vec![preset],
+            OptimizeLevel::Auto(_) => {
+                self.auto_optimize.presets(&config.optimize_auto, Utc::now())
+            }
+        }
+    }
+
+    /// Format of the pending image, going by the name it was saved under
+    pub fn image_format(&self) -> ImageFormat {
+        self.filename
+            .as_ref()
+            .and_then(|filename| ImageFormat::from_path(Path::new(filename)))
+            .unwrap_or(ImageFormat::Png)
+    }
+
+    /// Make the updates that follow posting the pending image, and persist the state
+    ///
+    /// They go through the journal, see the `journal` module.
+    pub fn complete_post(self, config: &BotConfig) -> State {
+        // Scheduled from the instance's time of posting, where there is one
+        let receipt = self.receipt();
+        let intent = Intent {
+            id: self.id,
+            filename: self.filename.clone(),
+            status_id: receipt.map(|receipt| receipt.status_id.clone()),
+            posted: receipt.map_or_else(Utc::now, |receipt| receipt.created_at),
+            remote_media: self
+                .progress
+                .get("mastodon")
+                .and_then(|progress| progress.remote_media.clone()),
+        };
+
+        let journaled = !self.paths.state.as_os_str().is_empty();
+        if journaled {
+            if let Err(e) = journal::begin(&self.paths.state, &intent) {
+                eprintln!("WARNING: Going on without a journal: {:#}", e);
+            }
+        }
+        let state = self.apply_intent(config, &intent);
+        if journaled {
+            if let Err(e) = journal::finish(&state.paths.state) {
+                eprintln!("WARNING: {:#}", e);
+            }
+        }
+        state
+    }
+
+    /// Make whatever updates for `intent` aren't made yet
+    ///
+    /// The state file is updated last, so once it has moved past the image, everything is done.
+    pub fn apply_intent(mut self, config: &BotConfig, intent: &Intent) -> State {
+        if self.id != intent.id {
+            return self;
+        }
+
+        self.record_remote_media(config, intent.remote_media.as_ref());
+        if let (false, &Some(ref filename)) = (self.attempts.is_empty(), &self.filename) {
+            self.attempts.write(&self.paths.images, filename);
+        }
+        let state = self.posted(config, intent.posted);
+        state.persist().expect("Unable to persist state");
+        state
+    }
+
+    /// Finish the updates after a post that the last run was interrupted in, if there are any
+    pub fn replay_journal(self, config: &BotConfig) -> State {
+        let intent = match journal::pending(&self.paths.state) {
+            Ok(Some(intent)) => intent,
+            Ok(None) => return self,
+            Err(e) => {
+                eprintln!("WARNING: Ignoring the journal: {:#}", e);
+                let _ = journal::finish(&self.paths.state);
+                return self;
+            }
+        };
+
+        eprintln!(
+            "Finishing the updates after posting image {} (status {}), which were interrupted",
+            intent.id,
+            intent.status_id.as_ref().map_or("unknown", String::as_str)
+        );
+        let state = self.apply_intent(config, &intent);
+        if let Err(e) = journal::finish(&state.paths.state) {
+            eprintln!("WARNING: {:#}", e);
+        }
+        state
+    }
+
+    /// Update state to indicate posting was successful, at `now`
+    ///
+    /// If the status is to be boosted later, that is scheduled here.
+    pub fn posted(self, config: &BotConfig, now: DateTime<Utc>) -> State {
+        let public = self.milestone(config).map_or(false, |milestone| milestone.public);
+        // Taken while the state is still whole
+        let url = self.receipt().and_then(PostReceipt::link).map(str::to_string);
+        let boost = config.boost_after_minutes.filter(|_| !public).and_then(|minutes| {
+            let progress = self.progress.get("mastodon")?;
+            Some(PendingBoost {
+                status_id: progress.receipt.as_ref()?.status_id.clone(),
+                due: now + ChrDuration::minutes(minutes),
+                fallback: progress.fallback,
+                attempts: 0,
+            })
+        });
+        if let (Some(_), Some(old)) = (&boost, &self.pending_boost) {
+            eprintln!("Status {} was never boosted, giving up on it", old.status_id);
+        }
+
+        // Only the main account's posts can be pinned to its profile
+        let mut recent_posts = self.recent_posts;
+        if config.pin_best.is_some() {
+            let status_id = self
+                .progress
+                .get("mastodon")
+                .filter(|progress| !progress.fallback)
+                .and_then(|progress| progress.receipt.as_ref())
+                .map(|receipt| receipt.status_id.clone());
+            if let Some(status_id) = status_id {
+                recent_posts.push(PostedStatus {
+                    status_id,
+                    posted: now,
+                });
+            }
+            pin::prune(&mut recent_posts, now);
+        }
+
+        let mut digest_entries = self.digest_entries;
+        if config.digest.is_some() {
+            if let Some(filename) = self.filename {
+                digest_entries.push(DigestEntry {
+                    filename,
+                    url,
+                    posted: now,
+                });
+            }
+            digest::prune(&mut digest_entries, now);
+        }
+
+        let mut pending_polls = self.pending_polls;
+        if let Some(ref poll) = config.poll {
+            let posted = self.progress.get("mastodon").and_then(|progress| {
+                let posted = progress.receipt.as_ref()?.poll.as_ref()?;
+                Some(PendingPoll::new(self.id, posted, poll, now, progress.fallback))
+            });
+            pending_polls.extend(posted);
+        }
+
+        let mut engagement = self.engagement;
+        if let Some(ref adaptive) = config.adaptive {
+            let status_id = self
+                .progress
+                .get("mastodon")
+                .filter(|progress| !progress.fallback)
+                .and_then(|progress| progress.receipt.as_ref())
+                .map(|receipt| receipt.status_id.clone());
+            if let Some(status_id) = status_id {
+                engagement.record(status_id, now, adaptive.posts);
+            }
+        }
+
+        // Everything about the posted image is forgotten, and the rest kept
+        State {
+            last_post: Some(now),
+            id: self.id + 1,
+            world: self.params.or(self.world),
+            pending_boost: boost.or(self.pending_boost),
+            used_names: self.used_names,
+            recent_posts,
+            pin: self.pin,
+            pin_checked: self.pin_checked,
+            digest_entries,
+            digest_week: self.digest_week,
+            thread_root: self.thread_root,
+            auto_optimize: self.auto_optimize,
+            remote_downloads: self.remote_downloads,
+            pending_polls,
+            engagement,
+            paths: self.paths,
+            ..State::default()
+        }
+    }
+
+    /// Pin the best post of last month, if it's time to
+    ///
+    /// Failures are only logged. The month counts as done either way, so that an instance which
+    /// keeps failing isn't asked again every cycle.
+    pub fn pin_best_if_due(
+        &mut self,
+        config: &BotConfig,
+        account: &MastoData,
+        shutdown: &Shutdown,
+    ) {
+        let pin_config = match config.pin_best {
+            Some(ref pin_config) => pin_config,
+            None => return,
+        };
+        let now = Utc::now();
+        if !pin::is_due(pin_config, self.pin_checked.as_ref().map(String::as_str), now) {
+            return;
+        }
+
+        let month = pin::previous_month(now);
+        eprintln!("Picking the best post of {}...", month);
+        let masto = mastodon_client(account.clone());
+        let picked = pin::pin_best(
+            pin_config,
+            &masto,
+            &self.recent_posts,
+            self.pin.as_ref(),
+            now,
+            shutdown,
+        );
+        match picked {
+            Ok(Some(pin)) => self.pin = Some(pin),
+            Ok(None) => eprintln!("Nothing was posted in {}, leaving the pin as it is", month),
+            // Not done, so this is tried again after restarting
+            Err(ref e) if e.downcast_ref::<Cancelled>().is_some() => return,
+            Err(e) => eprintln!("Unable to pin the best post of {}: {}", month, e),
+        }
+
+        self.pin_checked = Some(month);
+        pin::prune(&mut self.recent_posts, now);
+        self.persist().expect("Unable to persist state");
+    }
+
+    /// Post the weekly digest, if it's time to
+    ///
+    /// Like `pin_best_if_due`, failures are only logged, and the week counts as done either way.
+    pub fn post_digest_if_due(
+        &mut self,
+        config: &BotConfig,
+        account: &MastoData,
+        shutdown: &Shutdown,
+    ) {
+        let digest_config = match config.digest {
+            Some(ref digest_config) => digest_config,
+            None => return,
+        };
+        let now = Utc::now();
+        if !digest::is_due(digest_config, self.digest_week.as_ref().map(String::as_str), now) {
+            return;
+        }
+
+        let week = digest::week_of(now);
+        eprintln!("Posting the digest of {}...", week);
+        let masto = mastodon_client(account.clone());
+        let images_dir = self.paths.images.clone();
+        let entries = self.digest_entries.clone();
+        let digest_config = digest_config.clone();
+        let posted = shutdown.run(move || {
+            digest::post_digest(&digest_config, &masto, &images_dir, &entries, now)
+        });
+        match posted {
+            Ok(Ok(0)) => eprintln!("No images to put in the digest of {}", week),
+            Ok(Ok(count)) => eprintln!("Posted the digest of {} with {} images", week, count),
+            Ok(Err(e)) => eprintln!("Unable to post the digest of {}: {}", week, e),
+            // Not done, so this is tried again after restarting
+            Err(Cancelled) => return,
+        }
+
+        self.digest_week = Some(week);
+        digest::prune(&mut self.digest_entries, now);
+        self.persist().expect("Unable to persist state");
+    }
+
+    /// Record what the instance made of the just posted image, see the `remote_media` module
+    pub fn record_remote_media(&mut self, config: &BotConfig, remote: Option<&RemoteMedia>) {
+        let (remote, filename) = match (remote, &self.filename) {
+            (Some(remote), &Some(ref filename)) => (remote.clone(), filename.clone()),
+            _ => return,
+        };
+        let image = match read(self.paths.images.join(&filename)) {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("WARNING: Unable to read {} to record it: {}", filename, e);
+                return;
+            }
+        };
+
+        let format = self.image_format();
+        remote_media::record(&self.paths.images, &filename, &image, format, &remote);
+        if config.match_remote {
+            self.remote_downloads.push(PendingDownload {
+                filename,
+                url: remote.url,
+                attempts: 0,
+            });
+            remote_media::download_pending(&self.paths.images, &mut self.remote_downloads);
+        }
+    }
+
+    /// Retry downloads of the instance's versions of posted images which failed before
+    pub fn download_remote_media(&mut self) {
+        if remote_media::download_pending(&self.paths.images, &mut self.remote_downloads) {
+            self.persist().expect("Unable to persist state");
+        }
+    }
+
+    /// Log the results of posted polls which have closed, see `poll`
+    ///
+    /// `fallback` is the fallback account, whose polls are looked up with it.
+    pub fn log_poll_results(
+        &mut self,
+        account: &MastoData,
+        fallback: Option<&MastoData>,
+        events: &EventLog,
+    ) {
+        let now = Utc::now();
+        if !self.pending_polls.iter().any(|poll| poll.closes <= now) {
+            return;
+        }
+
+        let polls: Vec<PendingPoll> = self.pending_polls.drain(..).collect();
+        for mut poll in polls {
+            if poll.closes > now {
+                self.pending_polls.push(poll);
+                continue;
+            }
+            let account = if poll.fallback {
+                fallback.unwrap_or(account)
+            } else {
+                account
+            };
+            match poll::fetch(account, &poll.poll_id) {
+                Ok(results) => {
+                    eprintln!("Poll on image {} closed: {}", poll.id, results.summary());
+                    events.emit(Event::PollClosed {
+                        id: poll.id,
+                        poll_id: poll.poll_id,
+                        votes: results.votes_count,
+                        options: results
+                            .options
+                            .into_iter()
+                            .map(|option| PollVotes {
+                                title: option.title,
+                                votes: option.votes_count,
+                            })
+                            .collect(),
+                    });
+                }
+                Err(e) => {
+                    if poll.failed() {
+                        eprintln!("Unable to look up poll on image {}: {}", poll.id, e);
+                        self.pending_polls.push(poll);
+                    } else {
+                        eprintln!(
+                            "Unable to look up poll on image {} {} times, giving up: {}",
+                            poll.id, poll.attempts, e
+                        );
+                    }
+                }
+            }
+        }
+
+        self.persist().expect("Unable to persist state");
+    }
+
+    /// Boost the pending unlisted status, scheduling another attempt if that fails
+    ///
+    /// `fallback` is the fallback account, which boosts statuses it posted itself.
+    pub fn boost(&mut self, config: &BotConfig, account: &MastoData, fallback: Option<&MastoData>) {
+        let mut boost = match self.pending_boost.take() {
+            Some(boost) => boost,
+            None => return,
+        };
+        let account = if boost.fallback {
+            fallback.unwrap_or(account)
+        } else {
+            account
+        };
+
+        match mastodon_client(account.clone()).reblog(&boost.status_id) {
+            Ok(_) => eprintln!("Boosted status {}", boost.status_id),
+            Err(e) => {
+                boost.attempts += 1;
+                if boost.attempts >= config.boost_attempts {
+                    eprintln!(
+                        "Failed to boost status {} {} times, giving up: {}",
+                        boost.status_id, boost.attempts, e
+                    );
+                } else {
+                    let backoff = get_backoff(boost.attempts as usize);
+                    eprintln!(
+                        "Failed to boost status {}, retrying after {} seconds: {}",
+                        boost.status_id, backoff, e
+                    );
+                    boost.due = Utc::now() + ChrDuration::seconds(backoff as i64);
+                    self.pending_boost = Some(boost);
+                }
+            }
+        }
+
+        self.persist().expect("Unable to persist state");
+    }
+
+    /// Update state to indicate `image` was generated and saved, but not yet posted
+    ///
+    /// This is when the locale for the image is picked, and when it's decided whether it is a
+    /// milestone.
+    pub fn generated(self, image: &CreatedImage, config: &BotConfig) -> State {
+        let locale = config
+            .locales
+            .as_ref()
+            .and_then(|locales| {
+                locale::choose(locales, &mut seed::choice_rng(image.params.name_seed, "locale"))
+            })
+            .map(|locale| locale.language.clone());
+        let milestone = config
+            .milestones
+            .as_ref()
+            .map_or(false, |milestones| milestones.is_milestone(self.id));
+        if milestone {
+            eprintln!("Image {} is a milestone", self.id);
+        }
+
+        let mut auto_optimize = self.auto_optimize;
+        if !image.optimizer_trial.is_empty() {
+            auto_optimize.record(
+                &config.optimize_auto,
+                Utc::now(),
+                image.optimizer_trial.clone(),
+            );
+        }
+
+        let mut used_names = self.used_names;
+        used_names.push(image.name.clone());
+        if used_names.len() > names::HISTORY_LEN {
+            let excess = used_names.len() - names::HISTORY_LEN;
+            used_names.drain(..excess);
+        }
+
+        State {
+            phase: Phase::Generated,
+            filename: image
+                .filename
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+            description: image.description.clone(),
+            name: Some(image.name.clone()),
+            regenerations: image.regenerations,
+            fingerprint: Some(Fingerprint::of(&image.data)),
+            auto_optimize,
+            used_names,
+            params: Some(image.params.clone()),
+            rolled: None,
+            stats: Some(image.stats.clone()),
+            heightmap: image.heightmap.clone(),
+            locale,
+            milestone,
+            generated_at: Some(Utc::now()),
+            ..self
+        }
+    }
+
+    /// Move the image being taken from the queue into the images directory, if there is one
+    pub fn finish_taking_from_queue(&mut self, config: &BotConfig) -> Result<(), Error> {
+        let (image, filename) = match (&self.queue_head, &self.filename) {
+            (&Some(ref image), &Some(ref filename)) => (image, filename),
+            _ => return Ok(()),
+        };
+        let queue_dir = config.queue_dir.as_ref().ok_or_else(|| {
+            Error::msg(format!(
+                "{} is being taken from the queue, but queue_dir is no longer set",
+                image
+            ))
+        })?;
+
+        if let Some(heightmap) = self.heightmap.clone() {
+            let target = self.heightmap_path(&heightmap);
+            if let Err(e) = queue::take_heightmap(queue_dir, image, &target) {
+                eprintln!("WARNING: Unable to take the heightmap of {}: {:#}", image, e);
+                self.heightmap = None;
+            }
+        }
+        queue::take(queue_dir, image, &self.paths.images.join(filename))?;
+        self.queue_head = None;
+        self.persist()
+    }
+
+    /// Locale picked for the pending image
+    ///
+    /// `None` if none was picked, or if it was since removed from the config, in which case the
+    /// bot-wide text is used.
+    pub fn locale<'a>(&self, config: &'a BotConfig) -> Option<&'a LocaleConfig> {
+        let language = self.locale.as_ref()?;
+        config
+            .locales
+            .as_ref()?
+            .iter()
+            .find(|locale| &locale.language == language)
+    }
+
+    /// Milestone settings which apply to the pending image, if it is one
+    pub fn milestone<'a>(&self, config: &'a BotConfig) -> Option<&'a MilestoneConfig> {
+        config.milestones.as_ref().filter(|_| self.milestone)
+    }
+
+    /// Text of the status for the pending image
+    pub fn post_body(&self, config: &BotConfig) -> String {
+        self.post_body_for(config, None)
+    }
+
+    /// Text of the status for the pending image, as changed for a backend by `overrides`
+    pub fn post_body_for(
+        &self,
+        config: &BotConfig,
+        overrides: Option<&BackendOverrides>,
+    ) -> String {
+        let locale = self.locale(config);
+        let template = self
+            .milestone(config)
+            .and_then(|milestone| milestone.body.as_ref())
+            .or_else(|| overrides.and_then(|overrides| overrides.body.as_ref()))
+            .or_else(|| locale.and_then(|locale| locale.body.as_ref()))
+            .or_else(|| config.body.as_ref());
+        let body = match (template, &self.description) {
+            (Some(template), _) => self.fill_text(template, config),
+            (None, &Some(ref description))
+                if config.description.placement == Placement::Replace =>
+            {
+                description.clone()
+            }
+            (None, &Some(ref description)) => format!("{} {}", POST_BODY, description),
+            (None, &None) => POST_BODY.to_string(),
+        };
+        let hashtags = match overrides.and_then(|overrides| overrides.hashtags.as_ref()) {
+            Some(hashtags) => locale::hashtag_line(hashtags),
+            None => locale.and_then(LocaleConfig::hashtag_line),
+        };
+        let body = match hashtags {
+            Some(hashtags) => format!("{}\n\n{}", body, hashtags),
+            None => body,
+        };
+
+        match self.gap_notice {
+            Some(ref notice) => format!("{}\n\n{}", body, notice),
+            None => body,
+        }
+    }
+
+    /// Work out the note about an outage for the pending image, before an attempt at posting it
+    ///
+    /// A note once worked out is kept, with the outage's length as it was then, and once the
+    /// image went out anywhere it's too late for one, so that every backend gets the same text.
+    pub fn note_gap(&mut self, config: &BotConfig) {
+        if self.gap_notice.is_none() && self.posted_to.is_empty() {
+            self.gap_notice = self.outage_notice(config);
+        }
+    }
+
+    /// Note about an outage, if failures kept us from posting for longer than configured
+    ///
+    /// Only time past when the post was due counts, so a long `sleep_time` alone does not cause
+    /// a notice.
+    pub fn outage_notice(&self, config: &BotConfig) -> Option<String> {
+        let after_hours = config.gap_notice_after_hours?;
+        let last_post = self.last_post?;
+        if self.failures == 0 {
+            return None;
+        }
+
+        let sleep_time = self.sleep_time(config);
+        let latest = sleep_time + config.jitter.late(sleep_time);
+        let outage = Utc::now() - last_post - ChrDuration::seconds(latest);
+        if (outage.num_seconds() as f64) < after_hours * 3600.0 {
+            return None;
+        }
+
+        Some(fill_template(&config.gap_notice, |name| match name {
+            "hours" => Some(outage.num_hours().to_string()),
+            _ => None,
+        }))
+    }
+
+    /// Parameters for the image to generate next, rolled and saved if there are none yet
+    ///
+    /// With `evolution`, they drift from the last post's. They are drawn from the world RNG, see
+    /// the `seed` module.
+    pub fn rolled_params(&mut self, config: &BotConfig) -> GenerationParams {
+        if let Some(ref params) = self.rolled {
+            return params.clone();
+        }
+
+        let mut world_rng = config.seed_mode.world_rng(Utc::now());
+        let mut params = roll_params(config, config.map_size_for(self.id), &mut world_rng);
+        if let (true, Some(world)) = (config.evolution, self.world.as_ref()) {
+            evolve::evolve(
+                &mut params,
+                world,
+                config.frequency.as_ref(),
+                config.max_water_level.as_ref(),
+                &config.drift,
+                &mut world_rng,
+            );
+        }
+        params.make_consistent();
+        self.rolled = Some(params.clone());
+        self.persist().expect("Unable to persist state");
+        params
+    }
+
+    /// Count a failed attempt towards the current outage
+    pub fn failed(&mut self) {
+        self.failures += 1;
+        if self.first_failure.is_none() {
+            self.first_failure = Some(Utc::now());
+        }
+        self.persist().expect("Unable to persist state");
+    }
+
+    /// Add the attempt at posting which started at `started`, `timer` ago, to the attempt log
+    pub fn attempted(
+        &mut self,
+        started: DateTime<Utc>,
+        timer: Instant,
+        posted: &Result<(), String>,
+    ) {
+        let duration_ms = timer.elapsed().as_millis() as u64;
+        let error = posted.as_ref().err().map(String::as_str);
+        self.attempts.record(started, duration_ms, error);
+    }
+
+    /// Give up on the current slot, so the next post is scheduled a full `sleep_time` from now
+    pub fn skip_slot(&mut self) {
+        self.slot_skipped = Some(Utc::now());
+        self.failed();
+    }
+
+    /// Seconds between posts, as adapted to engagement if `adaptive` is on
+    pub fn sleep_time(&self, config: &BotConfig) -> i64 {
+        match (config.adaptive(), self.engagement.sleep_time) {
+            (Some(adaptive), Some(sleep_time)) => sleep_time
+                .max(adaptive.min_sleep_time)
+                .min(adaptive.max_sleep_time),
+            _ => config.sleep_time,
+        }
+    }
+
+    /// Look up how the last posts did, adapt the interval between posts to it, and log the
+    /// interval, see `adaptive`
+    pub fn adapt_sleep_time(&mut self, config: &BotConfig, account: &MastoData, events: &EventLog) {
+        let adaptive = match config.adaptive() {
+            Some(adaptive) => adaptive,
+            None => return,
+        };
+        let current = self.sleep_time(config);
+        // Only a new count changes anything, so restarts don't move the interval by themselves
+        if self.engagement.look_up(adaptive, account, Utc::now()) > 0 {
+            let average = self.engagement.average();
+            let adapted = engagement::adapt(current, average.map(|(average, _)| average), adaptive);
+            self.engagement.sleep_time = Some(adapted);
+            if adapted != current {
+                events.emit(Event::IntervalAdapted {
+                    sleep_time: adapted,
+                    previous: current,
+                    average: average.map(|(average, _)| average),
+                });
+            }
+        }
+        self.persist().expect("Unable to persist state");
+
+        let sleep_time = self.sleep_time(config);
+        match self.engagement.average() {
+            Some((average, posts)) => eprintln!(
+                "Posting every {} seconds, from {:.1} favourites and boosts on average over the \
+                 last {} posts",
+                sleep_time, average, posts
+            ),
+            None => eprintln!(
+                "Posting every {} seconds, until there's engagement to adapt to",
+                sleep_time
+            ),
+        }
+    }
+
+    /// When the first post is due, if `first_post_delay` puts it off
+    ///
+    /// Only for a state with nothing to schedule from. The time is picked once and kept, so
+    /// restarts don't move it.
+    pub fn first_post_at(&mut self, config: &BotConfig) -> Option<DateTime<Utc>> {
+        if config.first_post_delay.is_immediate() {
+            return None;
+        }
+        if self.first_post_at.is_none() {
+            let delay = config.first_post_delay;
+            let sleep_time = self.sleep_time(config);
+            let due = schedule::first_post(Utc::now(), delay, sleep_time, &mut thread_rng())?;
+            self.first_post_at = Some(due);
+            self.persist().expect("Unable to persist state");
+        }
+        self.first_post_at
+    }
+
+    /// What the next post is scheduled from, if anything
+    pub fn schedule_base(&self) -> Option<DateTime<Utc>> {
+        match (self.schedule_anchor.or(self.last_post), self.slot_skipped) {
+            (Some(base), Some(skipped)) => Some(base.max(skipped)),
+            (base, skipped) => base.or(skipped),
+        }
+    }
+
+    /// Apply `on_prolonged_outage` after a failed attempt at posting
+    ///
+    /// Returns whether the pending image was dropped, leaving the state awaiting a new one.
+    /// Images already posted to some of the backends are always kept, as a new one would be
+    /// posted there a second time.
+    pub fn handle_outage(&mut self, config: &BotConfig) -> bool {
+        let policy = match config.on_prolonged_outage {
+            Some(ref policy) => policy,
+            None => return false,
+        };
+        let now = Utc::now();
+        let threshold = ChrDuration::seconds((policy.after_hours * 3600.0) as i64);
+        let outage = match self.first_failure {
+            Some(first_failure) => now - first_failure,
+            None => return false,
+        };
+        if policy.action == OutageAction::Keep || outage < threshold || !self.posted_to.is_empty()
+        {
+            return false;
+        }
+        if policy.action == OutageAction::Regenerate
+            && self.generated_at.map_or(false, |at| now - at < threshold)
+        {
+            return false;
+        }
+
+        match policy.action {
+            OutageAction::SkipSlot => {
+                eprintln!(
+                    "Failing to post for {} hours, dropping this post",
+                    outage.num_hours()
+                );
+                self.slot_skipped = Some(now);
+            }
+            _ => eprintln!(
+                "Failing to post for {} hours, replacing the pending image with a fresh one",
+                outage.num_hours()
+            ),
+        }
+        self.discard_pending();
+        self.persist().expect("Unable to persist state");
+        true
+    }
+
+    /// Deal with the pending image before `--immediate` posts, returning its data if it is to be
+    /// posted
+    ///
+    /// A generated image is posted, unless it's damaged, in which case it's quarantined and
+    /// generated again. With `force_new`, it is moved to the stale images instead, so a new one
+    /// is generated. An image awaiting approval isn't posted without it, so that exits, unless
+    /// `force_new` sets it aside too.
+    pub fn take_up_pending(&mut self, force_new: bool) -> Option<Vec<u8>> {
+        match self.phase {
+            Phase::Awaiting => return None,
+            Phase::Generated if !force_new => {
+                let data = self
+                    .get_saved_image()
+                    .unwrap_or_else(|e| panic!("Problem loading the pending image: {:#}", e));
+                match self.check_saved_image(&data) {
+                    Ok(()) => return Some(data),
+                    Err(problem) => {
+                        eprintln!("Pending image is damaged, generating it again: {}", problem);
+                        self.quarantine_pending();
+                    }
+                }
+            }
+            Phase::AwaitingApproval if !force_new => {
+                eprintln!(
+                    "Image {} is awaiting approval. Approve or reject it, or pass --force-new to \
+                     set it aside and post a new one.",
+                    self.id
+                );
+                exit(EXIT_FAILED);
+            }
+            Phase::AwaitingApproval | Phase::Generated => {
+                eprintln!("Setting pending image {} aside for a new one", self.id);
+                if let Some(ref filename) = self.filename {
+                    ApprovalFiles::new(&self.paths.images, filename).clean_up();
+                }
+                self.discard_pending();
+            }
+        }
+        self.persist().expect("Unable to persist state");
+        None
+    }
+
+    /// Move the pending image out of the way and forget it
+    ///
+    /// The id is not reused, so the moved file keeps a name of its own.
+    pub fn discard_pending(&mut self) {
+        self.set_aside_pending(STALE_DIR);
+        self.id += 1;
+    }
+
+    /// Move the damaged pending image to quarantine and forget it, so it's generated again
+    ///
+    /// The new image keeps the id.
+    pub fn quarantine_pending(&mut self) {
+        self.set_aside_pending(QUARANTINE_DIR);
+    }
+
+    /// Move the pending image to `dir` in the images directory and go back to awaiting a new one
+    pub fn set_aside_pending(&mut self, dir: &str) {
+        if let Some(filename) = self.filename.take() {
+            let target_dir = self.paths.images.join(dir);
+            let target = target_dir.join(&filename);
+            let moved = create_dir_all(&target_dir)
+                .and_then(|_| rename(self.paths.images.join(&filename), &target));
+            match moved {
+                Ok(()) => eprintln!("Moved {} to {}", filename, target.display()),
+                Err(e) => eprintln!("Unable to move {} to {}: {}", filename, target.display(), e),
+            }
+            // Whichever format it's in, as that may have changed since the image was made
+            for &extension in archive_copy::EXTENSIONS {
+                let copy = Path::new(&filename).with_extension(extension);
+                let source = self.paths.images.join(&copy);
+                if source.exists() {
+                    if let Err(e) = rename(&source, target_dir.join(&copy)) {
+                        eprintln!("Unable to move {} to {}: {}", copy.display(), dir, e);
+                    }
+                }
+            }
+            if !self.attempts.is_empty() {
+                self.attempts.write(&target_dir, &filename);
+            }
+        }
+        self.attempts = AttemptLog::default();
+        if let Some(heightmap) = self.heightmap.take() {
+            let target = self.paths.images.join(dir).join(&heightmap);
+            if let Err(e) = rename(self.heightmap_path(&heightmap), &target) {
+                eprintln!("Unable to move {} to {}: {}", heightmap, target.display(), e);
+            }
+        }
+
+        self.phase = Phase::Awaiting;
+        self.description = None;
+        self.name = None;
+        self.regenerations = 0;
+        self.fingerprint = None;
+        self.params = None;
+        self.stats = None;
+        self.locale = None;
+        self.milestone = false;
+        self.gap_notice = None;
+        self.generated_at = None;
+        self.approval_requested = None;
+        self.given_up_on.clear();
+        self.progress.clear();
+    }
+
+    /// Hold the pending image until it is approved, writing out what would be posted
+    pub fn request_approval(&mut self, config: &BotConfig) {
+        let now = Utc::now();
+        self.phase = Phase::AwaitingApproval;
+        self.approval_requested = Some(now);
+
+        if let Some(ref filename) = self.filename {
+            let files = ApprovalFiles::new(&self.paths.images, filename);
+            let pending = PendingPost {
+                id: self.id,
+                image: filename,
+                body: self.post_body(config),
+                alt_text: self.alt_text(config),
+                requested: now,
+            };
+            match files.write_pending(&pending) {
+                Ok(()) => eprintln!("Wrote {}, awaiting approval", files.pending.display()),
+                Err(e) => eprintln!("Unable to write {}: {}", files.pending.display(), e),
+            }
+        }
+    }
+
+    /// Wait for the pending image to be approved or rejected, and act on that
+    ///
+    /// A rejected image is moved out of the way like a stale one, which has the next image
+    /// generated right away.
+    pub fn await_approval(
+        &mut self,
+        config: &BotConfig,
+        shutdown: &Shutdown,
+    ) -> Result<Decision, Cancelled> {
+        let filename = self
+            .filename
+            .clone()
+            .expect("Image awaiting approval has no filename");
+        let files = ApprovalFiles::new(&self.paths.images, &filename);
+
+        let decision = if config.approval_required {
+            let requested = self.approval_requested.unwrap_or_else(Utc::now);
+            approval::wait(&files, config, requested, shutdown)?
+        } else {
+            eprintln!("approval_required was turned off, posting {} without it", filename);
+            Decision::Approve
+        };
+
+        match decision {
+            Decision::Approve => {
+                eprintln!("{} approved", filename);
+                self.phase = Phase::Generated;
+                self.approval_requested = None;
+            }
+            Decision::Reject => {
+                eprintln!("{} rejected, generating a new image", filename);
+                self.discard_pending();
+            }
+        }
+        files.clean_up();
+        self.persist().expect("Unable to persist state");
+        Ok(decision)
+    }
+
+    /// Alt text for the pending image
+    pub fn alt_text(&self, config: &BotConfig) -> String {
+        self.alt_text_for(config, None)
+    }
+
+    /// Alt text for the pending image, as changed for a backend by `overrides`
+    pub fn alt_text_for(&self, config: &BotConfig, overrides: Option<&BackendOverrides>) -> String {
+        let template = overrides
+            .and_then(|overrides| overrides.alt_text.as_ref())
+            .or_else(|| self.locale(config).and_then(|locale| locale.alt_text.as_ref()))
+            .unwrap_or(&config.alt_text);
+        self.fill_text(template, config)
+    }
+
+    /// Fill out a post body or alt text template for the pending image
+    ///
+    /// Unknown placeholders are refused when the config is loaded. A known one the image has no
+    /// value for, like a statistic from a state file older than statistics, doesn't hold up the
+    /// post: it is replaced with `placeholder_fallback`, with a warning.
+    pub fn fill_text(&self, template: &str, config: &BotConfig) -> String {
+        fill_template(template, |name| {
+            let value = match name {
+                "description" => self.description.clone(),
+                "name" => self.name.clone(),
+                "id" => Some(self.id.to_string()),
+                _ if STATS_PLACEHOLDERS.contains(&name) => {
+                    self.stats.as_ref().and_then(|stats| stats.placeholder(name))
+                }
+                _ => return None,
+            };
+            Some(value.unwrap_or_else(|| {
+                eprintln!(
+                    "WARNING: Image {} has no value for {{{}}}, using {:?} instead",
+                    self.id, name, config.placeholder_fallback
+                );
+                config.placeholder_fallback.clone()
+            }))
+        })
+    }
+
+    /// Put together the post for the pending image, `image`
+    pub fn draft_post(&self, config: &BotConfig, image: Arc<[u8]>) -> Post {
+        let attachments = self.attachments(config, &image);
+        Post {
+            id: self.id,
+            body: self.post_body(config),
+            alt_text: self.alt_text(config),
+            alt_params: if config.alt_text_params {
+                self.params.as_ref().map(GenerationParams::summary_line)
+            } else {
+                None
+            },
+            image,
+            format: self.image_format(),
+            file: if config.low_memory {
+                self.filename.as_ref().map(|name| self.paths.images.join(name))
+            } else {
+                None
+            },
+            params: self.params.clone(),
+            language: self.locale(config).map(|locale| locale.language.clone()),
+            name: self.name.clone(),
+            regenerations: self.regenerations,
+            in_reply_to: None,
+            attachments,
+            poll: config.poll.clone(),
+            public: self.milestone(config).map_or(false, |milestone| milestone.public),
+        }
+    }
+
+    /// `post` as `backend` is to get it, if the config changes it for that backend
+    pub fn adapt_post(&self, post: &Post, config: &BotConfig, backend: &str) -> Option<Post> {
+        let overrides = config.backends.get(backend)?;
+        let mut adapted = post.clone();
+        if overrides.changes_text() {
+            adapted.body = self.post_body_for(config, Some(overrides));
+            adapted.alt_text = self.alt_text_for(config, Some(overrides));
+        }
+        if let Some(public) = overrides.public {
+            adapted.public = public;
+        }
+        if overrides.still && adapted.format == ImageFormat::Gif {
+            match first_frame(&adapted.image) {
+                Ok(still) => {
+                    adapted.image = still.into();
+                    adapted.format = ImageFormat::Png;
+                    adapted.file = None;
+                }
+                Err(e) => eprintln!(
+                    "WARNING: Unable to make a still for {}, posting the animation: {}",
+                    backend, e
+                ),
+            }
+        }
+        Some(adapted)
+    }
+
+    /// Images to attach after the pending `image`: its heightmap, then its high-contrast
+    /// variant
+    ///
+    /// Attachments which can't be read or made are left out with a warning, as the post is fine
+    /// without.
+    pub fn attachments(&self, config: &BotConfig, image: &[u8]) -> Vec<Attachment> {
+        let mut attachments = Vec::new();
+
+        if let (Some(heightmap), Some(filename)) = (&config.heightmap, &self.heightmap) {
+            let path = self.heightmap_path(filename);
+            match read(&path) {
+                Ok(data) => attachments.push(Attachment {
+                    image: data.into(),
+                    format: ImageFormat::Png,
+                    alt_text: heightmap.alt_text.clone(),
+                }),
+                Err(e) => eprintln!(
+                    "WARNING: Unable to read heightmap {}, posting without it: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        if let Some(ref contrast) = config.contrast {
+            match contrast.render(image) {
+                Ok(data) => attachments.push(Attachment {
+                    image: data.into(),
+                    format: ImageFormat::Png,
+                    alt_text: contrast.alt_text.clone(),
+                }),
+                Err(e) => eprintln!(
+                    "WARNING: Unable to make the high-contrast variant, posting without it: {}",
+                    e
+                ),
+            }
+        }
+
+        attachments
+    }
+
+    /// Where the heightmap called `filename` is kept
+    pub fn heightmap_path(&self, filename: &str) -> PathBuf {
+        self.paths.images.join(HEIGHTMAPS_DIR).join(filename)
+    }
+
+    /// Run `hook` for the pending image, if hooks are configured, returning whether it succeeded
+    pub fn run_hook(&self, hooks: Option<&HooksConfig>, hook: Hook, error: Option<&str>) -> bool {
+        hooks.map_or(true, |hooks| hooks.run(hook, &self.hook_context(error)))
+    }
+
+    /// Whether the `pre_post` hook, if any, lets a posting attempt go ahead
+    pub fn pre_post_allows(&self, hooks: Option<&HooksConfig>) -> bool {
+        hooks.map_or(true, |hooks| hooks.allows_posting(&self.hook_context(None)))
+    }
+
+    /// Whether `poster` is done with the pending image, either by posting it or by failing
+    /// permanently
+    pub fn is_done_with(&self, poster: &dyn Poster) -> bool {
+        self.posted_to
+            .iter()
+            .chain(self.given_up_on.iter())
+            .any(|name| name == poster.name())
+    }
+
+    /// Whether every one of `posters` gave up on the pending image without it being posted
+    /// anywhere, so that retrying would never post it
+    pub fn given_up_everywhere(&self, posters: &[Arc<dyn Poster>]) -> bool {
+        self.posted_to.is_empty() && posters.iter().all(|poster| self.is_done_with(poster.as_ref()))
+    }
+
+    /// Move the pending image to quarantine once every backend gave up on it, returning whether
+    /// it was
+    ///
+    /// Such an image would otherwise be retried forever, as no backend takes it any more. The
+    /// new image keeps the id, as this one was never posted.
+    pub fn quarantine_if_given_up(&mut self, posters: &[Arc<dyn Poster>]) -> bool {
+        if !self.given_up_everywhere(posters) {
+            return false;
+        }
+        eprintln!("Every backend gave up on image {}, moving it to quarantine", self.id);
+        self.quarantine_pending();
+        self.persist().expect("Unable to persist state");
+        true
+    }
+
+    /// Status the pending image's Mastodon post is to reply to, see `thread_mode`
+    ///
+    /// The month's root status is posted here if there is none yet. If that fails, this post
+    /// goes out unthreaded, and the next one tries again. The month is the one the post is
+    /// actually made in, so a post retried past the end of a month goes into the new month.
+    pub fn thread_root(&mut self, config: &BotConfig, account: &MastoData) -> Option<String> {
+        let posted = self.posted_to.iter().any(|name| name == "mastodon");
+        if config.thread_mode == ThreadMode::Off || posted {
+            return None;
+        }
+
+        let now = Utc::now();
+        let month = thread::month_of(now);
+        let existing = self
+            .thread_root
+            .as_ref()
+            .filter(|root| root.month == month)
+            .map(|root| root.status_id.clone());
+        let text = thread::root_text(&config.thread_root_text, now);
+        let masto = mastodon_client(account.clone());
+        match thread::root_for(&masto, existing.as_ref().map(String::as_str), &text) {
+            Ok(status_id) => {
+                if existing.as_ref() != Some(&status_id) {
+                    self.thread_root = Some(ThreadRoot {
+                        month,
+                        status_id: status_id.clone(),
+                    });
+                    self.persist().expect("Unable to persist state");
+                }
+                Some(status_id)
+            }
+            Err(e) => {
+                eprintln!("Unable to post the thread root, posting without it: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Event describing the phase the state is currently in
+    pub fn changed_event(&self) -> Event {
+        Event::StateChanged {
+            id: self.id,
+            phase: format!("{:?}", self.phase),
+        }
+    }
+
+    /// Post the pending image to every backend it has not been posted to yet
+    ///
+    /// Returns whether all of them are done, with the image posted to at least one. Each outcome
+    /// is persisted right away, so that a retry only goes to the backends that failed. Backends
+    /// which fail in a way that retrying won't fix are given up on for this image, and if that's
+    /// all of them, this is an error, see `quarantine_if_given_up`.
+    pub fn post_everywhere(
+        &mut self,
+        posters: &[Arc<dyn Poster>],
+        post: &Post,
+        attempt: usize,
+        config: &BotConfig,
+        events: &EventLog,
+    ) -> Result<(), String> {
+        let mut failures = Vec::new();
+
+        for poster in posters {
+            if self.is_done_with(poster.as_ref()) {
+                continue;
+            }
+
+            let mut progress = self
+                .progress
+                .get(poster.name())
+                .cloned()
+                .unwrap_or_default();
+            let adapted = self.adapt_post(post, config, poster.name());
+            if let Some(ref adapted) = adapted {
+                log_draft(adapted, Some(poster.name()), config, events);
+            }
+            let post = adapted.as_ref().unwrap_or(post);
+            let limit = StdDuration::from_secs(config.max_attempt_secs);
+            let result = if chaos_strikes(config, "upload") {
+                Err(PostingError::Rejected {
+                    backend: poster.name().to_string(),
+                    status: 503,
+                    message: CHAOS_MESSAGE.to_string(),
+                })
+            } else {
+                post_within(poster, post, &mut progress, limit)
+            };
+            if let Some(upload) = progress.last_upload.take() {
+                events.emit(Event::MediaUploaded {
+                    id: self.id,
+                    backend: poster.name().to_string(),
+                    bytes: upload.bytes,
+                    duration_ms: upload.duration.as_millis() as u64,
+                });
+            }
+            if progress.varied_duplicate {
+                progress.varied_duplicate = false;
+                events.emit(Event::DuplicateStatus {
+                    id: self.id,
+                    backend: poster.name().to_string(),
+                });
+            }
+            events.emit(upload_attempt_event(self, poster.name(), attempt, &result));
+            if let (&Ok(()), Some(receipt)) = (&result, progress.receipt.as_ref()) {
+                eprintln!("New status posted to {} at: {}", poster.name(), receipt.location());
+            }
+
+            if result.is_err() {
+                progress.failures += 1;
+            }
+            self.progress.insert(poster.name().to_string(), progress);
+
+            match result {
+                Ok(()) => {
+                    self.posted_to.push(poster.name().to_string());
+                    self.persist().expect("Unable to persist state");
+                }
+                Err(ref e) if !e.is_transient() => {
+                    eprintln!(
+                        "Failed to post to {}, giving up on it for this image: {}",
+                        poster.name(),
+                        e
+                    );
+                    self.given_up_on.push(poster.name().to_string());
+                    self.persist().expect("Unable to persist state");
+                }
+                Err(e) => {
+                    eprintln!("Failed to post to {}: {}", poster.name(), e);
+                    failures.push(format!("{}: {}", poster.name(), e));
+                    self.persist().expect("Unable to persist state");
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            Err(failures.join("; "))
+        } else if self.posted_to.is_empty() {
+            Err("every backend gave up on the image".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// What Mastodon said about posting the pending image, once it's posted there
+    pub fn receipt(&self) -> Option<&PostReceipt> {
+        self.progress
+            .get("mastodon")
+            .and_then(|progress| progress.receipt.as_ref())
+    }
+
+    /// What hooks are told about the pending image, with `error` if the hook is about a failure
+    pub fn hook_context<'a>(&'a self, error: Option<&'a str>) -> HookContext<'a> {
+        HookContext {
+            image_id: self.id,
+            image_path: self.filename.as_ref().map(|name| self.paths.images.join(name)),
+            status_url: self.receipt().and_then(PostReceipt::link),
+            error,
+        }
+    }
+}
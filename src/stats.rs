@@ -0,0 +1,111 @@
+//! Statistics about a generated map
+//!
+//! Worked out from the map's blocks rather than the rendered image, so they don't depend on the
+//! tileset or on what the background and overlay do to the picture.
+
+use cubeglobe::map::{Block, Map};
+
+/// Placeholders text templates can use to show map statistics
+pub const STATS_PLACEHOLDERS: &[&str] = &["water_pct", "min_height", "max_height", "mean_height"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MapStats {
+    /// Share of columns with water on top, from 0 to 100
+    pub water_pct: f64,
+    /// Height of the lowest column, in blocks. Empty columns count as 0.
+    pub min_height: usize,
+    /// Height of the highest column, in blocks
+    pub max_height: usize,
+    pub mean_height: f64,
+    /// Number of different kinds of block in the map, not counting empty space
+    pub block_types: usize,
+}
+
+impl MapStats {
+    /// Work out the statistics for `map`
+    pub fn of(map: &Map) -> MapStats {
+        let (len_x, len_y, len_z) = (map.len_x(), map.len_y(), map.len_z());
+        // A fresh map is all empty space, which saves relying on the name of that block
+        let empty = Map::new(1, 1, 1).get(0, 0, 0);
+
+        let mut water_columns = 0;
+        let mut min_height = usize::max_value();
+        let mut max_height = 0;
+        let mut total_height = 0;
+        let mut block_types: Vec<Block> = Vec::new();
+
+        for x in 0..len_x {
+            for y in 0..len_y {
+                let mut height = 0;
+                for z in 0..len_z {
+                    let block = map.get(x, y, z);
+                    if block == empty {
+                        continue;
+                    }
+                    height = z + 1;
+                    if !block_types.contains(&block) {
+                        block_types.push(block);
+                    }
+                }
+
+                if height > 0 && map.get(x, y, height - 1) == Block::Water {
+                    water_columns += 1;
+                }
+                min_height = min_height.min(height);
+                max_height = max_height.max(height);
+                total_height += height;
+            }
+        }
+
+        let columns = len_x * len_y;
+        if columns == 0 {
+            return MapStats {
+                water_pct: 0.0,
+                min_height: 0,
+                max_height: 0,
+                mean_height: 0.0,
+                block_types: 0,
+            };
+        }
+
+        MapStats {
+            water_pct: water_columns as f64 * 100.0 / columns as f64,
+            min_height,
+            max_height,
+            mean_height: total_height as f64 / columns as f64,
+            block_types: block_types.len(),
+        }
+    }
+
+    /// Value for one of the `STATS_PLACEHOLDERS`
+    pub fn placeholder(&self, name: &str) -> Option<String> {
+        match name {
+            "water_pct" => Some(format!("{:.0}", self.water_pct)),
+            "min_height" => Some(self.min_height.to_string()),
+            "max_height" => Some(self.max_height.to_string()),
+            "mean_height" => Some(format!("{:.1}", self.mean_height)),
+            _ => None,
+        }
+    }
+}
+
+/// Height of every column of `map`, in blocks, going along x first, then along y
+///
+/// Counted the same way as for `MapStats`: up to the highest block that isn't empty space, with
+/// empty columns as 0.
+pub fn column_heights(map: &Map) -> Vec<usize> {
+    let (len_x, len_y, len_z) = (map.len_x(), map.len_y(), map.len_z());
+    let empty = Map::new(1, 1, 1).get(0, 0, 0);
+
+    let mut heights = Vec::with_capacity(len_x * len_y);
+    for y in 0..len_y {
+        for x in 0..len_x {
+            let height = (0..len_z)
+                .rev()
+                .find(|&z| map.get(x, y, z) != empty)
+                .map_or(0, |z| z + 1);
+            heights.push(height);
+        }
+    }
+    heights
+}
@@ -0,0 +1,305 @@
+//! Helpers shared by the tests
+//!
+//! `MockServer` stands in for an instance or webhook receiver: an HTTP server on localhost
+//! answering each connection with the next canned response, and handing back what it received.
+//!
+//! `CountingAllocator` is the tests' global allocator, counting what each thread has allocated,
+//! so that `peak_allocation` can measure a test's memory use while others run alongside it.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use serde_json::Value;
+
+use posting::{ImageFormat, Post};
+
+/// A request the mock server received
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Headers, with lowercased names
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers
+            .iter()
+            .find(|header| header.0 == name)
+            .map(|header| header.1.as_str())
+    }
+
+    /// Parts of a `multipart/form-data` body, by name
+    pub fn parts(&self) -> Vec<(String, Vec<u8>)> {
+        let boundary = self
+            .header("content-type")
+            .and_then(|value| value.split("boundary=").nth(1))
+            .map(|boundary| boundary.trim_matches('"').to_string())
+            .expect("Request is not multipart");
+        let delimiter = format!("--{}", boundary).into_bytes();
+
+        let mut positions = Vec::new();
+        let mut i = 0;
+        while i + delimiter.len() <= self.body.len() {
+            if self.body[i..].starts_with(&delimiter) {
+                positions.push(i);
+                i += delimiter.len();
+            } else {
+                i += 1;
+            }
+        }
+
+        let mut parts = Vec::new();
+        for window in positions.windows(2) {
+            // Past the delimiter and its CRLF, up to the CRLF before the next delimiter
+            let part = &self.body[window[0] + delimiter.len() + 2..window[1] - 2];
+            let split = part
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .expect("Part without headers");
+            let headers = String::from_utf8_lossy(&part[..split]);
+            let name = headers
+                .split("name=\"")
+                .nth(1)
+                .and_then(|rest| rest.split('"').next())
+                .unwrap_or("")
+                .to_string();
+            parts.push((name, part[split + 4..].to_vec()));
+        }
+        parts
+    }
+
+    /// Content of the part called `name`
+    pub fn part(&self, name: &str) -> Vec<u8> {
+        self.parts()
+            .into_iter()
+            .find(|part| part.0 == name)
+            .map(|part| part.1)
+            .unwrap_or_else(|| panic!("No {} part", name))
+    }
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Request {
+    let mut line = String::new();
+    reader.read_line(&mut line).expect("Unable to read request");
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().unwrap_or("").to_string();
+    let path = request_line.next().unwrap_or("").to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).expect("Unable to read header");
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let mut split = header.splitn(2, ':');
+        let name = split.next().unwrap_or("").trim().to_lowercase();
+        let value = split.next().unwrap_or("").trim().to_string();
+        headers.push((name, value));
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    if let Some(length) = request.header("content-length").and_then(|n| n.parse().ok()) {
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).expect("Unable to read body");
+    } else if request.header("transfer-encoding") == Some("chunked") {
+        loop {
+            line.clear();
+            reader.read_line(&mut line).expect("Unable to read chunk size");
+            let size = usize::from_str_radix(line.trim(), 16).expect("Invalid chunk size");
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).expect("Unable to read chunk");
+            if size == 0 {
+                break;
+            }
+            request.body.extend_from_slice(&chunk[..size]);
+        }
+    }
+    request
+}
+
+/// An HTTP server on localhost, answering one request per connection
+pub struct MockServer {
+    /// Where it listens, like `http://127.0.0.1:12345`
+    pub url: String,
+    handle: JoinHandle<Vec<Request>>,
+}
+
+impl MockServer {
+    /// Answer the next requests with `responses` in turn, as a status and a JSON body each
+    pub fn start(responses: Vec<(u16, String)>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to listen");
+        let url = format!("http://{}", listener.local_addr().expect("No local address"));
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().expect("Unable to accept");
+                let mut reader = BufReader::new(stream.try_clone().expect("Unable to clone"));
+                requests.push(read_request(&mut reader));
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                ).expect("Unable to respond");
+            }
+            requests
+        });
+        MockServer { url, handle }
+    }
+
+    /// What the server received, once it has answered every response
+    pub fn requests(self) -> Vec<Request> {
+        self.handle.join().expect("Mock server panicked")
+    }
+}
+
+/// The system allocator, counting the bytes each thread has allocated and not yet freed
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = Cell::new(0);
+    static PEAK: Cell<isize> = Cell::new(0);
+}
+
+/// Count `change` bytes allocated, or freed if negative, by the current thread
+///
+/// Memory can be freed on another thread than it was allocated on, so the count can go below
+/// zero. Threads already tearing down their thread locals aren't counted.
+fn count(change: isize) {
+    let _ = ALLOCATED.try_with(|allocated| {
+        let now = allocated.get() + change;
+        allocated.set(now);
+        let _ = PEAK.try_with(|peak| {
+            if now > peak.get() {
+                peak.set(now);
+            }
+        });
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        count(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            count(new_size as isize - layout.size() as isize);
+        }
+        new
+    }
+}
+
+/// Run `f`, returning what it returned and the most it had allocated at once on this thread,
+/// beyond what was allocated before
+///
+/// Memory C libraries allocate themselves, like SDL's surfaces, goes uncounted.
+pub fn peak_allocation<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+    let start = ALLOCATED.with(Cell::get);
+    PEAK.with(|peak| peak.set(start));
+    let result = f();
+    let peak = PEAK.with(Cell::get);
+    (result, (peak - start) as usize)
+}
+
+/// An uploaded image as the Mastodon API describes it
+pub fn attachment_json(id: &str, description: &str) -> String {
+    format!(
+        r#"{{"id": "{id}", "type": "image", "url": "https://files.example.org/{id}.png",
+            "preview_url": "https://files.example.org/{id}-small.png", "remote_url": null,
+            "text_url": null, "meta": {{"original": {{"width": 640, "height": 480}}}},
+            "description": {description}}}"#,
+        id = id,
+        description = Value::String(description.to_string()),
+    )
+}
+
+/// The bot's account as the Mastodon API describes it
+pub fn account_json() -> String {
+    r#"{
+        "id": "1", "username": "cubeglobe", "acct": "cubeglobe",
+        "display_name": "cubeglobe", "locked": false, "bot": true,
+        "created_at": "2024-01-01T00:00:00.000Z", "followers_count": 0,
+        "following_count": 0, "statuses_count": 1, "note": "",
+        "url": "https://example.org/@cubeglobe",
+        "avatar": "https://example.org/avatar.png",
+        "avatar_static": "https://example.org/avatar.png",
+        "header": "https://example.org/header.png",
+        "header_static": "https://example.org/header.png"
+    }"#
+    .to_string()
+}
+
+/// A status as the Mastodon API describes it, with `attachments` from `attachment_json`
+pub fn status_json(id: &str, uri: &str, url: Option<&str>, attachments: &[String]) -> String {
+    format!(
+        r#"{{"id": "{id}", "uri": "{uri}", "url": {url}, "account": {account},
+            "in_reply_to_id": null, "in_reply_to_account_id": null, "reblog": null,
+            "content": "<p>Landscape</p>", "created_at": "2024-05-01T12:00:00.000Z",
+            "emojis": [], "replies_count": 0, "reblogs_count": 0, "favourites_count": 0,
+            "reblogged": false, "favourited": false, "sensitive": false, "spoiler_text": "",
+            "visibility": "public", "media_attachments": [{attachments}], "mentions": [],
+            "tags": [], "card": null, "application": null, "language": null, "pinned": null}}"#,
+        id = id,
+        uri = uri,
+        account = account_json(),
+        url = url.map_or(Value::Null, |url| Value::String(url.to_string())),
+        attachments = attachments.join(", "),
+    )
+}
+
+/// A post of a small PNG-like image, with nothing optional set
+pub fn post(id: u32) -> Post {
+    let image: Arc<[u8]> = b"\x89PNG\r\n\x1a\nnot really an image"[..].into();
+    Post {
+        id,
+        body: format!("Landscape {}", id),
+        alt_text: "An isometric landscape".to_string(),
+        alt_params: None,
+        image,
+        format: ImageFormat::Png,
+        file: None,
+        params: None,
+        language: None,
+        name: None,
+        regenerations: 0,
+        in_reply_to: None,
+        attachments: Vec::new(),
+        poll: None,
+        public: false,
+    }
+}
@@ -0,0 +1,79 @@
+//! Monthly threads
+//!
+//! With `thread_mode = "monthly"`, the first post of each month starts with a root status of its
+//! own, and every post that month replies to it, so the month's images read as one thread. The
+//! root is only posted to the main Mastodon account; posts from the fallback account, and to
+//! other backends, are not threaded.
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use elefren::status_builder::Visibility;
+use elefren::{self, Mastodon, MastodonClient, StatusBuilder};
+use reqwest::StatusCode;
+
+use fill_template;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadMode {
+    Off,
+    Monthly,
+}
+
+impl Default for ThreadMode {
+    fn default() -> ThreadMode {
+        ThreadMode::Off
+    }
+}
+
+/// Root status of a month's thread
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ThreadRoot {
+    /// Month, as `YYYY-MM`
+    pub month: String,
+    pub status_id: String,
+}
+
+/// Month `now` is in, as `YYYY-MM`
+pub fn month_of(now: DateTime<Utc>) -> String {
+    now.format("%Y-%m").to_string()
+}
+
+/// Text of the root status for the month `now` is in
+///
+/// {month} is replaced with the month's name and year, like "June 2024".
+pub fn root_text(template: &str, now: DateTime<Utc>) -> String {
+    fill_template(template, |name| match name {
+        "month" => Some(now.format("%B %Y").to_string()),
+        _ => None,
+    })
+}
+
+/// Id of the month's root status, posting it with `text` first if there is none
+///
+/// `existing` is the root posted earlier in the month, if any. It is looked up first, and posted
+/// again if a moderator or the account itself deleted it. If it can't be looked up for any other
+/// reason, it is assumed to still be there.
+pub fn root_for(masto: &Mastodon, existing: Option<&str>, text: &str) -> Result<String, Error> {
+    if let Some(status_id) = existing {
+        match masto.get_status(status_id) {
+            Ok(_) => return Ok(status_id.to_string()),
+            Err(elefren::Error::Client(StatusCode::NOT_FOUND)) => {
+                eprintln!("Thread root {} was deleted, posting a new one", status_id)
+            }
+            Err(e) => {
+                eprintln!("Unable to look up thread root {}, using it anyway: {}", status_id, e);
+                return Ok(status_id.to_string());
+            }
+        }
+    }
+
+    let status = masto.new_status(
+        StatusBuilder::new()
+            .status(text)
+            .visibility(Visibility::Public)
+            .build()?,
+    )?;
+    eprintln!("Posted thread root at: {}", status.uri);
+    Ok(status.id)
+}
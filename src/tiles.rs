@@ -0,0 +1,109 @@
+//! Tiles config checks
+//!
+//! cubeglobe doesn't say which entry of a tiles config it had trouble with, so before handing a
+//! config over, we look through it ourselves: that it parses, and that every image file it names
+//! exists and can be decoded. All problems found are reported together, by key.
+
+use std::fs::read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use cubeglobe::renderer::Renderer;
+use image;
+use toml::{self, Value};
+
+use bmp;
+
+/// Extensions of values taken to name image files
+const IMAGE_EXTENSIONS: &[&str] = &["png", "bmp", "gif", "jpg", "jpeg"];
+
+/// Check `tiles_config`, read from `path`, and set up a renderer with it
+pub fn load_renderer(path: &Path, tiles_config: &str) -> Result<Renderer, Error> {
+    let problems = check(tiles_config);
+    if !problems.is_empty() {
+        return Err(Error::msg(format!(
+            "tiles config {} has problems:\n  {}",
+            path.display(),
+            problems.join("\n  ")
+        )));
+    }
+
+    Renderer::from_config_str(tiles_config).map_err(|e| {
+        Error::msg(format!(
+            "problem initializing renderer from {}: {:?}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Find everything wrong with `tiles_config` that we know to look for
+fn check(tiles_config: &str) -> Vec<String> {
+    // TOML errors say which line they are on
+    let root: Value = match toml::from_str(tiles_config) {
+        Ok(root) => root,
+        Err(e) => return vec![e.to_string()],
+    };
+
+    let assets = root
+        .get("assets_path")
+        .and_then(Value::as_str)
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    let mut problems = Vec::new();
+    check_images(&root, &assets, "", &mut problems);
+    problems
+}
+
+/// Check every image file named anywhere under `value`, which is at `key`
+fn check_images(value: &Value, assets: &Path, key: &str, problems: &mut Vec<String>) {
+    match *value {
+        Value::String(ref name) if is_image_name(name) => {
+            let path = assets.join(name);
+            if !path.is_file() {
+                problems.push(format!("{}: {} does not exist", key, path.display()));
+            } else if let Err(e) = decode(&path) {
+                problems.push(format!("{}: unable to load {}: {}", key, path.display(), e));
+            }
+        }
+        Value::Table(ref table) => {
+            for (name, value) in table {
+                let key = if key.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", key, name)
+                };
+                check_images(value, assets, &key, problems);
+            }
+        }
+        Value::Array(ref values) => {
+            for (i, value) in values.iter().enumerate() {
+                check_images(value, assets, &format!("{}[{}]", key, i), problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Decode the image at `path`, BMPs with our own decoder, as the `image` crate is built without
+fn decode(path: &Path) -> Result<(), Error> {
+    let is_bmp = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("bmp"));
+    if is_bmp {
+        bmp::decode(&read(path)?)?;
+    } else {
+        image::open(path)?;
+    }
+    Ok(())
+}
+
+fn is_image_name(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| {
+            IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+        })
+}
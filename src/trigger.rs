@@ -0,0 +1,131 @@
+//! Posting right away on request
+//!
+//! `kill -USR1` on the bot, or `cubeglobe-bot trigger`, has it stop waiting and make the next
+//! post right away, like `--immediate` but without a restart. The subcommand works without
+//! signals too: it creates an empty file next to the state file, named like it with `.trigger`
+//! added, which the bot checks for while it waits and removes once it's seen.
+//!
+//! Only the wait for the next post is cut short. A request made while a post is already under
+//! way, being generated, posted or retried, is ignored with a log line once that's done, rather
+//! than having another post follow right after it.
+//!
+//! The schedule then counts from the requested post, as from any other. With
+//! `trigger_keeps_schedule`, it keeps counting from where it did before, so the next post is due
+//! when it would have been anyway.
+
+use std::ffi::OsString;
+use std::fs::{remove_file, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Error;
+use clap::{App, SubCommand};
+
+use {BotConfig, StatePaths};
+
+/// Requests to post now, by signal or by file
+pub struct Trigger {
+    requested: Arc<AtomicBool>,
+    file: PathBuf,
+}
+
+impl Trigger {
+    /// Watch for SIGUSR1, on Unix, and for the trigger file of the state file at `state`
+    pub fn register(state: &Path) -> Trigger {
+        let requested = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::SIGUSR1, Arc::clone(&requested))
+            .expect("Unable to set up SIGUSR1 handler");
+        Trigger {
+            requested,
+            file: path(state),
+        }
+    }
+
+    /// Whether posting now was asked for since the last check, forgetting the request
+    pub fn take(&self) -> bool {
+        let signalled = self.requested.swap(false, Ordering::SeqCst);
+        // Removing it is the check, so a file created in between isn't lost
+        let touched = remove_file(&self.file).is_ok();
+        signalled || touched
+    }
+
+    /// Forget a request which came while a post was under way
+    pub fn ignore_pending(&self) {
+        if self.take() {
+            eprintln!("Ignoring a request to post now, which came while a post was being made");
+        }
+    }
+}
+
+/// Where the trigger file for the state file at `state` goes
+pub fn path(state: &Path) -> PathBuf {
+    let mut name = state
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_else(|| OsString::from("state"));
+    name.push(".trigger");
+    state.with_file_name(name)
+}
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("trigger")
+        .about("have the running bot stop waiting and post right away")
+}
+
+/// Create the trigger file for the running bot to find
+pub fn run(config: &BotConfig) -> Result<(), Error> {
+    let path = path(&StatePaths::from_config(config).state);
+    File::create(&path)?;
+    println!("Created {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    /// A trigger on the state file in `dir`, without the signal handler
+    fn trigger(dir: &Path) -> Trigger {
+        Trigger {
+            requested: Arc::new(AtomicBool::new(false)),
+            file: path(&dir.join("state")),
+        }
+    }
+
+    #[test]
+    fn named_after_the_state_file() {
+        assert_eq!(
+            path(Path::new("/var/lib/bot/state-cubes")),
+            PathBuf::from("/var/lib/bot/state-cubes.trigger")
+        );
+    }
+
+    #[test]
+    fn signal_or_file_is_taken_once() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let trigger = trigger(dir.path());
+        assert!(!trigger.take());
+
+        trigger.requested.store(true, Ordering::SeqCst);
+        assert!(trigger.take());
+        assert!(!trigger.take());
+
+        File::create(&trigger.file).expect("Unable to create trigger file");
+        assert!(trigger.take());
+        assert!(!trigger.file.exists());
+        assert!(!trigger.take());
+    }
+
+    #[test]
+    fn requests_during_a_post_are_dropped() {
+        let dir = tempfile::tempdir().expect("Unable to create temporary directory");
+        let trigger = trigger(dir.path());
+        trigger.requested.store(true, Ordering::SeqCst);
+        File::create(&trigger.file).expect("Unable to create trigger file");
+        trigger.ignore_pending();
+        assert!(!trigger.take());
+    }
+}
@@ -0,0 +1,230 @@
+//! Generic webhook backend
+//!
+//! POSTs the image as `multipart/form-data` to a configured URL, for wiring the bot into things
+//! it does not know about itself. The request has two parts:
+//!
+//! * `metadata`: JSON, see `Metadata`
+//! * `image`: the PNG, or GIF if animations are enabled
+//!
+//! If a secret is configured, the `X-Cubeglobe-Signature` header carries `sha256=<hex HMAC>`, so
+//! the receiver can check the request came from us. The HMAC-SHA256 is over the length of the
+//! metadata part as 8 big-endian bytes, the metadata part, the length of the image part the same
+//! way, and the image part. With the lengths in, no bytes can be moved from one part to the other
+//! without changing the signature. See `examples/webhook_receiver.rs` for a receiver.
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::multipart::{Form, Part};
+use reqwest::Client;
+use serde_json;
+use sha2::Sha256;
+
+use build_info::{self, BuildInfo};
+use errors::PostingError;
+use posting::{Post, Poster, Progress};
+use GenerationParams;
+
+const BACKEND_NAME: &str = "webhook";
+const SIGNATURE_HEADER: &str = "X-Cubeglobe-Signature";
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Sent as `Authorization: Bearer <token>`, if set
+    pub bearer_token: Option<String>,
+    /// Key for the signature header, if set
+    pub secret: Option<String>,
+}
+
+/// Contents of the `metadata` part
+#[derive(Serialize, Deserialize)]
+pub struct Metadata {
+    pub id: u32,
+    pub body: String,
+    pub alt_text: String,
+    /// Name of the landscape
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Times the image was generated again before it turned out
+    #[serde(default)]
+    pub regenerations: u32,
+    pub params: Option<GenerationParams>,
+    pub timestamp: DateTime<Utc>,
+    /// Build of the bot which sent the request
+    #[serde(default)]
+    pub build: Option<BuildInfo>,
+}
+
+pub struct WebhookPoster {
+    config: WebhookConfig,
+    client: Client,
+}
+
+impl WebhookPoster {
+    pub fn new(config: WebhookConfig) -> WebhookPoster {
+        WebhookPoster {
+            config,
+            client: build_info::http_client(),
+        }
+    }
+}
+
+impl Poster for WebhookPoster {
+    fn name(&self) -> &str {
+        BACKEND_NAME
+    }
+
+    fn post(&self, post: &Post, _progress: &mut Progress) -> Result<(), PostingError> {
+        let metadata = serde_json::to_string(&Metadata {
+            id: post.id,
+            body: post.body.clone(),
+            alt_text: post.alt_text.clone(),
+            name: post.name.clone(),
+            regenerations: post.regenerations,
+            params: post.params.clone(),
+            timestamp: Utc::now(),
+            build: Some(BuildInfo::current()),
+        }).expect("Unable to serialize webhook metadata");
+
+        let signature = self
+            .config
+            .secret
+            .as_ref()
+            .map(|secret| sign(secret.as_bytes(), metadata.as_bytes(), &post.image));
+
+        let form = Form::new()
+            .part(
+                "metadata",
+                Part::text(metadata).mime_str("application/json")?,
+            ).part(
+                "image",
+                Part::bytes(post.image.to_vec())
+                    .file_name(format!("{}.{}", post.id, post.format.extension()))
+                    .mime_str(post.format.mimetype())?,
+            );
+
+        let mut request = self.client.post(&self.config.url).multipart(form);
+        if let Some(ref token) = self.config.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(signature) = signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+
+        let mut response = request.send()?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(PostingError::Rejected {
+                backend: BACKEND_NAME.to_string(),
+                status: status.as_u16(),
+                message: response.text().unwrap_or_default(),
+            });
+        }
+
+        eprintln!("Webhook delivered to {}", self.config.url);
+
+        Ok(())
+    }
+}
+
+/// Compute the signature header value for a request
+fn sign(secret: &[u8], metadata: &[u8], image: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any length");
+    for part in &[metadata, image] {
+        mac.input(&(part.len() as u64).to_be_bytes());
+        mac.input(part);
+    }
+
+    let hex: String = mac
+        .result()
+        .code()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{self, MockServer};
+
+    fn poster(url: &str, secret: Option<&str>) -> WebhookPoster {
+        WebhookPoster::new(WebhookConfig {
+            url: format!("{}/hook", url),
+            bearer_token: Some("token".to_string()),
+            secret: secret.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn signature_is_pinned() {
+        assert_eq!(
+            sign(b"hunter2", b"{\"id\":1}", b"image"),
+            "sha256=f08ef2dc5a30901a2a0812fcbbf6f0e79a950ea2770b3bd2336ab88bf6f419e6"
+        );
+    }
+
+    #[test]
+    fn moving_bytes_between_parts_changes_signature() {
+        assert_ne!(sign(b"key", b"ab", b"c"), sign(b"key", b"a", b"bc"));
+        assert_ne!(sign(b"key", b"", b"abc"), sign(b"key", b"abc", b""));
+    }
+
+    #[test]
+    fn posts_signed_multipart() {
+        let server = MockServer::start(vec![(204, String::new())]);
+        let post = test_support::post(7);
+        poster(&server.url, Some("hunter2"))
+            .post(&post, &mut Progress::default())
+            .expect("Webhook refused");
+
+        let requests = server.requests();
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/hook");
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+
+        let metadata = request.part("metadata");
+        let image = request.part("image");
+        assert_eq!(&image[..], &post.image[..]);
+        let parsed: Metadata = serde_json::from_slice(&metadata).expect("Invalid metadata");
+        assert_eq!(parsed.id, 7);
+        assert_eq!(parsed.body, post.body);
+        assert_eq!(
+            request.header(SIGNATURE_HEADER),
+            Some(sign(b"hunter2", &metadata, &image).as_str())
+        );
+    }
+
+    #[test]
+    fn unsigned_without_secret() {
+        let server = MockServer::start(vec![(200, String::new())]);
+        poster(&server.url, None)
+            .post(&test_support::post(1), &mut Progress::default())
+            .expect("Webhook refused");
+        assert_eq!(server.requests()[0].header(SIGNATURE_HEADER), None);
+    }
+
+    #[test]
+    fn error_responses_are_rejections() {
+        let server = MockServer::start(vec![
+            (503, "{\"error\":\"busy\"}".to_string()),
+            (400, "{\"error\":\"bad\"}".to_string()),
+        ]);
+        let poster = poster(&server.url, None);
+        let post = test_support::post(1);
+
+        let busy = poster.post(&post, &mut Progress::default()).unwrap_err();
+        assert!(busy.is_transient());
+        assert_eq!(busy.status(), Some(503));
+
+        let bad = poster.post(&post, &mut Progress::default()).unwrap_err();
+        assert!(!bad.is_transient());
+        match bad {
+            PostingError::Rejected { message, .. } => assert!(message.contains("bad")),
+            other => panic!("Unexpected error: {}", other),
+        }
+        server.requests();
+    }
+}